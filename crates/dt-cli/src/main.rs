@@ -11,8 +11,7 @@ use std::str::FromStr;
 use anyhow::Error;
 use chrono::{DateTime, SecondsFormat, Timelike, Utc};
 use clap::{Parser, Subcommand};
-use rand::distributions::Alphanumeric;
-use rand::{Rng, RngCore};
+use rand::RngCore;
 use serde::Serialize;
 use similar::{ChangeTag, TextDiff};
 use similar::utils::TextDiffRemapper;
@@ -166,9 +165,8 @@ enum Commands {
         patch: bool,
 
         /// Do not store inserted content. This prevents the editing trace being replayed, but an
-        /// oplog with no inserted content can still have changes merged into it.
-        ///
-        /// Note: Support for this in Diamond types is still a work in progress.
+        /// oplog with no inserted content can still have changes merged into it. The content can
+        /// be filled in later by merging a patch which contains it.
         #[arg(long)]
         no_inserted_content: bool,
 
@@ -351,8 +349,10 @@ fn main() -> Result<(), anyhow::Error> {
 
             if let Some(content_file) = content_file {
                 let content = fs::read_to_string(content_file)?;
-                let agent_name = agent.unwrap_or_else(random_agent_name);
-                let agent = oplog.get_or_create_agent_id(&agent_name);
+                let agent = match agent {
+                    Some(name) => oplog.get_or_create_agent_id(&name),
+                    None => oplog.new_session_agent(),
+                };
                 oplog.add_insert(agent, 0, &content);
             }
 
@@ -480,8 +480,10 @@ fn main() -> Result<(), anyhow::Error> {
             let diff = TextDiff::from_chars(&old, &new);
             let remapper = TextDiffRemapper::from_text_diff(&diff, &old, &new);
 
-            let agent_name = agent.unwrap_or_else(random_agent_name);
-            let agent_id = oplog.get_or_create_agent_id(&agent_name);
+            let agent_id = match agent {
+                Some(name) => oplog.get_or_create_agent_id(&name),
+                None => oplog.new_session_agent(),
+            };
 
             let mut pos = 0;
             for (tag, str) in diff.ops().iter()
@@ -737,11 +739,3 @@ fn maybe_overwrite(output: &OsString, new_data: &Vec<u8>, force: bool) -> Result
     file_result?.write_all(&new_data)?;
     Ok(())
 }
-
-fn random_agent_name() -> String {
-    rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(12)
-        .map(char::from)
-        .collect()
-}
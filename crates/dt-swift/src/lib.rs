@@ -1,7 +1,6 @@
 use diamond_types::AgentId;
 use diamond_types::list::{ListCRDT as InnerListCRDT};
 use diamond_types::list::encoding::ENCODE_FULL;
-use rand::{distributions::Alphanumeric, Rng};
 
 #[swift_bridge::bridge]
 mod ffi {
@@ -34,12 +33,7 @@ pub struct ListCRDT {
 
 
 fn create_agent(crdt: &mut InnerListCRDT) -> AgentId {
-    let s: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(8)
-        .map(char::from)
-        .collect();
-    crdt.get_or_create_agent_id(&s)
+    crdt.oplog.new_session_agent()
 }
 // fn get_agent(crdt: &mut InnerListCRDT, agent_name: Option<&str>) -> AgentId {
 //     agent_name.map(|name| {
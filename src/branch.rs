@@ -1,7 +1,9 @@
 use std::collections::{btree_map, BTreeMap, BTreeSet};
+use jumprope::JumpRopeBuf;
 use smallvec::SmallVec;
-use crate::{CRDTKind, DTRange, Branch, OpLog, LV, LVKey, RegisterInfo, RegisterState, RegisterValue, ROOT_CRDT_ID, Primitive};
 use smartstring::alias::String as SmartString;
+use crate::oplog::create_to_snapshot;
+use crate::{CRDTKind, DTRange, Branch, OpLog, LV, LVKey, RegisterInfo, RegisterState, RegisterValue, ROOT_CRDT_ID, Primitive, Frontier};
 
 pub(crate) fn btree_range_for_crdt<V>(map: &BTreeMap<(LVKey, SmartString), V>, crdt: LVKey) -> btree_map::Range<'_, (LVKey, SmartString), V> {
     let empty_str: SmartString = "".into();
@@ -23,18 +25,70 @@ pub(crate) fn btree_range_mut_for_crdt<V>(map: &mut BTreeMap<(LVKey, SmartString
     }
 }
 
+impl RegisterInfo {
+    /// Returns true if any of this register's operations are within the specified range.
+    fn has_op_in_range(&self, range: DTRange) -> bool {
+        let idx = self.ops
+            .binary_search_by_key(&range.start, |e| e.0)
+            .unwrap_or_else(|idx| idx);
+        self.ops.get(idx).is_some_and(|(v, _)| *v < range.end)
+    }
+}
+
 impl RegisterState {
+    /// The state of a register which hasn't been set yet.
+    fn unset() -> Self {
+        Self {
+            version: ROOT_CRDT_ID,
+            value: RegisterValue::Primitive(Primitive::Nil),
+            conflicts_with: vec![],
+        }
+    }
+
+    /// Returns true if the register holds multiple values which were set concurrently.
+    pub fn has_conflicts(&self) -> bool {
+        !self.conflicts_with.is_empty()
+    }
+
+    /// Iterate over all the values in the register, along with the version which set each value.
+    /// The winning value is always yielded first.
+    pub fn values(&self) -> impl Iterator<Item = (LV, &RegisterValue)> + '_ {
+        std::iter::once((self.version, &self.value))
+            .chain(self.conflicts_with.iter().map(|(v, rv)| (*v, rv)))
+    }
+
     fn each_value<F: FnMut(&RegisterValue)>(&self, mut f: F) {
-        f(&self.value);
-        for rv in self.conflicts_with.iter() {
+        for (_, rv) in self.values() {
             f(rv);
         }
     }
+
+    fn owned_crdts(&self) -> SmallVec<[(CRDTKind, LVKey); 2]> {
+        let mut result = SmallVec::new();
+        self.each_value(|v| {
+            if let RegisterValue::OwnedCRDT(kind, crdt) = v {
+                result.push((*kind, *crdt));
+            }
+        });
+        result
+    }
 }
 
 impl OpLog {
-    pub fn checkout_at_version(_frontier: &[LV]) -> Branch {
-        todo!()
+    /// Check out the whole document tree (maps, registers and texts) at some version in time. This
+    /// is the equivalent of [`ListOpLog::checkout`](crate::list::ListOpLog::checkout) for the
+    /// multi-CRDT oplog.
+    pub fn checkout_at_version(&self, frontier: &[LV]) -> Branch {
+        let frontier = self.cg.graph.find_dominators(Frontier::from_unsorted(frontier).as_ref());
+        if frontier == self.cg.version { return self.checkout_tip(); }
+
+        self.checkout_internal(
+            frontier.clone(),
+            |info| self.get_state_for_register_at(info, frontier.as_ref()),
+            |crdt| self.checkout_text_at(crdt, frontier.as_ref()),
+            |crdt| self.collection_state_at(crdt, frontier.as_ref()),
+            |crdt| self.list_state_at(crdt, frontier.as_ref())
+        )
     }
 
     /// Get the current value for this register, ignoring any other conflicting values.
@@ -49,16 +103,83 @@ impl OpLog {
 
     /// Get this register's state. This includes the current value and any other conflicting values.
     fn get_state_for_register(&self, info: &RegisterInfo) -> RegisterState {
-        let (active_idx, other_idxes) = self.tie_break_mv(info);
+        self.register_state_from_idxes(info, &info.supremum)
+    }
+
+    fn register_state_from_idxes(&self, info: &RegisterInfo, idxes: &[usize]) -> RegisterState {
+        let (active_idx, other_idxes) = self.tie_break_idxes(&info.ops, idxes);
 
         RegisterState {
+            version: info.ops[active_idx].0,
             value: (&info.ops[active_idx]).into(),
             conflicts_with: other_idxes.map(|iter| {
-                iter.map(|idx| (&info.ops[idx]).into()).collect()
+                iter.map(|idx| (info.ops[idx].0, (&info.ops[idx]).into())).collect()
             }).unwrap_or_default(),
         }
     }
 
+    /// Get this register's state at some historical version. Returns None if the register had not
+    /// been set yet at that version.
+    fn get_state_for_register_at(&self, info: &RegisterInfo, frontier: &[LV]) -> Option<RegisterState> {
+        // The register's ops are sorted by LV, so nothing after the last frontier item can be
+        // included.
+        let last = *frontier.last()?;
+
+        let visible_versions: Vec<LV> = info.ops.iter()
+            .map(|(v, _)| *v)
+            .take_while(|v| *v <= last)
+            .filter(|v| self.cg.graph.frontier_contains_version(frontier, *v))
+            .collect();
+
+        if visible_versions.is_empty() { return None; }
+
+        let idxes: SmallVec<[usize; 2]> = self.cg.graph.find_dominators(&visible_versions)
+            .iter()
+            .map(|v| info.ops.binary_search_by_key(v, |e| e.0).unwrap())
+            .collect();
+
+        Some(self.register_state_from_idxes(info, &idxes))
+    }
+
+
+    /// Get the current state of a standalone register CRDT. This contains the register's value, and
+    /// any other values which were set concurrently. Registers which have never been set are nil.
+    pub fn checkout_register(&self, crdt: LVKey) -> RegisterState {
+        let info = &self.registers[&crdt];
+        if info.ops.is_empty() { RegisterState::unset() }
+        else { self.get_state_for_register(info) }
+    }
+
+    /// Get the items in a list at the current version.
+    fn list_state(&self, crdt: LVKey) -> Vec<(LV, RegisterValue)> {
+        self.lists[&crdt].live_items()
+            .map(|(item, value)| (item, create_to_snapshot(item, value)))
+            .collect()
+    }
+
+    /// Get the items in a list at some historical version.
+    fn list_state_at(&self, crdt: LVKey, frontier: &[LV]) -> Vec<(LV, RegisterValue)> {
+        let info = &self.lists[&crdt];
+        info.items_at(&self.cg, frontier)
+            .into_iter()
+            .map(|item| (item, create_to_snapshot(item, &info.values[&item])))
+            .collect()
+    }
+
+    /// Get the items in a collection at the current version.
+    fn collection_state(&self, crdt: LVKey) -> BTreeMap<LV, RegisterValue> {
+        self.collections[&crdt].live_items()
+            .map(|(item, value)| (item, create_to_snapshot(item, value)))
+            .collect()
+    }
+
+    /// Get the items in a collection at some historical version.
+    fn collection_state_at(&self, crdt: LVKey, frontier: &[LV]) -> BTreeMap<LV, RegisterValue> {
+        self.collections[&crdt].items_at(&self.cg.graph, frontier)
+            .into_iter()
+            .map(|(item, value)| (item, create_to_snapshot(item, value)))
+            .collect()
+    }
 
     fn checkout_map_key_nc(&self, crdt: LVKey, key: &str) -> Option<RegisterValue> {
         // Just checkout this path item.
@@ -90,47 +211,94 @@ impl OpLog {
     }
 
     pub fn checkout_tip(&self) -> Branch {
+        self.checkout_internal(
+            self.cg.version.clone(),
+            |info| (!info.ops.is_empty()).then(|| self.get_state_for_register(info)),
+            |crdt| self.checkout_text(crdt),
+            |crdt| self.collection_state(crdt),
+            |crdt| self.list_state(crdt)
+        )
+    }
+
+    /// Shared implementation for checkout_tip and checkout_at_version. The passed functions look up
+    /// the state of each register, text, collection and list CRDT at the version being checked out.
+    fn checkout_internal<R, T, C, L>(&self, frontier: Frontier, register_state: R, text_content: T, collection_items: C, list_items: L) -> Branch
+        where R: Fn(&RegisterInfo) -> Option<RegisterState>,
+              T: Fn(LVKey) -> JumpRopeBuf,
+              C: Fn(LVKey) -> BTreeMap<LV, RegisterValue>,
+              L: Fn(LVKey) -> Vec<(LV, RegisterValue)>
+    {
         // There's 2 strategies I could employ here:
         // 1. Walk recursively through the tree and copy items
         // 2. Walk through all the living items (registers, maps, texts) and copy them
 
         // I'm going with option 2, but that might not be the best option.
 
-        let mut maps_to_copy = vec![ROOT_CRDT_ID];
+        // I could use recursion here but this avoids stack-smashing attacks.
+        let mut crdts_to_copy = vec![(CRDTKind::Map, ROOT_CRDT_ID)];
         let mut result = Branch {
-            frontier: self.cg.version.clone(),
+            frontier,
             maps: Default::default(),
             texts: Default::default(),
+            collections: Default::default(),
+            registers: Default::default(),
+            lists: Default::default(),
         };
 
-        while let Some(crdt) = maps_to_copy.pop() {
-            let mut this_map = BTreeMap::new();
-            for ((this_id, key), info) in btree_range_for_crdt(&self.map_keys, crdt) {
-                debug_assert_eq!(*this_id, crdt);
-                let state = self.get_state_for_register(info);
-
-                state.each_value(|rv| {
-                    // Recursively copy value and conflicting values.
-                    match rv {
-                        RegisterValue::Primitive(_) => {}
-                        RegisterValue::OwnedCRDT(CRDTKind::Map, child_map) => {
-                            // I could use recursion here but this avoids stack-smashing attacks.
-                            maps_to_copy.push(*child_map);
+        while let Some((kind, crdt)) = crdts_to_copy.pop() {
+            match kind {
+                CRDTKind::Map => {
+                    let mut this_map = BTreeMap::new();
+                    for ((this_id, key), info) in btree_range_for_crdt(&self.map_keys, crdt) {
+                        debug_assert_eq!(*this_id, crdt);
+                        // Keys which hadn't been set yet at this version are skipped.
+                        let Some(state) = register_state(info) else { continue; };
+
+                        // Recursively copy value and conflicting values.
+                        state.each_value(|rv| {
+                            if let RegisterValue::OwnedCRDT(child_kind, child_crdt) = rv {
+                                crdts_to_copy.push((*child_kind, *child_crdt));
+                            }
+                        });
+
+                        this_map.insert(key.clone(), state);
+                    }
+                    result.maps.insert(crdt, this_map);
+                }
+                CRDTKind::Collection => {
+                    let items = collection_items(crdt);
+                    for rv in items.values() {
+                        if let RegisterValue::OwnedCRDT(child_kind, child_crdt) = rv {
+                            crdts_to_copy.push((*child_kind, *child_crdt));
                         }
-                        RegisterValue::OwnedCRDT(CRDTKind::Register, _) => { todo!() }
-                        RegisterValue::OwnedCRDT(CRDTKind::Collection, _) => { todo!() }
-                        RegisterValue::OwnedCRDT(CRDTKind::Text, text_crdt) => {
-                            // Eventually (rich) text items might contain more embedded CRDTs. But for
-                            // now this is fine.
-                            let rope = self.checkout_text(*text_crdt);
-                            result.texts.insert(*text_crdt, rope);
+                    }
+                    result.collections.insert(crdt, items);
+                }
+                CRDTKind::List => {
+                    let items = list_items(crdt);
+                    for (_, rv) in items.iter() {
+                        if let RegisterValue::OwnedCRDT(child_kind, child_crdt) = rv {
+                            crdts_to_copy.push((*child_kind, *child_crdt));
                         }
                     }
-                });
-
-                this_map.insert(key.clone(), state);
+                    result.lists.insert(crdt, items);
+                }
+                CRDTKind::Text => {
+                    // Eventually (rich) text items might contain more embedded CRDTs. But for
+                    // now this is fine.
+                    result.texts.insert(crdt, text_content(crdt));
+                }
+                CRDTKind::Register => {
+                    let state = register_state(&self.registers[&crdt])
+                        .unwrap_or_else(RegisterState::unset);
+                    state.each_value(|rv| {
+                        if let RegisterValue::OwnedCRDT(child_kind, child_crdt) = rv {
+                            crdts_to_copy.push((*child_kind, *child_crdt));
+                        }
+                    });
+                    result.registers.insert(crdt, state);
+                }
             }
-            result.maps.insert(crdt, this_map);
         }
 
         result
@@ -149,6 +317,9 @@ impl Branch {
             frontier: Default::default(),
             maps: BTreeMap::from([(ROOT_CRDT_ID, Default::default())]),
             texts: Default::default(),
+            collections: Default::default(),
+            registers: Default::default(),
+            lists: Default::default(),
         }
     }
 
@@ -160,7 +331,7 @@ impl Branch {
         }
 
         delete_value(self, state.value);
-        for rv in state.conflicts_with {
+        for (_, rv) in state.conflicts_with {
             delete_value(self, rv);
         }
     }
@@ -177,7 +348,123 @@ impl Branch {
             CRDTKind::Text => {
                 self.texts.remove(&crdt); // Easy peasy!
             }
-            _ => { todo!() }
+            CRDTKind::Collection => {
+                let Some(items) = self.collections.remove(&crdt) else { return; };
+                for (_, rv) in items {
+                    if let RegisterValue::OwnedCRDT(kind, key) = rv {
+                        self.recursive_delete(kind, key);
+                    }
+                }
+            }
+            CRDTKind::Register => {
+                let Some(state) = self.registers.remove(&crdt) else { return; };
+                self.recursive_delete_reg_state(state);
+            }
+            CRDTKind::List => {
+                let Some(items) = self.lists.remove(&crdt) else { return; };
+                for (_, rv) in items {
+                    if let RegisterValue::OwnedCRDT(kind, key) = rv {
+                        self.recursive_delete(kind, key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Make sure an (empty) entry exists for a newly created CRDT.
+    fn create_empty_crdt(&mut self, kind: CRDTKind, crdt: LVKey) {
+        match kind {
+            CRDTKind::Map => { self.maps.entry(crdt).or_default(); }
+            CRDTKind::Text => { self.texts.entry(crdt).or_default(); }
+            CRDTKind::Collection => { self.collections.entry(crdt).or_default(); }
+            CRDTKind::Register => { self.registers.entry(crdt).or_insert_with(RegisterState::unset); }
+            CRDTKind::List => { self.lists.entry(crdt).or_default(); }
+        }
+    }
+
+    /// Replace the state of a map key. Any child CRDTs which are no longer referenced by the register
+    /// are recursively deleted, and (empty) entries are created for newly referenced CRDTs.
+    fn set_map_register(&mut self, map_crdt: LVKey, key: &SmartString, state: RegisterState) {
+        let new_crdts = state.owned_crdts();
+        let old_state = self.maps.entry(map_crdt).or_default()
+            .insert(key.clone(), state);
+        self.replace_owned_crdts(old_state, new_crdts);
+    }
+
+    /// Replace the state of a standalone register CRDT. Like set_map_register, this keeps the
+    /// register's child CRDTs up to date.
+    fn set_register(&mut self, crdt: LVKey, state: RegisterState) {
+        let new_crdts = state.owned_crdts();
+        let old_state = self.registers.insert(crdt, state);
+        self.replace_owned_crdts(old_state, new_crdts);
+    }
+
+    fn replace_owned_crdts(&mut self, old_state: Option<RegisterState>, new_crdts: SmallVec<[(CRDTKind, LVKey); 2]>) {
+        if let Some(old_state) = old_state {
+            old_state.each_value(|v| {
+                if let RegisterValue::OwnedCRDT(kind, crdt) = v {
+                    // A register was superceded which used to store a CRDT value. Recursively
+                    // delete the old value - unless its still there as a conflicting value.
+                    if !new_crdts.contains(&(*kind, *crdt)) {
+                        self.recursive_delete(*kind, *crdt);
+                    }
+                }
+            });
+        }
+
+        for (kind, crdt) in new_crdts {
+            self.create_empty_crdt(kind, crdt);
+        }
+    }
+
+    /// Replace the items in a collection. Like set_map_register, child CRDTs of removed items are
+    /// recursively deleted and entries are created for CRDTs in new items.
+    fn set_collection(&mut self, crdt: LVKey, items: BTreeMap<LV, RegisterValue>) {
+        let old_items = self.collections.remove(&crdt).unwrap_or_default();
+
+        for (item, rv) in old_items.iter() {
+            if let RegisterValue::OwnedCRDT(kind, key) = rv {
+                if !items.contains_key(item) {
+                    self.recursive_delete(*kind, *key);
+                }
+            }
+        }
+        for rv in items.values() {
+            if let RegisterValue::OwnedCRDT(kind, key) = rv {
+                self.create_empty_crdt(*kind, *key);
+            }
+        }
+
+        self.collections.insert(crdt, items);
+    }
+
+    /// Merge the changes to a list between two versions. Like set_collection, child CRDTs of deleted
+    /// items are recursively deleted and entries are created for CRDTs in new items.
+    fn merge_list(&mut self, oplog: &OpLog, crdt: LVKey, from: &[LV], merge_frontier: &[LV]) {
+        let info = &oplog.lists[&crdt];
+        let mut items = self.lists.remove(&crdt).unwrap_or_default();
+
+        let mut created = vec![];
+        let mut removed = vec![];
+        info.merge_into(&mut items, &oplog.cg, from, merge_frontier, |item| {
+            let rv = create_to_snapshot(item, &info.values[&item]);
+            if let RegisterValue::OwnedCRDT(kind, key) = rv {
+                created.push((kind, key));
+            }
+            (item, rv)
+        }, |(_, rv)| {
+            if let RegisterValue::OwnedCRDT(kind, key) = rv {
+                removed.push((kind, key));
+            }
+        });
+        self.lists.insert(crdt, items);
+
+        // Items can be inserted then deleted in the same merge. So create new CRDTs first.
+        for (kind, key) in created {
+            self.create_empty_crdt(kind, key);
+        }
+        for (kind, key) in removed {
+            self.recursive_delete(kind, key);
         }
     }
 
@@ -186,6 +473,10 @@ impl Branch {
         // Well, for now nothing can be deleted yet. So that makes things easier.
         let diff_rev = oplog.cg.diff_since_rev(self.frontier.as_ref());
 
+        // Lists are merged from the branch's version to the tip in one go, so each list only needs
+        // to be visited once.
+        let mut lists = BTreeSet::new();
+
         for range in diff_rev.iter().rev() {
             // for (_, text_crdt) in self.text_index.range(*range) {
             //     text_crdts_to_send.insert(*text_crdt);
@@ -196,24 +487,27 @@ impl Branch {
 
                 // I could be more clever here, but the easier answer is to just fully replace this
                 // object key with the new (current) value.
-                let obj = self.maps.entry(*map_crdt).or_default();
                 let info = oplog.map_keys.get(&(*map_crdt, key.clone())).unwrap();
                 let state = oplog.get_state_for_register(info);
+                self.set_map_register(*map_crdt, key, state);
+            }
 
-                // I could iterate through the state looking for new CRDT items to insert, but I
-                // don't think I need to since they'll also show up in the map_index set.
-                let old_state = obj.insert(key.clone(), state);
+            for (_v, register_crdt) in oplog.register_index.range(*range) {
+                if oplog.deleted_crdts.contains(register_crdt) { continue; }
+                let state = oplog.checkout_register(*register_crdt);
+                self.set_register(*register_crdt, state);
+            }
 
-                let Some(old_state) = old_state else { continue; };
-                old_state.each_value(|v| {
-                    if let RegisterValue::OwnedCRDT(kind, key) = v {
-                        // A register was superceded which used to store a CRDT value. Recursively
-                        // delete the old value.
-                        self.recursive_delete(*kind, *key);
-                    }
-                })
+            let collections: BTreeSet<LVKey> = oplog.collection_index.range(*range)
+                .map(|(_v, crdt)| *crdt)
+                .collect();
+            for collection_crdt in collections {
+                if oplog.deleted_crdts.contains(&collection_crdt) { continue; }
+                self.set_collection(collection_crdt, oplog.collection_state(collection_crdt));
             }
 
+            lists.extend(oplog.list_index.range(*range).map(|(_v, crdt)| *crdt));
+
             for (_v, text_crdt) in oplog.text_index.range(*range) {
                 if oplog.deleted_crdts.contains(text_crdt) { continue; }
 
@@ -224,10 +518,103 @@ impl Branch {
             }
         }
 
+        for list_crdt in lists {
+            if oplog.deleted_crdts.contains(&list_crdt) { continue; }
+            self.merge_list(oplog, list_crdt, self.frontier.clone().as_ref(), oplog.cg.version.as_ref());
+        }
+
         self.frontier = oplog.cg.version.clone();
         diff_rev
     }
 
+    /// Merge all the changes named by `merge_frontier` into this branch. This is the multi-CRDT
+    /// equivalent of [`ListBranch::merge`](crate::list::ListBranch::merge).
+    ///
+    /// Unlike [`merge_changes_to_tip`](Branch::merge_changes_to_tip), the merged version doesn't
+    /// need to contain everything in the oplog. This lets different branches preview changes from
+    /// different peers independently.
+    pub fn merge(&mut self, oplog: &OpLog, merge_frontier: &[LV]) {
+        let new_frontier = oplog.cg.graph.version_union(self.frontier.as_ref(), merge_frontier);
+        if new_frontier == self.frontier { return; } // Nothing to do!
+        if new_frontier == oplog.cg.version {
+            self.merge_changes_to_tip(oplog);
+            return;
+        }
+
+        let (_, new_ranges) = oplog.cg.graph.diff(self.frontier.as_ref(), new_frontier.as_ref());
+
+        // We can't use the map_index here because it only names the operations at the tip of each
+        // register. Instead, look for any register with operations in the merged ranges.
+        //
+        // Child CRDTs are always created after the container which holds them. So by visiting keys
+        // in the root first, then in order of their containing map (or register / collection),
+        // containers deleted by this merge are removed before we get to their contents.
+        let map_keys = btree_range_for_crdt(&oplog.map_keys, ROOT_CRDT_ID)
+            .chain(oplog.map_keys.range(..(ROOT_CRDT_ID, SmartString::new())));
+
+        let mut other_crdts: Vec<(LVKey, CRDTKind)> = oplog.collections.iter()
+            .filter(|(_, info)| new_ranges.iter().any(|r| info.has_op_in_range(*r)))
+            .map(|(crdt, _)| (*crdt, CRDTKind::Collection))
+            .chain(oplog.registers.iter()
+                .filter(|(_, info)| new_ranges.iter().any(|r| info.has_op_in_range(*r)))
+                .map(|(crdt, _)| (*crdt, CRDTKind::Register)))
+            .chain(oplog.lists.iter()
+                .filter(|(_, info)| new_ranges.iter().any(|r| info.ops.ops.iter_range_ctx(*r, &info.ops.ctx).next().is_some()))
+                .map(|(crdt, _)| (*crdt, CRDTKind::List)))
+            .collect();
+        other_crdts.sort_unstable_by_key(|(crdt, _)| *crdt);
+        let mut other_crdts = other_crdts.into_iter().peekable();
+
+        // As with maps, CRDTs missing from the branch were deleted at the merged version.
+        let old_frontier = self.frontier.clone();
+        let merge_other = |branch: &mut Self, (crdt, kind): (LVKey, CRDTKind)| {
+            match kind {
+                CRDTKind::Collection if branch.collections.contains_key(&crdt) => {
+                    branch.set_collection(crdt, oplog.collection_state_at(crdt, new_frontier.as_ref()));
+                }
+                CRDTKind::Register if branch.registers.contains_key(&crdt) => {
+                    let state = oplog.get_state_for_register_at(&oplog.registers[&crdt], new_frontier.as_ref())
+                        .unwrap_or_else(RegisterState::unset);
+                    branch.set_register(crdt, state);
+                }
+                CRDTKind::List if branch.lists.contains_key(&crdt) => {
+                    branch.merge_list(oplog, crdt, old_frontier.as_ref(), new_frontier.as_ref());
+                }
+                _ => {}
+            }
+        };
+
+        for ((map_crdt, key), info) in map_keys {
+            if !new_ranges.iter().any(|r| info.has_op_in_range(*r)) { continue; }
+
+            if *map_crdt != ROOT_CRDT_ID {
+                while let Some(other) = other_crdts.next_if(|(crdt, _)| *crdt < *map_crdt) {
+                    merge_other(self, other);
+                }
+            }
+
+            // If the container doesn't exist, it was deleted at the merged version. Ignore!
+            if !self.maps.contains_key(map_crdt) { continue; }
+
+            let state = oplog.get_state_for_register_at(info, new_frontier.as_ref()).unwrap();
+            self.set_map_register(*map_crdt, key, state);
+        }
+        for other in other_crdts {
+            merge_other(self, other);
+        }
+
+        for (text_crdt, text_content) in self.texts.iter_mut() {
+            let textinfo = oplog.texts.get(text_crdt).unwrap();
+            if !new_ranges.iter().any(|r| textinfo.ops.iter_range_ctx(*r, &textinfo.ctx).next().is_some()) {
+                continue;
+            }
+
+            textinfo.merge_into(text_content, &oplog.cg, self.frontier.as_ref(), new_frontier.as_ref());
+        }
+
+        self.frontier = new_frontier;
+    }
+
     pub fn crdt_at_path(&self, path: &[&str]) -> (CRDTKind, LVKey) {
         let mut kind = CRDTKind::Map;
         let mut key = ROOT_CRDT_ID;
@@ -264,6 +651,43 @@ impl Branch {
         } else { key }
     }
 
+    pub fn collection_at_path(&self, path: &[&str]) -> LVKey {
+        let (kind, key) = self.crdt_at_path(path);
+        if kind != CRDTKind::Collection {
+            panic!("Unexpected CRDT kind {:?}", kind);
+        } else { key }
+    }
+
+    pub fn register_at_path(&self, path: &[&str]) -> LVKey {
+        let (kind, key) = self.crdt_at_path(path);
+        if kind != CRDTKind::Register {
+            panic!("Unexpected CRDT kind {:?}", kind);
+        } else { key }
+    }
+
+    /// Get the state of a standalone register CRDT. If other values were set concurrently with the
+    /// register's value, they're listed in `conflicts_with`.
+    pub fn register(&self, crdt: LVKey) -> Option<&RegisterState> {
+        self.registers.get(&crdt)
+    }
+
+    pub fn list_at_path(&self, path: &[&str]) -> LVKey {
+        let (kind, key) = self.crdt_at_path(path);
+        if kind != CRDTKind::List {
+            panic!("Unexpected CRDT kind {:?}", kind);
+        } else { key }
+    }
+
+    /// Get the items in a list, in order. Each item is named by the version of its insert.
+    pub fn list_items(&self, crdt: LVKey) -> Option<&[(LV, RegisterValue)]> {
+        self.lists.get(&crdt).map(|items| items.as_slice())
+    }
+
+    /// Get the items in a collection, keyed by the version of each item's insert.
+    pub fn collection_items(&self, crdt: LVKey) -> Option<&BTreeMap<LV, RegisterValue>> {
+        self.collections.get(&crdt)
+    }
+
     pub fn register_in_map(&self, path: &[&str], key: &str) -> Option<&RegisterValue> {
         let (kind, crdt) = self.crdt_at_path(path);
         if kind != CRDTKind::Map {
@@ -273,6 +697,24 @@ impl Branch {
         Some(&self.maps.get(&crdt)?.get(key)?.value)
     }
 
+    /// Get the full state of a key in a map, including any values set concurrently with the winning
+    /// value. Use this to show the user conflicting edits instead of silently accepting the
+    /// tie-break.
+    pub fn register_state_in_map(&self, path: &[&str], key: &str) -> Option<&RegisterState> {
+        let (kind, crdt) = self.crdt_at_path(path);
+        if kind != CRDTKind::Map {
+            panic!("Expected a map, found a {:?}", kind);
+        }
+
+        self.maps.get(&crdt)?.get(key)
+    }
+
+    /// Get every concurrent value of a key in a map, along with the version of the operation which
+    /// set each value. The value which wins the tie-break is listed first.
+    pub fn values_in_map(&self, path: &[&str], key: &str) -> Option<Vec<(LV, &RegisterValue)>> {
+        Some(self.register_state_in_map(path, key)?.values().collect())
+    }
+
     // TODO: Probably better to return a Result here.
     pub fn str_in_map(&self, path: &[&str], key: &str) -> Option<&str> {
         if let RegisterValue::Primitive(Primitive::Str(s)) = self.register_in_map(path, key)? {
@@ -292,30 +734,61 @@ impl Branch {
             .copied()
             .collect();
 
+        let mut owned_collection_crdts = BTreeSet::new();
+        let root_collection_crdts: BTreeSet<_> = self.collections.keys()
+            .copied()
+            .collect();
+
+        let mut owned_register_crdts = BTreeSet::new();
+        let root_register_crdts: BTreeSet<_> = self.registers.keys()
+            .copied()
+            .collect();
+
+        let mut owned_list_crdts = BTreeSet::new();
+        let root_list_crdts: BTreeSet<_> = self.lists.keys()
+            .copied()
+            .collect();
+
+        let mut visit = |v: &RegisterValue| {
+            if let RegisterValue::OwnedCRDT(kind, key) = v {
+                assert!(match kind {
+                    CRDTKind::Map => &mut owned_map_crdts,
+                    CRDTKind::Text => &mut owned_text_crdts,
+                    CRDTKind::Collection => &mut owned_collection_crdts,
+                    CRDTKind::Register => &mut owned_register_crdts,
+                    CRDTKind::List => &mut owned_list_crdts,
+                }.insert(*key));
+            }
+        };
+
         for (map_crdt, state) in &self.maps {
             root_map_crdts.insert(*map_crdt);
 
             for reg_state in state.values() {
-                reg_state.each_value(|v| {
-                    if let RegisterValue::OwnedCRDT(kind, key) = v {
-                        match kind {
-                            CRDTKind::Map => &mut owned_map_crdts,
-                            CRDTKind::Text => &mut owned_text_crdts,
-                            _ => { unimplemented!() }
-                        }.insert(*key);
-                    }
-                });
+                reg_state.each_value(&mut visit);
             }
         }
+        for items in self.collections.values() {
+            items.values().for_each(&mut visit);
+        }
+        for state in self.registers.values() {
+            state.each_value(&mut visit);
+        }
+        for items in self.lists.values() {
+            items.iter().for_each(|(_, rv)| visit(rv));
+        }
 
         assert_eq!(owned_map_crdts, root_map_crdts);
         assert_eq!(owned_text_crdts, root_text_crdts);
+        assert_eq!(owned_collection_crdts, root_collection_crdts);
+        assert_eq!(owned_register_crdts, root_register_crdts);
+        assert_eq!(owned_list_crdts, root_list_crdts);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{CRDTKind, CreateValue, Branch, OpLog, Primitive, ROOT_CRDT_ID};
+    use crate::{CRDTKind, CreateValue, Branch, LV, OpLog, Primitive, RegisterValue, ROOT_CRDT_ID};
     use crate::list::operation::TextOperation;
 
     fn check_oplog_checkouts_match(oplog: &OpLog) -> Branch {
@@ -369,6 +842,163 @@ mod tests {
         // dbg!(oplog.checkout_tip().simple_val());
     }
 
+    #[test]
+    fn checkout_at_version() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+
+        // Make a series of changes, remembering what the document looked like after each one.
+        let mut history = vec![(oplog.cg.version.clone(), oplog.checkout_tip())];
+        let mut snapshot = |oplog: &OpLog| history.push((oplog.cg.version.clone(), oplog.checkout_tip()));
+
+        let child_obj = oplog.local_map_set(seph, ROOT_CRDT_ID, "child", CreateValue::NewCRDT(CRDTKind::Map));
+        snapshot(&oplog);
+        let text = oplog.local_map_set(seph, child_obj, "text", CreateValue::NewCRDT(CRDTKind::Text));
+        snapshot(&oplog);
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi there"));
+        snapshot(&oplog);
+        oplog.local_map_set(seph, child_obj, "num", CreateValue::Primitive(Primitive::I64(1)));
+        snapshot(&oplog);
+        oplog.local_text_op(seph, text, TextOperation::new_delete(2..8));
+        snapshot(&oplog);
+        oplog.local_map_set(seph, child_obj, "num", CreateValue::Primitive(Primitive::I64(2)));
+        snapshot(&oplog);
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "child", CreateValue::Primitive(Primitive::Nil));
+        snapshot(&oplog);
+
+        for (version, expected) in history {
+            let branch = oplog.checkout_at_version(version.as_ref());
+            branch.dbg_check(true);
+            assert_eq!(branch, expected);
+        }
+    }
+
+    #[test]
+    fn checkout_concurrent_versions() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+
+        let a = oplog.cg.assign_local_op_with_parents(&[], seph, 1).start;
+        let b = oplog.cg.assign_local_op_with_parents(&[], kaarina, 1).start;
+        oplog.remote_map_set(ROOT_CRDT_ID, a, "yo", CreateValue::Primitive(Primitive::I64(123)));
+        oplog.remote_map_set(ROOT_CRDT_ID, b, "yo", CreateValue::Primitive(Primitive::I64(321)));
+
+        let branch_a = oplog.checkout_at_version(&[a]);
+        branch_a.dbg_check(true);
+        assert_eq!(branch_a.register_in_map(&[], "yo"), Some(&RegisterValue::Primitive(Primitive::I64(123))));
+
+        let branch_b = oplog.checkout_at_version(&[b]);
+        assert_eq!(branch_b.register_in_map(&[], "yo"), Some(&RegisterValue::Primitive(Primitive::I64(321))));
+
+        let root = oplog.checkout_at_version(&[]);
+        assert_eq!(root, Branch::new());
+
+        // Checking out both versions should match the tip, including the conflicting value.
+        assert_eq!(oplog.checkout_at_version(&[b, a]), oplog.checkout_tip());
+    }
+
+    #[test]
+    fn concurrent_map_values() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+
+        let a = oplog.cg.assign_local_op_with_parents(&[], seph, 1).start;
+        let b = oplog.cg.assign_local_op_with_parents(&[], kaarina, 1).start;
+        oplog.remote_map_set(ROOT_CRDT_ID, a, "yo", CreateValue::Primitive(Primitive::I64(123)));
+        oplog.remote_map_set(ROOT_CRDT_ID, b, "yo", CreateValue::Primitive(Primitive::I64(321)));
+
+        let branch = oplog.checkout_tip();
+        let state = branch.register_state_in_map(&[], "yo").unwrap();
+        assert!(state.has_conflicts());
+
+        // The winner is listed first, and matches the value returned by register_in_map.
+        let values = branch.values_in_map(&[], "yo").unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(Some(values[0].1), branch.register_in_map(&[], "yo"));
+        let mut versions: Vec<LV> = values.iter().map(|(v, _)| *v).collect();
+        versions.sort_unstable();
+        assert_eq!(versions, vec![a, b]);
+        for (v, value) in values {
+            let expected = if v == a { 123 } else { 321 };
+            assert_eq!(value, &RegisterValue::Primitive(Primitive::I64(expected)));
+        }
+
+        // Once the conflict is resolved, only one value remains.
+        let c = oplog.cg.assign_local_op_with_parents(&[a, b], seph, 1).start;
+        oplog.remote_map_set(ROOT_CRDT_ID, c, "yo", CreateValue::Primitive(Primitive::I64(5)));
+        let branch = oplog.checkout_tip();
+        assert!(!branch.register_state_in_map(&[], "yo").unwrap().has_conflicts());
+        assert_eq!(branch.values_in_map(&[], "yo").unwrap(), vec![(c, &RegisterValue::Primitive(Primitive::I64(5)))]);
+        assert_eq!(branch.values_in_map(&[], "missing"), None);
+    }
+
+    #[test]
+    fn merge_to_version() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "text", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi there"));
+        let child_obj = oplog.local_map_set(seph, ROOT_CRDT_ID, "child", CreateValue::NewCRDT(CRDTKind::Map));
+        let base = oplog.cg.version.clone();
+
+        // Seph and Kaarina make concurrent changes.
+        let a = oplog.cg.assign_local_op_with_parents(base.as_ref(), seph, 1).start;
+        oplog.remote_map_set(child_obj, a, "name", CreateValue::Primitive(Primitive::Str("seph".into())));
+        let a_text = oplog.cg.assign_local_op_with_parents(&[a], seph, 3);
+        oplog.remote_text_op(text, a_text, TextOperation::new_insert(8, "!!!"));
+
+        let b = oplog.cg.assign_local_op_with_parents(base.as_ref(), kaarina, 1).start;
+        oplog.remote_map_set(ROOT_CRDT_ID, b, "child", CreateValue::NewCRDT(CRDTKind::Text));
+        let b_text = oplog.cg.assign_local_op_with_parents(&[b], kaarina, 2);
+        oplog.remote_text_op(text, b_text, TextOperation::new_delete(0..2));
+
+        // Each branch can preview one peer's changes independently.
+        let mut branch_a = oplog.checkout_at_version(base.as_ref());
+        branch_a.merge(&oplog, &[a_text.last()]);
+        branch_a.dbg_check(true);
+        assert_eq!(branch_a, oplog.checkout_at_version(&[a_text.last()]));
+        assert_eq!(branch_a.texts[&text].to_string(), "hi there!!!");
+
+        let mut branch_b = Branch::new();
+        branch_b.merge(&oplog, &[b_text.last()]);
+        branch_b.dbg_check(true);
+        assert_eq!(branch_b, oplog.checkout_at_version(&[b_text.last()]));
+        assert_eq!(branch_b.texts[&text].to_string(), " there");
+
+        // Merging the other peer's changes in should converge on the same state.
+        branch_a.merge(&oplog, &[b_text.last()]);
+        branch_b.merge(&oplog, &[a_text.last()]);
+        branch_a.dbg_check(true);
+        branch_b.dbg_check(true);
+        assert_eq!(branch_a, branch_b);
+        assert_eq!(branch_a, oplog.checkout_tip());
+    }
+
+    #[test]
+    fn merge_one_at_a_time() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+
+        let child_obj = oplog.local_map_set(seph, ROOT_CRDT_ID, "overwritten", CreateValue::NewCRDT(CRDTKind::Map));
+        let text_item = oplog.local_map_set(seph, child_obj, "text_item", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text_item, TextOperation::new_insert(0, "yooo"));
+        oplog.local_map_set(seph, child_obj, "smol_embedded", CreateValue::NewCRDT(CRDTKind::Map));
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "overwritten", CreateValue::Primitive(Primitive::I64(123)));
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "title", CreateValue::NewCRDT(CRDTKind::Text));
+
+        let mut branch = Branch::new();
+        for v in 0..oplog.cg.len() {
+            branch.merge(&oplog, &[v]);
+            branch.dbg_check(true);
+            assert_eq!(branch, oplog.checkout_at_version(&[v]));
+        }
+        assert_eq!(branch, oplog.checkout_tip());
+    }
+
     #[test]
     fn checkout_simple_items() {
         let mut oplog = OpLog::new();
@@ -404,4 +1034,141 @@ mod tests {
 
         assert_eq!(branch_expected, branch_incremental);
     }
+
+    #[test]
+    fn collections() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+
+        let mut history = vec![];
+        let mut snapshot = |oplog: &OpLog| history.push((oplog.cg.version.clone(), oplog.checkout_tip()));
+
+        let set = oplog.local_map_set(seph, ROOT_CRDT_ID, "set", CreateValue::NewCRDT(CRDTKind::Collection));
+        snapshot(&oplog);
+        let a = oplog.local_collection_insert(seph, set, CreateValue::Primitive(Primitive::I64(1)));
+        snapshot(&oplog);
+        let text = oplog.local_collection_insert(seph, set, CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+        snapshot(&oplog);
+        let inner = oplog.local_collection_insert(seph, set, CreateValue::NewCRDT(CRDTKind::Map));
+        oplog.local_map_set(seph, inner, "x", CreateValue::Primitive(Primitive::Bool(true)));
+        snapshot(&oplog);
+        oplog.local_collection_remove(seph, set, a);
+        snapshot(&oplog);
+        oplog.local_collection_remove(seph, set, inner);
+        snapshot(&oplog);
+
+        let branch = check_oplog_checkouts_match(&oplog);
+        assert_eq!(branch.collection_at_path(&["set"]), set);
+        let items = branch.collection_items(set).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[&text], RegisterValue::OwnedCRDT(CRDTKind::Text, text));
+        assert_eq!(branch.texts[&text].to_string(), "hi");
+
+        for (version, expected) in history {
+            let checkout = oplog.checkout_at_version(version.as_ref());
+            checkout.dbg_check(true);
+            assert_eq!(checkout, expected);
+        }
+
+        let mut branch = Branch::new();
+        for v in 0..oplog.cg.len() {
+            branch.merge(&oplog, &[v]);
+            branch.dbg_check(true);
+            assert_eq!(branch, oplog.checkout_at_version(&[v]));
+        }
+    }
+
+    #[test]
+    fn lists() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+
+        let mut history = vec![];
+        let mut snapshot = |oplog: &OpLog| history.push((oplog.cg.version.clone(), oplog.checkout_tip()));
+
+        let list = oplog.local_map_set(seph, ROOT_CRDT_ID, "list", CreateValue::NewCRDT(CRDTKind::List));
+        snapshot(&oplog);
+        let a = oplog.local_list_insert(seph, list, 0, CreateValue::Primitive(Primitive::I64(1)));
+        snapshot(&oplog);
+        let text = oplog.local_list_insert(seph, list, 1, CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+        snapshot(&oplog);
+        let base = oplog.cg.version.clone();
+
+        // Seph deletes the text while kaarina concurrently inserts a map before it.
+        oplog.local_list_delete(seph, list, 1..2);
+        snapshot(&oplog);
+        let b = oplog.cg.assign_local_op_with_parents(base.as_ref(), kaarina, 1).start;
+        oplog.remote_list_insert(list, b, 1, CreateValue::NewCRDT(CRDTKind::Map));
+        let b2 = oplog.cg.assign_local_op_with_parents(&[b], kaarina, 1).start;
+        oplog.remote_map_set(b, b2, "x", CreateValue::Primitive(Primitive::Bool(true)));
+        oplog.dbg_check(true);
+        snapshot(&oplog);
+
+        let branch = check_oplog_checkouts_match(&oplog);
+        assert_eq!(branch.list_at_path(&["list"]), list);
+        assert_eq!(branch.list_items(list).unwrap(), &[
+            (a, RegisterValue::Primitive(Primitive::I64(1))),
+            (b, RegisterValue::OwnedCRDT(CRDTKind::Map, b)),
+        ]);
+        assert!(!branch.texts.contains_key(&text));
+
+        for (version, expected) in history {
+            let checkout = oplog.checkout_at_version(version.as_ref());
+            checkout.dbg_check(true);
+            assert_eq!(checkout, expected);
+        }
+
+        let mut branch = Branch::new();
+        for v in 0..oplog.cg.len() {
+            branch.merge(&oplog, &[v]);
+            branch.dbg_check(true);
+            assert_eq!(branch, oplog.checkout_at_version(branch.frontier.as_ref()));
+        }
+    }
+
+    #[test]
+    fn registers() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+
+        let reg = oplog.local_map_set(seph, ROOT_CRDT_ID, "reg", CreateValue::NewCRDT(CRDTKind::Register));
+        let text = oplog.local_register_set(seph, reg, CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+        let base = oplog.cg.version.clone();
+
+        // Concurrently overwrite the text with a map and a primitive.
+        let a = oplog.cg.assign_local_op_with_parents(base.as_ref(), seph, 1).start;
+        oplog.remote_register_set(reg, a, CreateValue::NewCRDT(CRDTKind::Map));
+        let a2 = oplog.cg.assign_local_op_with_parents(&[a], seph, 1).start;
+        oplog.remote_map_set(a, a2, "x", CreateValue::Primitive(Primitive::I64(5)));
+        let b = oplog.cg.assign_local_op_with_parents(base.as_ref(), kaarina, 1).start;
+        oplog.remote_register_set(reg, b, CreateValue::Primitive(Primitive::Str("yo".into())));
+        oplog.dbg_check(true);
+
+        let branch = check_oplog_checkouts_match(&oplog);
+        assert_eq!(branch.register_at_path(&["reg"]), reg);
+        let state = branch.register(reg).unwrap();
+        assert_eq!(state.conflicts_with.len(), 1);
+        // The map is still accessible as a conflicting value.
+        assert!(branch.maps.contains_key(&a));
+        assert!(!branch.texts.contains_key(&text));
+
+        let at_base = oplog.checkout_at_version(base.as_ref());
+        at_base.dbg_check(true);
+        assert_eq!(at_base.register(reg).unwrap().value, RegisterValue::OwnedCRDT(CRDTKind::Text, text));
+        assert_eq!(at_base.texts[&text].to_string(), "hi");
+
+        // Merging one version at a time picks up both branches of the history.
+        let mut branch = Branch::new();
+        for v in 0..oplog.cg.len() {
+            branch.merge(&oplog, &[v]);
+            branch.dbg_check(true);
+            assert_eq!(branch, oplog.checkout_at_version(branch.frontier.as_ref()));
+        }
+        assert_eq!(branch, oplog.checkout_tip());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,384 @@
+//! Probabilistic set reconciliation for causal graphs.
+//!
+//! When two peers have diverged a lot, a [`VersionSummary`] can get large - we create a new agent
+//! for every editing session, so a long lived document might have thousands of agents. Instead, a
+//! peer can send a Bloom filter containing the agent spans it knows about. Spans are split into
+//! aligned blocks of a few sizes, so a filter only needs a few bits for each block.
+//!
+//! The protocol looks like this:
+//!
+//! 1. Peer A sends B a [`VersionBloom`] made with [`CausalGraph::version_bloom`].
+//! 2. B calls [`CausalGraph::intersect_with_bloom`] to find the operations A is missing, and sends
+//!    them to A along with B's current frontier (as remote versions).
+//! 3. A merges the operations, then checks B's frontier with [`CausalGraph::confirm_bloom_sync`].
+//!
+//! Bloom filters have false positives - B might think A has an operation when it doesn't. If that
+//! happens, either the patch from B will name parents A doesn't know (so merging fails with
+//! `DataMissing` or `BaseVersionUnknown`), or A won't know everything in B's frontier. In both
+//! cases A falls back to sending B its full [`VersionSummary`].
+
+use smallvec::SmallVec;
+use rle::HasLength;
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersionOwned;
+use crate::causalgraph::summary::VersionSummary;
+use crate::encoding::parseerror::ParseError;
+use crate::rle::KVPair;
+use crate::{CausalGraph, DTRange, Frontier, LV};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Each agent's spans are split into aligned blocks of these sizes, using the biggest blocks which
+/// fit. Each block is a single item in the filter, keyed by its size. So the size of the filter is
+/// roughly (operations / 64) + (up to 28 items per agent span). Checking a version needs one probe
+/// for each block size.
+const BLOCK_SIZES: [usize; 3] = [64, 8, 1];
+
+/// Filters with more hashes than this are rejected when they're decoded. (Sensible filters use
+/// about 7.)
+const MAX_HASHES: u32 = 32;
+
+/// A Bloom filter containing the agent spans of a set of operations.
+///
+/// Each agent's known sequence numbers are split into aligned blocks of 64, 8 and 1 operations. The
+/// filter contains each block.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "VersionBloomParts"))]
+pub struct VersionBloom {
+    num_hashes: u32,
+    bits: Vec<u64>,
+}
+
+/// The unvalidated fields of a VersionBloom, used when deserializing.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct VersionBloomParts {
+    num_hashes: u32,
+    bits: Vec<u64>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<VersionBloomParts> for VersionBloom {
+    type Error = ParseError;
+
+    fn try_from(parts: VersionBloomParts) -> Result<Self, Self::Error> {
+        Self::from_parts(parts.num_hashes, parts.bits)
+    }
+}
+
+/// 64 bit FNV-1a. The hash needs to be stable across platforms and compiler versions, since the
+/// filter is sent over the network.
+fn hash_key(name: &str, kind: u8, val: usize) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in name.bytes().chain([kind]).chain((val as u64).to_le_bytes()) {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// The splitmix64 finalizer. Used to derive a second hash for double hashing.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+impl VersionBloom {
+    fn with_capacity(num_items: usize, bits_per_item: usize) -> Self {
+        let bits_per_item = bits_per_item.max(1);
+        let num_words = (num_items * bits_per_item).div_ceil(64).max(1);
+
+        Self {
+            // The optimal number of hashes is bits_per_item * ln(2).
+            num_hashes: ((bits_per_item as f32 * std::f32::consts::LN_2).round() as u32).clamp(1, MAX_HASHES),
+            bits: vec![0; num_words],
+        }
+    }
+
+    /// Make a filter from the fields of a filter made by [`into_parts`](VersionBloom::into_parts).
+    /// This is used to read a filter sent by a remote peer, so the fields are checked.
+    pub fn from_parts(num_hashes: u32, bits: Vec<u64>) -> Result<Self, ParseError> {
+        if bits.is_empty() || num_hashes == 0 || num_hashes > MAX_HASHES {
+            return Err(ParseError::GenericInvalidData);
+        }
+        Ok(Self { num_hashes, bits })
+    }
+
+    /// Returns the number of hashes and the filter's bits, to send to a remote peer.
+    pub fn into_parts(self) -> (u32, Vec<u64>) {
+        (self.num_hashes, self.bits)
+    }
+
+    fn bit_indexes(&self, name: &str, kind: u8, val: usize) -> impl Iterator<Item = usize> {
+        let h1 = hash_key(name, kind, val);
+        let h2 = mix(h1) | 1;
+        let num_bits = self.bits.len() as u64 * 64;
+
+        (0..self.num_hashes as u64).map(move |i| {
+            (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize
+        })
+    }
+
+    fn insert(&mut self, name: &str, kind: u8, val: usize) {
+        for idx in self.bit_indexes(name, kind, val).collect::<SmallVec<[usize; 8]>>() {
+            self.bits[idx / 64] |= 1 << (idx % 64);
+        }
+    }
+
+    fn contains_key(&self, name: &str, kind: u8, val: usize) -> bool {
+        self.bit_indexes(name, kind, val).all(|idx| self.bits[idx / 64] & (1 << (idx % 64)) != 0)
+    }
+
+    /// Check if the filter (probably) contains the named version. This never returns false for
+    /// versions in the filter, but it can return true for versions which aren't. Each check probes
+    /// the filter (at most) once for each block size, so false positives are up to 3 times as
+    /// likely as they are for a single item.
+    pub fn contains(&self, name: &str, seq: usize) -> bool {
+        BLOCK_SIZES.iter().enumerate()
+            .any(|(kind, size)| self.contains_key(name, kind as u8, seq / size))
+    }
+}
+
+/// Split a span of sequence numbers into aligned blocks. Yields (kind, block) pairs, where kind is
+/// the index of the block's size in BLOCK_SIZES.
+fn span_blocks(seq_range: DTRange) -> impl Iterator<Item = (u8, usize)> {
+    let mut pos = seq_range.start;
+    std::iter::from_fn(move || {
+        if pos >= seq_range.end { return None; }
+        let (kind, size) = BLOCK_SIZES.iter().enumerate()
+            .find(|(_, size)| pos.is_multiple_of(**size) && pos + *size <= seq_range.end)
+            .unwrap(); // Blocks of size 1 always fit.
+        let block = pos / size;
+        pos += size;
+        Some((kind as u8, block))
+    })
+}
+
+impl CausalGraph {
+    /// Make a Bloom filter containing every operation in the causal graph.
+    ///
+    /// More bits per item make the filter bigger, but make false positives less likely. Each item
+    /// has a false positive rate of about 1% with 10 bits per item, and checking a version can
+    /// probe up to 3 items. So use 12 bits per item (or more) for a false positive rate of about
+    /// 1% per version. The filter contains an item for each aligned block of 64, 8 or 1 operations
+    /// by the same agent.
+    pub fn version_bloom(&self, bits_per_item: usize) -> VersionBloom {
+        // Each agent's known sequence numbers, merged into maximal spans.
+        let spans: Vec<(&str, DTRange)> = self.agent_assignment.client_data.iter()
+            .flat_map(|client| {
+                let mut spans: SmallVec<[DTRange; 2]> = SmallVec::new();
+                for KVPair(seq, lvs) in client.lv_for_seq.iter() {
+                    let range: DTRange = (*seq..*seq + lvs.len()).into();
+                    match spans.last_mut() {
+                        Some(last) if last.end == range.start => { last.end = range.end; }
+                        _ => { spans.push(range); }
+                    }
+                }
+                spans.into_iter().map(|range| (client.name.as_str(), range))
+            })
+            .collect();
+
+        let num_items = spans.iter()
+            .map(|(_, range)| span_blocks(*range).count())
+            .sum();
+        let mut bloom = VersionBloom::with_capacity(num_items, bits_per_item);
+        for (name, range) in spans {
+            for (kind, block) in span_blocks(range) {
+                bloom.insert(name, kind, block);
+            }
+        }
+
+        bloom
+    }
+
+    /// Compare the causal graph to a remote peer's Bloom filter.
+    ///
+    /// Returns the frontier of the operations the remote peer (probably) has, and the list of local
+    /// spans it (definitely) doesn't have. Operations after the returned frontier can be sent with
+    /// `encode_from`.
+    ///
+    /// Because of false positives, the remote peer might also be missing some operations we think
+    /// it has. See [`CausalGraph::confirm_bloom_sync`].
+    pub fn intersect_with_bloom(&self, bloom: &VersionBloom) -> (Frontier, Vec<DTRange>) {
+        let mut missing: Vec<DTRange> = vec![];
+        let mut versions: SmallVec<[LV; 4]> = SmallVec::new();
+
+        let is_missing = |missing: &[DTRange], v: LV| -> bool {
+            let idx = missing.partition_point(|r| r.end <= v);
+            idx < missing.len() && missing[idx].contains(v)
+        };
+
+        for entry in self.graph.entries.iter() {
+            // The remote peer can't have an operation without also having all of its parents. And
+            // each graph entry is a linear run, so once we find an operation the remote peer is
+            // missing, it'll be missing the rest of the entry too.
+            let first_missing = if entry.parents.iter().any(|p| is_missing(&missing, *p)) {
+                Some(entry.span.start)
+            } else {
+                self.agent_assignment.client_with_localtime.iter_range(entry.span)
+                    .find_map(|KVPair(lv, span)| {
+                        let name = &self.agent_assignment.client_data[span.agent as usize].name;
+                        span.seq_range.iter()
+                            .position(|seq| !bloom.contains(name, seq))
+                            .map(|offset| lv + offset)
+                    })
+            };
+
+            let known_end = first_missing.unwrap_or(entry.span.end);
+            if known_end > entry.span.start {
+                versions.push(known_end - 1);
+            }
+
+            if let Some(start) = first_missing {
+                match missing.last_mut() {
+                    Some(last) if last.end == start => { last.end = entry.span.end; }
+                    _ => { missing.push((start..entry.span.end).into()); }
+                }
+            }
+        }
+
+        (self.graph.find_dominators(&versions), missing)
+    }
+
+    /// Check that we've received everything from a remote peer after syncing using a Bloom filter.
+    ///
+    /// `remote_frontier` is the remote peer's frontier. If we're missing some of the remote peer's
+    /// operations because of a false positive in our Bloom filter, this returns a summary of our
+    /// versions to send to the remote peer instead.
+    pub fn confirm_bloom_sync(&self, remote_frontier: &[RemoteVersionOwned]) -> Result<(), VersionSummary> {
+        if self.agent_assignment.try_remote_to_local_frontier(remote_frontier.iter()).is_ok() {
+            Ok(())
+        } else {
+            Err(self.agent_assignment.summarize_versions())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::encoding::parseerror::ParseError;
+    use crate::list::encoding::ENCODE_PATCH;
+    use crate::list::ListOpLog;
+    use crate::rle::KVPair;
+    use super::VersionBloom;
+
+    fn make_diverged() -> (ListOpLog, ListOpLog) {
+        let mut a = ListOpLog::new();
+        a.get_or_create_agent_id("seph");
+        for i in 0..50 {
+            a.add_insert(0, i, "a");
+        }
+
+        let mut b = a.clone();
+        a.add_insert(0, 10, "xyz");
+        for i in 0..20 {
+            let agent = b.get_or_create_agent_id(&format!("session {i}"));
+            b.add_insert(agent, i, "b");
+        }
+
+        (a, b)
+    }
+
+    #[test]
+    fn bloom_finds_missing_spans() {
+        let (mut a, b) = make_diverged();
+
+        let bloom = a.cg.version_bloom(16);
+        let (common, missing) = b.cg.intersect_with_bloom(&bloom);
+        assert_eq!(common.as_ref(), &[49]);
+        assert_eq!(missing, vec![(50..70).into()]);
+
+        // Everything in a's filter is in a.
+        assert_eq!(a.cg.intersect_with_bloom(&bloom).1, vec![]);
+
+        let remote_frontier = b.cg.agent_assignment.local_to_remote_frontier_owned(b.cg.version.as_ref());
+        a.decode_and_add(&b.encode_from(ENCODE_PATCH, common.as_ref())).unwrap();
+        assert!(a.cg.confirm_bloom_sync(&remote_frontier).is_ok());
+    }
+
+    #[test]
+    fn bloom_false_positive_falls_back_to_summary() {
+        let (mut a, mut b) = make_diverged();
+
+        // Simulate a filter where every one of b's operations is a false positive.
+        let mut everything = a.clone();
+        everything.decode_and_add(&b.encode(ENCODE_PATCH)).unwrap();
+        let bloom = everything.cg.version_bloom(16);
+
+        let (common, missing) = b.cg.intersect_with_bloom(&bloom);
+        assert!(missing.is_empty());
+        // The (empty) patch is based on a version a doesn't know about, so merging it fails.
+        assert_eq!(a.decode_and_add(&b.encode_from(ENCODE_PATCH, common.as_ref())), Err(ParseError::BaseVersionUnknown));
+
+        let remote_frontier = b.cg.agent_assignment.local_to_remote_frontier_owned(b.cg.version.as_ref());
+        let summary = a.cg.confirm_bloom_sync(&remote_frontier).unwrap_err();
+        a.decode_and_add(&b.encode_for_summary(&summary, ENCODE_PATCH)).unwrap();
+        assert!(a.cg.confirm_bloom_sync(&remote_frontier).is_ok());
+
+        b.decode_and_add(&a.encode(ENCODE_PATCH)).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn bloom_size_depends_on_spans() {
+        let mut oplog = ListOpLog::new();
+        oplog.get_or_create_agent_id("seph");
+        for i in 0..1000 {
+            oplog.add_insert(0, i, "a");
+        }
+
+        // 1000 operations in one span is 15 blocks of 64 and 5 blocks of 8, so 10 bits per item
+        // fits in 4 words.
+        let bloom = oplog.cg.version_bloom(10);
+        assert_eq!(bloom.clone().into_parts().1.len(), 4);
+        assert!((0..1000).all(|seq| bloom.contains("seph", seq)));
+        assert!(!bloom.contains("seph", 1000));
+    }
+
+    #[test]
+    fn bloom_spans_are_exact() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, &"a".repeat(100));
+        oplog.add_insert(mike, 0, &"b".repeat(77));
+        // Pretend seph's span starts part way through a block.
+        oplog.cg.agent_assignment.client_data[seph as usize].lv_for_seq.0 = vec![KVPair(3, (0..100).into())];
+
+        let bloom = oplog.cg.version_bloom(16);
+        assert!((3..103).all(|seq| bloom.contains("seph", seq)));
+        assert!((0..77).all(|seq| bloom.contains("mike", seq)));
+    }
+
+    #[test]
+    fn bloom_false_positive_rate() {
+        // A peer with 1000 operations by an agent checks the next operation by the same agent.
+        let false_positives = (0..200).filter(|i| {
+            let mut oplog = ListOpLog::new();
+            let agent = oplog.get_or_create_agent_id(&format!("agent {i}"));
+            oplog.add_insert(agent, 0, &"a".repeat(1000));
+            oplog.cg.version_bloom(12).contains(&format!("agent {i}"), 1000)
+        }).count();
+        assert!(false_positives <= 6, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn invalid_bloom_rejected() {
+        assert_eq!(VersionBloom::from_parts(7, vec![]), Err(ParseError::GenericInvalidData));
+        assert_eq!(VersionBloom::from_parts(0, vec![0]), Err(ParseError::GenericInvalidData));
+        assert_eq!(VersionBloom::from_parts(u32::MAX, vec![0]), Err(ParseError::GenericInvalidData));
+
+        let (num_hashes, bits) = ListOpLog::new().cg.version_bloom(10).into_parts();
+        assert!(VersionBloom::from_parts(num_hashes, bits).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn invalid_bloom_rejected_by_serde() {
+        assert!(serde_json::from_str::<VersionBloom>(r#"{"num_hashes":7,"bits":[]}"#).is_err());
+        let bloom = serde_json::from_str::<VersionBloom>(r#"{"num_hashes":7,"bits":[0]}"#).unwrap();
+        assert!(!bloom.contains("seph", 0));
+    }
+}
@@ -7,7 +7,9 @@ use crate::causalgraph::agent_assignment::remote_ids::{RemoteFrontier, RemoteFro
 use crate::causalgraph::entry::CGEntry;
 use crate::causalgraph::graph::GraphEntrySimple;
 use crate::causalgraph::agent_span::AgentSpan;
-use crate::rle::{RleSpanHelpers, RleVec};
+use crate::rle::{RleKeyedAndSplitable, RleSpanHelpers, RleVec};
+#[cfg(feature = "random_agents")]
+use crate::list::encoding::agent_table::binary_agent_name;
 
 impl CausalGraph {
     pub fn new() -> Self {
@@ -20,6 +22,35 @@ impl CausalGraph {
         self.agent_assignment.get_or_create_agent_id(name)
     }
 
+    /// Create a new agent for a local editing session, with a random name.
+    ///
+    /// Agent names are made from a random 128 bit number, formatted as 32 lowercase hex
+    /// characters. (These names are stored as 16 raw bytes by
+    /// [`AgentTable`](crate::list::encoding::AgentTable)). The random number comes from the
+    /// thread-local RNG in `rand`, which is seeded by the operating system.
+    ///
+    /// Call this once per editing session. See the crate docs for why agent IDs shouldn't be
+    /// reused.
+    #[cfg(feature = "random_agents")]
+    pub fn new_session_agent(&mut self) -> AgentId {
+        self.new_session_agent_from_rng(&mut rand::thread_rng())
+    }
+
+    /// Create a new agent for a local editing session, with a random name generated using the
+    /// passed random number generator. Use a cryptographically secure RNG - a predictable agent
+    /// name might collide with another peer's.
+    #[cfg(feature = "random_agents")]
+    pub fn new_session_agent_from_rng<R: rand::RngCore + ?Sized>(&mut self, rng: &mut R) -> AgentId {
+        use rand::Rng;
+        loop {
+            let name = binary_agent_name(rng.gen());
+            // Collisions should never happen with a good RNG, but its cheap to check.
+            if self.agent_assignment.get_agent_id(&name).is_none() {
+                return self.get_or_create_agent_id(&name);
+            }
+        }
+    }
+
     pub fn num_agents(&self) -> usize {
         self.agent_assignment.client_data.len()
     }
@@ -268,6 +299,84 @@ impl CausalGraph {
         debug_assert!(only_a.is_empty());
         only_b
     }
+
+    /// Remove every entry from `len` onwards. This is used to unwind a partially merged change
+    /// when decoding fails. `num_agents` and `version` are the values from before the merge.
+    pub(crate) fn truncate_to(&mut self, len: usize, num_agents: usize, version: Frontier) {
+        // This would be nicer with an RleVec iterator, but the iter implementation doesn't
+        // support iterating backwards.
+        while let Some(last) = self.agent_assignment.client_with_localtime.0.last_mut() {
+            debug_assert!(len <= last.end());
+            if len == last.end() { break; }
+            else {
+                // Truncate!
+                let KVPair(_, removed) = if len <= last.0 {
+                    // Drop entire entry
+                    self.agent_assignment.client_with_localtime.0.pop().unwrap()
+                } else {
+                    last.truncate(len - last.0)
+                };
+
+                let client_data = &mut self.agent_assignment.client_data[removed.agent as usize];
+                client_data.lv_for_seq.remove_ctx(removed.seq_range, &());
+            }
+        }
+
+        // Trim history
+        let hist_entries = &mut self.graph.entries;
+        let history_length = hist_entries.end();
+        if history_length > len {
+            // We can't use entries.remove because HistoryEntry doesn't support SplitableSpan.
+            // And also because we need to update child_indexes.
+            let del_span_start = len;
+
+            let first_idx = hist_entries.find_index(len).unwrap();
+
+            let e = &mut hist_entries.0[first_idx];
+            let first_truncated_idx = if del_span_start > e.span.start {
+                // The first entry just needs to be trimmed down.
+                e.span.truncate_from(del_span_start);
+                first_idx + 1
+            } else {
+                first_idx
+            };
+
+            let mut idx = first_truncated_idx;
+
+            // Go through and unwind from idx.
+            while idx < hist_entries.num_entries() {
+                // Cloning here is an ugly and kinda slow hack to work around the borrow
+                // checker. But this whole case is rare anyway, so idk.
+                let parents = hist_entries.0[idx].parents.clone();
+
+                for p in parents {
+                    if p < len { // If p >= len, the target will be discarded anyway.
+                        let parent_entry = hist_entries.find_mut(p).unwrap().0;
+                        while let Some(&c_idx) = parent_entry.child_indexes.last() {
+                            if c_idx >= first_truncated_idx {
+                                parent_entry.child_indexes.pop();
+                            } else { break; }
+                        }
+                    }
+                }
+
+                idx += 1;
+            }
+
+            self.graph.entries.0.truncate(first_truncated_idx);
+
+            while let Some(&last_idx) = self.graph.root_child_indexes.last() {
+                if last_idx >= self.graph.entries.num_entries() {
+                    self.graph.root_child_indexes.pop();
+                } else { break; }
+            }
+        }
+
+        // Remove excess agents
+        self.agent_assignment.client_data.truncate(num_agents);
+
+        self.version = version;
+    }
 }
 
 #[cfg(test)]
@@ -289,4 +398,23 @@ mod tests {
         cg.merge_and_assign(&[4], (agent, 5..15).into());
         cg.dbg_check(true);
     }
-}
\ No newline at end of file
+
+    #[test]
+    #[cfg(feature = "random_agents")]
+    fn session_agents_are_unique() {
+        use rand::rngs::SmallRng;
+        use rand::SeedableRng;
+
+        let mut cg = CausalGraph::new();
+        let a = cg.new_session_agent();
+        let b = cg.new_session_agent();
+        assert_ne!(a, b);
+        assert_eq!(cg.agent_assignment.get_agent_name(a).len(), 32);
+
+        // Even if the RNG repeats itself, we get a new agent.
+        let c = cg.new_session_agent_from_rng(&mut SmallRng::seed_from_u64(10));
+        let d = cg.new_session_agent_from_rng(&mut SmallRng::seed_from_u64(10));
+        assert_ne!(c, d);
+        assert_eq!(cg.num_agents(), 4);
+    }
+}
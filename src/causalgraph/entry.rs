@@ -1,6 +1,10 @@
 use rle::{HasLength, MergableSpan, SplitableSpan, SplitableSpanHelpers};
+use smallvec::SmallVec;
 use crate::{DTRange, Frontier, LV};
+use crate::causalgraph::agent_assignment::AgentAssignment;
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
 use crate::causalgraph::agent_span::AgentSpan;
+use crate::encoding::varint::push_usize;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct CGEntry {
@@ -55,6 +59,35 @@ impl CGEntry {
     pub fn clear(&mut self) {
         self.span.seq_range.clear()
     }
+
+    /// The start of the message an agent signs to prove it authored this entry. The message names
+    /// the entry's agent, sequence numbers and parents using remote IDs, so it is the same on every
+    /// peer. The operations themselves are appended by the list encoder.
+    ///
+    /// The message only describes the entry's parents. The operations in the entry after the first
+    /// are implicitly parented by the previous operation in the entry.
+    pub fn signing_message(&self, aa: &AgentAssignment) -> Vec<u8> {
+        let mut parents: SmallVec<[RemoteVersion; 2]> = self.parents.iter()
+            .map(|p| aa.local_to_remote_version(*p))
+            .collect();
+        parents.sort_unstable_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+        let push_str = |msg: &mut Vec<u8>, s: &str| {
+            push_usize(msg, s.len());
+            msg.extend_from_slice(s.as_bytes());
+        };
+
+        let mut msg = Vec::new();
+        push_str(&mut msg, aa.get_agent_name(self.span.agent));
+        push_usize(&mut msg, self.span.seq_range.start);
+        push_usize(&mut msg, self.span.len());
+        push_usize(&mut msg, parents.len());
+        for RemoteVersion(name, seq) in parents {
+            push_str(&mut msg, name);
+            push_usize(&mut msg, seq);
+        }
+        msg
+    }
 }
 
 impl SplitableSpanHelpers for CGEntry {
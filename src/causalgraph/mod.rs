@@ -10,6 +10,7 @@ pub mod graph;
 mod eq;
 pub mod entry;
 pub mod summary;
+pub mod bloom;
 pub mod agent_span;
 pub mod agent_assignment;
 
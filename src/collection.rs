@@ -0,0 +1,86 @@
+use std::collections::{BTreeMap, BTreeSet};
+use crate::{CollectionOp, CreateValue, DTRange, LV};
+use crate::causalgraph::graph::Graph;
+
+/// The oplog data for a collection (set) CRDT.
+///
+/// Collections are add-wins sets. Every inserted item has its own identity (the version of its
+/// insert operation), and remove operations name the specific item being removed. So a remove can
+/// only ever remove items its author has seen - any concurrently inserted items (even with equal
+/// values) are kept.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CollectionInfo {
+    /// All the operations on this collection, sorted by local version.
+    pub(crate) ops: Vec<(LV, CollectionOp)>,
+
+    /// The set of items which exist at the current version, named by the version of their insert.
+    pub(crate) live: BTreeSet<LV>,
+}
+
+impl CollectionInfo {
+    /// Look up the value of the item inserted at the specified version.
+    pub(crate) fn item_value(&self, item: LV) -> Option<&CreateValue> {
+        let idx = self.ops.binary_search_by_key(&item, |e| e.0).ok()?;
+        match &self.ops[idx].1 {
+            CollectionOp::Insert(value) => Some(value),
+            CollectionOp::Remove(_) => None,
+        }
+    }
+
+    pub(crate) fn live_items(&self) -> impl Iterator<Item = (LV, &CreateValue)> + '_ {
+        self.live.iter().map(|item| (*item, self.item_value(*item).unwrap()))
+    }
+
+    /// Returns true if any of this collection's operations are within the specified range.
+    pub(crate) fn has_op_in_range(&self, range: DTRange) -> bool {
+        let idx = self.ops
+            .binary_search_by_key(&range.start, |e| e.0)
+            .unwrap_or_else(|idx| idx);
+        self.ops.get(idx).is_some_and(|(v, _)| *v < range.end)
+    }
+
+    /// Add an operation to the collection. The operation's version must be higher than all other
+    /// operations in the collection, and removes must name an item in the collection.
+    ///
+    /// If the operation removes a (live) item, the ID of the removed item is returned.
+    pub(crate) fn push_op(&mut self, v: LV, op: CollectionOp) -> Option<LV> {
+        if let Some((last_v, _)) = self.ops.last() {
+            assert!(*last_v < v);
+        }
+
+        let removed = match &op {
+            CollectionOp::Insert(_) => {
+                self.live.insert(v);
+                None
+            }
+            CollectionOp::Remove(target) => {
+                assert!(self.item_value(*target).is_some(), "Removed item is not in the collection");
+                // The item might already have been removed by a concurrent operation.
+                if self.live.remove(target) { Some(*target) } else { None }
+            }
+        };
+
+        self.ops.push((v, op));
+        removed
+    }
+
+    /// Get the items in the collection at some (possibly historical) version.
+    pub(crate) fn items_at(&self, graph: &Graph, frontier: &[LV]) -> BTreeMap<LV, &CreateValue> {
+        let mut result = BTreeMap::new();
+        let Some(last) = frontier.last() else { return result; };
+
+        // Any removed item must have been inserted earlier in the same version. So we can just
+        // scan the operations in order.
+        for (v, op) in self.ops.iter() {
+            if *v > *last { break; }
+            if !graph.frontier_contains_version(frontier, *v) { continue; }
+
+            match op {
+                CollectionOp::Insert(value) => { result.insert(*v, value); }
+                CollectionOp::Remove(target) => { result.remove(target); }
+            }
+        }
+
+        result
+    }
+}
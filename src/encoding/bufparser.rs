@@ -56,7 +56,7 @@ impl<'a> BufParser<'a> {
     }
 
     pub(crate) fn next_u32_le(&mut self) -> Result<u32, ParseError> {
-        // self.check_has_bytes(size_of::<u32>())?;
+        self.check_has_bytes(size_of::<u32>())?;
         let val = u32::from_le_bytes(self.0[0..4].try_into().map_err(|_| ParseError::UnexpectedEOF)?);
         self.consume(size_of::<u32>());
         Ok(val)
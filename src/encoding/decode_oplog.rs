@@ -0,0 +1,706 @@
+use std::borrow::Cow;
+use rle::{HasLength, SplitableSpan};
+use std::collections::{BTreeMap, BTreeSet};
+use smartstring::alias::String as SmartString;
+use crate::{CollectionOp, CRDTKind, CreateValue, DTRange, LV, LVKey, MarkAnchor, MarkOp, OpLog, ROOT_CRDT_ID};
+use crate::encoding::{ChunkType, CompressionFormat, OPLOG_MAGIC_BYTES, OPLOG_PROTOCOL_VERSION};
+use crate::encoding::bufparser::BufParser;
+use crate::encoding::cg_entry::read_cg_entry_into_cg;
+use crate::encoding::chunk_reader::ChunkReader;
+use crate::encoding::map::ReadMap;
+use crate::encoding::op_contents::{OpType, read_create_value, read_primitive, read_time};
+use crate::encoding::parseerror::ParseError;
+use crate::encoding::tools::calc_checksum;
+use crate::encoding::varint::*;
+use crate::list::operation::{ListOpKind, TextOperation};
+use crate::rev_range::RangeRev;
+use crate::unicount::{consume_chars, count_chars};
+
+fn read_content_chunk(mut chunk: BufParser) -> Result<Cow<str>, ParseError> {
+    let format = CompressionFormat::try_from(chunk.next_u32()?)
+        .map_err(|_| ParseError::InvalidContent)?;
+
+    match format {
+        CompressionFormat::Uncompressed => {
+            std::str::from_utf8(chunk.0)
+                .map(Cow::Borrowed)
+                .map_err(|_| ParseError::InvalidUTF8)
+        }
+        #[cfg(feature = "lz4")]
+        CompressionFormat::LZ4 => {
+            let uncompressed_len = chunk.next_usize()?;
+            let data = lz4_flex::decompress(chunk.0, uncompressed_len)
+                .map_err(|_e| ParseError::LZ4DecompressionError)?;
+            String::from_utf8(data)
+                .map(Cow::Owned)
+                .map_err(|_| ParseError::InvalidUTF8)
+        }
+        #[cfg(not(feature = "lz4"))]
+        CompressionFormat::LZ4 => Err(ParseError::LZ4DecoderNeeded),
+    }
+}
+
+/// An operation read from a file. Operations are checked as they're read, but they aren't added to
+/// the oplog until the whole file has been read successfully.
+enum DecodedOp {
+    MapSet(LVKey, LV, SmartString, CreateValue),
+    RegisterSet(LVKey, LV, CreateValue),
+    Collection(LVKey, LV, CollectionOp),
+    Text(LVKey, DTRange, TextOperation),
+    List(LVKey, DTRange, TextOperation, Vec<CreateValue>),
+    Mark(LVKey, LV, MarkOp),
+}
+
+/// Things created by earlier operations in the file being read, which later operations in the
+/// same file can refer to.
+#[derive(Debug, Default)]
+struct PendingItems {
+    crdts: BTreeMap<LV, CRDTKind>,
+    collection_items: BTreeSet<(LVKey, LV)>,
+    text_inserts: Vec<(LVKey, DTRange)>,
+}
+
+impl PendingItems {
+    fn created(&mut self, v: LV, value: &CreateValue) {
+        if let CreateValue::NewCRDT(kind) = value {
+            self.crdts.insert(v, *kind);
+        }
+    }
+
+    fn has_crdt(&self, crdt: LVKey, kind: CRDTKind) -> bool {
+        self.crdts.get(&crdt) == Some(&kind)
+    }
+
+    fn is_text_insert(&self, crdt: LVKey, item: LV) -> bool {
+        self.text_inserts.iter().any(|(c, range)| *c == crdt && range.contains(item))
+    }
+}
+
+impl OpLog {
+    /// Load an oplog from data created by [`OpLog::encode`].
+    pub fn load_from(data: &[u8]) -> Result<Self, ParseError> {
+        let mut oplog = Self::new();
+        oplog.decode_and_add(data)?;
+        Ok(oplog)
+    }
+
+    /// Add all operations from a binary chunk (created by [`OpLog::encode`] or
+    /// [`OpLog::encode_from`]) into this oplog. Any operations we already know about are ignored.
+    ///
+    /// Returns the range of local versions which were added. If the data is invalid, an error is
+    /// returned and the oplog is left unchanged.
+    pub fn decode_and_add(&mut self, data: &[u8]) -> Result<DTRange, ParseError> {
+        // The causal graph entries are merged in while the file is read, so if an error happens
+        // we need to unwind them before returning. Operations are only applied once the whole file
+        // has been read and checked.
+        let len = self.cg.len();
+        let num_known_agents = self.cg.agent_assignment.client_data.len();
+        let old_frontier = self.cg.version.clone();
+
+        match self.decode_internal(data) {
+            Ok((new_range, ops)) => {
+                for op in ops {
+                    match op {
+                        DecodedOp::MapSet(crdt, lv, key, value) => self.remote_map_set(crdt, lv, &key, value),
+                        DecodedOp::RegisterSet(crdt, lv, value) => self.remote_register_set(crdt, lv, value),
+                        DecodedOp::Collection(crdt, lv, op) => self.remote_collection_op(crdt, lv, op),
+                        DecodedOp::Text(crdt, v_range, op) => self.remote_text_op(crdt, v_range, op),
+                        DecodedOp::List(crdt, v_range, op, values) => self.remote_list_op(crdt, v_range, op, values),
+                        DecodedOp::Mark(crdt, lv, op) => self.remote_mark(crdt, lv, op),
+                    }
+                }
+                Ok(new_range)
+            }
+            Err(err) => {
+                self.cg.truncate_to(len, num_known_agents, old_frontier);
+                Err(err)
+            }
+        }
+    }
+
+    /// Read the file, merging its causal graph entries in. The operations in the file are checked
+    /// and returned, but not applied.
+    fn decode_internal(&mut self, data: &[u8]) -> Result<(DTRange, Vec<DecodedOp>), ParseError> {
+        let mut reader = BufParser(data);
+        reader.check_has_bytes(OPLOG_MAGIC_BYTES.len())?;
+        if reader.next_n_bytes(OPLOG_MAGIC_BYTES.len())? != OPLOG_MAGIC_BYTES {
+            return Err(ParseError::InvalidMagic);
+        }
+
+        let protocol_version = reader.next_usize()?;
+        if protocol_version != OPLOG_PROTOCOL_VERSION {
+            return Err(ParseError::UnsupportedProtocolVersion);
+        }
+
+        let mut chunks = ChunkReader(reader);
+        let _user_data = chunks.read_chunk_if_eq(ChunkType::UserData)?;
+        let mut cg_chunk = chunks.expect_chunk(ChunkType::CausalGraph)?;
+        let mut ops_chunk = chunks.expect_chunk(ChunkType::Operations)?;
+        let content_chunk = chunks.read_chunk_if_eq(ChunkType::PatchContent)?;
+
+        // The checksummed bytes are everything up to (but not including) the Crc chunk.
+        let reader_len = chunks.0.len();
+        if let Some(mut crc_reader) = chunks.read_chunk_if_eq(ChunkType::Crc)? {
+            let expected_crc = crc_reader.next_u32_le()?;
+            if calc_checksum(&data[..data.len() - reader_len]) != expected_crc {
+                return Err(ParseError::ChecksumFailed);
+            }
+        }
+        chunks.expect_empty()?;
+
+        // The content is only decompressed once we know the data isn't corrupt.
+        let content = content_chunk.map(read_content_chunk).transpose()?;
+
+        let mut read_map = ReadMap::new();
+        let old_end = self.cg.len();
+        while !cg_chunk.is_empty() {
+            read_cg_entry_into_cg(&mut cg_chunk, true, &mut self.cg, &mut read_map)?;
+        }
+        let new_range: DTRange = (old_end..self.cg.len()).into();
+
+        // Like merge_ops, any operations we already know about are skipped.
+        if new_range.is_empty() { return Ok((new_range, vec![])); }
+
+        let mut content = content.as_deref().unwrap_or("");
+        let mut next_time = 0;
+        let mut last_crdt = ROOT_CRDT_ID;
+        let mut ops = vec![];
+        let mut pending = PendingItems::default();
+
+        while !ops_chunk.is_empty() {
+            let mut n = ops_chunk.next_u32()?;
+            let has_time_skip = strip_bit_u32_2(&mut n);
+            let has_crdt_id = strip_bit_u32_2(&mut n);
+            let op_type = OpType::try_from(n).map_err(|_| ParseError::GenericInvalidData)?;
+
+            if has_time_skip {
+                next_time += ops_chunk.next_usize()?;
+            }
+            if has_crdt_id {
+                last_crdt = read_time(&mut ops_chunk, next_time, true, &mut self.cg.agent_assignment, &mut read_map)?;
+            }
+
+            match op_type {
+                OpType::MapSet => {
+                    let key = ops_chunk.next_str()?;
+                    let value = read_create_value(&mut ops_chunk)?;
+
+                    let (entry, offset) = read_map.txn_map.find_with_offset(next_time)
+                        .ok_or(ParseError::GenericInvalidData)?;
+                    let lv = entry.1.start + offset;
+                    if lv >= new_range.start {
+                        if !self.is_map(last_crdt) && !pending.has_crdt(last_crdt, CRDTKind::Map) {
+                            return Err(ParseError::DataMissing);
+                        }
+                        pending.created(lv, &value);
+                        ops.push(DecodedOp::MapSet(last_crdt, lv, key.into(), value));
+                    }
+                    next_time += 1;
+                }
+                OpType::RegisterSet => {
+                    let value = read_create_value(&mut ops_chunk)?;
+
+                    let (entry, offset) = read_map.txn_map.find_with_offset(next_time)
+                        .ok_or(ParseError::GenericInvalidData)?;
+                    let lv = entry.1.start + offset;
+                    if lv >= new_range.start {
+                        if !self.registers.contains_key(&last_crdt) && !pending.has_crdt(last_crdt, CRDTKind::Register) {
+                            return Err(ParseError::DataMissing);
+                        }
+                        pending.created(lv, &value);
+                        ops.push(DecodedOp::RegisterSet(last_crdt, lv, value));
+                    }
+                    next_time += 1;
+                }
+                OpType::CollectionInsert | OpType::CollectionRemove => {
+                    let op = if op_type == OpType::CollectionInsert {
+                        CollectionOp::Insert(read_create_value(&mut ops_chunk)?)
+                    } else {
+                        CollectionOp::Remove(read_time(&mut ops_chunk, next_time, true, &mut self.cg.agent_assignment, &mut read_map)?)
+                    };
+
+                    let (entry, offset) = read_map.txn_map.find_with_offset(next_time)
+                        .ok_or(ParseError::GenericInvalidData)?;
+                    let lv = entry.1.start + offset;
+                    if lv >= new_range.start {
+                        let info = self.collections.get(&last_crdt);
+                        if info.is_none() && !pending.has_crdt(last_crdt, CRDTKind::Collection) {
+                            return Err(ParseError::DataMissing);
+                        }
+                        match &op {
+                            CollectionOp::Insert(value) => {
+                                pending.created(lv, value);
+                                pending.collection_items.insert((last_crdt, lv));
+                            }
+                            CollectionOp::Remove(target) => {
+                                let known = info.is_some_and(|info| info.item_value(*target).is_some())
+                                    || pending.collection_items.contains(&(last_crdt, *target));
+                                if !known { return Err(ParseError::GenericInvalidData); }
+                            }
+                        }
+                        ops.push(DecodedOp::Collection(last_crdt, lv, op));
+                    }
+                    next_time += 1;
+                }
+                OpType::TextInsert | OpType::TextDelete => {
+                    let kind = if op_type == OpType::TextInsert { ListOpKind::Ins } else { ListOpKind::Del };
+                    let mut n = ops_chunk.next_usize()?;
+                    let has_content = strip_bit_usize_2(&mut n);
+                    let fwd = strip_bit_usize_2(&mut n);
+                    let len = n;
+                    let start = ops_chunk.next_usize()?;
+
+                    let op_content = if has_content {
+                        let c = consume_chars(&mut content, len);
+                        if count_chars(c) != len { return Err(ParseError::InvalidContent); }
+                        Some(c.into())
+                    } else { None };
+
+                    let mut op = TextOperation {
+                        loc: RangeRev { span: (start..start + len).into(), fwd },
+                        kind,
+                        content: op_content,
+                    };
+
+                    if !self.texts.contains_key(&last_crdt) && !pending.has_crdt(last_crdt, CRDTKind::Text) {
+                        return Err(ParseError::DataMissing);
+                    }
+
+                    // The operation might span multiple runs of local versions.
+                    loop {
+                        let (entry, offset) = read_map.txn_map.find_with_offset(next_time)
+                            .ok_or(ParseError::GenericInvalidData)?;
+                        let lv = entry.1.start + offset;
+                        let here_len = op.len().min(entry.1.len() - offset);
+                        let rest = if here_len < op.len() { Some(op.truncate(here_len)) } else { None };
+                        next_time += here_len;
+
+                        let mut v_range: DTRange = (lv..lv + here_len).into();
+                        if v_range.end > new_range.start {
+                            if v_range.start < new_range.start {
+                                // Trim the part we already have.
+                                op.truncate_keeping_right(new_range.start - v_range.start);
+                                v_range.start = new_range.start;
+                            }
+                            if kind == ListOpKind::Ins {
+                                pending.text_inserts.push((last_crdt, v_range));
+                            }
+                            ops.push(DecodedOp::Text(last_crdt, v_range, op));
+                        }
+
+                        if let Some(rest) = rest { op = rest; } else { break; }
+                    }
+                }
+                OpType::ListInsert | OpType::ListDelete => {
+                    let kind = if op_type == OpType::ListInsert { ListOpKind::Ins } else { ListOpKind::Del };
+                    let mut n = ops_chunk.next_usize()?;
+                    let fwd = strip_bit_usize_2(&mut n);
+                    let len = n;
+                    let start = ops_chunk.next_usize()?;
+
+                    let mut values = if kind == ListOpKind::Ins {
+                        (0..len).map(|_| read_create_value(&mut ops_chunk))
+                            .collect::<Result<Vec<_>, _>>()?
+                    } else { vec![] };
+
+                    let mut op = TextOperation {
+                        loc: RangeRev { span: (start..start + len).into(), fwd },
+                        kind,
+                        content: None,
+                    };
+
+                    if !self.lists.contains_key(&last_crdt) && !pending.has_crdt(last_crdt, CRDTKind::List) {
+                        return Err(ParseError::DataMissing);
+                    }
+
+                    // Like text operations, this might span multiple runs of local versions.
+                    loop {
+                        let (entry, offset) = read_map.txn_map.find_with_offset(next_time)
+                            .ok_or(ParseError::GenericInvalidData)?;
+                        let lv = entry.1.start + offset;
+                        let here_len = op.len().min(entry.1.len() - offset);
+                        let rest = if here_len < op.len() { Some(op.truncate(here_len)) } else { None };
+                        let rest_values = if kind == ListOpKind::Ins { values.split_off(here_len) } else { vec![] };
+                        next_time += here_len;
+
+                        let mut v_range: DTRange = (lv..lv + here_len).into();
+                        if v_range.end > new_range.start {
+                            if v_range.start < new_range.start {
+                                // Trim the part we already have.
+                                let known = new_range.start - v_range.start;
+                                op.truncate_keeping_right(known);
+                                if kind == ListOpKind::Ins { values.drain(..known); }
+                                v_range.start = new_range.start;
+                            }
+                            for (v, value) in v_range.iter().zip(values.iter()) {
+                                pending.created(v, value);
+                            }
+                            ops.push(DecodedOp::List(last_crdt, v_range, op, values));
+                        }
+
+                        if let Some(rest) = rest { op = rest; values = rest_values; } else { break; }
+                    }
+                }
+                OpType::TextMark => {
+                    let mut read_anchor = |ops_chunk: &mut BufParser| -> Result<MarkAnchor, ParseError> {
+                        Ok(match ops_chunk.next_u32()? {
+                            0 => MarkAnchor::Start,
+                            1 => MarkAnchor::Before(read_time(ops_chunk, next_time, true, &mut self.cg.agent_assignment, &mut read_map)?),
+                            2 => MarkAnchor::After(read_time(ops_chunk, next_time, true, &mut self.cg.agent_assignment, &mut read_map)?),
+                            3 => MarkAnchor::End,
+                            _ => { return Err(ParseError::GenericInvalidData); }
+                        })
+                    };
+                    let start = read_anchor(&mut ops_chunk)?;
+                    let end = read_anchor(&mut ops_chunk)?;
+                    let op = MarkOp {
+                        start,
+                        end,
+                        key: ops_chunk.next_str()?.into(),
+                        value: read_primitive(&mut ops_chunk)?,
+                    };
+
+                    let (entry, offset) = read_map.txn_map.find_with_offset(next_time)
+                        .ok_or(ParseError::GenericInvalidData)?;
+                    let lv = entry.1.start + offset;
+                    if lv >= new_range.start {
+                        if !self.texts.contains_key(&last_crdt) && !pending.has_crdt(last_crdt, CRDTKind::Text) {
+                            return Err(ParseError::DataMissing);
+                        }
+                        let anchors_valid = [op.start.item(), op.end.item()].into_iter()
+                            .flatten()
+                            .all(|item| self.texts.get(&last_crdt).is_some_and(|info| info.is_insert(item))
+                                || pending.is_text_insert(last_crdt, item));
+                        if !anchors_valid {
+                            return Err(ParseError::GenericInvalidData);
+                        }
+                        ops.push(DecodedOp::Mark(last_crdt, lv, op));
+                    }
+                    next_time += 1;
+                }
+            }
+        }
+
+        Ok((new_range, ops))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rle::HasLength;
+    use crate::{CRDTKind, CreateValue, DTValue, MarkExpand, OpLog, Primitive, ROOT_CRDT_ID};
+    use crate::encoding::parseerror::ParseError;
+    use crate::list::encoding::{ENCODE_FULL, EncodeOptions};
+    use crate::list::operation::TextOperation;
+
+    fn simple_oplog() -> OpLog {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let mike = oplog.cg.get_or_create_agent_id("mike");
+
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "name", CreateValue::Primitive(Primitive::Str("seph".into())));
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "age", CreateValue::Primitive(Primitive::I64(-21)));
+        let inner = oplog.local_map_set(mike, ROOT_CRDT_ID, "facts", CreateValue::NewCRDT(CRDTKind::Map));
+        oplog.local_map_set(mike, inner, "cool", CreateValue::Primitive(Primitive::Bool(true)));
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "content", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi there 😃"));
+        oplog.local_text_op(mike, text, TextOperation::new_delete(0..3));
+        oplog.local_map_set(mike, inner, "cool", CreateValue::Primitive(Primitive::Nil));
+        oplog
+    }
+
+    fn check_round_trips(oplog: &OpLog) {
+        for opts in [ENCODE_FULL, EncodeOptions { compress_content: false, ..ENCODE_FULL }] {
+            let data = oplog.encode(opts);
+            let result = OpLog::load_from(&data).unwrap();
+            result.dbg_check(true);
+            assert_eq!(oplog.cg.version, result.cg.version);
+            assert_eq!(oplog.checkout(), result.checkout());
+        }
+    }
+
+    #[test]
+    fn empty_oplog_round_trips() {
+        check_round_trips(&OpLog::new());
+    }
+
+    #[test]
+    fn simple_round_trip() {
+        check_round_trips(&simple_oplog());
+    }
+
+    #[test]
+    fn concurrent_round_trip() {
+        let mut a = simple_oplog();
+        let mut b = a.clone();
+        // Local versions match in both oplogs up to here.
+        let base_version = a.cg.version.clone();
+
+        let text = a.text_at_path(&["content"]);
+        let seph = a.cg.get_or_create_agent_id("seph");
+        a.local_text_op(seph, text, TextOperation::new_insert(2, "abc"));
+        a.local_map_set(seph, ROOT_CRDT_ID, "age", CreateValue::Primitive(Primitive::I64(100)));
+
+        let kaarina = b.cg.get_or_create_agent_id("kaarina");
+        b.local_text_op(kaarina, text, TextOperation::new_insert(0, "xyz"));
+        b.local_map_set(kaarina, ROOT_CRDT_ID, "age", CreateValue::Primitive(Primitive::I64(200)));
+
+        let a_changes = a.encode_from(ENCODE_FULL, base_version.as_ref());
+        a.decode_and_add(&b.encode_from(ENCODE_FULL, &[])).unwrap();
+        b.decode_and_add(&a_changes).unwrap();
+        a.dbg_check(true);
+        b.dbg_check(true);
+        assert_eq!(a.checkout(), b.checkout());
+
+        check_round_trips(&a);
+    }
+
+    #[test]
+    fn overlapping_merges() {
+        let mut oplog = simple_oplog();
+        let data = oplog.encode(ENCODE_FULL);
+
+        // Merging the same data twice does nothing.
+        let mut result = OpLog::load_from(&data).unwrap();
+        assert!(result.decode_and_add(&data).unwrap().is_empty());
+        let full_version = result.cg.version.clone();
+
+        let text = oplog.text_at_path(&["content"]);
+        let mike = oplog.cg.get_or_create_agent_id("mike");
+        oplog.local_text_op(mike, text, TextOperation::new_insert(1, "ooo"));
+        oplog.local_map_set(mike, ROOT_CRDT_ID, "name", CreateValue::Primitive(Primitive::Str("mike".into())));
+
+        // Merging the whole file only adds the new operations.
+        let mut full = result.clone();
+        let added = full.decode_and_add(&oplog.encode(ENCODE_FULL)).unwrap();
+        assert_eq!(added.len(), 4);
+        full.dbg_check(true);
+        assert_eq!(full.checkout(), oplog.checkout());
+
+        // And merging just the new operations does the same thing.
+        result.decode_and_add(&oplog.encode_from(ENCODE_FULL, full_version.as_ref())).unwrap();
+        result.dbg_check(true);
+        assert_eq!(result.checkout(), oplog.checkout());
+    }
+
+    #[test]
+    fn collections_round_trip() {
+        let mut a = simple_oplog();
+        let seph = a.cg.get_or_create_agent_id("seph");
+        let set = a.local_map_set(seph, ROOT_CRDT_ID, "set", CreateValue::NewCRDT(CRDTKind::Collection));
+        let item = a.local_collection_insert(seph, set, CreateValue::Primitive(Primitive::I64(1)));
+        let text = a.local_collection_insert(seph, set, CreateValue::NewCRDT(CRDTKind::Text));
+        a.local_text_op(seph, text, TextOperation::new_insert(0, "yo"));
+        check_round_trips(&a);
+
+        let mut b = a.clone();
+        let base_version = a.cg.version.clone();
+
+        // Kaarina removes the items seph created, while seph adds another item.
+        let kaarina = b.cg.get_or_create_agent_id("kaarina");
+        b.local_collection_remove(kaarina, set, item);
+        b.local_collection_remove(kaarina, set, text);
+        a.local_collection_insert(seph, set, CreateValue::Primitive(Primitive::Str("hi".into())));
+
+        a.decode_and_add(&b.encode_from(ENCODE_FULL, base_version.as_ref())).unwrap();
+        a.dbg_check(true);
+        let items = a.checkout_collection(set);
+        assert_eq!(items.len(), 1);
+        assert_eq!(*items.values().next().unwrap().as_ref(), DTValue::Primitive(Primitive::Str("hi".into())));
+
+        check_round_trips(&a);
+    }
+
+    #[test]
+    fn registers_round_trip() {
+        let mut a = simple_oplog();
+        let seph = a.cg.get_or_create_agent_id("seph");
+        let reg = a.local_map_set(seph, ROOT_CRDT_ID, "reg", CreateValue::NewCRDT(CRDTKind::Register));
+        let inner = a.local_register_set(seph, reg, CreateValue::NewCRDT(CRDTKind::Register));
+        a.local_register_set(seph, inner, CreateValue::Primitive(Primitive::I64(1)));
+        check_round_trips(&a);
+
+        let mut b = a.clone();
+        let base_version = a.cg.version.clone();
+
+        a.local_register_set(seph, reg, CreateValue::Primitive(Primitive::Str("a".into())));
+        let kaarina = b.cg.get_or_create_agent_id("kaarina");
+        b.local_register_set(kaarina, reg, CreateValue::Primitive(Primitive::Str("b".into())));
+
+        let a_changes = a.encode_from(ENCODE_FULL, base_version.as_ref());
+        a.decode_and_add(&b.encode_from(ENCODE_FULL, base_version.as_ref())).unwrap();
+        b.decode_and_add(&a_changes).unwrap();
+        a.dbg_check(true);
+        b.dbg_check(true);
+        assert_eq!(a.checkout(), b.checkout());
+        assert_eq!(a.checkout_register(reg).conflicts_with.len(), 1);
+
+        check_round_trips(&a);
+    }
+
+    #[test]
+    fn lists_round_trip() {
+        let mut a = simple_oplog();
+        let seph = a.cg.get_or_create_agent_id("seph");
+        let list = a.local_map_set(seph, ROOT_CRDT_ID, "list", CreateValue::NewCRDT(CRDTKind::List));
+        for i in 0..3 {
+            // Typing backwards makes a reversed run of inserts.
+            a.local_list_insert(seph, list, 0, CreateValue::Primitive(Primitive::I64(i)));
+        }
+        let text = a.local_list_insert(seph, list, 3, CreateValue::NewCRDT(CRDTKind::Text));
+        a.local_text_op(seph, text, TextOperation::new_insert(0, "yo"));
+        check_round_trips(&a);
+
+        let mut b = a.clone();
+        let base_version = a.cg.version.clone();
+
+        let kaarina = b.cg.get_or_create_agent_id("kaarina");
+        b.local_list_delete(kaarina, list, 1..4);
+        a.local_list_insert(seph, list, 2, CreateValue::Primitive(Primitive::Str("hi".into())));
+
+        let a_changes = a.encode_from(ENCODE_FULL, base_version.as_ref());
+        a.decode_and_add(&b.encode_from(ENCODE_FULL, base_version.as_ref())).unwrap();
+        b.decode_and_add(&a_changes).unwrap();
+        a.dbg_check(true);
+        b.dbg_check(true);
+        assert_eq!(a.checkout(), b.checkout());
+        assert_eq!(a.checkout_list(list), vec![
+            DTValue::Primitive(Primitive::I64(2)),
+            DTValue::Primitive(Primitive::Str("hi".into())),
+        ]);
+
+        check_round_trips(&a);
+    }
+
+    #[test]
+    fn marks_round_trip() {
+        let mut a = simple_oplog();
+        let text = a.text_at_path(&["content"]);
+        let seph = a.cg.get_or_create_agent_id("seph");
+        a.local_mark(seph, text, 0..5, MarkExpand::After, "bold", Primitive::Bool(true));
+        a.local_mark(seph, text, 1..2, MarkExpand::Both, "size", Primitive::F64(1.5));
+        a.local_text_op(seph, text, TextOperation::new_delete(0..2));
+        let data = a.encode(ENCODE_FULL);
+
+        let mut b = OpLog::load_from(&data).unwrap();
+        b.dbg_check(true);
+        assert_eq!(a.checkout_formatted_text(text), b.checkout_formatted_text(text));
+        let base_version = a.cg.version.clone();
+
+        // And merge a concurrent mark.
+        let kaarina = b.cg.get_or_create_agent_id("kaarina");
+        b.local_mark(kaarina, text, 0..3, MarkExpand::None, "bold", Primitive::Nil);
+        a.decode_and_add(&b.encode_from(ENCODE_FULL, base_version.as_ref())).unwrap();
+        a.dbg_check(true);
+        assert_eq!(a.checkout_formatted_text(text), b.checkout_formatted_text(text));
+        assert!(a.checkout_formatted_text(text)[0].marks.is_empty());
+    }
+
+    fn check_unroll_works(dest: &OpLog, data: &[u8], expected_err: ParseError) {
+        let mut result = dest.clone();
+        assert_eq!(result.decode_and_add(data).unwrap_err(), expected_err);
+        result.dbg_check(true);
+        assert_eq!(result.cg, dest.cg);
+        assert_eq!(result.checkout(), dest.checkout());
+    }
+
+    #[test]
+    fn error_unrolling() {
+        let mut oplog = simple_oplog();
+        let base = OpLog::new();
+        let data = oplog.encode(ENCODE_FULL);
+
+        // Corrupting any byte should either fail and leave the oplog alone, or (if the corruption
+        // removes the checksum) load something.
+        for i in 0..data.len() {
+            let mut corrupted = data.clone();
+            corrupted[i] = !corrupted[i];
+            let mut result = base.clone();
+            if result.decode_and_add(&corrupted).is_err() {
+                assert_eq!(result.cg, base.cg);
+                assert_eq!(result.checkout(), base.checkout());
+            }
+        }
+
+        // This data has valid causal graph entries, but its operations modify a register the
+        // destination doesn't have. The graph entries must be removed again.
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let reg = oplog.local_map_set(seph, ROOT_CRDT_ID, "reg", CreateValue::NewCRDT(CRDTKind::Register));
+        let after_reg = oplog.cg.version.clone();
+        let text = oplog.text_at_path(&["content"]);
+        let kaarina = oplog.cg.get_or_create_agent_id("kaarina");
+        oplog.local_text_op(kaarina, text, TextOperation::new_insert(0, "abc"));
+        oplog.local_register_set(seph, reg, CreateValue::Primitive(Primitive::I64(1)));
+
+        // The destination knows about the register's creation in its causal graph, but it never
+        // created the register itself.
+        let mut dest = simple_oplog();
+        let version = dest.cg.version.clone();
+        dest.cg.merge_and_assign(version.as_ref(), oplog.cg.agent_assignment.local_span_to_agent_span((reg..reg + 1).into()));
+        check_unroll_works(&dest, &oplog.encode_from(ENCODE_FULL, after_reg.as_ref()), ParseError::DataMissing);
+    }
+
+    #[test]
+    fn map_set_needs_a_map() {
+        // The destination knows about the map's creation in its causal graph, but it never
+        // created the map itself.
+        let mut oplog = simple_oplog();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let map = oplog.local_map_set(seph, ROOT_CRDT_ID, "map", CreateValue::NewCRDT(CRDTKind::Map));
+        let after_map = oplog.cg.version.clone();
+        oplog.local_map_set(seph, map, "x", CreateValue::Primitive(Primitive::I64(1)));
+
+        let mut dest = simple_oplog();
+        let version = dest.cg.version.clone();
+        dest.cg.merge_and_assign(version.as_ref(), oplog.cg.agent_assignment.local_span_to_agent_span((map..map + 1).into()));
+        check_unroll_works(&dest, &oplog.encode_from(ENCODE_FULL, after_map.as_ref()), ParseError::DataMissing);
+
+        // Setting a key in a text CRDT is rejected too.
+        let mut oplog = simple_oplog();
+        let text = oplog.text_at_path(&["content"]);
+        oplog.local_map_set(seph, text, "x", CreateValue::Primitive(Primitive::I64(1)));
+        assert_eq!(OpLog::load_from(&oplog.encode(ENCODE_FULL)).unwrap_err(), ParseError::DataMissing);
+    }
+
+    #[test]
+    fn checksum_and_magic_are_checked() {
+        let oplog = simple_oplog();
+        let data = oplog.encode(EncodeOptions { compress_content: false, ..ENCODE_FULL });
+
+        let mut corrupted = data.clone();
+        corrupted[20] ^= 0x01;
+        let mut result = OpLog::new();
+        assert!(result.decode_and_add(&corrupted).is_err());
+        assert_eq!(result.cg.len(), 0);
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(OpLog::load_from(&bad_magic).unwrap_err(), ParseError::InvalidMagic);
+    }
+
+    #[test]
+    fn truncated_checksum_is_an_error() {
+        let oplog = simple_oplog();
+        let data = oplog.encode(ENCODE_FULL);
+
+        // The file ends with the Crc chunk's length (4) and the checksum. Replace them with an
+        // empty chunk.
+        let mut truncated = data[..data.len() - 5].to_vec();
+        truncated.push(0);
+        assert_eq!(OpLog::load_from(&truncated).unwrap_err(), ParseError::UnexpectedEOF);
+    }
+
+    #[test]
+    fn deleted_content_is_optional() {
+        let oplog = simple_oplog();
+        // Inserted content is stored anyway, since its needed to check out the document.
+        let data = oplog.encode(EncodeOptions {
+            store_inserted_content: false,
+            store_deleted_content: false,
+            ..ENCODE_FULL
+        });
+        let result = OpLog::load_from(&data).unwrap();
+        assert_eq!(result.cg.version, oplog.cg.version);
+        assert_eq!(result.checkout(), oplog.checkout());
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::{BTreeMap, BTreeSet};
+use rle::HasLength;
+use crate::{CollectionOp, CreateValue, LV, LVKey, MarkAnchor, MarkOp, OpLog, ROOT_CRDT_ID};
+use crate::encoding::{ChunkType, CompressionFormat, OPLOG_MAGIC_BYTES, OPLOG_PROTOCOL_VERSION};
+use crate::encoding::cg_entry::write_cg_entry_iter;
+use crate::encoding::map::WriteMap;
+use crate::encoding::op_contents::{OpType, write_create_value, write_primitive, write_time};
+use crate::encoding::tools::{calc_checksum, push_chunk, push_str};
+use crate::encoding::varint::*;
+use crate::list::encoding::EncodeOptions;
+use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
+use crate::list::operation::ListOpKind;
+use crate::rle::KVPair;
+
+/// An operation borrowed out of the oplog, waiting to be written.
+#[derive(Debug, Clone)]
+enum OpRef<'a> {
+    MapSet(&'a str, &'a CreateValue),
+    RegisterSet(&'a CreateValue),
+    Text(ListOpMetrics, &'a ListOperationCtx),
+    CollectionInsert(&'a CreateValue),
+    CollectionRemove(LV),
+    /// A list operation, with the values of any inserted items.
+    List(ListOpMetrics, &'a BTreeMap<LV, CreateValue>),
+    Mark(&'a MarkOp),
+}
+
+impl<'a> OpRef<'a> {
+    fn len(&self) -> usize {
+        match self {
+            OpRef::Text(metrics, _) | OpRef::List(metrics, _) => metrics.len(),
+            _ => 1,
+        }
+    }
+}
+
+/// Write the content chunk. Content is compressed with LZ4 if requested, and the lz4 feature is
+/// enabled.
+fn write_content_chunk(result: &mut Vec<u8>, content: &str, compress: bool) {
+    let mut buf = vec![];
+
+    #[cfg(feature = "lz4")]
+    if compress {
+        push_u32(&mut buf, CompressionFormat::LZ4 as u32);
+        push_usize(&mut buf, content.len());
+        buf.extend_from_slice(&lz4_flex::compress(content.as_bytes()));
+        push_chunk(result, ChunkType::PatchContent, &buf).unwrap();
+        return;
+    }
+
+    #[cfg(not(feature = "lz4"))]
+    let _ = compress;
+
+    push_u32(&mut buf, CompressionFormat::Uncompressed as u32);
+    buf.extend_from_slice(content.as_bytes());
+    push_chunk(result, ChunkType::PatchContent, &buf).unwrap();
+}
+
+impl OpLog {
+    /// Encode the whole oplog into a compact binary format, which can be loaded again with
+    /// [`OpLog::load_from`].
+    pub fn encode(&self, opts: EncodeOptions) -> Vec<u8> {
+        self.encode_from(opts, &[])
+    }
+
+    /// Encode all operations in the oplog since the named version. The result can be merged into
+    /// any oplog which knows about `from_version` using [`OpLog::decode_and_add`].
+    ///
+    /// The file contains (in order):
+    ///
+    /// - Magic bytes and the protocol version
+    /// - (Optional) UserData chunk
+    /// - CausalGraph chunk, containing the changes since `from_version`
+    /// - Operations chunk, with the map, register, collection, list, text and mark operations in
+    ///   those changes
+    /// - (Optional) PatchContent chunk, with the content of text operations
+    /// - Crc chunk, with a checksum of everything before it
+    ///
+    /// Inserted text is always stored, since the document can't be checked out without it.
+    /// `opts.store_inserted_content` is ignored.
+    pub fn encode_from(&self, opts: EncodeOptions, from_version: &[LV]) -> Vec<u8> {
+        let mut write_map = WriteMap::with_capacity_from(&self.cg.agent_assignment.client_data);
+
+        let diff = self.cg.diff_since(from_version);
+
+        let mut cg_data = vec![];
+        let mut text_crdts = BTreeSet::new();
+        let mut map_keys = BTreeSet::new();
+        let mut collections = BTreeSet::new();
+        let mut registers = BTreeSet::new();
+        let mut lists = BTreeSet::new();
+        let mut marked_texts = BTreeSet::new();
+        for r in diff.iter() {
+            write_cg_entry_iter(&mut cg_data, self.cg.iter_range(*r), &mut write_map, &self.cg);
+
+            // Any register, list or text which has been modified in the range will have a current
+            // version in the range as well.
+            for (_, text_crdt) in self.text_index.range(*r) {
+                text_crdts.insert(*text_crdt);
+            }
+            for (_, key) in self.map_index.range(*r) {
+                map_keys.insert(key);
+            }
+            for (_, collection_crdt) in self.collection_index.range(*r) {
+                collections.insert(*collection_crdt);
+            }
+            for (_, register_crdt) in self.register_index.range(*r) {
+                registers.insert(*register_crdt);
+            }
+            for (_, list_crdt) in self.list_index.range(*r) {
+                lists.insert(*list_crdt);
+            }
+            for (_, text_crdt) in self.mark_index.range(*r) {
+                marked_texts.insert(*text_crdt);
+            }
+        }
+
+        // Gather up all the operations in the range.
+        let mut ops: Vec<(LV, LVKey, OpRef)> = vec![];
+        for key in map_keys {
+            let info = &self.map_keys[key];
+            for r in diff.iter() {
+                let start_idx = info.ops
+                    .binary_search_by_key(&r.start, |e| e.0)
+                    .unwrap_or_else(|idx| idx);
+
+                for (lv, value) in &info.ops[start_idx..] {
+                    if *lv >= r.end { break; }
+                    ops.push((*lv, key.0, OpRef::MapSet(key.1.as_str(), value)));
+                }
+            }
+        }
+
+        for crdt in registers {
+            let info = &self.registers[&crdt];
+            for r in diff.iter() {
+                let start_idx = info.ops
+                    .binary_search_by_key(&r.start, |e| e.0)
+                    .unwrap_or_else(|idx| idx);
+
+                for (lv, value) in &info.ops[start_idx..] {
+                    if *lv >= r.end { break; }
+                    ops.push((*lv, crdt, OpRef::RegisterSet(value)));
+                }
+            }
+        }
+
+        for crdt in text_crdts {
+            let info = &self.texts[&crdt];
+            for r in diff.iter() {
+                for KVPair(lv, metrics) in info.ops.iter_range_ctx(*r, &info.ctx) {
+                    ops.push((lv, crdt, OpRef::Text(metrics, &info.ctx)));
+                }
+            }
+        }
+
+        for crdt in lists {
+            let info = &self.lists[&crdt];
+            for r in diff.iter() {
+                for KVPair(lv, metrics) in info.ops.ops.iter_range_ctx(*r, &info.ops.ctx) {
+                    ops.push((lv, crdt, OpRef::List(metrics, &info.values)));
+                }
+            }
+        }
+
+        for crdt in marked_texts {
+            let marks = &self.text_marks[&crdt];
+            for r in diff.iter() {
+                let start_idx = marks
+                    .binary_search_by_key(&r.start, |e| e.0)
+                    .unwrap_or_else(|idx| idx);
+
+                for (lv, op) in &marks[start_idx..] {
+                    if *lv >= r.end { break; }
+                    ops.push((*lv, crdt, OpRef::Mark(op)));
+                }
+            }
+        }
+
+        for crdt in collections {
+            let info = &self.collections[&crdt];
+            for r in diff.iter() {
+                let start_idx = info.ops
+                    .binary_search_by_key(&r.start, |e| e.0)
+                    .unwrap_or_else(|idx| idx);
+
+                for (lv, op) in &info.ops[start_idx..] {
+                    if *lv >= r.end { break; }
+                    ops.push((*lv, crdt, match op {
+                        CollectionOp::Insert(value) => OpRef::CollectionInsert(value),
+                        CollectionOp::Remove(target) => OpRef::CollectionRemove(*target),
+                    }));
+                }
+            }
+        }
+
+        ops.sort_unstable_by_key(|(lv, _, _)| *lv);
+
+        // And write them out. Operations are written in file order, which lines up with the
+        // order we wrote the causal graph entries above.
+        let mut ops_data = vec![];
+        let mut content = String::new();
+        let mut expected_time = 0;
+        let mut last_crdt = ROOT_CRDT_ID;
+
+        for (lv, crdt, op) in ops {
+            let (entry, offset) = write_map.txn_map.find_with_offset(lv).unwrap();
+            let file_time = entry.1.start + offset;
+            debug_assert!(entry.1.len() - offset >= op.len());
+            debug_assert!(file_time >= expected_time);
+
+            let encode_crdt_id = crdt != last_crdt;
+            let encode_time_skip = file_time != expected_time;
+
+            let mut n = match &op {
+                OpRef::MapSet(_, _) => OpType::MapSet,
+                OpRef::RegisterSet(_) => OpType::RegisterSet,
+                OpRef::Text(ListOpMetrics { kind: ListOpKind::Ins, .. }, _) => OpType::TextInsert,
+                OpRef::Text(ListOpMetrics { kind: ListOpKind::Del, .. }, _) => OpType::TextDelete,
+                OpRef::CollectionInsert(_) => OpType::CollectionInsert,
+                OpRef::CollectionRemove(_) => OpType::CollectionRemove,
+                OpRef::List(ListOpMetrics { kind: ListOpKind::Ins, .. }, _) => OpType::ListInsert,
+                OpRef::List(ListOpMetrics { kind: ListOpKind::Del, .. }, _) => OpType::ListDelete,
+                OpRef::Mark(_) => OpType::TextMark,
+            } as u32;
+            n = mix_bit_u32(n, encode_crdt_id);
+            n = mix_bit_u32(n, encode_time_skip);
+            push_u32(&mut ops_data, n);
+
+            if encode_time_skip {
+                push_usize(&mut ops_data, file_time - expected_time);
+            }
+            if encode_crdt_id {
+                write_time(&mut ops_data, crdt, file_time, true, &mut write_map, &self.cg.agent_assignment);
+            }
+
+            match &op {
+                OpRef::MapSet(key, value) => {
+                    push_str(&mut ops_data, key);
+                    write_create_value(&mut ops_data, value);
+                }
+                OpRef::Text(metrics, ctx) => {
+                    let store_content = match metrics.kind {
+                        ListOpKind::Ins => true,
+                        ListOpKind::Del => opts.store_deleted_content,
+                    };
+                    let op_content = metrics.get_content(ctx).filter(|_| store_content);
+                    if let Some(c) = op_content {
+                        content.push_str(c);
+                    }
+
+                    let mut n = metrics.len();
+                    n = mix_bit_usize(n, metrics.loc.fwd);
+                    n = mix_bit_usize(n, op_content.is_some());
+                    push_usize(&mut ops_data, n);
+                    push_usize(&mut ops_data, metrics.start());
+                }
+                OpRef::RegisterSet(value) | OpRef::CollectionInsert(value) => {
+                    write_create_value(&mut ops_data, value);
+                }
+                OpRef::CollectionRemove(target) => {
+                    write_time(&mut ops_data, *target, file_time, true, &mut write_map, &self.cg.agent_assignment);
+                }
+                OpRef::List(metrics, values) => {
+                    push_usize(&mut ops_data, mix_bit_usize(metrics.len(), metrics.loc.fwd));
+                    push_usize(&mut ops_data, metrics.start());
+                    if metrics.kind == ListOpKind::Ins {
+                        // Inserted items are named by the version of their insert.
+                        for value in values.range(lv..lv + metrics.len()).map(|(_, value)| value) {
+                            write_create_value(&mut ops_data, value);
+                        }
+                    }
+                }
+                OpRef::Mark(op) => {
+                    for anchor in [op.start, op.end] {
+                        match anchor {
+                            MarkAnchor::Start => push_u32(&mut ops_data, 0),
+                            MarkAnchor::Before(item) => {
+                                push_u32(&mut ops_data, 1);
+                                write_time(&mut ops_data, item, file_time, true, &mut write_map, &self.cg.agent_assignment);
+                            }
+                            MarkAnchor::After(item) => {
+                                push_u32(&mut ops_data, 2);
+                                write_time(&mut ops_data, item, file_time, true, &mut write_map, &self.cg.agent_assignment);
+                            }
+                            MarkAnchor::End => push_u32(&mut ops_data, 3),
+                        }
+                    }
+                    push_str(&mut ops_data, &op.key);
+                    write_primitive(&mut ops_data, &op.value);
+                }
+            }
+
+            last_crdt = crdt;
+            expected_time = file_time + op.len();
+        }
+
+        let mut result = Vec::new();
+        result.extend_from_slice(&OPLOG_MAGIC_BYTES);
+        push_usize(&mut result, OPLOG_PROTOCOL_VERSION);
+
+        if let Some(user_data) = opts.user_data {
+            push_chunk(&mut result, ChunkType::UserData, user_data).unwrap();
+        }
+
+        push_chunk(&mut result, ChunkType::CausalGraph, &cg_data).unwrap();
+        push_chunk(&mut result, ChunkType::Operations, &ops_data).unwrap();
+
+        if !content.is_empty() {
+            write_content_chunk(&mut result, &content, opts.compress_content);
+        }
+
+        // The checksum covers everything in the file up to (but not including) the Crc chunk.
+        let checksum = calc_checksum(&result);
+        push_chunk(&mut result, ChunkType::Crc, &checksum.to_le_bytes()).unwrap();
+
+        result
+    }
+}
@@ -20,8 +20,15 @@ pub(crate) mod cg_entry;
 pub(crate) mod op;
 pub(crate) mod chunk_reader;
 pub(crate) mod map;
+mod encode_oplog;
+mod decode_oplog;
 // mod agent_assignment;
 
+/// Files containing a multi-CRDT OpLog start with these bytes.
+const OPLOG_MAGIC_BYTES: [u8; 8] = *b"DMNDT_OL";
+
+const OPLOG_PROTOCOL_VERSION: usize = 0;
+
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, TryFromPrimitive)]
 #[repr(u32)]
@@ -50,13 +57,22 @@ pub(crate) enum ChunkType {
     Operations = 20,
     // OpTypeAndPosition = 22,
 
-    // PatchContent = 24,
+    /// Content of the text operations (inserted and / or deleted) in the order the operations
+    /// appear in the Operations chunk.
+    PatchContent = 24,
     // /// ContentKnown is a RLE expressing which ranges of patches have known content
     // ContentIsKnown = 25,
 
     // TransformedPositions = 27, // Currently unused
 
-    // Crc = 100,
+    Crc = 100,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, TryFromPrimitive)]
+#[repr(u32)]
+enum CompressionFormat {
+    Uncompressed = 0,
+    LZ4 = 1,
 }
 
 #[derive(Clone)]
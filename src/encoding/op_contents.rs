@@ -1,63 +1,236 @@
-// use bumpalo::Bump;
-// use bumpalo::collections::vec::Vec as BumpVec;
-// use crate::{OpLog, Primitive, SnapshotValue};
-// use num_enum::TryFromPrimitive;
-// use crate::encoding::tools::{push_str, push_u32, push_u64};
-// use crate::encoding::varint::num_encode_zigzag_i64;
-//
-// #[derive(Debug, PartialEq, Eq, Copy, Clone, TryFromPrimitive)]
-// #[repr(u32)]
-// enum ValueType {
-//     // TODO: Assign numbers!
-//     PrimFalse,
-//     PrimTrue,
-//
-//     PrimSInt,
-//     PrimUInt,
-//
-//     PrimFloat,
-//     PrimDouble,
-//
-//     PrimStr,
-//
-//     LWWRegister,
-//     MVRegister,
-//     Map,
-//     Set,
-//     Text,
-//     // TODO: Arbitrary shifty list
-// }
-//
-// pub fn encode_op_contents<'a, 'b: 'a, I: Iterator<Item=&'b SnapshotValue>>(bump: &'a Bump, iter: I, _oplog: &OpLog) -> BumpVec<'a, u8> {
-//     let mut result = BumpVec::new_in(bump);
-//
-//     for val in iter {
-//         match val {
-//             SnapshotValue::Primitive(Primitive::I64(n)) => {
-//                 push_u32(&mut result, ValueType::PrimSInt as u32);
-//                 push_u64(&mut result, num_encode_zigzag_i64(*n));
-//             }
-//             SnapshotValue::Primitive(Primitive::Str(s)) => {
-//                 push_u32(&mut result, ValueType::PrimStr as u32);
-//                 push_str(&mut result, s);
-//             }
-//             SnapshotValue::Primitive(_) => {
-//                 todo!()
-//             }
-//             // Value::Map(_) => {
-//             //     push_u32(&mut result, ValueType::Map as u32);
-//             // }
-//             SnapshotValue::InnerCRDT(_crdt_id) => {
-//                 todo!();
-//                 // let kind = oplog.get_kind(*crdt_id);
-//                 // let kind_value = match kind {
-//                 //     CRDTKind::LWW => ValueType::LWWRegister,
-//                 //     CRDTKind::Map => ValueType::Map,
-//                 // };
-//                 // push_u32(&mut result, kind_value as u32);
-//             }
-//         }
-//     }
-//
-//     result
-// }
+//! This file contains the helpers used to encode the contents of operations in the multi-CRDT
+//! oplog - values, CRDT references and operation types.
+
+use num_enum::TryFromPrimitive;
+use crate::{CRDTKind, CreateValue, LV, Primitive, ROOT_CRDT_ID};
+use crate::causalgraph::agent_assignment::AgentAssignment;
+use crate::encoding::bufparser::BufParser;
+use crate::encoding::map::{ReadMap, WriteMap};
+use crate::encoding::parseerror::ParseError;
+use crate::encoding::tools::{ExtendFromSlice, push_str};
+use crate::encoding::varint::*;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, TryFromPrimitive)]
+#[repr(u32)]
+enum PrimitiveType {
+    Nil = 0,
+    Bool = 1,
+    I64 = 2,
+    F64 = 3,
+    Str = 4,
+    Bytes = 5,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, TryFromPrimitive)]
+#[repr(u32)]
+pub(crate) enum OpType {
+    RegisterSet = 1,
+    MapSet = 2,
+    // MapDelete = 3,
+    CollectionInsert = 4,
+    CollectionRemove = 5,
+    TextInsert = 6,
+    TextDelete = 7,
+    ListInsert = 8,
+    ListDelete = 9,
+    TextMark = 10,
+}
+
+/// Write a reference to some version (usually the ID of a CRDT) from an operation at `ref_time`.
+///
+/// `ref_time` is the *file* time of the operation doing the referencing.
+pub(crate) fn write_time<R: ExtendFromSlice>(result: &mut R, time: LV, ref_time: LV, persist: bool, write_map: &mut WriteMap, aa: &AgentAssignment) {
+    // This code is adapted from parents encoding. There are 3 kinds of values we store:
+    // - Local versions - which reference times in the output write map.
+    // - Foreign versions - which reference times not in the output write map
+    //   - And they either have an as-of-yet unnamed agent name (in which case this is included)
+    //   - Or the agent name is known, and named.
+    //
+    // Like parents, the ROOT is encoded as foreign agent 0.
+    let mut write_n = |mut n: usize, is_foreign: bool| {
+        n = mix_bit_usize(n, is_foreign);
+        push_usize(result, n);
+    };
+
+    if time == ROOT_CRDT_ID {
+        write_n(0, true);
+    } else if let Some((map, offset)) = write_map.txn_map.find_with_offset(time) {
+        // Local change
+        let mapped_time = map.1.start + offset;
+        debug_assert!(ref_time >= mapped_time);
+        write_n(ref_time - mapped_time, false);
+    } else {
+        // Foreign change
+        let item = aa.local_to_agent_version(time);
+
+        match write_map.map_mut(&aa.client_data, item.0, persist) {
+            Ok(mapped_agent) => {
+                write_n(mapped_agent as usize + 2, true);
+            }
+            Err(name) => {
+                write_n(1, true);
+                push_str(result, name);
+            }
+        }
+
+        // And write the sequence number.
+        push_usize(result, item.1);
+    }
+}
+
+pub(crate) fn read_time(reader: &mut BufParser, ref_time: LV, persist: bool, aa: &mut AgentAssignment, read_map: &mut ReadMap) -> Result<LV, ParseError> {
+    let mut n = reader.next_usize()?;
+    let is_foreign = strip_bit_usize_2(&mut n);
+
+    if !is_foreign {
+        let file_time = ref_time.checked_sub(n).ok_or(ParseError::GenericInvalidData)?;
+        let (entry, offset) = read_map.txn_map.find_with_offset(file_time)
+            .ok_or(ParseError::GenericInvalidData)?;
+        Ok(entry.1.start + offset)
+    } else {
+        let agent = match n {
+            0 => { return Ok(ROOT_CRDT_ID); }
+            1 => {
+                let agent_name = reader.next_str()?;
+                let agent = aa.get_or_create_agent_id(agent_name);
+                if persist {
+                    read_map.agent_map.push((agent, 0));
+                }
+                agent
+            }
+            n => {
+                read_map.agent_map.get(n - 2)
+                    .ok_or(ParseError::GenericInvalidData)?.0
+            }
+        };
+
+        let seq = reader.next_usize()?;
+        aa.try_agent_version_to_lv((agent, seq))
+            .ok_or(ParseError::DataMissing)
+    }
+}
+
+pub(crate) fn write_create_value<R: ExtendFromSlice>(result: &mut R, value: &CreateValue) {
+    match value {
+        CreateValue::Primitive(p) => write_primitive(result, p),
+        CreateValue::NewCRDT(kind) => {
+            // NewCRDT vs Primitive.
+            push_u32(result, mix_bit_u32(*kind as u32, true));
+        }
+    }
+}
+
+pub(crate) fn write_primitive<R: ExtendFromSlice>(result: &mut R, value: &Primitive) {
+    use crate::Primitive::*;
+
+    let mut write_type = |t: PrimitiveType| {
+        push_u32(result, mix_bit_u32(t as u32, false));
+    };
+
+    match value {
+        Nil => {
+            write_type(PrimitiveType::Nil);
+        }
+        Bool(b) => {
+            write_type(PrimitiveType::Bool);
+            push_u32(result, if *b { 1 } else { 0 });
+        }
+        I64(num) => {
+            write_type(PrimitiveType::I64);
+            push_u64(result, num_encode_zigzag_i64(*num));
+        }
+        F64(num) => {
+            write_type(PrimitiveType::F64);
+            // Floats are written as raw little endian bytes, since varints don't help here.
+            result.extend_from_slice(&num.to_le_bytes());
+        }
+        Str(str) => {
+            write_type(PrimitiveType::Str);
+            push_str(result, str);
+        }
+        Bytes(bytes) => {
+            write_type(PrimitiveType::Bytes);
+            push_usize(result, bytes.len());
+            result.extend_from_slice(bytes);
+        }
+        InvalidUninitialized => { panic!("Invalid set") }
+    }
+}
+
+pub(crate) fn read_create_value(reader: &mut BufParser) -> Result<CreateValue, ParseError> {
+    let mut n = reader.next_u32()?;
+    let is_crdt = strip_bit_u32_2(&mut n);
+
+    if is_crdt {
+        let kind = u16::try_from(n).ok()
+            .and_then(|n| CRDTKind::try_from(n).ok())
+            .ok_or(ParseError::GenericInvalidData)?;
+        Ok(CreateValue::NewCRDT(kind))
+    } else {
+        Ok(CreateValue::Primitive(read_primitive_body(reader, n)?))
+    }
+}
+
+/// Read a value written by write_primitive.
+pub(crate) fn read_primitive(reader: &mut BufParser) -> Result<Primitive, ParseError> {
+    let mut n = reader.next_u32()?;
+    if strip_bit_u32_2(&mut n) { return Err(ParseError::GenericInvalidData); }
+    read_primitive_body(reader, n)
+}
+
+fn read_primitive_body(reader: &mut BufParser, n: u32) -> Result<Primitive, ParseError> {
+    Ok(match PrimitiveType::try_from(n).map_err(|_| ParseError::GenericInvalidData)? {
+        PrimitiveType::Nil => Primitive::Nil,
+        PrimitiveType::Bool => Primitive::Bool(match reader.next_u32()? {
+            0 => false,
+            1 => true,
+            _ => { return Err(ParseError::GenericInvalidData); }
+        }),
+        PrimitiveType::I64 => Primitive::I64(num_decode_zigzag_i64(reader.next_u64()?)),
+        PrimitiveType::F64 => {
+            let bytes = reader.next_n_bytes(8)?;
+            Primitive::F64(f64::from_le_bytes(bytes.try_into().unwrap()))
+        }
+        PrimitiveType::Str => Primitive::Str(reader.next_str()?.into()),
+        PrimitiveType::Bytes => {
+            let len = reader.next_usize()?;
+            Primitive::Bytes(reader.next_n_bytes(len)?.into())
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{CRDTKind, CreateValue, Primitive};
+    use crate::encoding::bufparser::BufParser;
+    use super::{read_create_value, write_create_value};
+
+    #[test]
+    fn create_value_round_trips() {
+        let values = [
+            CreateValue::Primitive(Primitive::Nil),
+            CreateValue::Primitive(Primitive::Bool(true)),
+            CreateValue::Primitive(Primitive::Bool(false)),
+            CreateValue::Primitive(Primitive::I64(-1234)),
+            CreateValue::Primitive(Primitive::F64(-12.5)),
+            CreateValue::Primitive(Primitive::F64(f64::NAN)),
+            CreateValue::Primitive(Primitive::Str("hi there".into())),
+            CreateValue::Primitive(Primitive::Bytes(vec![])),
+            CreateValue::Primitive(Primitive::Bytes(vec![0, 1, 2, 255])),
+            CreateValue::NewCRDT(CRDTKind::Map),
+            CreateValue::NewCRDT(CRDTKind::Text),
+            CreateValue::NewCRDT(CRDTKind::Register),
+        ];
+
+        let mut result = vec![];
+        for v in &values {
+            write_create_value(&mut result, v);
+        }
+
+        let mut reader = BufParser(&result);
+        for v in &values {
+            assert_eq!(&read_create_value(&mut reader).unwrap(), v);
+        }
+        assert!(reader.is_empty());
+    }
+}
@@ -45,6 +45,16 @@ pub enum ParseError {
 
     ChecksumFailed,
 
+    /// Encrypted content in the file couldn't be decrypted with the provided cipher.
+    DecryptionFailed,
+
+    /// The EntryVerifier rejected one of the operations in the file.
+    SignatureRejected,
+
+    /// A signature in the file doesn't name a linear run of operations in the file, or names
+    /// operations whose content we don't know. So it can't be checked.
+    InvalidSignature,
+
     /// This error is interesting. We're loading a chunk but missing some of the data. In the future
     /// I'd like to explicitly support this case, and allow the oplog to contain a somewhat- sparse
     /// set of data, and load more as needed.
@@ -122,6 +122,9 @@
 //! editor, generate an ID in memory when the user opens the document. Don't save the ID to disk.
 //! Just discard it when the user's editing session ends.
 //!
+//! With the `random_agents` feature enabled, `new_session_agent()` (on [`list::ListOpLog`],
+//! [`OpLog`] and [`CausalGraph`]) does this for you.
+//!
 //!
 //! ### Aside on atomic transactions
 //!
@@ -133,8 +136,11 @@
 //! 2. Add a special commit message to your network protocol which "commits" marks when a set of
 //! operations in the oplog is safe to merge.
 //!
-//! Diamond types does not (yet) support deleting operations from the oplog. If this matters to you,
-//! please start open an issue about it.
+//! Old operations can be deleted from a list oplog with [`ListOpLog::prune_before`](list::ListOpLog::prune_before).
+//! This replaces the history of a version every peer has seen with a snapshot of the document at
+//! that version. The causal graph is kept, so the oplog can still sync with peers. But afterwards
+//! the oplog can only be checked out at versions which contain the prune point, and it can't merge
+//! operations which are concurrent with it. The multi-CRDT [`OpLog`] does not support pruning yet.
 //!
 //!
 //! ## Parents
@@ -188,6 +194,7 @@ use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Formatter};
 use jumprope::{JumpRope, JumpRopeBuf};
 use smallvec::SmallVec;
+use num_enum::TryFromPrimitive;
 use smartstring::alias::String as SmartString;
 pub use crate::causalgraph::CausalGraph;
 pub use crate::dtrange::DTRange;
@@ -203,6 +210,8 @@ use crate::causalgraph::agent_span::AgentVersion;
 use serde::{Deserialize, Serialize};
 use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
 use crate::textinfo::TextInfo;
+use crate::collection::CollectionInfo;
+use crate::listinfo::ListInfo;
 
 // use crate::list::internal_op::OperationInternal as TextOpInternal;
 
@@ -229,6 +238,9 @@ mod list_fuzzer_tools;
 mod fuzzer;
 mod branch;
 mod textinfo;
+mod collection;
+mod listinfo;
+mod textmarks;
 mod oplog;
 #[cfg(feature = "storage")]
 mod storage;
@@ -245,20 +257,43 @@ pub type AgentId = u32;
 /// converted to RawVersions before being sent over the wire or saved to disk.
 pub type LV = usize;
 
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(untagged))]
 // #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Primitive {
     Nil,
     Bool(bool),
     I64(i64),
-    // F64(f64),
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::primitive_f64"))]
+    F64(f64),
     Str(SmartString),
+    Bytes(Vec<u8>),
 
     #[cfg_attr(feature = "serde", serde(skip))]
     InvalidUninitialized,
 }
 
+// Floats are compared by their bit pattern, so primitives can still be Eq. This means NaN is equal
+// to itself (if the bits match), and 0.0 != -0.0. Thats what we want for a CRDT - two values
+// are only the same if they encode the same way.
+impl PartialEq for Primitive {
+    fn eq(&self, other: &Self) -> bool {
+        use Primitive::*;
+        match (self, other) {
+            (Nil, Nil) => true,
+            (Bool(a), Bool(b)) => a == b,
+            (I64(a), I64(b)) => a == b,
+            (F64(a), F64(b)) => a.to_bits() == b.to_bits(),
+            (Str(a), Str(b)) => a == b,
+            (Bytes(a), Bytes(b)) => a == b,
+            (InvalidUninitialized, InvalidUninitialized) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Primitive {}
+
 impl Debug for Primitive {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -266,21 +301,31 @@ impl Debug for Primitive {
             Primitive::Bool(val) => val.fmt(f),
             // Primitive::I64(val) => f.debug_tuple("I64").field(val).finish(),
             Primitive::I64(val) => val.fmt(f),
+            Primitive::F64(val) => val.fmt(f),
             Primitive::Str(val) => val.fmt(f),
+            Primitive::Bytes(val) => f.debug_tuple("Bytes").field(val).finish(),
             Primitive::InvalidUninitialized => f.debug_tuple("InvalidUninitialized").finish()
         }
     }
 }
 
-// #[derive(Debug, Eq, PartialEq, Copy, Clone, TryFromPrimitive)]
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, TryFromPrimitive)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-// #[repr(u16)]
+#[repr(u16)]
 pub enum CRDTKind {
     Map, // String => Register (like a JS object)
     Register,
     Collection, // SQL table / mongo collection
     Text,
+    List, // Ordered sequence of values
+}
+
+impl CRDTKind {
+    /// Containers (maps, registers, collections and lists) can own other CRDTs. When a container
+    /// is deleted, so is everything inside it.
+    pub(crate) fn is_container(self) -> bool {
+        matches!(self, CRDTKind::Map | CRDTKind::Register | CRDTKind::Collection | CRDTKind::List)
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -291,11 +336,63 @@ pub enum CreateValue {
     // Deleted, // Marks that the key / contents should be deleted.
 }
 
-// #[derive(Debug, Clone, Eq, PartialEq)]
-// pub enum CollectionOp {
-//     Insert(CreateValue),
-//     Remove(LV),
-// }
+/// An operation on a collection (set) CRDT. Each inserted item is named by the version of the
+/// operation which inserted it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CollectionOp {
+    Insert(CreateValue),
+    /// Remove the item inserted at the named version.
+    Remove(LV),
+}
+
+/// One end of a formatting mark on a text CRDT. Marks are attached to the gaps between characters,
+/// and characters are named by the (local) version of their insert. Anchors still work after the
+/// named character has been deleted.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MarkAnchor {
+    /// The start of the document.
+    Start,
+    /// The gap directly before the named character.
+    Before(LV),
+    /// The gap directly after the named character.
+    After(LV),
+    /// The end of the document.
+    End,
+}
+
+/// Controls whether text inserted at the edges of a marked range picks up the mark. Eg, bold
+/// usually expands after (typing at the end of bold text makes more bold text) while links don't
+/// expand at all.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MarkExpand {
+    None,
+    Before,
+    After,
+    Both,
+}
+
+/// A formatting operation on a text CRDT, setting `key` to `value` for all characters between the
+/// anchors. Setting a key to nil removes that formatting. (Eg, `bold: true` or `link: "..."`.)
+///
+/// When marks with the same key overlap, the most recent mark wins. Concurrent marks are tie-broken
+/// in the same way as concurrent register writes.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MarkOp {
+    pub start: MarkAnchor,
+    pub end: MarkAnchor,
+    pub key: SmartString,
+    pub value: Primitive,
+}
+
+/// A run of text in a formatted text checkout, with the formatting which applies to all of it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FormattedSpan {
+    pub text: String,
+    pub marks: BTreeMap<SmartString, Primitive>,
+}
 
 // #[derive(Debug, Clone, Eq, PartialEq)]
 // pub(crate) enum OpContents {
@@ -392,18 +489,33 @@ pub struct OpLog {
     // Information about whether the map still exists!
     // maps: BTreeMap<LVKey, MapInfo>,
 
+    /// The IDs of all map CRDTs, except the root map.
+    maps: BTreeSet<LVKey>,
     /// (CRDT ID, key) -> MVRegister.
     map_keys: BTreeMap<(LVKey, SmartString), RegisterInfo>,
     /// CRDT ID -> Text CRDT.
     texts: BTreeMap<LVKey, TextInfo>,
+    /// CRDT ID -> Collection CRDT.
+    collections: BTreeMap<LVKey, CollectionInfo>,
+    /// CRDT ID -> MVRegister, for standalone register CRDTs.
+    registers: BTreeMap<LVKey, RegisterInfo>,
+    /// CRDT ID -> List CRDT.
+    lists: BTreeMap<LVKey, ListInfo>,
+    /// Text CRDT ID -> formatting marks on that text, sorted by version.
+    text_marks: BTreeMap<LVKey, Vec<(LV, MarkOp)>>,
 
     // These are always inserted at the end, but items in the middle are removed. There's probably
     // a better data structure to accomplish this.
     map_index: BTreeMap<LV, (LVKey, SmartString)>,
     text_index: BTreeMap<LV, LVKey>,
-
-    // TODO: Vec -> SmallVec.
-    // registers: BTreeMap<LVKey, RegisterInfo>,
+    register_index: BTreeMap<LV, LVKey>,
+    // Like text_index, this names the frontier of each list.
+    list_index: BTreeMap<LV, LVKey>,
+    // Unlike the other indexes, this names every collection operation. Removed items are still
+    // needed to check out older versions.
+    collection_index: BTreeMap<LV, LVKey>,
+    // Like collection_index, this names every mark operation.
+    mark_index: BTreeMap<LV, LVKey>,
 
     // The set of CRDTs which have been deleted or superceded in the current version. This data is
     // pretty similar to the _index data, in that its mainly just useful for branches doing
@@ -419,17 +531,25 @@ pub struct Branch {
     // range.
     //
     // TODO: Replace BTreeMap with something more appropriate later.
-    // registers: BTreeMap<LVKey, SmallVec<[LV; 2]>>, // TODO.
     maps: BTreeMap<LVKey, BTreeMap<SmartString, RegisterState>>, // any objects.
     pub texts: BTreeMap<LVKey, JumpRopeBuf>,
+    /// Collection CRDT -> (item ID -> value).
+    collections: BTreeMap<LVKey, BTreeMap<LV, RegisterValue>>,
+    registers: BTreeMap<LVKey, RegisterState>,
+    /// List CRDT -> items, named by the version of each item's insert.
+    lists: BTreeMap<LVKey, Vec<(LV, RegisterValue)>>,
 }
 
 /// The register stores the specified value, but if conflicts_with is not empty, it has some
 /// conflicting concurrent values too. The `value` field will be consistent across all peers.
+///
+/// Each value is stored alongside the (local) version of the operation which set it. Registers
+/// which have never been set hold nil, at the root version.
 #[derive(Debug, Clone, PartialEq, Eq)]
-struct RegisterState {
-    value: RegisterValue,
-    conflicts_with: Vec<RegisterValue>,
+pub struct RegisterState {
+    pub version: LV,
+    pub value: RegisterValue,
+    pub conflicts_with: Vec<(LV, RegisterValue)>,
 }
 
 #[derive(Debug, Clone)]
@@ -442,6 +562,47 @@ pub struct SerializedOps<'a> {
     map_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, &'a str, CreateValue)>,
     text_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, ListOpMetrics)>,
     text_context: ListOperationCtx,
+    #[cfg_attr(feature = "serde", serde(default))]
+    collection_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, RemoteCollectionOp<'a>)>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    register_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, CreateValue)>,
+    /// List operations don't store content. Instead inserts list the value of each inserted item.
+    #[cfg_attr(feature = "serde", serde(default))]
+    list_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, ListOpMetrics, Vec<CreateValue>)>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    mark_ops: Vec<(RemoteVersion<'a>, RemoteVersion<'a>, RemoteMarkOp<'a>)>,
+}
+
+/// A collection operation with the removed item named by its remote version.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+enum RemoteCollectionOp<'a> {
+    Insert(CreateValue),
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    Remove(RemoteVersion<'a>),
+}
+
+/// A mark anchor with the named character given by its remote version.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+enum RemoteMarkAnchor<'a> {
+    Start,
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    Before(RemoteVersion<'a>),
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    After(RemoteVersion<'a>),
+    End,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct RemoteMarkOp<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    start: RemoteMarkAnchor<'a>,
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    end: RemoteMarkAnchor<'a>,
+    key: SmartString,
+    value: Primitive,
 }
 
 /// This is used for checkouts. This is a value tree.
@@ -450,8 +611,9 @@ pub struct SerializedOps<'a> {
 // #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum DTValue {
     Primitive(Primitive),
-    // Register(Box<DTValue>),
+    Register(Box<DTValue>),
     Map(BTreeMap<SmartString, Box<DTValue>>),
-    // Collection(BTreeMap<LV, Box<DTValue>>),
+    Collection(BTreeMap<LV, Box<DTValue>>),
     Text(String),
+    List(Vec<DTValue>),
 }
@@ -0,0 +1,280 @@
+//! Anchors are stable references to positions in a document. Unlike plain positions, they stay
+//! attached to the same place in the text when concurrent changes are merged. This is useful for
+//! remote cursors, comments, bookmarks and so on.
+
+use rle::HasLength;
+use crate::list::{ListBranch, ListOpLog};
+use crate::listmerge::merge::items_in_order;
+use crate::{DTRange, LV};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// When text is inserted at an anchor's position, this controls which side of the new text the
+/// anchor ends up on.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum AnchorBias {
+    /// Stick to the character before the position. Text inserted at the position goes after the
+    /// anchor.
+    Left,
+    /// Stick to the character after the position. Text inserted at the position goes before the
+    /// anchor.
+    Right,
+}
+
+/// A stable reference to a position in a document, created with [`ListOpLog::anchor_at`].
+///
+/// Characters are named by the (local) version of their insert. Anchors still resolve after the
+/// named character has been deleted. In a pruned oplog, characters from the pruned snapshot are
+/// named by placeholder versions, so those anchors are only valid until the oplog is pruned again.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Anchor {
+    /// The start of the document.
+    Start,
+    /// The position directly after the named character.
+    After(LV),
+    /// The position directly before the named character.
+    Before(LV),
+    /// The end of the document.
+    End,
+}
+
+/// Every item in a document at some version, in document order. Building this replays the
+/// document's history, so its built once and reused when looking up lots of anchors at a time.
+#[derive(Debug, Clone)]
+pub(crate) struct ItemOrder {
+    /// Each run of items, whether the items are visible, and the number of visible items before
+    /// the run. In document order.
+    items: Vec<(DTRange, bool, usize)>,
+    /// Indexes into items, sorted by the run's first version.
+    by_lv: Vec<usize>,
+    /// The number of visible items.
+    len: usize,
+}
+
+impl ItemOrder {
+    fn new(order: Vec<(DTRange, bool)>) -> Self {
+        let mut len = 0;
+        let items: Vec<_> = order.into_iter().map(|(range, visible)| {
+            let pos = len;
+            if visible { len += range.len(); }
+            (range, visible, pos)
+        }).collect();
+
+        let mut by_lv: Vec<usize> = (0..items.len()).collect();
+        by_lv.sort_unstable_by_key(|&i| items[i].0.start);
+
+        Self { items, by_lv, len }
+    }
+
+    /// Find the nth visible item.
+    pub(crate) fn nth_visible(&self, n: usize) -> Option<LV> {
+        let idx = self.items.partition_point(|(range, visible, pos)| {
+            pos + if *visible { range.len() } else { 0 } <= n
+        });
+        self.items.get(idx).map(|(range, _, pos)| range.start + n - pos)
+    }
+
+    /// Find the run containing the named item. Returns the run, whether its visible and the number
+    /// of visible items before the run.
+    pub(crate) fn find(&self, item: LV) -> Option<(DTRange, bool, usize)> {
+        let idx = self.by_lv.partition_point(|&i| self.items[i].0.start <= item);
+        if idx == 0 { return None; }
+        let entry = self.items[self.by_lv[idx - 1]];
+        if entry.0.contains(item) { Some(entry) } else { None }
+    }
+
+    fn anchor_at(&self, pos: usize, bias: AnchorBias) -> Anchor {
+        match bias {
+            AnchorBias::Left => {
+                if pos == 0 { Anchor::Start }
+                else {
+                    Anchor::After(self.nth_visible(pos - 1)
+                        .expect("Anchor position is past the end of the document"))
+                }
+            }
+            AnchorBias::Right => {
+                match self.nth_visible(pos) {
+                    Some(item) => Anchor::Before(item),
+                    None => {
+                        assert_eq!(pos, self.len, "Anchor position is past the end of the document");
+                        Anchor::End
+                    }
+                }
+            }
+        }
+    }
+
+    fn resolve(&self, anchor: Anchor) -> Option<usize> {
+        let (item, after) = match anchor {
+            Anchor::Start => { return Some(0); }
+            Anchor::End => { return Some(self.len); }
+            Anchor::After(item) => (item, true),
+            Anchor::Before(item) => (item, false),
+        };
+
+        let (range, visible, pos) = self.find(item)?;
+        Some(if !visible { pos }
+            else if after { pos + item - range.start + 1 }
+            else { pos + item - range.start })
+    }
+}
+
+impl ListOpLog {
+    /// List every item in the document at some version in document order, including deleted
+    /// items. If the oplog has been pruned, the characters in the pruned snapshot are named by
+    /// underwater versions.
+    pub(crate) fn item_order(&self, version: &[LV]) -> ItemOrder {
+        // The document is always empty at ROOT, even if we don't have the operations after it.
+        if version.is_empty() { return ItemOrder::new(vec![]); }
+
+        assert!(self.start_version.is_root()
+            || self.cg.graph.frontier_contains_frontier(version, self.start_version.as_ref()),
+            "Cannot look up items at a version from before the oplog was pruned");
+
+        ItemOrder::new(items_in_order(&self.cg, &self.operation_ctx, &self.operations, version,
+            self.start_content.len_chars()))
+    }
+
+    /// Make an anchor for the position `pos` in the document at the specified version.
+    ///
+    /// This replays the document's history up to `version` to find the order of its characters,
+    /// so each call takes time proportional to the size of the history. Use
+    /// [`anchors_at`](ListOpLog::anchors_at) to make several anchors at once.
+    ///
+    /// Panics if the position is past the end of the document at that version.
+    pub fn anchor_at(&self, version: &[LV], pos: usize, bias: AnchorBias) -> Anchor {
+        self.item_order(version).anchor_at(pos, bias)
+    }
+
+    /// Make anchors for a list of positions in the document at the specified version. This is much
+    /// faster than calling [`anchor_at`](ListOpLog::anchor_at) for each position.
+    pub fn anchors_at(&self, version: &[LV], positions: &[(usize, AnchorBias)]) -> Vec<Anchor> {
+        let order = self.item_order(version);
+        positions.iter().map(|&(pos, bias)| order.anchor_at(pos, bias)).collect()
+    }
+}
+
+impl ListBranch {
+    /// Find the current position of an anchor in this branch.
+    ///
+    /// Returns None if the anchor names a character this branch doesn't know about (ie, the branch
+    /// hasn't merged the character's insert yet).
+    ///
+    /// This replays the branch's whole history to find the order of its characters, so each call
+    /// takes time proportional to the size of the history. Editors showing remote cursors should
+    /// resolve them all with a single call to [`resolve_anchors`](ListBranch::resolve_anchors)
+    /// after each change, rather than calling this for each cursor.
+    pub fn resolve_anchor(&self, oplog: &ListOpLog, anchor: Anchor) -> Option<usize> {
+        match anchor {
+            Anchor::Start => Some(0),
+            Anchor::End => Some(self.len()),
+            // This needs the position of the character amongst all the characters in the
+            // document, including deleted characters. We get that from a merge tracker.
+            _ => oplog.item_order(self.version.as_ref()).resolve(anchor),
+        }
+    }
+
+    /// Find the current positions of a list of anchors in this branch. This is much faster than
+    /// calling [`resolve_anchor`](ListBranch::resolve_anchor) for each anchor.
+    pub fn resolve_anchors(&self, oplog: &ListOpLog, anchors: &[Anchor]) -> Vec<Option<usize>> {
+        let order = oplog.item_order(self.version.as_ref());
+        anchors.iter().map(|&anchor| order.resolve(anchor)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::anchor::{Anchor, AnchorBias};
+    use crate::list::ListOpLog;
+
+    #[test]
+    fn anchors_survive_concurrent_edits() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "hello world");
+        let base = oplog.local_frontier();
+
+        let left = oplog.anchor_at(base.as_ref(), 5, AnchorBias::Left);
+        let right = oplog.anchor_at(base.as_ref(), 5, AnchorBias::Right);
+        assert_eq!(oplog.anchor_at(base.as_ref(), 0, AnchorBias::Left), Anchor::Start);
+        assert_eq!(oplog.anchor_at(base.as_ref(), 11, AnchorBias::Right), Anchor::End);
+
+        // Concurrently insert at the anchor's position and delete the character before it.
+        let a = oplog.add_insert_at(seph, base.as_ref(), 5, "!!");
+        let b = oplog.add_delete_at(mike, base.as_ref(), 4..5);
+
+        let branch = oplog.checkout_tip();
+        assert_eq!(branch.content().to_string(), "hell!! world");
+        assert_eq!(branch.resolve_anchor(&oplog, left), Some(4));
+        assert_eq!(branch.resolve_anchor(&oplog, right), Some(6));
+        assert_eq!(branch.resolve_anchor(&oplog, Anchor::End), Some(12));
+
+        // Anchors made at other versions work too.
+        let bang = oplog.anchor_at(&[a], 6, AnchorBias::Left);
+        assert_eq!(branch.resolve_anchor(&oplog, bang), Some(5));
+        assert_eq!(oplog.checkout(&[b]).resolve_anchor(&oplog, right), Some(4));
+
+        // A branch which doesn't have the anchored character can't resolve it.
+        assert_eq!(oplog.checkout(&[b]).resolve_anchor(&oplog, bang), None);
+    }
+
+    #[test]
+    fn batch_anchors() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "abc");
+        oplog.add_insert(mike, 3, "def");
+        let v = oplog.local_frontier();
+
+        let positions: Vec<_> = (0..=6)
+            .flat_map(|pos| [(pos, AnchorBias::Left), (pos, AnchorBias::Right)])
+            .collect();
+        let anchors = oplog.anchors_at(v.as_ref(), &positions);
+        for (&(pos, bias), anchor) in positions.iter().zip(anchors.iter()) {
+            assert_eq!(*anchor, oplog.anchor_at(v.as_ref(), pos, bias));
+        }
+
+        oplog.add_delete_without_content(mike, 1..4);
+        oplog.add_insert(seph, 0, "__");
+        let branch = oplog.checkout_tip();
+        assert_eq!(branch.content().to_string(), "__aef");
+        let resolved = branch.resolve_anchors(&oplog, &anchors);
+        for (anchor, pos) in anchors.iter().zip(resolved.iter()) {
+            assert_eq!(*pos, branch.resolve_anchor(&oplog, *anchor));
+        }
+        assert_eq!(resolved, vec![
+            Some(0), Some(2), Some(3), Some(3), Some(3), Some(3), Some(3), Some(3),
+            Some(3), Some(3), Some(4), Some(4), Some(5), Some(5),
+        ]);
+    }
+
+    #[test]
+    fn anchors_in_pruned_oplog() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hello world");
+        let v = oplog.local_frontier();
+        let before = oplog.anchor_at(v.as_ref(), 6, AnchorBias::Right);
+        oplog.prune_before(v.as_ref());
+
+        // Anchors into the pruned snapshot work too.
+        let world = oplog.anchor_at(v.as_ref(), 6, AnchorBias::Right);
+        let end = oplog.anchor_at(v.as_ref(), 11, AnchorBias::Left);
+        oplog.add_insert(seph, 0, ">> ");
+        oplog.add_delete_without_content(seph, 3..5);
+
+        let branch = oplog.checkout_tip();
+        assert_eq!(branch.content().to_string(), ">> llo world");
+        assert_eq!(branch.resolve_anchor(&oplog, world), Some(7));
+        assert_eq!(branch.resolve_anchor(&oplog, end), Some(12));
+        assert_eq!(branch.resolve_anchors(&oplog, &[world, end, Anchor::Start]), vec![Some(7), Some(12), Some(0)]);
+
+        // Anchors naming pruned operations can't be resolved.
+        assert_eq!(branch.resolve_anchor(&oplog, before), None);
+    }
+}
@@ -0,0 +1,289 @@
+//! The agent table lets a stream of patches refer to agent names which were sent in earlier
+//! patches by index, rather than writing the names out again every time.
+//!
+//! Each direction of a connection has its own pair of tables. The sender gives every agent name a
+//! fixed index the first time it writes the name into a patch, and writes the name out in full
+//! (along with its index) until the receiver acknowledges it. When the receiver merges a patch, it
+//! adds the names in the patch to its table. Then it tells the sender how many entries it has,
+//! with [`received_len`](AgentTable::received_len), and the sender passes that to
+//! [`acknowledge`](AgentTable::acknowledge). So if a patch is lost, later patches still make
+//! sense - they repeat any names the receiver hasn't confirmed.
+//!
+//! The tables aren't part of the oplog. Save them alongside the oplog using
+//! [`encode`](AgentTable::encode) and [`decode`](AgentTable::decode), so patches keep working
+//! after a restart.
+//!
+//! Agent names which look like 128 bit random IDs (32 lowercase hex characters) are stored as 16
+//! raw bytes, rather than as a string.
+
+use std::collections::BTreeMap;
+use smartstring::alias::String as SmartString;
+use crate::encoding::parseerror::ParseError;
+use crate::list::encoding::decode_tools::BufReader;
+use crate::list::encoding::encode_tools::{push_leb_str, push_leb_usize};
+
+// Each entry in the AgentTable chunk starts with a LEB number with the entry kind in the low 2 bits
+// and the entry's index in the rest.
+const ENTRY_STRING: usize = 0;
+const ENTRY_INDEX: usize = 1;
+const ENTRY_BINARY: usize = 2;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct AgentTable {
+    /// Map from index to agent name. The receiver's table can have gaps if patches were lost.
+    names: BTreeMap<usize, SmartString>,
+    index: BTreeMap<SmartString, usize>,
+    /// The number of entries the remote peer has confirmed receiving. Only used when sending.
+    acknowledged: usize,
+}
+
+impl AgentTable {
+    pub fn new() -> Self { Self::default() }
+
+    /// The number of agent names in the table.
+    pub fn len(&self) -> usize { self.names.len() }
+
+    pub fn is_empty(&self) -> bool { self.names.is_empty() }
+
+    /// The number of entries at the start of the table with no gaps. The receiver sends this to the
+    /// sender, which passes it to [`acknowledge`](AgentTable::acknowledge).
+    pub fn received_len(&self) -> usize {
+        self.names.keys().enumerate()
+            .take_while(|(i, idx)| i == *idx)
+            .count()
+    }
+
+    /// Mark the first `len` entries in the table as received by the remote peer. Later patches will
+    /// refer to those agents by index.
+    pub fn acknowledge(&mut self, len: usize) {
+        self.acknowledged = self.acknowledged.max(len.min(self.names.len()));
+    }
+
+    pub(super) fn insert(&mut self, idx: usize, name: &str) {
+        self.names.insert(idx, name.into());
+        self.index.entry(name.into()).or_insert(idx);
+    }
+
+    /// Write an entry for the named agent, and add the agent to the table if its new.
+    pub(super) fn write_entry(&mut self, dest: &mut Vec<u8>, name: &str) {
+        let idx = match self.index.get(name) {
+            Some(&idx) => idx,
+            None => {
+                // The sender's table never has gaps.
+                let idx = self.names.len();
+                self.insert(idx, name);
+                idx
+            }
+        };
+
+        if idx < self.acknowledged {
+            push_leb_usize(dest, (idx << 2) | ENTRY_INDEX);
+        } else if let Some(bytes) = agent_name_to_binary(name) {
+            push_leb_usize(dest, (idx << 2) | ENTRY_BINARY);
+            dest.extend_from_slice(&bytes);
+        } else {
+            push_leb_usize(dest, (idx << 2) | ENTRY_STRING);
+            push_leb_str(dest, name);
+        }
+    }
+
+    /// Read an entry from an AgentTable chunk. Returns the agent's name, and the entry's index if
+    /// it introduced a new name. New names aren't added to the table here, because the patch might
+    /// fail to merge.
+    pub(super) fn read_entry(table: Option<&Self>, reader: &mut BufReader) -> Result<(SmartString, Option<usize>), ParseError> {
+        let n = reader.next_usize()?;
+        let idx = n >> 2;
+        let name: SmartString = match n & 0b11 {
+            ENTRY_STRING => reader.next_str()?.into(),
+            ENTRY_INDEX => {
+                // If we don't have the entry, we can't make sense of the patch.
+                let name = table.and_then(|t| t.names.get(&idx))
+                    .ok_or(ParseError::DataMissing)?;
+                return Ok((name.clone(), None));
+            }
+            ENTRY_BINARY => {
+                let bytes: [u8; 16] = reader.next_n_bytes(16)?.try_into().unwrap();
+                binary_agent_name(u128::from_be_bytes(bytes))
+            }
+            _ => { return Err(ParseError::InvalidContent); }
+        };
+
+        match table.and_then(|t| t.names.get(&idx)) {
+            None => Ok((name, Some(idx))),
+            Some(existing) if *existing == name => Ok((name, None)),
+            // The sender never changes the name at an index.
+            Some(_) => Err(ParseError::InvalidContent),
+        }
+    }
+
+    /// Encode the table, to save it alongside the oplog.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        push_leb_usize(&mut result, self.acknowledged);
+        for (idx, name) in self.names.iter() {
+            push_leb_usize(&mut result, *idx);
+            push_leb_str(&mut result, name);
+        }
+        result
+    }
+
+    /// Load a table saved with [`encode`](AgentTable::encode).
+    pub fn decode(data: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = BufReader(data);
+        let mut result = Self::new();
+        let acknowledged = reader.next_usize()?;
+        while !reader.is_empty() {
+            let idx = reader.next_usize()?;
+            let name = reader.next_str()?;
+            if result.names.contains_key(&idx) { return Err(ParseError::InvalidContent); }
+            result.insert(idx, name);
+        }
+        if acknowledged > result.names.len() { return Err(ParseError::InvalidContent); }
+        result.acknowledged = acknowledged;
+        Ok(result)
+    }
+}
+
+/// Make an agent name from a 128 bit (random) ID. Agent names made this way are stored compactly
+/// when encoding with an [`AgentTable`].
+pub fn binary_agent_name(id: u128) -> SmartString {
+    let mut name = SmartString::new();
+    for b in id.to_be_bytes() {
+        name.push(char::from_digit((b >> 4) as u32, 16).unwrap());
+        name.push(char::from_digit((b & 0xf) as u32, 16).unwrap());
+    }
+    name
+}
+
+fn agent_name_to_binary(name: &str) -> Option<[u8; 16]> {
+    if name.len() != 32 || !name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    u128::from_str_radix(name, 16).ok().map(u128::to_be_bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::encoding::agent_table::{agent_name_to_binary, binary_agent_name, AgentTable};
+    use crate::list::encoding::ENCODE_PATCH;
+    use crate::list::ListOpLog;
+
+    #[test]
+    fn binary_names_round_trip() {
+        let name = binary_agent_name(0x0123456789abcdef_0011223344556677);
+        assert_eq!(name, "0123456789abcdef0011223344556677");
+        assert_eq!(agent_name_to_binary(&name), Some(0x0123456789abcdef_0011223344556677u128.to_be_bytes()));
+        assert_eq!(agent_name_to_binary("0123456789ABCDEF0011223344556677"), None);
+        assert_eq!(agent_name_to_binary("seph"), None);
+    }
+
+    #[test]
+    fn patches_share_agent_table() {
+        let mut a = ListOpLog::new();
+        let mut b = ListOpLog::new();
+        let mut send_table = AgentTable::new();
+        let mut recv_table = AgentTable::new();
+
+        let names = ["seph", &binary_agent_name(u128::MAX - 100), &binary_agent_name(12345)];
+        let mut sizes = vec![];
+        for _ in 0..2 {
+            let v = a.local_frontier();
+            for name in names {
+                let agent = a.get_or_create_agent_id(name);
+                a.add_insert(agent, 0, "hi");
+            }
+
+            let bytes = a.encode_from_with_agents(ENCODE_PATCH, v.as_ref(), &mut send_table);
+            sizes.push(bytes.len());
+            b.decode_and_add_with_agents(&bytes, &mut recv_table).unwrap();
+            assert_eq!(a, b);
+            send_table.acknowledge(recv_table.received_len());
+        }
+
+        // The second patch refers to the agents by index.
+        assert_eq!(send_table.len(), 3);
+        assert_eq!(recv_table.received_len(), 3);
+        assert!(sizes[1] < sizes[0], "{sizes:?}");
+
+        // Without the table, the third patch can't be read.
+        let mut c = ListOpLog::load_from(&a.encode_from(ENCODE_PATCH, &[])).unwrap();
+        let v = a.local_frontier();
+        a.add_insert(0, 0, "x");
+        let bytes = a.encode_from_with_agents(ENCODE_PATCH, v.as_ref(), &mut send_table);
+        assert!(c.decode_and_add(&bytes).is_err());
+        b.decode_and_add_with_agents(&bytes, &mut recv_table).unwrap();
+        assert_eq!(a, b);
+
+        // And an empty table works for standalone files.
+        let bytes = a.encode_from_with_agents(ENCODE_PATCH, &[], &mut AgentTable::new());
+        assert!(bytes.len() < a.encode(ENCODE_PATCH).len());
+        c = ListOpLog::new();
+        c.decode_and_add_with_agents(&bytes, &mut AgentTable::new()).unwrap();
+        assert_eq!(a, c);
+    }
+
+    #[test]
+    fn lost_patch_keeps_tables_in_sync() {
+        let mut a = ListOpLog::new();
+        let mut b = ListOpLog::new();
+        let mut send_table = AgentTable::new();
+        let mut recv_table = AgentTable::new();
+
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "a");
+        let lost = a.encode_from_with_agents(ENCODE_PATCH, &[], &mut send_table);
+
+        // The first patch never arrives, so nothing is acknowledged and seph is written out again.
+        let mike = a.get_or_create_agent_id("mike");
+        a.add_insert(mike, 1, "b");
+        let bytes = a.encode_from_with_agents(ENCODE_PATCH, &[], &mut send_table);
+        b.decode_and_add_with_agents(&bytes, &mut recv_table).unwrap();
+        assert_eq!(a, b);
+        send_table.acknowledge(recv_table.received_len());
+
+        // A late copy of the lost patch still makes sense.
+        b.decode_and_add_with_agents(&lost, &mut recv_table).unwrap();
+
+        // Names are only referred to by index once they're acknowledged.
+        let v = a.local_frontier();
+        let fred = a.get_or_create_agent_id("fred");
+        a.add_insert(fred, 0, "c");
+        a.add_insert(seph, 0, "d");
+        let lost = a.encode_from_with_agents(ENCODE_PATCH, v.as_ref(), &mut send_table);
+        a.add_insert(fred, 0, "e");
+        let bytes = a.encode_from_with_agents(ENCODE_PATCH, v.as_ref(), &mut send_table);
+        b.decode_and_add_with_agents(&bytes, &mut recv_table).unwrap();
+        assert_eq!(a, b);
+        b.decode_and_add_with_agents(&lost, &mut recv_table).unwrap();
+        assert_eq!(recv_table.received_len(), 3);
+    }
+
+    #[test]
+    fn tables_can_be_saved() {
+        let mut a = ListOpLog::new();
+        let mut b = ListOpLog::new();
+        let mut send_table = AgentTable::new();
+        let mut recv_table = AgentTable::new();
+
+        for name in ["seph", &binary_agent_name(1)] {
+            let agent = a.get_or_create_agent_id(name);
+            a.add_insert(agent, 0, "hi");
+        }
+        let bytes = a.encode_from_with_agents(ENCODE_PATCH, &[], &mut send_table);
+        b.decode_and_add_with_agents(&bytes, &mut recv_table).unwrap();
+        send_table.acknowledge(recv_table.received_len());
+
+        // Both peers restart.
+        let mut send_table = AgentTable::decode(&send_table.encode()).unwrap();
+        let mut recv_table = AgentTable::decode(&recv_table.encode()).unwrap();
+        let mut b = ListOpLog::load_from(&b.encode(ENCODE_PATCH)).unwrap();
+
+        let v = a.local_frontier();
+        a.add_insert(0, 0, "x");
+        let bytes = a.encode_from_with_agents(ENCODE_PATCH, v.as_ref(), &mut send_table);
+        b.decode_and_add_with_agents(&bytes, &mut recv_table).unwrap();
+        assert_eq!(a, b);
+
+        assert!(AgentTable::decode(&[5]).is_err());
+    }
+}
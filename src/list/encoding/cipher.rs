@@ -0,0 +1,110 @@
+//! Encrypted patch content.
+//!
+//! When patches are relayed through an untrusted server, the document's text can be encrypted
+//! while leaving the causal graph and operation positions in clear text. The server can still merge
+//! patches, transform operations and compute version summaries, but it can't read what was typed.
+//!
+//! When a patch is encoded with
+//! [`encode_from_encrypted`](crate::list::ListOpLog::encode_from_encrypted), the
+//! `PatchContent` and `Content` chunks are replaced with `EncryptedPatchContent` and
+//! `EncryptedContent` chunks. Encrypted content is never put in the file's LZ4 compressed chunk.
+//!
+//! A peer which decodes an encrypted patch without the cipher ends up with an oplog without any
+//! content. Re-encoding that oplog can't recover the content, so relays should forward the patches
+//! they receive rather than re-encoding them. Peers with the cipher can fill the content back in by
+//! merging the original patches.
+
+/// A (caller supplied) cipher used to encrypt and decrypt document content in patches.
+///
+/// Diamond types doesn't ship any implementations of this trait. Implementations should use an
+/// authenticated encryption scheme, so tampering is detected when the content is decrypted.
+pub trait ContentCipher {
+    /// Encrypt some content.
+    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8>;
+
+    /// Decrypt content which was encrypted with [`encrypt`](ContentCipher::encrypt). Returns None
+    /// if the content can't be decrypted - for example because it was encrypted with a different
+    /// key.
+    fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>>;
+}
+
+#[cfg(test)]
+mod test {
+    use crate::causalgraph::summary::VersionSummary;
+    use crate::encoding::parseerror::ParseError;
+    use crate::list::encoding::cipher::ContentCipher;
+    use crate::list::encoding::{ENCODE_FULL, ENCODE_PATCH};
+    use crate::list::ListOpLog;
+
+    /// A toy cipher for testing. It just XORs the content with the key, and adds a checksum byte.
+    struct XorCipher(u8);
+
+    impl ContentCipher for XorCipher {
+        fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+            let mut result: Vec<u8> = plaintext.iter().map(|b| b ^ self.0).collect();
+            result.push(self.0);
+            result
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+            let (&key, data) = ciphertext.split_last()?;
+            if key != self.0 { return None; }
+            Some(data.iter().map(|b| b ^ self.0).collect())
+        }
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle.as_bytes())
+    }
+
+    #[test]
+    fn relay_merges_encrypted_patches() {
+        let cipher = XorCipher(0x5a);
+
+        let mut a = ListOpLog::new();
+        a.get_or_create_agent_id("seph");
+        a.add_insert(0, 0, "secret message which is long enough to compress");
+        let mut b = ListOpLog::new();
+
+        let patch = a.encode_from_encrypted(ENCODE_PATCH, &[], &cipher);
+        assert!(!contains(&patch, "secret"));
+
+        // The relay can merge the patch without the cipher, but it doesn't learn the content.
+        let mut relay = ListOpLog::new();
+        relay.decode_and_add(&patch).unwrap();
+        assert_eq!(relay.checkout_tip().len(), a.checkout_tip().len());
+        assert!(relay.operation_ctx.ins_content.is_empty());
+        let summary: VersionSummary = relay.cg.agent_assignment.summarize_versions();
+        assert_eq!(summary, a.cg.agent_assignment.summarize_versions());
+
+        // Only peers with the right key can read the patch.
+        assert_eq!(b.decode_and_add_encrypted(&patch, &XorCipher(1)), Err(ParseError::DecryptionFailed));
+        assert!(b.is_empty());
+        b.decode_and_add_encrypted(&patch, &cipher).unwrap();
+        assert_eq!(a, b);
+
+        // Content can be filled in later using the original patch.
+        relay.decode_and_add_encrypted(&patch, &cipher).unwrap();
+        assert_eq!(relay, a);
+    }
+
+    #[test]
+    fn encrypted_start_branch() {
+        let cipher = XorCipher(0x33);
+
+        let mut oplog = ListOpLog::new();
+        oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(0, 0, "hidden text");
+        let v = oplog.local_frontier();
+        oplog.add_insert(0, 0, ">> ");
+
+        let bytes = oplog.encode_from_encrypted(ENCODE_FULL, v.as_ref(), &cipher);
+        assert!(!contains(&bytes, "hidden"));
+
+        // The file can only be loaded into an empty oplog using the cipher.
+        assert!(ListOpLog::new().decode_and_add(&bytes).is_err());
+        let mut loaded = ListOpLog::new();
+        loaded.decode_and_add_encrypted(&bytes, &cipher).unwrap();
+        assert_eq!(loaded.checkout_tip().content().to_string(), ">> hidden text");
+    }
+}
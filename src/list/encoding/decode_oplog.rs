@@ -1,11 +1,13 @@
+use jumprope::JumpRope;
 use smallvec::{smallvec, SmallVec};
+use smartstring::alias::String as SmartString;
 use crate::list::encoding::*;
 use crate::list::{ListOpLog, switch};
 use crate::frontier::*;
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
 use crate::list::operation::ListOpKind::{Del, Ins};
 use crate::rev_range::RangeRev;
-use crate::{AgentId, Frontier, LV};
+use crate::{AgentId, AgentVersion, Frontier, LV};
 use crate::unicount::*;
 use rle::*;
 use crate::list::buffered_iter::Buffered;
@@ -67,7 +69,9 @@ impl<'a> BufReader<'a> {
         }))
     }
 
-    fn read_version(mut self, oplog: &ListOpLog, agent_map: &[(AgentId, usize)]) -> Result<Frontier, ParseError> {
+    /// Read the (agent, seq) pairs named in a version chunk. This doesn't require the versions to
+    /// be known locally.
+    fn read_agent_versions(mut self, agent_map: &[(AgentId, usize)]) -> Result<SmallVec<[AgentVersion; 2]>, ParseError> {
         let mut result = smallvec![];
         // All frontiers contain at least one item.
         loop {
@@ -76,20 +80,16 @@ impl<'a> BufReader<'a> {
             let seq = self.next_usize()?; // Bleh. Skip me when root!
             if mapped_agent == 0 { break; } // Root.
 
-            let agent = agent_map[mapped_agent - 1].0;
-
-            let time = oplog.try_crdt_id_to_time((agent, seq))
-                .ok_or(ParseError::BaseVersionUnknown)?;
-            result.push(time);
+            let agent = agent_map.get(mapped_agent - 1)
+                .ok_or(ParseError::InvalidLength)?.0;
+            result.push((agent, seq));
 
             if !has_more { break; }
         }
 
-        sort_frontier(&mut result);
-
         self.expect_empty()?;
 
-        Ok(Frontier(result))
+        Ok(result)
     }
 
     fn read_parents(&mut self, oplog: &ListOpLog, next_time: LV, agent_map: &[(AgentId, usize)]) -> Result<Frontier, ParseError> {
@@ -150,26 +150,12 @@ impl<'a> BufReader<'a> {
 }
 
 impl<'a> ChunkReader<'a> {
-    fn read_version(&mut self, oplog: &ListOpLog, agent_map: &[(AgentId, usize)]) -> Result<Frontier, ParseError> {
-        let chunk = self.read_chunk_if_eq(ListChunkType::Version)?;
-        if let Some(chunk) = chunk {
-            chunk.read_version(oplog, agent_map).map_err(|e| {
-                // We can't read a frontier if it names agents or sequence numbers we haven't seen
-                // before. If this happens, its because we're trying to load a data set from the
-                // future.
-                //
-                // That should be possible - if we prune history, we should be able to load a
-                // data set from some future version and just set start_version and start_content
-                // properties on the oplog. But thats NYI!
-
-                // TODO: Remove this!
-                if let ParseError::InvalidRemoteID(_) = e {
-                    ParseError::DataMissing
-                } else { e }
-            })
+    fn read_agent_versions(&mut self, agent_map: &[(AgentId, usize)]) -> Result<SmallVec<[AgentVersion; 2]>, ParseError> {
+        if let Some(chunk) = self.read_chunk_if_eq(ListChunkType::Version)? {
+            chunk.read_agent_versions(agent_map)
         } else {
             // If the start_frontier chunk is missing, it means we're reading from ROOT.
-            Ok(Frontier::root())
+            Ok(smallvec![])
         }
     }
 
@@ -194,11 +180,13 @@ impl<'a> ChunkReader<'a> {
         }
     }
 
-    fn read_fileinfo(&mut self, oplog: &mut ListOpLog) -> Result<FileInfoData, ParseError> {
+    fn read_fileinfo(&mut self, oplog: &mut ListOpLog, agents: Option<&AgentTable>) -> Result<FileInfoData, ParseError> {
         let mut fileinfo = self.expect_chunk(ListChunkType::FileInfo)?.chunks();
 
         let doc_id = fileinfo.read_chunk_if_eq(ListChunkType::DocId)?;
-        let mut agent_names_chunk = fileinfo.expect_chunk(ListChunkType::AgentNames)?;
+        let (agent_names_type, mut agent_names_chunk) = fileinfo.expect_chunk_pred(|c| {
+            c == ListChunkType::AgentNames || c == ListChunkType::IndexedAgentNames
+        }, ListChunkType::AgentNames)?;
         let userdata = fileinfo.read_chunk_if_eq(ListChunkType::UserData)?;
 
         let doc_id = if let Some(doc_id) = doc_id {
@@ -212,9 +200,17 @@ impl<'a> ChunkReader<'a> {
         // 0 implicitly maps to ROOT.
         // let mut file_to_self_agent_map = vec![(ROOT_AGENT, 0)];
         let mut agent_map = Vec::new();
+        let mut new_agent_names = Vec::new();
         while !agent_names_chunk.0.is_empty() {
-            let name = agent_names_chunk.next_str()?;
-            let id = oplog.get_or_create_agent_id(name);
+            let id = if agent_names_type == ListChunkType::IndexedAgentNames {
+                let (name, new_idx) = AgentTable::read_entry(agents, &mut agent_names_chunk)?;
+                let id = oplog.get_or_create_agent_id(&name);
+                if let Some(idx) = new_idx { new_agent_names.push((idx, name)); }
+                id
+            } else {
+                let name = agent_names_chunk.next_str()?;
+                oplog.get_or_create_agent_id(name)
+            };
             agent_map.push((id, 0));
         }
 
@@ -222,6 +218,7 @@ impl<'a> ChunkReader<'a> {
             userdata,
             doc_id,
             agent_map,
+            new_agent_names,
         })
     }
 }
@@ -233,6 +230,8 @@ struct FileInfoData<'a> {
     userdata: Option<BufReader<'a>>,
     doc_id: Option<&'a str>,
     agent_map: Vec<(AgentId, usize)>,
+    /// Agent names which should be added to the agent table once the file has been merged.
+    new_agent_names: Vec<(usize, SmartString)>,
 }
 
 
@@ -446,13 +445,13 @@ impl Default for DecodeOptions {
 impl ListOpLog {
     pub fn load_from(data: &[u8]) -> Result<Self, ParseError> {
         let mut oplog = Self::new();
-        oplog.decode_internal(data, DecodeOptions::default())?;
+        oplog.decode_internal(data, DecodeOptions::default(), None, None, None)?;
         Ok(oplog)
     }
 
     pub fn load_from_opts(data: &[u8], opts: DecodeOptions) -> Result<Self, ParseError> {
         let mut oplog = Self::new();
-        oplog.decode_internal(data, opts)?;
+        oplog.decode_internal(data, opts, None, None, None)?;
         Ok(oplog)
     }
 
@@ -474,6 +473,38 @@ impl ListOpLog {
     /// This method takes an options object, which for now doesn't do much. Most users should just
     /// call [`OpLog::decode_and_add`](OpLog::decode_and_add)
     pub fn decode_and_add_opts(&mut self, data: &[u8], opts: DecodeOptions) -> Result<Frontier, ParseError> {
+        self.decode_and_add_internal(data, opts, None, None, None)
+    }
+
+    /// Add all operations from a patch made with
+    /// [`encode_from_with_agents`](ListOpLog::encode_from_with_agents) into this document.
+    ///
+    /// Any new agent names in the patch are added to the agent table, but only if the patch is
+    /// merged successfully.
+    pub fn decode_and_add_with_agents(&mut self, data: &[u8], agents: &mut AgentTable) -> Result<Frontier, ParseError> {
+        self.decode_and_add_internal(data, DecodeOptions::default(), Some(agents), None, None)
+    }
+
+    /// Add all operations from a patch made with
+    /// [`encode_from_encrypted`](ListOpLog::encode_from_encrypted) into this document, decrypting
+    /// its content with the provided cipher.
+    ///
+    /// Encrypted patches can also be merged with [`decode_and_add`](ListOpLog::decode_and_add), but
+    /// the operations' content will be unknown.
+    pub fn decode_and_add_encrypted(&mut self, data: &[u8], cipher: &dyn ContentCipher) -> Result<Frontier, ParseError> {
+        self.decode_and_add_internal(data, DecodeOptions::default(), None, Some(cipher), None)
+    }
+
+    /// Add all operations from a binary chunk into this document, checking every new operation
+    /// with the provided verifier. If the verifier rejects any operation, nothing is merged and
+    /// this returns [`ParseError::SignatureRejected`].
+    ///
+    /// Signatures are added to patches by [`encode_from_signed`](ListOpLog::encode_from_signed).
+    pub fn decode_and_add_verified(&mut self, data: &[u8], verifier: &dyn EntryVerifier) -> Result<Frontier, ParseError> {
+        self.decode_and_add_internal(data, DecodeOptions::default(), None, None, Some(verifier))
+    }
+
+    fn decode_and_add_internal(&mut self, data: &[u8], opts: DecodeOptions, agents: Option<&mut AgentTable>, cipher: Option<&dyn ContentCipher>, verifier: Option<&dyn EntryVerifier>) -> Result<Frontier, ParseError> {
         // In order to merge data safely, when an error happens we need to unwind all the merged
         // operations before returning. Otherwise self is in an invalid state.
         //
@@ -490,104 +521,59 @@ impl ListOpLog {
         let ins_content_length = self.operation_ctx.ins_content.len();
         let del_content_length = self.operation_ctx.del_content.len();
 
-        let result = self.decode_internal(data, opts);
+        let result = self.decode_internal(data, opts, agents, cipher, verifier);
 
         if result.is_err() {
             // Unwind changes back to len.
-            // This would be nicer with an RleVec iterator, but the iter implementation doesn't
-            // support iterating backwards.
             self.doc_id = doc_id;
 
-            while let Some(last) = self.cg.agent_assignment.client_with_localtime.0.last_mut() {
-                debug_assert!(len <= last.end());
-                if len == last.end() { break; }
-                else {
-                    // Truncate!
-                    let KVPair(_, removed) = if len <= last.0 {
-                        // Drop entire entry
-                        self.cg.agent_assignment.client_with_localtime.0.pop().unwrap()
-                    } else {
-                        last.truncate(len - last.0)
-                    };
-
-                    let client_data = &mut self.cg.agent_assignment.client_data[removed.agent as usize];
-                    client_data.lv_for_seq.remove_ctx(removed.seq_range, &());
-                }
-            }
-
             let num_operations = self.operations.end();
             if num_operations > len {
                 self.operations.remove_ctx((len..num_operations).into(), &self.operation_ctx);
             }
 
-            // Trim history
-            let hist_entries = &mut self.cg.graph.entries;
-            let history_length = hist_entries.end();
-            if history_length > len {
-                // We can't use entries.remove because HistoryEntry doesn't support SplitableSpan.
-                // And also because we need to update child_indexes.
-                let del_span_start = len;
-
-                let first_idx = hist_entries.find_index(len).unwrap();
-
-                let e = &mut hist_entries.0[first_idx];
-                let first_truncated_idx = if del_span_start > e.span.start {
-                    // The first entry just needs to be trimmed down.
-                    e.span.truncate_from(del_span_start);
-                    first_idx + 1
-                } else {
-                    first_idx
-                };
-
-                let mut idx = first_truncated_idx;
-
-                // Go through and unwind from idx.
-                while idx < hist_entries.num_entries() {
-                    // Cloning here is an ugly and kinda slow hack to work around the borrow
-                    // checker. But this whole case is rare anyway, so idk.
-                    let parents = hist_entries.0[idx].parents.clone();
-
-                    for p in parents {
-                        if p < len { // If p >= len, the target will be discarded anyway.
-                            let parent_entry = hist_entries.find_mut(p).unwrap().0;
-                            while let Some(&c_idx) = parent_entry.child_indexes.last() {
-                                if c_idx >= first_truncated_idx {
-                                    parent_entry.child_indexes.pop();
-                                } else { break; }
-                            }
-                        }
-                    }
-
-                    idx += 1;
-                }
-
-                self.cg.graph.entries.0.truncate(first_truncated_idx);
-
-                while let Some(&last_idx) = self.cg.graph.root_child_indexes.last() {
-                    if last_idx >= self.cg.graph.entries.num_entries() {
-                        self.cg.graph.root_child_indexes.pop();
-                    } else { break; }
-                }
-            }
-
-            // Remove excess agents
-            self.cg.agent_assignment.client_data.truncate(num_known_agents);
+            self.cg.truncate_to(len, num_known_agents, old_frontier);
 
             self.operation_ctx.ins_content.truncate(ins_content_length);
             self.operation_ctx.del_content.truncate(del_content_length);
 
-            self.cg.version = old_frontier;
+            // If we were empty, we might have started loading a pruned oplog.
+            if len == 0 {
+                self.start_version = Frontier::root();
+                self.start_content = JumpRope::new();
+            }
         }
 
         result
     }
 
+    /// Set up an empty oplog to load a file whose history was pruned at `start_ids`, with the given
+    /// document content at that version.
+    ///
+    /// We don't have the operations from before the prune point, but we still need local versions
+    /// for them. Each agent's operations up to the named version are assigned as a placeholder run
+    /// in the causal graph.
+    fn init_pruned(&mut self, start_ids: &[AgentVersion], content: &str) -> Frontier {
+        for &(agent, seq) in start_ids {
+            self.cg.merge_and_assign(&[], AgentSpan { agent, seq_range: (0..seq + 1).into() });
+        }
+
+        let version: SmallVec<[LV; 2]> = start_ids.iter()
+            .map(|&id| self.try_crdt_id_to_time(id).unwrap())
+            .collect();
+        let version = self.cg.graph.find_dominators(&version);
+
+        self.start_version = version.clone();
+        self.start_content = content.into();
+        version
+    }
+
     /// Merge data from the remote source into our local document state.
     ///
     /// NOTE: This code is quite new.
     /// TODO: Currently if this method returns an error, the local state is undefined & invalid.
     /// Until this is fixed, the signature of the method will stay kinda weird to prevent misuse.
-    fn decode_internal(&mut self, data: &[u8], opts: DecodeOptions) -> Result<Frontier, ParseError> {
+    fn decode_internal(&mut self, data: &[u8], opts: DecodeOptions, agents: Option<&mut AgentTable>, cipher: Option<&dyn ContentCipher>, verifier: Option<&dyn EntryVerifier>) -> Result<Frontier, ParseError> {
         // Written to be symmetric with encode functions.
         let mut reader = BufReader(data);
 
@@ -636,8 +622,8 @@ impl ListOpLog {
         // fileinfo has DocID, UserData and AgentNames.
         // The agent_map is a map from agent_id in the file to agent_id in self.
         let FileInfoData {
-            userdata: _userdata, doc_id, mut agent_map,
-        } = reader.read_fileinfo(self)?;
+            userdata: _userdata, doc_id, mut agent_map, new_agent_names,
+        } = reader.read_fileinfo(self, agents.as_deref())?;
 
         // If we already have a doc_id, make sure they match before merging.
         if let Some(file_doc_id) = doc_id {
@@ -653,15 +639,33 @@ impl ListOpLog {
         let mut start_branch = reader.expect_chunk(ListChunkType::StartBranch)?.chunks();
 
         // Start version - which if missing defaults to ROOT ([]).
-        let start_version = start_branch.read_version(self, &agent_map)?;
-
-        // The start branch also optionally contains the document content at this version. We can't
-        // use it yet (NYI) but it needs to be parsed because it because it might be compressed.
-        if !start_branch.is_empty() {
-            let _start_content = start_branch.expect_content_str(compressed_chunk.as_mut())?;
-            // dbg!(start_content);
-            // TODO! Attach start_content if we're empty and start_version != ROOT.
-        }
+        let start_ids = start_branch.read_agent_versions(&agent_map)?;
+
+        // The start branch also optionally contains the document content at this version. This
+        // needs to be parsed even if we don't use it, because it might be compressed.
+        let decrypted_start_content; // Pulled out so its lifetime escapes the block.
+        let start_content = if let Some(chunk) = start_branch.read_chunk_if_eq(ListChunkType::EncryptedContent)? {
+            // Without the cipher, we don't know the content.
+            if let Some(cipher) = cipher {
+                decrypted_start_content = cipher.decrypt(chunk.0).ok_or(ParseError::DecryptionFailed)?;
+                Some(BufReader(&decrypted_start_content).chunks().expect_content_str(None)?)
+            } else { None }
+        } else if !start_branch.is_empty() {
+            Some(start_branch.expect_content_str(compressed_chunk.as_mut())?)
+        } else { None };
+
+        let start_version = match start_ids.iter().map(|&id| self.try_crdt_id_to_time(id)).collect::<Option<SmallVec<[LV; 2]>>>() {
+            Some(mut version) => {
+                sort_frontier(&mut version);
+                Frontier(version)
+            }
+            // We don't know about the start version. But if the file has the document's content
+            // there (and we're empty), we can load it as a pruned oplog.
+            None => match start_content {
+                Some(content) if self.is_empty() => self.init_pruned(&start_ids, content),
+                _ => { return Err(ParseError::BaseVersionUnknown); }
+            }
+        };
 
         // Usually the version data will be strictly separated. Either we're loading data into an
         // empty document, or we've been sent catchup data from a remote peer. If the data set
@@ -670,6 +674,12 @@ impl ListOpLog {
         let patches_overlap = !local_frontier_eq(start_version.as_ref(), self.cg.version.as_ref());
         // dbg!(patches_overlap);
 
+        // Decrypted patch content, if the file has encrypted content and we have the cipher.
+        let mut decrypted_content: Vec<Vec<u8>> = vec![];
+
+        // Content for operations we already have, but whose content we don't know.
+        let mut fill_content: Vec<(DTRange, ListOpKind, &str)> = vec![];
+
         // *** Patches ***
         let file_frontier = {
             // This chunk contains the actual set of edits to the document.
@@ -679,8 +689,28 @@ impl ListOpLog {
             let mut ins_content = None;
             let mut del_content = None;
 
-            while let Some(chunk) = patch_chunk.read_chunk_if_eq(ListChunkType::PatchContent)? {
-                let (tag, content_chunk) = ReadPatchContentIter::new(chunk, compressed_chunk.as_mut())?;
+            let mut content_chunks = vec![];
+            loop {
+                if let Some(chunk) = patch_chunk.read_chunk_if_eq(ListChunkType::PatchContent)? {
+                    content_chunks.push(chunk);
+                } else if let Some(chunk) = patch_chunk.read_chunk_if_eq(ListChunkType::EncryptedPatchContent)? {
+                    // Without the cipher, the content of these operations is unknown.
+                    if let Some(cipher) = cipher {
+                        decrypted_content.push(cipher.decrypt(chunk.0).ok_or(ParseError::DecryptionFailed)?);
+                    }
+                } else { break; }
+            }
+
+            let mut parsed_content = vec![];
+            for chunk in content_chunks {
+                parsed_content.push(ReadPatchContentIter::new(chunk, compressed_chunk.as_mut())?);
+            }
+            // Decrypted content is never compressed.
+            for bytes in decrypted_content.iter() {
+                parsed_content.push(ReadPatchContentIter::new(BufReader(bytes), None)?);
+            }
+
+            for (tag, content_chunk) in parsed_content {
                 // let iter = content_chunk.take_max();
                 let iter = content_chunk.buffered();
                 match tag {
@@ -695,6 +725,10 @@ impl ListOpLog {
             let mut agent_assignment_chunk = patch_chunk.expect_chunk(ListChunkType::OpVersions)?;
             let pos_patches_chunk = patch_chunk.expect_chunk(ListChunkType::OpTypeAndPosition)?;
             let mut history_chunk = patch_chunk.expect_chunk(ListChunkType::OpParents)?;
+            let signatures = match patch_chunk.read_chunk_if_eq(ListChunkType::OpSignatures)? {
+                Some(chunk) => chunk.read_signatures(&agent_map)?,
+                None => vec![],
+            };
 
             // We need an insert ctx in some situations, though it'll never be accessed.
             let dummy_ctx = ListOperationCtx::new();
@@ -727,8 +761,10 @@ impl ListOpLog {
             // let mut version_map: SmallVec<[KVPair<TimeSpan>; 1]> = SmallVec::new();
             let mut version_map = RleVec::new();
 
-            // Take and merge the next exactly n patches
-            let mut parse_next_patches = |oplog: &mut ListOpLog, mut n: usize, keep: bool| -> Result<(), ParseError> {
+            // Take and merge the next exactly n patches. If the patches overlap with operations we
+            // already have, overlap is the local version of the first overlapping operation. The
+            // content of overlapping operations is used to fill in content we don't know.
+            let mut parse_next_patches = |oplog: &mut ListOpLog, mut n: usize, mut overlap: Option<LV>| -> Result<(), ParseError> {
                 while n > 0 {
                     let mut max_len = n;
 
@@ -761,7 +797,14 @@ impl ListOpLog {
                         // dbg!(keep, (next_patch_time, &op, content_here));
 
                         // self.operations.push(KVPair(next_time, op));
-                        if keep {
+                        if let Some(lv) = overlap.as_mut() {
+                            // Content is filled in after the whole patch has been read, so if
+                            // anything goes wrong we don't need to unwind it.
+                            if let Some(content) = content_here {
+                                fill_content.push(((*lv..*lv + max_len).into(), op.kind, content));
+                            }
+                            *lv += max_len;
+                        } else {
                             oplog.push_op_internal(next_patch_time, op.loc, op.kind, content_here);
                             next_patch_time += max_len;
                         }
@@ -803,12 +846,11 @@ impl ListOpLog {
                         let consume_here = crdt_span.seq_range.truncate_keeping_right_from(end);
                         let len = consume_here.len();
 
-                        let keep = if let Some(overlap_start) = overlap_start {
+                        if let Some(overlap_start) = overlap_start {
                             let overlap = (overlap_start .. overlap_start + len).into();
                             // There's overlap. We'll filter out this item.
                             version_map.push_rle(KVPair(next_file_time, overlap));
                             // println!("push overlap {:?}", KVPair(next_file_time, overlap));
-                            false
                         } else {
                             self.assign_time_to_crdt_span(next_assignment_time, AgentSpan {
                                 agent: crdt_span.agent,
@@ -824,13 +866,12 @@ impl ListOpLog {
                                 (next_assignment_time..next_assignment_time + len).into(),
                             ));
                             next_assignment_time += len;
-                            true
-                        };
+                        }
                         next_file_time += len;
 
                         // dbg!(&file_to_local_version_map);
 
-                        parse_next_patches(self, len, keep)?;
+                        parse_next_patches(self, len, overlap_start)?;
 
                         // And deal with history.
                         // parse_next_history(&mut self, &file_to_self_agent_map, &version_map, len, keep)?;
@@ -845,7 +886,7 @@ impl ListOpLog {
                     let timespan = (next_assignment_time..next_assignment_time+len).into();
                     // file_to_local_version_map.push_rle((next_assignment_time..next_assignment_time + len).into());
                     version_map.push_rle(KVPair(next_file_time, timespan));
-                    parse_next_patches(self, len, true)?;
+                    parse_next_patches(self, len, None)?;
                     // parse_next_history(&mut self, &file_to_self_agent_map, &version_map, len, true)?;
 
                     next_assignment_time += len;
@@ -920,6 +961,21 @@ impl ListOpLog {
             patch_chunk.expect_empty()?;
             history_chunk.expect_empty()?;
 
+            // If history has been pruned, we can't merge operations which are concurrent with the
+            // prune point because we don't have the operations they'd be transformed against.
+            let pruned_version = self.start_version.as_ref();
+            if !pruned_version.is_empty() {
+                for entry in self.cg.graph.iter_range((first_new_time..self.len()).into()) {
+                    if !self.cg.graph.frontier_contains_frontier(entry.parents.as_ref(), pruned_version) {
+                        return Err(ParseError::DataMissing);
+                    }
+                }
+            }
+
+            if let Some(verifier) = verifier {
+                self.verify_signatures((first_new_time..self.len()).into(), &signatures, verifier)?;
+            }
+
             if let Some(mut iter) = ins_content {
                 if iter.next().is_some() {
                     return Err(ParseError::InvalidContent);
@@ -956,6 +1012,16 @@ impl ListOpLog {
 
         // self.frontier = end_frontier_chunk.read_full_frontier(&self)?;
 
+        if let Some(agents) = agents {
+            for (idx, name) in new_agent_names {
+                agents.insert(idx, &name);
+            }
+        }
+
+        for (range, kind, content) in fill_content {
+            self.fill_op_content(range, kind, content);
+        }
+
         Ok(file_frontier)
     }
 }
@@ -2,6 +2,7 @@ use jumprope::JumpRope;
 use rle::{HasLength, RleRun};
 use crate::list::encoding::*;
 use crate::causalgraph::graph::GraphEntrySimple;
+use crate::causalgraph::summary::VersionSummary;
 use crate::list::operation::ListOpKind::{Del, Ins};
 use crate::list::{ListBranch, ListOpLog, switch};
 use crate::rle::{KVPair, RleVec};
@@ -101,6 +102,9 @@ pub struct EncodeOptions<'a> {
 
     pub experimentally_store_end_branch_content: bool,
 
+    /// Store the content of insert operations. Files without inserted content can still be merged
+    /// and transformed, but can't be checked out. The content can be filled in later by merging a
+    /// patch which contains it.
     pub store_inserted_content: bool,
     pub store_deleted_content: bool,
 
@@ -188,23 +192,26 @@ fn write_assignment_run(dest: &mut Vec<u8>, run: AgentAssignmentRun) {
     dest.extend_from_slice(&buf[..pos]);
 }
 
-#[derive(Debug, Clone)]
-struct AgentMapping {
+#[derive(Debug)]
+struct AgentMapping<'a> {
     /// Map from oplog's agent ID to the agent id in the file. Paired with the last assigned agent
     /// ID, to support agent IDs bouncing around.
     map: Vec<Option<(AgentId, usize)>>,
     next_mapped_agent: AgentId,
     output: Vec<u8>,
+    /// If set, agent names are written as AgentTable entries instead of plain strings.
+    table: Option<&'a mut AgentTable>,
 }
 
-impl AgentMapping {
+impl<'a> AgentMapping<'a> {
     // TODO: This should only need the agent assignment I think!
-    fn new(oplog: &ListOpLog) -> Self {
+    fn new(oplog: &ListOpLog, table: Option<&'a mut AgentTable>) -> Self {
         let client_len = oplog.cg.agent_assignment.client_data.len();
         let mut result = Self {
             map: Vec::with_capacity(client_len),
             next_mapped_agent: 1, // 0 is implicitly assigned to ROOT.
-            output: Vec::new()
+            output: Vec::new(),
+            table,
         };
         result.map.resize(client_len, None);
         result
@@ -220,7 +227,11 @@ impl AgentMapping {
         self.map[agent].map_or_else(|| {
             let mapped = self.next_mapped_agent;
             self.map[agent] = Some((mapped, 0));
-            push_leb_str(&mut self.output, oplog.cg.agent_assignment.client_data[agent].name.as_str());
+            let name = oplog.cg.agent_assignment.client_data[agent].name.as_str();
+            match self.table.as_mut() {
+                Some(table) => table.write_entry(&mut self.output, name),
+                None => push_leb_str(&mut self.output, name),
+            }
             // println!("Mapped agent {} -> {}", oplog.cg.client_data[agent].name, mapped);
             self.next_mapped_agent += 1;
             mapped
@@ -234,8 +245,9 @@ impl AgentMapping {
         (span.start as isize) - (old_seq as isize)
     }
 
-    fn consume(self) -> Vec<u8> {
-        self.output
+    fn consume(self) -> (Vec<u8>, ListChunkType) {
+        let chunk_type = if self.table.is_some() { ListChunkType::IndexedAgentNames } else { ListChunkType::AgentNames };
+        (self.output, chunk_type)
     }
 }
 
@@ -308,6 +320,17 @@ fn write_content_rope(dest: &mut Vec<u8>, rope: &JumpRope, compressed: Option<&m
     write_content(dest, DataType::PlainText, rope.len_bytes(),rope.substrings().map(|s| s.as_bytes()), compressed);
 }
 
+/// Write the content of a branch. If we have a cipher, the content chunk is encrypted.
+fn write_branch_content(dest: &mut Vec<u8>, rope: &JumpRope, compressed: Option<&mut Vec<u8>>, cipher: Option<&dyn ContentCipher>) {
+    if let Some(cipher) = cipher {
+        let mut buf = Vec::new();
+        write_content_rope(&mut buf, rope, None);
+        push_leb_chunk(dest, ListChunkType::EncryptedContent, &cipher.encrypt(&buf));
+    } else {
+        write_content_rope(dest, rope, compressed);
+    }
+}
+
 fn write_chunk_str(dest: &mut Vec<u8>, s: &str, chunk_type: ListChunkType) {
     debug_assert_ne!(chunk_type, ListChunkType::Content); // Use write_content_str instead.
 
@@ -402,9 +425,55 @@ impl ListOpLog {
     /// Encode the data stored in the OpLog into a (custom) compact binary form suitable for saving
     /// to disk, or sending over the network.
     pub fn encode_from(&self, opts: EncodeOptions, from_version: &[LV]) -> Vec<u8> {
+        self.encode_internal(opts, from_version, None, None, None)
+    }
+
+    /// Encode a patch like [`encode_from`](ListOpLog::encode_from), but write agent names using an
+    /// agent table. Agents the remote peer has acknowledged are referred to by index, and any new
+    /// agents are added to the table.
+    ///
+    /// The resulting patch can only be read by a peer which has received the acknowledged entries -
+    /// see [`decode_and_add_with_agents`](ListOpLog::decode_and_add_with_agents) and the
+    /// [`agent_table`](crate::list::encoding::agent_table) module.
+    pub fn encode_from_with_agents(&self, opts: EncodeOptions, from_version: &[LV], agents: &mut AgentTable) -> Vec<u8> {
+        self.encode_internal(opts, from_version, Some(agents), None, None)
+    }
+
+    /// Encode a patch like [`encode_from`](ListOpLog::encode_from), but encrypt the document's
+    /// content using the provided cipher. The causal graph and operation positions are not
+    /// encrypted. See the [`cipher`](crate::list::encoding::cipher) module for details.
+    ///
+    /// Peers need the same cipher to read the content - see
+    /// [`decode_and_add_encrypted`](ListOpLog::decode_and_add_encrypted).
+    pub fn encode_from_encrypted(&self, opts: EncodeOptions, from_version: &[LV], cipher: &dyn ContentCipher) -> Vec<u8> {
+        self.encode_internal(opts, from_version, None, Some(cipher), None)
+    }
+
+    /// Encode a patch like [`encode_from`](ListOpLog::encode_from), and sign the causal graph
+    /// entries in the patch using the provided signer. See the
+    /// [`signing`](crate::list::encoding::signing) module for details.
+    ///
+    /// Signatures are checked by [`decode_and_add_verified`](ListOpLog::decode_and_add_verified).
+    pub fn encode_from_signed(&self, opts: EncodeOptions, from_version: &[LV], signer: &dyn EntrySigner) -> Vec<u8> {
+        self.encode_internal(opts, from_version, None, None, Some(signer))
+    }
+
+    fn encode_internal(&self, mut opts: EncodeOptions, from_version: &[LV], agents: Option<&mut AgentTable>, cipher: Option<&dyn ContentCipher>, signer: Option<&dyn EntrySigner>) -> Vec<u8> {
         // if !frontier_is_root(from_frontier) {
         //     unimplemented!("Encoding from a non-root frontier is not implemented");
         // }
+
+        // If history has been pruned, we don't have the operations from before the prune point.
+        // Encode from there instead, including the document's content so the result can still be
+        // loaded into an empty oplog.
+        let pruned_version = self.start_version.as_ref();
+        let from_version = if self.cg.graph.frontier_contains_frontier(from_version, pruned_version) {
+            from_version
+        } else {
+            opts.store_start_branch_content = true;
+            pruned_version
+        };
+
         let verbose = ALLOW_VERBOSE && opts.verbose;
 
         // Before anything else, we'll scan the oplog and assemble all the data in memory that we
@@ -451,7 +520,7 @@ impl ListOpLog {
         // Map from old agent ID -> new agent ID in the file.
         //
         // (Agent ID 0 is reserved for ROOT, to make special parents slightly simpler.)
-        let mut agent_mapping = AgentMapping::new(self);
+        let mut agent_mapping = AgentMapping::new(self, agents);
 
         // let mut agent_assignment_chunk = SpanWriter::new(push_run_u32);
         let mut agent_assignment_chunk = Vec::new();
@@ -541,6 +610,8 @@ impl ListOpLog {
         });
 
 
+        let mut signatures_chunk = Vec::new();
+
         // If we just iterate in the current order, this code would be way simpler :p
         // let iter = self.cg.history.optimized_txns_between(from_frontier, &self.frontier);
         // for walk in self.cg.parents.iter() {
@@ -573,12 +644,9 @@ impl ListOpLog {
                 // ops_writer somehow. The reason is that the content_pos field on the merged
                 // OperationInternal objects will be invalid! Total foot gun there :p
 
-                if op.kind == Ins && opts.store_inserted_content {
-                    // For now at least, we can't skip inserted content for inserts.
-                    // TODO: Reconsider this at some point.
-                    assert!(content.is_some());
-                }
-
+                // If we don't know an operation's content (eg because the oplog was loaded from a
+                // file without inserted content), it is marked as unknown in the ContentIsKnown
+                // chunk.
                 let content_chunk = switch(op.kind,
                                            &mut inserted_content,
                                            &mut deleted_content
@@ -595,6 +663,23 @@ impl ListOpLog {
                 span: walk.consume,
                 parents: walk.parents
             }, &mut agent_mapping);
+
+            // 4. Signatures, if we're signing.
+            if let Some(signer) = signer {
+                let aa = &self.cg.agent_assignment;
+                for entry in self.cg.iter_range(walk.consume) {
+                    // If we don't know what was inserted, we can't sign the entry.
+                    let Some(msg) = self.signing_message(&entry, &[entry.time_span()]) else { continue; };
+                    if let Some(sig) = signer.sign(aa.get_agent_name(entry.span.agent), &msg) {
+                        let mapped_agent = agent_mapping.map(self, entry.span.agent);
+                        push_leb_usize(&mut signatures_chunk, mapped_agent as usize);
+                        push_leb_usize(&mut signatures_chunk, entry.span.seq_range.start);
+                        push_leb_usize(&mut signatures_chunk, entry.len());
+                        push_leb_usize(&mut signatures_chunk, sig.len());
+                        signatures_chunk.extend_from_slice(&sig);
+                    }
+                }
+            }
         }
 
         agent_assignment_writer.flush();
@@ -613,7 +698,7 @@ impl ListOpLog {
             if opts.store_start_branch_content {
                 let branch_here = ListBranch::new_at_local_version(self, from_version);
                 // dbg!(&branch_here);
-                write_content_rope(&mut start_branch, &branch_here.content.borrow(), compress_bytes.as_mut());
+                write_branch_content(&mut start_branch, &branch_here.content.borrow(), compress_bytes.as_mut(), cipher);
             }
         }
 
@@ -622,7 +707,7 @@ impl ListOpLog {
             write_local_version(&mut end_branch, self.cg.version.as_ref(), &mut agent_mapping, self);
 
             let branch_here = ListBranch::new_at_tip(self);
-            write_content_rope(&mut end_branch, &branch_here.content.borrow(), compress_bytes.as_mut());
+            write_branch_content(&mut end_branch, &branch_here.content.borrow(), compress_bytes.as_mut(), cipher);
 
             Some(end_branch)
         } else { None };
@@ -642,7 +727,8 @@ impl ListOpLog {
         }
 
         // agent names
-        push_leb_chunk(&mut fileinfo_buf, ListChunkType::AgentNames, &agent_mapping.consume());
+        let (agent_names, agent_names_chunk) = agent_mapping.consume();
+        push_leb_chunk(&mut fileinfo_buf, agent_names_chunk, &agent_names);
 
         // User data
         if let Some(data) = opts.user_data {
@@ -651,19 +737,22 @@ impl ListOpLog {
 
         // Bake inserted & deleted content. I need to do this here because the CompressedFields
         // chunk goes first in the file, so if we compress anything, it needs to be filled up.
+        //
+        // Encrypted content can't go in the compressed chunk, because that would leak plaintext.
+        let mut content_compress_bytes = if cipher.is_none() { compress_bytes.as_mut() } else { None };
         let inserted_content = inserted_content.and_then(|inserted_content| {
             if verbose {
                 println!("Inserted text length {}", inserted_content.content.len());
             }
 
-            inserted_content.flush(compress_bytes.as_mut())
+            inserted_content.flush(content_compress_bytes.as_deref_mut())
         });
         let deleted_content = deleted_content.and_then(|deleted_content| {
             if verbose {
                 println!("Deleted text length {}", deleted_content.content.len());
             }
 
-            deleted_content.flush(compress_bytes.as_mut())
+            deleted_content.flush(content_compress_bytes)
         });
 
 
@@ -713,16 +802,20 @@ impl ListOpLog {
         // I'll just assemble it in buf. There's a lot of sloppy use of vec<u8>'s in here.
         let mut patches_buf = fileinfo_buf;
 
-        if let Some(bytes) = inserted_content {
-            push_leb_chunk(&mut patches_buf, ListChunkType::PatchContent, &bytes);
-        }
-        if let Some(bytes) = deleted_content {
-            push_leb_chunk(&mut patches_buf, ListChunkType::PatchContent, &bytes);
+        for bytes in [inserted_content, deleted_content].into_iter().flatten() {
+            if let Some(cipher) = cipher {
+                push_leb_chunk(&mut patches_buf, ListChunkType::EncryptedPatchContent, &cipher.encrypt(&bytes));
+            } else {
+                push_leb_chunk(&mut patches_buf, ListChunkType::PatchContent, &bytes);
+            }
         }
 
         push_leb_chunk(&mut patches_buf, ListChunkType::OpVersions, &agent_assignment_chunk);
         push_leb_chunk(&mut patches_buf, ListChunkType::OpTypeAndPosition, &ops_chunk);
         push_leb_chunk(&mut patches_buf, ListChunkType::OpParents, &txns_chunk);
+        if signer.is_some() {
+            push_leb_chunk(&mut patches_buf, ListChunkType::OpSignatures, &signatures_chunk);
+        }
 
         write_chunk(ListChunkType::Patches, &mut patches_buf);
 
@@ -746,6 +839,16 @@ impl ListOpLog {
         self.encode_from(opts, &[])
     }
 
+    /// Encode all the operations a remote peer is missing, given a summary of the versions the
+    /// peer knows about.
+    ///
+    /// The peer's summary can name operations this oplog doesn't have. Those are ignored - the
+    /// resulting patch contains exactly the spans known locally which the peer lacks.
+    pub fn encode_for_summary(&self, summary: &VersionSummary, opts: EncodeOptions) -> Vec<u8> {
+        let (common, _) = self.cg.intersect_with_summary(summary, &[]);
+        self.encode_from(opts, common.as_ref())
+    }
+
     /// Encode the data stored in the OpLog into a (custom) compact binary form suitable for saving
     /// to disk, or sending over the network.
     pub fn encode_simple(&self, _opts: EncodeOptions) -> Vec<u8> {
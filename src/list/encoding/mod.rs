@@ -12,12 +12,18 @@ mod fuzzer;
 pub mod encode_tools;
 mod decode_tools;
 pub mod save_transformed;
+pub mod agent_table;
+pub mod cipher;
+pub mod signing;
 pub(crate) mod leb;
 
 use rle::MergableSpan;
 use crate::encoding::varint::*;
 use num_enum::TryFromPrimitive;
 pub use encode_oplog::{ENCODE_FULL, ENCODE_PATCH, EncodeOptions};
+pub use agent_table::AgentTable;
+pub use cipher::ContentCipher;
+pub use signing::{EntrySigner, EntryVerifier};
 
 const MAGIC_BYTES: [u8; 8] = *b"DMNDTYPS";
 
@@ -35,6 +41,8 @@ enum ListChunkType {
     DocId = 2,
     AgentNames = 3,
     UserData = 4,
+    /// Used instead of AgentNames when the file was encoded with an AgentTable.
+    IndexedAgentNames = 6,
 
     /// The StartBranch chunk describes the state of the document before included patches have been
     /// applied.
@@ -44,6 +52,8 @@ enum ListChunkType {
     /// StartBranch content is optional.
     Content = 13,
     ContentCompressed = 14, // Might make more sense to have a generic compression tag for chunks.
+    /// A Content chunk (including its header) encrypted with a ContentCipher.
+    EncryptedContent = 15,
 
     Patches = 20,
     OpVersions = 21,
@@ -53,8 +63,12 @@ enum ListChunkType {
     PatchContent = 24,
     /// ContentKnown is a RLE expressing which ranges of patches have known content
     ContentIsKnown = 25,
+    /// The body of a PatchContent chunk, encrypted with a ContentCipher.
+    EncryptedPatchContent = 26,
 
     TransformedPositions = 27, // Currently unused
+    /// Signatures for causal graph entries in the patch, made with an EntrySigner.
+    OpSignatures = 28,
 
     Crc = 100,
 }
@@ -0,0 +1,348 @@
+//! Signed operations.
+//!
+//! Anyone can write a patch claiming its operations were made by any agent. When patches arrive
+//! through a server, the server may want to check that each operation really was made by the agent
+//! it names - so one user can't forge edits by another, or reuse someone else's agent ID.
+//!
+//! Patches encoded with [`encode_from_signed`](crate::list::ListOpLog::encode_from_signed) contain
+//! an `OpSignatures` chunk with a signature for each causal graph entry the signer signed. Each
+//! signature covers the entry's [signing message](crate::causalgraph::entry::CGEntry::signing_message),
+//! which names the entry's agent, sequence numbers and parents, followed by each of the entry's
+//! operations (kind, position and inserted content). So a relay can't change what the operations
+//! do without invalidating the signature. Signature schemes hash the message they sign, so the
+//! message contains the operations directly rather than a hash of them.
+//!
+//! Entries can only be signed (and verified) if the content of their inserts is known.
+//!
+//! [`decode_and_add_verified`](crate::list::ListOpLog::decode_and_add_verified) checks every new
+//! operation in the patch with an [`EntryVerifier`]. If any entry is rejected, or any signature
+//! can't be checked, nothing in the patch is merged.
+//!
+//! Signatures aren't stored in the oplog. So only the author of a set of operations can send them
+//! signed - a peer which re-encodes operations it received from someone else sends them unsigned.
+
+use rle::HasLength;
+use smallvec::SmallVec;
+use crate::causalgraph::agent_span::AgentSpan;
+use crate::causalgraph::entry::CGEntry;
+use crate::encoding::parseerror::ParseError;
+use crate::list::encoding::decode_tools::BufReader;
+use crate::encoding::varint::push_usize;
+use crate::list::ListOpLog;
+use crate::list::operation::ListOpKind;
+use crate::rle::KVPair;
+use crate::{AgentId, DTRange};
+
+/// Signs causal graph entries when encoding a patch.
+pub trait EntrySigner {
+    /// Sign the message for an entry made by the named agent. Returns None if we can't sign for
+    /// this agent - for example because the operations were made by another user.
+    fn sign(&self, agent_name: &str, message: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Checks the signatures of causal graph entries when merging a patch.
+pub trait EntryVerifier {
+    /// Check an entry made by the named agent. `signature` is None if the patch doesn't contain a
+    /// signature for the entry. Returns true to accept the entry.
+    fn verify(&self, agent_name: &str, message: &[u8], signature: Option<&[u8]>) -> bool;
+}
+
+/// A signature read from an OpSignatures chunk. The agent is the local agent ID.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct SignatureRecord<'a> {
+    span: AgentSpan,
+    signature: &'a [u8],
+}
+
+impl<'a> BufReader<'a> {
+    /// Read the contents of an OpSignatures chunk. Each record contains (mapped agent, seq start,
+    /// length, signature length, signature).
+    pub(super) fn read_signatures(mut self, agent_map: &[(AgentId, usize)]) -> Result<Vec<SignatureRecord<'a>>, ParseError> {
+        let mut result = vec![];
+        while !self.is_empty() {
+            let mapped_agent = self.next_usize()?;
+            let start = self.next_usize()?;
+            let len = self.next_usize()?;
+            let sig_len = self.next_usize()?;
+            let signature = self.next_n_bytes(sig_len)?;
+
+            // Like everywhere else, mapped agent 0 is ROOT.
+            let agent = mapped_agent.checked_sub(1)
+                .and_then(|a| agent_map.get(a))
+                .ok_or(ParseError::InvalidLength)?.0;
+            let end = start.checked_add(len).ok_or(ParseError::InvalidLength)?;
+
+            result.push(SignatureRecord {
+                span: AgentSpan { agent, seq_range: (start..end).into() },
+                signature,
+            });
+        }
+        Ok(result)
+    }
+}
+
+impl ListOpLog {
+    /// The bytes an agent signs to prove it authored a causal graph entry. `lv_ranges` names the
+    /// entry's operations in sequence number order. (They might not be contiguous locally.)
+    ///
+    /// Each operation is written individually, so the message doesn't depend on how the
+    /// operations are run-length encoded. Returns None if we don't know the content of one of the
+    /// inserts.
+    pub(super) fn signing_message(&self, entry: &CGEntry, lv_ranges: &[DTRange]) -> Option<Vec<u8>> {
+        let mut msg = entry.signing_message(&self.cg.agent_assignment);
+        for &range in lv_ranges {
+            if range.start < self.ops_start() { return None; }
+
+            for KVPair(_, op) in self.operations.iter_range_ctx(range, &self.operation_ctx) {
+                let content = op.get_content(&self.operation_ctx);
+                let mut chars = content.map(|c| c.chars());
+                if op.kind == ListOpKind::Ins && content.is_none() { return None; }
+
+                for i in 0..op.len() {
+                    let pos = match (op.kind, op.loc.fwd) {
+                        (ListOpKind::Ins, true) => op.loc.span.start + i,
+                        (ListOpKind::Ins, false) => op.loc.span.start,
+                        (ListOpKind::Del, true) => op.loc.span.start,
+                        (ListOpKind::Del, false) => op.loc.span.end - i - 1,
+                    };
+                    push_usize(&mut msg, op.kind as usize);
+                    push_usize(&mut msg, pos);
+                    if op.kind == ListOpKind::Ins {
+                        let c = chars.as_mut().and_then(|c| c.next()).unwrap();
+                        push_usize(&mut msg, c as usize);
+                    }
+                }
+            }
+        }
+        Some(msg)
+    }
+
+    /// Check the signatures of the (newly merged) operations in `range` with the verifier.
+    ///
+    /// Signatures for operations we already had are ignored. Any operations in the range which
+    /// aren't covered by a signature are passed to the verifier unsigned. Signatures which don't
+    /// name a linear run of known operations are rejected with `InvalidSignature`.
+    pub(super) fn verify_signatures(&self, range: DTRange, signatures: &[SignatureRecord], verifier: &dyn EntryVerifier) -> Result<(), ParseError> {
+        let aa = &self.cg.agent_assignment;
+        let mut covered: Vec<DTRange> = vec![];
+
+        for record in signatures {
+            let span = record.span;
+            if span.seq_range.is_empty() { return Err(ParseError::InvalidSignature); }
+
+            // Find the local versions of the signed operations. The signature only names the
+            // first operation's parents, so the rest must be a linear run.
+            let client = &aa.client_data[span.agent as usize];
+            let mut lv_ranges: SmallVec<[DTRange; 2]> = SmallVec::new();
+            let mut parents = None;
+            let mut prev = None;
+            let mut seq = span.seq_range.start;
+            while seq < span.seq_range.end {
+                let lvs = client.try_seq_to_lv_span((seq..span.seq_range.end).into())
+                    .ok_or(ParseError::InvalidSignature)?;
+                for entry in self.cg.graph.iter_range(lvs) {
+                    match prev {
+                        None => { parents = Some(entry.parents); }
+                        Some(p) => if entry.parents.as_ref() != [p] {
+                            return Err(ParseError::InvalidSignature);
+                        }
+                    }
+                    prev = Some(entry.span.end - 1);
+                }
+                seq += lvs.len();
+                lv_ranges.push(lvs);
+            }
+
+            if lv_ranges.iter().all(|r| r.end <= range.start) { continue; }
+
+            let entry = CGEntry { start: lv_ranges[0].start, parents: parents.unwrap(), span };
+            let msg = self.signing_message(&entry, &lv_ranges)
+                .ok_or(ParseError::InvalidSignature)?;
+            if !verifier.verify(aa.get_agent_name(span.agent), &msg, Some(record.signature)) {
+                return Err(ParseError::SignatureRejected);
+            }
+            covered.extend(lv_ranges);
+        }
+
+        // Everything else is unsigned.
+        covered.sort_unstable_by_key(|r| r.start);
+        let mut next = range.start;
+        let check_unsigned = |r: DTRange| -> Result<(), ParseError> {
+            for entry in self.cg.iter_range(r) {
+                let msg = self.signing_message(&entry, &[entry.time_span()])
+                    .ok_or(ParseError::InvalidSignature)?;
+                if !verifier.verify(aa.get_agent_name(entry.span.agent), &msg, None) {
+                    return Err(ParseError::SignatureRejected);
+                }
+            }
+            Ok(())
+        };
+        for r in covered {
+            if r.start > next { check_unsigned((next..r.start).into())?; }
+            next = next.max(r.end);
+        }
+        if next < range.end { check_unsigned((next..range.end).into())?; }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use crate::causalgraph::agent_span::AgentSpan;
+    use crate::encoding::parseerror::ParseError;
+    use crate::list::encoding::signing::{EntrySigner, EntryVerifier, SignatureRecord};
+    use crate::list::encoding::ENCODE_PATCH;
+    use crate::list::ListOpLog;
+
+    /// A toy signature scheme for testing. Each agent's "key" is its name, and the signature is a
+    /// hash of the key and the message.
+    fn toy_signature(key: &str, message: &[u8]) -> Vec<u8> {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for &b in key.as_bytes().iter().chain(message) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash.to_le_bytes().to_vec()
+    }
+
+    /// Signs on behalf of a single agent.
+    struct Signer(&'static str);
+
+    impl EntrySigner for Signer {
+        fn sign(&self, agent_name: &str, message: &[u8]) -> Option<Vec<u8>> {
+            (agent_name == self.0).then(|| toy_signature(self.0, message))
+        }
+    }
+
+    /// Requires signatures from a list of known agents. Other agents may send unsigned operations.
+    struct Verifier(&'static [&'static str]);
+
+    impl EntryVerifier for Verifier {
+        fn verify(&self, agent_name: &str, message: &[u8], signature: Option<&[u8]>) -> bool {
+            if !self.0.contains(&agent_name) { return true; }
+            signature == Some(&toy_signature(agent_name, message))
+        }
+    }
+
+    #[test]
+    fn signed_patches_verify() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        let mike = a.get_or_create_agent_id("mike");
+        a.add_insert(seph, 0, "hi there");
+        let v = a.local_frontier();
+        a.add_insert_at(mike, &[], 0, "yo ");
+        a.add_delete_at(seph, v.as_ref(), 0..2);
+
+        // Signed by seph. Mike's operations are unsigned, which the verifier allows.
+        let patch = a.encode_from_signed(ENCODE_PATCH, &[], &Signer("seph"));
+        let mut server = ListOpLog::new();
+        server.decode_and_add_verified(&patch, &Verifier(&["seph"])).unwrap();
+        assert_eq!(server, a);
+
+        // Mike's operations need a signature from mike.
+        let mut server = ListOpLog::new();
+        assert_eq!(server.decode_and_add_verified(&patch, &Verifier(&["seph", "mike"])), Err(ParseError::SignatureRejected));
+        assert!(server.is_empty());
+
+        // Unsigned patches can still be merged without a verifier, and operations we already have
+        // aren't checked again.
+        server.decode_and_add(&a.encode(ENCODE_PATCH)).unwrap();
+        server.decode_and_add_verified(&a.encode(ENCODE_PATCH), &Verifier(&["seph", "mike"])).unwrap();
+        assert_eq!(server, a);
+    }
+
+    #[test]
+    fn forged_operations_are_rejected() {
+        let mut seph = ListOpLog::new();
+        seph.get_or_create_agent_id("seph");
+        seph.add_insert(0, 0, "hi");
+        let mut server = ListOpLog::new();
+        server.decode_and_add_verified(&seph.encode_from_signed(ENCODE_PATCH, &[], &Signer("seph")), &Verifier(&["seph"])).unwrap();
+
+        // Mallory makes changes using seph's agent ID. Mallory can't sign them as seph.
+        let mut mallory = server.clone();
+        let v = mallory.local_frontier();
+        mallory.add_insert(0, 2, "!!");
+        let forged = mallory.encode_from_signed(ENCODE_PATCH, v.as_ref(), &Signer("mallory"));
+        assert_eq!(server.decode_and_add_verified(&forged, &Verifier(&["seph"])), Err(ParseError::SignatureRejected));
+
+        // And signatures made with the wrong key don't verify.
+        struct WrongKey;
+        impl EntrySigner for WrongKey {
+            fn sign(&self, _agent_name: &str, message: &[u8]) -> Option<Vec<u8>> {
+                Some(toy_signature("mallory", message))
+            }
+        }
+        let forged = mallory.encode_from_signed(ENCODE_PATCH, v.as_ref(), &WrongKey);
+        assert_eq!(server.decode_and_add_verified(&forged, &Verifier(&["seph"])), Err(ParseError::SignatureRejected));
+        assert_eq!(server, seph);
+
+        // Seph's real changes are accepted.
+        seph.add_insert(0, 2, " there");
+        server.decode_and_add_verified(&seph.encode_from_signed(ENCODE_PATCH, v.as_ref(), &Signer("seph")), &Verifier(&["seph"])).unwrap();
+        assert_eq!(server.checkout_tip().content().to_string(), "hi there");
+    }
+
+    #[test]
+    fn changed_content_is_rejected() {
+        let mut seph = ListOpLog::new();
+        seph.get_or_create_agent_id("seph");
+        seph.add_insert(0, 0, "hi");
+
+        // Keep the signature seph makes for their insert.
+        struct Recorder(RefCell<Vec<u8>>);
+        impl EntrySigner for Recorder {
+            fn sign(&self, agent_name: &str, message: &[u8]) -> Option<Vec<u8>> {
+                let sig = toy_signature(agent_name, message);
+                *self.0.borrow_mut() = sig.clone();
+                Some(sig)
+            }
+        }
+        let recorder = Recorder(RefCell::new(vec![]));
+        let patch = seph.encode_from_signed(ENCODE_PATCH, &[], &recorder);
+
+        // A relay sends the same entry (same agent, seq and parents) with different content,
+        // reusing seph's signature.
+        struct Replay(Vec<u8>);
+        impl EntrySigner for Replay {
+            fn sign(&self, _agent_name: &str, _message: &[u8]) -> Option<Vec<u8>> {
+                Some(self.0.clone())
+            }
+        }
+        let mut relay = ListOpLog::new();
+        relay.get_or_create_agent_id("seph");
+        relay.add_insert(0, 0, "yo");
+        let forged = relay.encode_from_signed(ENCODE_PATCH, &[], &Replay(recorder.0.take()));
+
+        let mut server = ListOpLog::new();
+        assert_eq!(server.decode_and_add_verified(&forged, &Verifier(&["seph"])), Err(ParseError::SignatureRejected));
+        assert!(server.is_empty());
+        server.decode_and_add_verified(&patch, &Verifier(&["seph"])).unwrap();
+        assert_eq!(server, seph);
+    }
+
+    #[test]
+    fn unverifiable_signatures_are_rejected() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi");
+        let verifier = Verifier(&["seph"]);
+
+        let check = |seq_range: (usize, usize)| {
+            let record = SignatureRecord {
+                span: AgentSpan { agent: seph, seq_range: (seq_range.0..seq_range.1).into() },
+                signature: &[],
+            };
+            oplog.verify_signatures((0..2).into(), &[record], &verifier)
+        };
+        // Empty, unknown and partly unknown sequence ranges.
+        assert_eq!(check((1, 1)), Err(ParseError::InvalidSignature));
+        assert_eq!(check((5, 10)), Err(ParseError::InvalidSignature));
+        assert_eq!(check((0, 3)), Err(ParseError::InvalidSignature));
+        // And a known range with a bad signature.
+        assert_eq!(check((0, 2)), Err(ParseError::SignatureRejected));
+    }
+}
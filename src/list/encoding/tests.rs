@@ -93,10 +93,15 @@ fn merge_parts() {
 fn merge_future_patch_errors() {
     let oplog = simple_doc().oplog;
     let v = oplog.cg.version[0];
-    let bytes = oplog.encode_from(ENCODE_FULL, &[v-1]);
+    let bytes = oplog.encode_from(ENCODE_PATCH, &[v-1]);
 
     let err = ListOpLog::load_from(&bytes).unwrap_err();
     assert_eq!(err, ParseError::BaseVersionUnknown);
+
+    // But if the file contains the document at the start version, it loads as a pruned oplog.
+    let bytes = oplog.encode_from(ENCODE_FULL, &[v-1]);
+    let loaded = ListOpLog::load_from(&bytes).unwrap();
+    assert_eq!(loaded.checkout_tip().content(), oplog.checkout_tip().content());
 }
 
 // This test is ignored because it errors (arguably correctly) when reading the base version at
@@ -255,7 +260,7 @@ fn save_load_save_load() {
         user_data: None,
         store_start_branch_content: true,
         experimentally_store_end_branch_content: false,
-        store_inserted_content: false, // Need to say false here to avoid an assert for this.
+        store_inserted_content: true,
         store_deleted_content: true,
         compress_content: true,
         verbose: false
@@ -266,6 +271,60 @@ fn save_load_save_load() {
     assert_eq!(oplog2, oplog3);
 }
 
+#[test]
+fn content_free_oplogs_merge_and_rehydrate() {
+    const NO_CONTENT: EncodeOptions = EncodeOptions {
+        store_inserted_content: false,
+        ..ENCODE_PATCH
+    };
+
+    let mut a = ListOpLog::new();
+    a.get_or_create_agent_id("seph");
+    a.add_insert(0, 0, "hello world");
+    let mut b = ListOpLog::load_from(&a.encode(ENCODE_FULL)).unwrap();
+    // The relay only ever sees patches without inserted content.
+    let mut relay = ListOpLog::load_from(&a.encode(NO_CONTENT)).unwrap();
+
+    let v = a.local_frontier();
+    a.add_insert(0, 5, " there");
+    b.get_or_create_agent_id("mike");
+    b.add_insert(1, 0, "Oh, ");
+    b.add_delete_without_content(1, 4..5);
+
+    relay.decode_and_add(&a.encode_from(NO_CONTENT, v.as_ref())).unwrap();
+    relay.decode_and_add(&b.encode_from(NO_CONTENT, v.as_ref())).unwrap();
+    a.decode_and_add(&b.encode_from(ENCODE_PATCH, v.as_ref())).unwrap();
+
+    // The relay can still transform operations.
+    let strip = |oplog: &ListOpLog| -> Vec<_> {
+        oplog.iter_xf_operations()
+            .map(|(range, op)| (range, op.map(|op| (op.kind, op.loc))))
+            .collect()
+    };
+    assert_eq!(strip(&relay), strip(&a));
+    assert_eq!(relay.checkout_tip().len(), a.checkout_tip().len());
+
+    // Clients can load the relay's oplog, and fill in content from a peer's patch.
+    let relay_bytes = relay.encode(ENCODE_FULL);
+    let mut c = ListOpLog::load_from(&relay_bytes).unwrap();
+    c.decode_and_add(&b.encode_from(ENCODE_PATCH, v.as_ref())).unwrap();
+    let content = c.checkout_tip().content().to_string();
+    assert!(content.starts_with("Oh, "));
+    assert!(content.contains('\u{FFFD}'));
+
+    c.decode_and_add(&a.encode(ENCODE_PATCH)).unwrap();
+    assert_eq!(c, a);
+    assert_eq!(c.checkout_tip().content(), a.checkout_tip().content());
+
+    // Failing to merge a patch doesn't fill in any content.
+    let mut d = ListOpLog::load_from(&relay_bytes).unwrap();
+    let mut bytes = a.encode(ENCODE_PATCH);
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1; // Break the checksum.
+    assert!(d.decode_and_add(&bytes).is_err());
+    assert!(d.operation_ctx.ins_content.is_empty());
+}
+
 #[test]
 fn doc_id_preserved() {
     let mut oplog = simple_doc().oplog;
@@ -347,6 +406,34 @@ fn merge_patch_returns_correct_version() {
     assert!(local_frontier_eq(&version, oplog2.local_frontier_ref()));
 }
 
+#[test]
+fn encode_for_remote_summary() {
+    let mut a = simple_doc().oplog;
+    let base = a.local_frontier();
+    let mut b = a.clone();
+
+    // Both peers know operations the other doesn't.
+    a.add_insert(0, 0, "aaa");
+    b.get_or_create_agent_id("mike");
+    b.add_insert(1, 0, "bbb");
+    b.add_delete_without_content(1, 3..5);
+
+    // The patch only contains the insert b is missing.
+    let bytes = a.encode_for_summary(&b.cg.agent_assignment.summarize_versions(), ENCODE_PATCH);
+    assert_eq!(bytes, a.encode_from(ENCODE_PATCH, base.as_ref()));
+    b.decode_and_add(&bytes).unwrap();
+
+    let bytes = b.encode_for_summary(&a.cg.agent_assignment.summarize_versions(), ENCODE_PATCH);
+    a.decode_and_add(&bytes).unwrap();
+    assert_eq!(a, b);
+
+    // Now the peers are in sync, the patch is empty.
+    let bytes = a.encode_for_summary(&b.cg.agent_assignment.summarize_versions(), ENCODE_PATCH);
+    let len = b.len();
+    b.decode_and_add(&bytes).unwrap();
+    assert_eq!(b.len(), len);
+}
+
 #[test]
 fn merge_when_parents_unsorted() {
     let data: Vec<u8> = vec![68,77,78,68,84,89,80,83,0,1,224,1,3,221,1,12,52,111,114,55,75,56,78,112,52,109,122,113,12,90,77,80,70,45,69,49,95,116,114,114,74,12,68,80,84,95,104,99,107,75,121,55,102,77,12,82,56,108,87,77,99,112,54,76,68,99,83,12,53,98,78,79,116,82,85,56,120,88,113,83,12,100,85,101,81,83,77,66,54,122,45,72,115,12,50,105,105,80,104,101,116,101,85,107,57,49,12,108,65,71,75,68,90,68,53,108,111,99,75,12,78,113,55,109,65,70,55,104,67,56,52,122,12,116,51,113,52,84,101,121,73,76,85,54,53,12,120,95,120,51,68,95,105,109,81,100,78,115,12,102,120,103,87,90,100,82,111,105,108,73,99,12,115,87,67,73,67,97,78,100,68,65,77,86,12,110,100,56,118,55,74,79,45,114,81,122,45,12,110,85,69,75,69,73,53,81,49,49,45,83,12,120,97,55,121,102,81,88,98,45,120,54,87,12,85,116,82,100,98,71,117,106,57,49,98,49,10,7,12,2,0,0,13,1,4,20,157,2,24,182,1,0,13,174,1,4,120,100,102,120,120,102,100,115,49,120,120,121,122,113,119,101,114,115,100,102,115,100,115,100,97,115,100,115,100,115,100,115,100,97,115,100,97,115,100,113,119,101,119,113,101,119,113,119,107,106,107,106,107,106,107,107,106,107,106,107,108,106,108,107,106,108,107,106,108,107,106,101,101,114,108,106,107,114,101,108,107,116,101,114,116,101,111,114,106,116,111,105,101,106,114,116,111,105,119,106,100,97,98,99,49,49,49,57,49,98,115,110,102,103,104,102,100,103,104,100,102,103,104,100,103,104,100,102,103,104,100,102,103,104,100,107,106,102,108,107,115,100,106,102,108,115,59,107,106,107,108,106,59,107,106,107,106,107,106,59,107,106,108,59,107,106,59,107,108,106,107,106,108,25,2,219,2,21,44,2,3,4,1,6,4,8,1,10,1,12,10,14,1,16,1,18,1,20,4,22,4,24,18,26,99,28,58,30,4,28,1,30,1,32,3,34,2,32,1,34,23,32,39,22,31,81,175,1,21,177,2,239,4,77,169,3,223,6,107,33,79,9,0,26,47,3,0,19,3,18,42,177,1,187,2,43,23,19,211,1,1,1,8,3,10,4,1,8,2,6,8,1,8,22,4,39,96,100,4,142,143,169,235];
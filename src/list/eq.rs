@@ -34,13 +34,19 @@ impl PartialEq<Self> for ListOpLog {
         // This check isn't sufficient. We'll check the frontier entries more thoroughly below.
         if self.cg.version.len() != other.cg.version.len() { return false; }
 
+        // If either oplog has been pruned, we can only compare the document at the prune point and
+        // the operations stored after it. The oplogs might know about different sets of agents from
+        // before the prune point.
+        let is_pruned = !self.start_version.is_root() || !other.start_version.is_root();
+        if self.len() - self.ops_start() != other.len() - other.ops_start() { return false; }
+
         // [self.agent] => other.agent.
         let mut agent_a_to_b = Vec::new();
         for c in self.cg.agent_assignment.client_data.iter() {
             // If there's no corresponding client in other (and the agent is actually in use), the
             // oplogs don't match.
             let other_agent = if let Some(other_agent) = other.get_agent_id(&c.name) {
-                if other.cg.agent_assignment.client_data[other_agent as usize].get_next_seq() != c.get_next_seq() {
+                if !is_pruned && other.cg.agent_assignment.client_data[other_agent as usize].get_next_seq() != c.get_next_seq() {
                     // Make sure we have exactly the same number of edits for each agent.
                     return false;
                 }
@@ -48,7 +54,7 @@ impl PartialEq<Self> for ListOpLog {
                 other_agent
             } else {
                 #[allow(clippy::collapsible_else_if)]
-                if c.is_empty() {
+                if c.is_empty() || is_pruned {
                     AgentId::MAX // Just using this as a placeholder. Could use None but its awkward.
                 } else {
                     // Agent missing.
@@ -64,9 +70,23 @@ impl PartialEq<Self> for ListOpLog {
         let map_lv_to_other = |t: LV| -> Option<LV> {
             let mut av = self.lv_to_agent_version(t);
             av.0 = agent_a_to_b[av.0 as usize];
+            if av.0 == AgentId::MAX { return None; }
             other.try_crdt_id_to_time(av)
         };
 
+        // Check the pruned start branch.
+        if self.start_version.len() != other.start_version.len() { return false; }
+        for t in self.start_version.iter() {
+            if !map_lv_to_other(*t).is_some_and(|t| other.start_version.0.contains(&t)) {
+                if VERBOSE { println!("Oplogs were pruned at different versions"); }
+                return false;
+            }
+        }
+        if self.start_content != other.start_content {
+            if VERBOSE { println!("Oplogs were pruned with different content"); }
+            return false;
+        }
+
         // Check frontier contents. Note this is O(n^2) with the size of the respective frontiers.
         // Which should be fine in normal use, but its a DDOS risk.
         for t in self.cg.version.iter() {
@@ -92,10 +112,11 @@ impl PartialEq<Self> for ListOpLog {
 
         // Note this should be optimized if its going to be used for more than fuzz testing.
         // But this is pretty neat!
+        let range = (self.ops_start()..self.len()).into();
         for (mut op, mut txn, mut crdt_id) in rle_zip3(
             self.iter(),
-            self.iter_history(),
-            self.cg.agent_assignment.client_with_localtime.iter().map(|pair| pair.1)
+            self.iter_history_range(range),
+            self.cg.agent_assignment.client_with_localtime.iter_range(range).map(|pair| pair.1)
         ) {
 
             // println!("op {:?} txn {:?} crdt {:?}", op, txn, crdt_id);
@@ -0,0 +1,411 @@
+//! Line based three-way merging.
+//!
+//! Normally concurrent changes are merged character by character, and concurrent inserts at the
+//! same location are interleaved. For some documents (like source code) its better to treat
+//! overlapping changes as a conflict and ask the user to resolve it, like git does.
+//!
+//! [`ListOpLog::merge_lines`] does a classic diff3 merge of the document at two versions, using
+//! their common ancestor as the base. The result can be rendered with git style conflict markers.
+//! Once the user has resolved the conflicts, [`ListOpLog::commit_merged_text`] adds operations to
+//! the oplog which merge both versions and set the document to the resolved text.
+
+use std::ops::Range;
+use crate::list::ListOpLog;
+use crate::{AgentId, Frontier, LV};
+
+/// A section of the result of a [`LineMerge`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MergeHunk {
+    /// Text which merged cleanly.
+    Resolved(String),
+    /// Lines which were changed differently on both sides.
+    Conflict {
+        base: String,
+        ours: String,
+        theirs: String,
+    },
+}
+
+/// The result of a line based three-way merge. See [`ListOpLog::merge_lines`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LineMerge {
+    /// The common ancestor of the merged versions, which was used as the merge base.
+    pub base_version: Frontier,
+    pub hunks: Vec<MergeHunk>,
+}
+
+impl LineMerge {
+    pub fn has_conflicts(&self) -> bool {
+        self.hunks.iter().any(|h| matches!(h, MergeHunk::Conflict { .. }))
+    }
+
+    /// The merged text, if there were no conflicts.
+    pub fn resolved(&self) -> Option<String> {
+        if self.has_conflicts() { return None; }
+        Some(self.to_string_with_markers("", ""))
+    }
+
+    /// Render the merge result with git style conflict markers around each conflicting hunk. The
+    /// labels are written after the `<<<<<<<` and `>>>>>>>` markers.
+    pub fn to_string_with_markers(&self, ours_label: &str, theirs_label: &str) -> String {
+        fn push_lines(result: &mut String, s: &str) {
+            result.push_str(s);
+            if !s.is_empty() && !s.ends_with('\n') { result.push('\n'); }
+        }
+
+        let mut result = String::new();
+        for hunk in self.hunks.iter() {
+            match hunk {
+                MergeHunk::Resolved(s) => result.push_str(s),
+                MergeHunk::Conflict { ours, theirs, .. } => {
+                    if !result.is_empty() && !result.ends_with('\n') { result.push('\n'); }
+                    push_lines(&mut result, format!("<<<<<<< {ours_label}").trim_end());
+                    push_lines(&mut result, ours);
+                    result.push_str("=======\n");
+                    push_lines(&mut result, theirs);
+                    push_lines(&mut result, format!(">>>>>>> {theirs_label}").trim_end());
+                }
+            }
+        }
+        result
+    }
+}
+
+fn split_lines(s: &str) -> Vec<&str> {
+    s.split_inclusive('\n').collect()
+}
+
+fn common_prefix<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count()
+}
+
+fn common_suffix<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    a.iter().rev().zip(b.iter().rev()).take_while(|(x, y)| x == y).count()
+}
+
+/// Find the start of the middle snake of an optimal edit path from a to b. (The snake is the run
+/// of matching items crossed by the path halfway through its edits). This searches forwards from
+/// the start and backwards from the end at the same time, until the searches meet.
+///
+/// vf and vb are scratch space, indexed by diagonal (k) offset by half their length.
+fn middle_snake<T: PartialEq>(a: &[T], b: &[T], vf: &mut [isize], vb: &mut [isize]) -> Option<(usize, usize)> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let delta = n - m;
+    let odd = delta & 1 == 1;
+    let off = (vf.len() / 2) as isize;
+    let at = |k: isize| (k + off) as usize;
+
+    vf[at(1)] = 0;
+    vb[at(1)] = 0;
+    for d in 0..(n + m + 1) / 2 + 1 {
+        for k in (-d..=d).rev().step_by(2) {
+            let mut x = if k == -d || (k != d && vf[at(k - 1)] < vf[at(k + 1)]) {
+                vf[at(k + 1)]
+            } else {
+                vf[at(k - 1)] + 1
+            };
+            let y = x - k;
+            let (x0, y0) = (x, y);
+            if x < n && y < m {
+                x += common_prefix(&a[x as usize..], &b[y as usize..]) as isize;
+            }
+            vf[at(k)] = x;
+            if odd && (k - delta).abs() < d && x + vb[at(delta - k)] >= n {
+                return Some((x0 as usize, y0 as usize));
+            }
+        }
+
+        // x here counts items from the end.
+        for k in (-d..=d).rev().step_by(2) {
+            let mut x = if k == -d || (k != d && vb[at(k - 1)] < vb[at(k + 1)]) {
+                vb[at(k + 1)]
+            } else {
+                vb[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            if x < n && y < m {
+                let len = common_suffix(&a[..(n - x) as usize], &b[..(m - y) as usize]) as isize;
+                x += len;
+                y += len;
+            }
+            vb[at(k)] = x;
+            if !odd && (k - delta).abs() <= d && x + vf[at(delta - k)] >= n {
+                return Some(((n - x) as usize, (m - y) as usize));
+            }
+        }
+    }
+    None
+}
+
+/// Add the matches between a and b to result. a and b start at a_start and b_start in the
+/// original lists.
+fn push_matches<T: PartialEq>(a: &[T], b: &[T], a_start: usize, b_start: usize, vf: &mut [isize], vb: &mut [isize], result: &mut Vec<(usize, usize)>) {
+    // Trim the common prefix and suffix. This makes the common case of small edits fast.
+    let prefix = common_prefix(a, b);
+    result.extend((0..prefix).map(|i| (a_start + i, b_start + i)));
+    let suffix = common_suffix(&a[prefix..], &b[prefix..]);
+
+    let a_mid = &a[prefix..a.len() - suffix];
+    let b_mid = &b[prefix..b.len() - suffix];
+    let (a_start, b_start) = (a_start + prefix, b_start + prefix);
+
+    if !a_mid.is_empty() && !b_mid.is_empty() {
+        if let Some((x, y)) = middle_snake(a_mid, b_mid, vf, vb) {
+            push_matches(&a_mid[..x], &b_mid[..y], a_start, b_start, vf, vb, result);
+            push_matches(&a_mid[x..], &b_mid[y..], a_start + x, b_start + y, vf, vb, result);
+        }
+    }
+
+    result.extend((0..suffix).map(|i| (a_start + a_mid.len() + i, b_start + b_mid.len() + i)));
+}
+
+/// Find a longest common subsequence of a and b using Myers' diff algorithm. Returns the matching
+/// (a index, b index) pairs in order.
+///
+/// This uses the linear space variant, which splits the lists at the middle of the edit path and
+/// recurses. Memory use is O(N+M).
+fn diff_matches<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let max_d = (a.len() + b.len()).div_ceil(2) + 1;
+    let mut vf = vec![0isize; 2 * max_d + 1];
+    let mut vb = vec![0isize; 2 * max_d + 1];
+    let mut result = vec![];
+    push_matches(a, b, 0, 0, &mut vf, &mut vb, &mut result);
+    result
+}
+
+/// Find the regions which differ between a and b, as (a range, b range) pairs.
+fn diff_regions<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(Range<usize>, Range<usize>)> {
+    let mut result = vec![];
+    let (mut i, mut j) = (0, 0);
+    for (x, y) in diff_matches(a, b).into_iter().chain(std::iter::once((a.len(), b.len()))) {
+        if x > i || y > j { result.push((i..x, j..y)); }
+        i = x + 1;
+        j = y + 1;
+    }
+    result
+}
+
+fn three_way_merge(base: &str, ours: &str, theirs: &str) -> Vec<MergeHunk> {
+    let base_lines = split_lines(base);
+    let ours_lines = split_lines(ours);
+    let theirs_lines = split_lines(theirs);
+
+    let mut ours_match = vec![None; base_lines.len()];
+    for (b, o) in diff_matches(&base_lines, &ours_lines) { ours_match[b] = Some(o); }
+    let mut theirs_match = vec![None; base_lines.len()];
+    for (b, t) in diff_matches(&base_lines, &theirs_lines) { theirs_match[b] = Some(t); }
+
+    fn push_resolved(hunks: &mut Vec<MergeHunk>, lines: &[&str]) {
+        if lines.is_empty() { return; }
+        if let Some(MergeHunk::Resolved(s)) = hunks.last_mut() {
+            s.extend(lines.iter().copied());
+        } else {
+            hunks.push(MergeHunk::Resolved(lines.concat()));
+        }
+    }
+
+    let mut hunks: Vec<MergeHunk> = vec![];
+
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    loop {
+        // Lines which are unchanged on both sides.
+        let mut len = 0;
+        while b + len < base_lines.len()
+            && ours_match[b + len] == Some(o + len)
+            && theirs_match[b + len] == Some(t + len) {
+            len += 1;
+        }
+        if len > 0 {
+            push_resolved(&mut hunks, &base_lines[b..b + len]);
+            b += len;
+            o += len;
+            t += len;
+            continue;
+        }
+
+        // Find the end of the unstable chunk - the next base line which both sides kept.
+        let next_stable = (b..base_lines.len())
+            .find(|&i| ours_match[i].is_some() && theirs_match[i].is_some());
+        let (b_end, o_end, t_end) = match next_stable {
+            Some(i) => (i, ours_match[i].unwrap(), theirs_match[i].unwrap()),
+            None => (base_lines.len(), ours_lines.len(), theirs_lines.len()),
+        };
+
+        let base_chunk = &base_lines[b..b_end];
+        let ours_chunk = &ours_lines[o..o_end];
+        let theirs_chunk = &theirs_lines[t..t_end];
+
+        if ours_chunk == base_chunk || ours_chunk == theirs_chunk {
+            push_resolved(&mut hunks, theirs_chunk);
+        } else if theirs_chunk == base_chunk {
+            push_resolved(&mut hunks, ours_chunk);
+        } else {
+            hunks.push(MergeHunk::Conflict {
+                base: base_chunk.concat(),
+                ours: ours_chunk.concat(),
+                theirs: theirs_chunk.concat(),
+            });
+        }
+
+        if next_stable.is_none() { break; }
+        b = b_end;
+        o = o_end;
+        t = t_end;
+    }
+
+    hunks
+}
+
+impl ListOpLog {
+    /// Do a line based three-way merge between the document at versions `a` ("ours") and `b`
+    /// ("theirs"), using their common ancestor as the base. Lines changed differently on both
+    /// sides become conflicts, rather than being interleaved.
+    ///
+    /// This doesn't modify the oplog. Use [`commit_merged_text`](ListOpLog::commit_merged_text) to
+    /// save the resolved text.
+    ///
+    /// Panics if the common ancestor is from before the oplog was pruned.
+    pub fn merge_lines(&self, a: &[LV], b: &[LV]) -> LineMerge {
+        let base_version = self.cg.graph.find_conflicting(a, b, |_, _| {});
+
+        let base = self.checkout(base_version.as_ref()).content().to_string();
+        let ours = self.checkout(a).content().to_string();
+        let theirs = self.checkout(b).content().to_string();
+
+        LineMerge {
+            base_version,
+            hunks: three_way_merge(&base, &ours, &theirs),
+        }
+    }
+
+    /// Merge versions `a` and `b`, and replace the merged document's content with `text` (usually
+    /// the resolved result of [`merge_lines`](ListOpLog::merge_lines)). The new operations are
+    /// made by `agent`, and have both versions as parents.
+    ///
+    /// Returns the resulting version.
+    pub fn commit_merged_text(&mut self, agent: AgentId, a: &[LV], b: &[LV], text: &str) -> Frontier {
+        let version = self.cg.graph.find_dominators_2(a, b);
+        let mut branch = self.checkout(version.as_ref());
+        let current = branch.content().to_string();
+
+        let current_lines = split_lines(&current);
+        let new_lines = split_lines(text);
+
+        // Character offsets of the start of each line in the current document.
+        let mut line_pos = Vec::with_capacity(current_lines.len() + 1);
+        line_pos.push(0);
+        for line in current_lines.iter() {
+            line_pos.push(line_pos.last().unwrap() + line.chars().count());
+        }
+
+        // Apply the changes from the end of the document so positions stay valid.
+        for (old, new) in diff_regions(&current_lines, &new_lines).into_iter().rev() {
+            let pos = line_pos[old.start];
+            if !old.is_empty() {
+                branch.delete(self, agent, pos..line_pos[old.end]);
+            }
+            if !new.is_empty() {
+                branch.insert(self, agent, pos, &new_lines[new].concat());
+            }
+        }
+
+        branch.local_frontier()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use crate::list::line_merge::{diff_matches, MergeHunk};
+    use crate::list::ListOpLog;
+
+    fn lcs_len(a: &[u8], b: &[u8]) -> usize {
+        let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                table[i + 1][j + 1] = if a[i] == b[j] { table[i][j] + 1 } else { table[i][j + 1].max(table[i + 1][j]) };
+            }
+        }
+        table[a.len()][b.len()]
+    }
+
+    #[test]
+    fn diff_finds_lcs() {
+        let a: Vec<char> = "abcabba".chars().collect();
+        let b: Vec<char> = "cbabac".chars().collect();
+        let matches = diff_matches(&a, &b);
+        assert_eq!(matches.len(), 4);
+        for w in matches.windows(2) {
+            assert!(w[0].0 < w[1].0 && w[0].1 < w[1].1);
+        }
+        for &(x, y) in matches.iter() { assert_eq!(a[x], b[y]); }
+
+        assert_eq!(diff_matches::<char>(&[], &['a']), vec![]);
+        assert_eq!(diff_matches(&['a', 'b'], &['a', 'b']), vec![(0, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn diff_matches_random_lists() {
+        let mut rng = SmallRng::seed_from_u64(123);
+        for _ in 0..500 {
+            let a: Vec<u8> = (0..rng.gen_range(0..30)).map(|_| rng.gen_range(0..4)).collect();
+            let b: Vec<u8> = (0..rng.gen_range(0..30)).map(|_| rng.gen_range(0..4)).collect();
+            let matches = diff_matches(&a, &b);
+            assert_eq!(matches.len(), lcs_len(&a, &b));
+            for &(x, y) in &matches { assert_eq!(a[x], b[y]); }
+            for w in matches.windows(2) {
+                assert!(w[0].0 < w[1].0 && w[0].1 < w[1].1);
+            }
+        }
+    }
+
+    #[test]
+    fn diff_rewritten_file() {
+        let a: Vec<usize> = (0..2000).collect();
+        let b: Vec<usize> = (2000..4000).collect();
+        assert!(diff_matches(&a, &b).is_empty());
+    }
+
+    #[test]
+    fn clean_merge() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "a\nb\nc\n");
+        let a = oplog.add_insert_at(seph, &[base], 2, "B\n");
+        let b = oplog.add_insert_at(mike, &[base], 6, "C\n");
+
+        let result = oplog.merge_lines(&[a], &[b]);
+        assert_eq!(result.base_version.as_ref(), &[base]);
+        assert!(!result.has_conflicts());
+        assert_eq!(result.resolved().unwrap(), "a\nB\nb\nc\nC\n");
+    }
+
+    #[test]
+    fn conflicting_merge() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "fn main() {\n    hi();\n}\n");
+        let a = oplog.add_insert_at(seph, &[base], 16, "yo_");
+        let b = oplog.add_insert_at(mike, &[base], 16, "hey_");
+
+        let result = oplog.merge_lines(&[a], &[b]);
+        assert_eq!(result.hunks[1], MergeHunk::Conflict {
+            base: "    hi();\n".into(),
+            ours: "    yo_hi();\n".into(),
+            theirs: "    hey_hi();\n".into(),
+        });
+        assert_eq!(result.resolved(), None);
+        assert_eq!(result.to_string_with_markers("seph", "mike"),
+            "fn main() {\n<<<<<<< seph\n    yo_hi();\n=======\n    hey_hi();\n>>>>>>> mike\n}\n");
+
+        // Resolve the conflict and save it.
+        let v = oplog.commit_merged_text(seph, &[a], &[b], "fn main() {\n    yo_hey_hi();\n}\n");
+        assert_eq!(v, oplog.local_frontier());
+        assert_eq!(oplog.checkout_tip().content().to_string(), "fn main() {\n    yo_hey_hi();\n}\n");
+        let parents = oplog.cg.graph.find_dominators_2(&[a], &[b]);
+        assert!(oplog.cg.graph.frontier_contains_frontier(v.as_ref(), parents.as_ref()));
+    }
+}
@@ -1,10 +1,44 @@
+use std::ops::Range;
 use rle::HasLength;
 use crate::frontier::FrontierRef;
 use crate::list::{ListBranch, ListOpLog};
+use crate::list::op_metrics::ListOpMetrics;
 use crate::list::operation::{ListOpKind, TextOperation};
-use crate::listmerge::merge::{reverse_str, TransformedOpsIter};
+use crate::listmerge::merge::{reverse_str, TrackerCheckpoint, TransformedOpsIter, TransformedResult};
 use crate::listmerge::merge::TransformedResult::{BaseMoved, DeleteAlreadyHappened};
-use crate::{DTRange, LV};
+use crate::{AgentId, DTRange, Frontier, LV};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Checking out an oplog which doesn't know the content of some inserts fills them with this.
+pub(crate) const UNKNOWN_CHAR: char = '\u{FFFD}';
+
+/// A region of a merged document where concurrent inserts landed at the same location. Returned
+/// by [`ListBranch::merge_reporting_conflicts`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InsertConflict {
+    /// The range of the document (in characters) containing the newly merged inserts. If the
+    /// inserted text was deleted by a later change in the merge, this range is empty.
+    pub range: Range<usize>,
+    /// The (local version) spans of all the inserts involved - including inserts which were
+    /// already in the branch. Sorted and non-overlapping.
+    pub spans: Vec<DTRange>,
+    /// The agents which made the inserts. Use [`ListOpLog::get_agent_name`] to get their names.
+    pub authors: Vec<AgentId>,
+}
+
+fn transform_range_ins(range: Range<usize>, pos: usize, len: usize) -> Range<usize> {
+    if pos <= range.start { range.start + len..range.end + len }
+    else if pos < range.end { range.start..range.end + len }
+    else { range }
+}
+
+fn transform_range_del(range: Range<usize>, pos: usize, len: usize) -> Range<usize> {
+    let xf = |p: usize| if p >= pos + len { p - len } else { p.min(pos) };
+    xf(range.start)..xf(range.end)
+}
 
 impl ListOpLog {
     pub(crate) fn get_xf_operations_full(&self, from: FrontierRef, merging: FrontierRef) -> TransformedOpsIter {
@@ -14,6 +48,25 @@ impl ListOpLog {
         // TransformedOpsIter::new(self, from, merging)
     }
 
+    pub(crate) fn get_xf_operations_checkpointed(&self, from: FrontierRef, merging: FrontierRef, checkpoint: Option<TrackerCheckpoint>) -> TransformedOpsIter<'_> {
+        TransformedOpsIter::new_with_checkpoint(&self.cg.graph, &self.cg.agent_assignment,
+                                                &self.operation_ctx, &self.operations,
+                                                from, merging, checkpoint)
+    }
+
+    fn xf_to_text_op(&self, (lv, mut origin_op, xf): (LV, ListOpMetrics, TransformedResult)) -> (DTRange, Option<TextOperation>) {
+        let len = origin_op.len();
+        let op: Option<TextOperation> = match xf {
+            BaseMoved(base) => {
+                origin_op.loc.span = (base..base+len).into();
+                let content = origin_op.get_content(&self.operation_ctx);
+                Some((origin_op, content).into())
+            }
+            DeleteAlreadyHappened => None,
+        };
+        ((lv..lv +len).into(), op)
+    }
+
     /// Iterate through all the *transformed* operations from some point in time. Internally, the
     /// OpLog stores all changes as they were when they were created. This makes a lot of sense from
     /// CRDT academic point of view (and makes signatures and all that easy). But its is rarely
@@ -21,20 +74,44 @@ impl ListOpLog {
     ///
     /// `get_xf_operations` returns an iterator over the *transformed changes*. That is, the set of
     /// changes that could be applied linearly to a document to bring it up to date.
+    ///
+    /// If the oplog has been pruned, `from` and the merged version must each be ROOT or contain the
+    /// prune point. This panics otherwise. Transforming from ROOT starts with a single insert
+    /// containing the pruned snapshot.
     pub fn iter_xf_operations_from(&self, from: FrontierRef, merging: FrontierRef) -> impl Iterator<Item=(DTRange, Option<TextOperation>)> + '_ {
-        self.get_xf_operations_full(from, merging)
-            .map(|(lv, mut origin_op, xf)| {
-                let len = origin_op.len();
-                let op: Option<TextOperation> = match xf {
-                    BaseMoved(base) => {
-                        origin_op.loc.span = (base..base+len).into();
-                        let content = origin_op.get_content(&self.operation_ctx);
-                        Some((origin_op, content).into())
-                    }
-                    DeleteAlreadyHappened => None,
-                };
-                ((lv..lv +len).into(), op)
-            })
+        let (from, snapshot) = self.xf_start(from, merging);
+        snapshot.into_iter().chain(self.get_xf_operations_full(from.as_ref(), merging)
+            .map(|triple| self.xf_to_text_op(triple)))
+    }
+
+    /// We don't have the operations from before the prune point, so they can't be transformed.
+    /// Transforming from ROOT in a pruned oplog starts at the prune point instead, and returns the
+    /// snapshot as a single insert.
+    fn xf_start(&self, from: FrontierRef, merging: FrontierRef) -> (Frontier, Option<(DTRange, Option<TextOperation>)>) {
+        if self.start_version.is_root() { return (from.into(), None); }
+
+        self.assert_not_before_prune(from);
+        self.assert_not_before_prune(self.cg.graph.find_dominators_2(from, merging).as_ref());
+        if !from.is_empty() || merging.is_empty() { return (from.into(), None); }
+
+        let snapshot = if self.start_content.len_chars() > 0 {
+            let content = self.start_content.to_string();
+            Some(((0..self.ops_start()).into(), Some(TextOperation::new_insert(0, &content))))
+        } else { None };
+        (self.start_version.clone(), snapshot)
+    }
+
+    /// Like [`iter_xf_operations_from`](ListOpLog::iter_xf_operations_from), but resumes from a
+    /// checkpoint left by an earlier call (if its usable). The checkpoint is replaced with a new
+    /// checkpoint after the returned operations.
+    ///
+    /// This is much faster when this is called repeatedly from nearby versions.
+    pub fn xf_operations_from_checkpoint(&self, from: FrontierRef, merging: FrontierRef, checkpoint: &mut Option<TrackerCheckpoint>) -> Vec<(DTRange, Option<TextOperation>)> {
+        let (from, snapshot) = self.xf_start(from, merging);
+        let mut iter = self.get_xf_operations_checkpointed(from.as_ref(), merging, checkpoint.take());
+        let result = snapshot.into_iter().chain((&mut iter).map(|triple| self.xf_to_text_op(triple))).collect();
+        *checkpoint = iter.into_frontier_and_checkpoint().1;
+        result
     }
 
     /// Get all transformed operations from the start of time.
@@ -60,23 +137,67 @@ impl ListOpLog {
 
 impl ListBranch {
     /// Add everything in merge_frontier into the set..
+    ///
+    /// If the oplog has been pruned, the merged version must contain the version history was
+    /// pruned at. This panics otherwise.
     pub fn merge(&mut self, oplog: &ListOpLog, merge_frontier: &[LV]) {
-        let mut iter = oplog.get_xf_operations_full(self.version.as_ref(), merge_frontier);
+        // listmerge2 merge plans start at the last version shared by both sides. If the oplog was
+        // pruned at a single version, that version is never before the prune point.
+        #[cfg(feature = "listmerge2")]
+        if oplog.start_version.len() <= 1 {
+            self.merge_with_plan(oplog, merge_frontier);
+            return;
+        }
+
+        self.merge_with_xf_ops(oplog, merge_frontier);
+    }
+
+    /// Merge using the transformed operations from listmerge. This is the default merge engine.
+    pub(crate) fn merge_with_xf_ops(&mut self, oplog: &ListOpLog, merge_frontier: &[LV]) {
+        self.merge_xf_visiting(oplog, merge_frontier, None, false, |_, _, _, _| {});
+    }
+
+    /// Merge the named changes into this branch, like [`merge`](ListBranch::merge). The merge
+    /// resumes from the checkpoint if its usable, and the checkpoint is replaced with a checkpoint
+    /// after this merge.
+    ///
+    /// When a branch repeatedly merges changes from a long lived concurrent branch, this avoids
+    /// replaying all the concurrent operations each time.
+    pub fn merge_with_checkpoint(&mut self, oplog: &ListOpLog, merge_frontier: &[LV], checkpoint: &mut Option<TrackerCheckpoint>) {
+        *checkpoint = self.merge_xf_visiting(oplog, merge_frontier, checkpoint.take(), false, |_, _, _, _| {});
+    }
+
+    /// Merge using transformed operations. `visit` is called with each operation after it has been
+    /// applied to the branch, along with the position it was applied at. If `report_collisions` is
+    /// set, `visit` can read insert collisions from the iterator.
+    fn merge_xf_visiting<F>(&mut self, oplog: &ListOpLog, merge_frontier: &[LV], checkpoint: Option<TrackerCheckpoint>, report_collisions: bool, mut visit: F) -> Option<TrackerCheckpoint>
+        where F: FnMut(&mut TransformedOpsIter, LV, &ListOpMetrics, usize)
+    {
+        self.reset_if_before_prune(oplog, merge_frontier);
 
-        for (_lv, origin_op, xf) in &mut iter {
+        let mut iter = oplog.get_xf_operations_checkpointed(self.version.as_ref(), merge_frontier, checkpoint);
+        if report_collisions { iter.report_insert_collisions(); }
+
+        while let Some((lv, origin_op, xf)) = iter.next() {
             match (origin_op.kind, xf) {
                 (ListOpKind::Ins, BaseMoved(pos)) => {
                     // println!("Insert '{}' at {} (len {})", op.content, ins_pos, op.len());
-                    debug_assert!(origin_op.content_pos.is_some()); // Ok if this is false - we'll just fill with junk.
-                    let content = origin_op.get_content(&oplog.operation_ctx).unwrap();
                     assert!(pos <= self.content.len_chars());
-                    if origin_op.loc.fwd {
-                        self.content.insert(pos, content);
+                    if let Some(content) = origin_op.get_content(&oplog.operation_ctx) {
+                        if origin_op.loc.fwd {
+                            self.content.insert(pos, content);
+                        } else {
+                            // We need to insert the content in reverse order.
+                            let c = reverse_str(content);
+                            self.content.insert(pos, &c);
+                        }
                     } else {
-                        // We need to insert the content in reverse order.
-                        let c = reverse_str(content);
-                        self.content.insert(pos, &c);
+                        // The oplog doesn't know what was inserted. Fill with junk so the document
+                        // length (and the position of everything else) is still correct.
+                        let junk: String = std::iter::repeat_n(UNKNOWN_CHAR, origin_op.len()).collect();
+                        self.content.insert(pos, &junk);
                     }
+                    visit(&mut iter, lv, &origin_op, pos);
                 }
 
                 (_, DeleteAlreadyHappened) => {}, // Discard.
@@ -86,12 +207,353 @@ impl ListBranch {
                     debug_assert!(self.content.len_chars() >= del_end);
                     // println!("Delete {}..{} (len {}) '{}'", del_start, del_end, mut_len, to.content.slice_chars(del_start..del_end).collect::<String>());
                     self.content.remove(pos..del_end);
+                    visit(&mut iter, lv, &origin_op, pos);
                 }
             }
         }
 
         // dbg!(iter.count_range_tracker_size());
-        self.version = iter.into_frontier();
+        let (version, checkpoint) = iter.into_frontier_and_checkpoint();
+        self.version = version;
+        checkpoint
     }
 
-}
\ No newline at end of file
+    /// Merge the named changes into this branch, like [`merge`](ListBranch::merge). This also
+    /// returns a list of the places where newly merged inserts landed at the same location as
+    /// concurrent inserts. The order of the concurrent text in these regions is arbitrary (but
+    /// consistent on all peers), so editors may want to ask the user to review them.
+    ///
+    /// The returned conflicts are sorted by their position in the resulting document.
+    pub fn merge_reporting_conflicts(&mut self, oplog: &ListOpLog, merge_frontier: &[LV]) -> Vec<InsertConflict> {
+        let mut conflicts: Vec<InsertConflict> = vec![];
+
+        self.merge_xf_visiting(oplog, merge_frontier, None, true, |iter, lv, op, pos| {
+            let len = op.len();
+
+            // Move the conflicts we've already found.
+            for c in conflicts.iter_mut() {
+                c.range = match op.kind {
+                    ListOpKind::Ins => transform_range_ins(c.range.clone(), pos, len),
+                    ListOpKind::Del => transform_range_del(c.range.clone(), pos, len),
+                };
+            }
+
+            if op.kind == ListOpKind::Ins {
+                let lv_range: DTRange = (lv..lv + len).into();
+                let spans: Vec<DTRange> = iter.take_insert_collisions().into_iter()
+                    // Collisions between operations which were already in the branch have already
+                    // been merged.
+                    .filter(|(item, _)| lv_range.contains(item.start))
+                    .flat_map(|(item, other)| [item, other])
+                    .collect();
+
+                if !spans.is_empty() {
+                    conflicts.push(InsertConflict { range: pos..pos + len, spans, authors: vec![] });
+                }
+            }
+        });
+
+        // Combine conflicts which touch in the document.
+        conflicts.sort_unstable_by_key(|c| c.range.start);
+        let mut result: Vec<InsertConflict> = vec![];
+        for c in conflicts {
+            match result.last_mut() {
+                Some(last) if last.range.end >= c.range.start => {
+                    last.range.end = last.range.end.max(c.range.end);
+                    last.spans.extend(c.spans);
+                }
+                _ => result.push(c),
+            }
+        }
+
+        let aa = &oplog.cg.agent_assignment;
+        for c in result.iter_mut() {
+            c.spans.sort_unstable_by_key(|r| r.start);
+            let mut spans: Vec<DTRange> = vec![];
+            for r in c.spans.drain(..) {
+                match spans.last_mut() {
+                    Some(last) if last.end >= r.start => { last.end = last.end.max(r.end); }
+                    _ => spans.push(r),
+                }
+            }
+
+            for &r in spans.iter() {
+                let mut r = r;
+                while !r.is_empty() {
+                    let agent_span = aa.local_span_to_agent_span(r);
+                    c.authors.push(agent_span.agent);
+                    r.start += agent_span.len();
+                }
+            }
+            c.authors.sort_unstable();
+            c.authors.dedup();
+            c.spans = spans;
+        }
+
+        result
+    }
+
+    /// After pruning, every operation is either in the history of the prune point or has the
+    /// prune point in its history. So a branch which doesn't contain the prune point (like a new
+    /// branch at ROOT) is at an earlier version, and we can start from the snapshot instead.
+    fn reset_if_before_prune(&mut self, oplog: &ListOpLog, merge_frontier: &[LV]) {
+        if oplog.start_version.is_root() { return; }
+
+        let version = oplog.cg.graph.find_dominators_2(self.version.as_ref(), merge_frontier);
+        oplog.assert_not_before_prune(version.as_ref());
+        if !oplog.cg.graph.frontier_contains_frontier(self.version.as_ref(), oplog.start_version.as_ref())
+            && version != self.version {
+            *self = oplog.start_branch();
+        }
+    }
+
+    /// Merge using the listmerge2 engine. This runs a merge plan for the operations since the last
+    /// version shared by the branch and merge_frontier, and applies the result to the branch.
+    pub(crate) fn merge_with_plan(&mut self, oplog: &ListOpLog, merge_frontier: &[LV]) {
+        self.reset_if_before_prune(oplog, merge_frontier);
+
+        let version = oplog.cg.graph.find_dominators_2(self.version.as_ref(), merge_frontier);
+        if version == self.version { return; }
+
+        oplog.merge_content_with_plan(&mut self.content, self.version.as_ref(), merge_frontier);
+        self.version = version;
+    }
+
+}
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use crate::list::{InsertConflict, ListBranch, ListOpLog, TrackerCheckpoint};
+    use crate::AgentId;
+    use crate::list_fuzzer_tools::{random_oplog, random_str, random_version};
+
+    #[test]
+    fn reports_concurrent_inserts() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let a = oplog.add_insert_at(seph, &[], 0, "aaa");
+        let b = oplog.add_insert_at(mike, &[], 0, "bbb");
+
+        let mut branch = ListBranch::new_at_local_version(&oplog, &[a]);
+        let conflicts = branch.merge_reporting_conflicts(&oplog, &[b]);
+        assert_eq!(branch.content().to_string(), "bbbaaa");
+        assert_eq!(conflicts, vec![InsertConflict {
+            range: 0..3,
+            spans: vec![(0..6).into()],
+            authors: vec![seph, mike],
+        }]);
+
+        // Merging again finds nothing new.
+        assert!(branch.merge_reporting_conflicts(&oplog, &[a, b]).is_empty());
+    }
+
+    #[test]
+    fn conflicts_move_with_later_changes() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "hi");
+        let a = oplog.add_insert_at(seph, &[base], 0, "AA");
+        let b = oplog.add_insert_at(mike, &[base], 0, "BB");
+        let b = oplog.add_insert_at(mike, &[b], 0, "x");
+        // This edit doesn't conflict with anything.
+        oplog.add_insert_at(mike, &[b], 5, "!");
+
+        let mut branch = ListBranch::new_at_local_version(&oplog, &[a]);
+        let conflicts = branch.merge_reporting_conflicts(&oplog, oplog.local_frontier_ref());
+        assert_eq!(branch.content().to_string(), "xBBAAhi!");
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].range, 1..3);
+        assert_eq!(conflicts[0].authors, vec![seph, mike]);
+
+        // Merging both sides at once finds the same conflict. This time both inserts are new.
+        let mut branch = ListBranch::new_at_local_version(&oplog, &[base]);
+        let conflicts = branch.merge_reporting_conflicts(&oplog, oplog.local_frontier_ref());
+        assert_eq!(branch.content().to_string(), "xBBAAhi!");
+        assert_eq!(conflicts.len(), 1);
+
+        // Edits in different places don't conflict.
+        let mut oplog = ListOpLog::new();
+        oplog.get_or_create_agent_id("seph");
+        oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(0, 0, "hi there");
+        let a = oplog.add_insert_at(0, &[base], 0, "A");
+        let b = oplog.add_insert_at(1, &[base], 8, "B");
+        let mut branch = ListBranch::new_at_local_version(&oplog, &[a]);
+        assert!(branch.merge_reporting_conflicts(&oplog, &[b]).is_empty());
+        assert_eq!(branch.content().to_string(), "Ahi thereB");
+    }
+
+    #[test]
+    fn checkpoints_resume_merges() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let mut a = ListBranch::new();
+        let mut b = ListBranch::new();
+        let mut checkpoint = None;
+
+        // Two long lived branches. a keeps merging b's changes.
+        for i in 0..20 {
+            a.insert(&mut oplog, seph, i % (a.len() + 1), "a");
+            b.insert(&mut oplog, mike, 0, "bb");
+            if i % 3 == 0 { b.delete(&mut oplog, mike, 1..2); }
+
+            a.merge_with_checkpoint(&oplog, b.local_frontier_ref(), &mut checkpoint);
+            let expected = oplog.checkout(a.local_frontier_ref());
+            assert_eq!(a.content(), expected.content());
+        }
+        assert!(checkpoint.is_some());
+
+        let mut c = ListBranch::new();
+        let mut checkpoint = None;
+        let ops = oplog.xf_operations_from_checkpoint(&[], oplog.local_frontier_ref(), &mut checkpoint);
+        assert_eq!(ops, oplog.iter_xf_operations().collect::<Vec<_>>());
+        c.merge_with_checkpoint(&oplog, oplog.local_frontier_ref(), &mut checkpoint);
+        assert_eq!(c.content(), oplog.checkout_tip().content());
+    }
+
+    #[test]
+    fn encoded_checkpoints_resume_merges() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let mut a = ListBranch::new();
+        let mut b = ListBranch::new();
+        let mut saved: Option<Vec<u8>> = None;
+
+        for i in 0..20 {
+            a.insert(&mut oplog, seph, i % (a.len() + 1), "a");
+            b.insert(&mut oplog, mike, 0, "bb");
+            if i % 3 == 0 { b.delete(&mut oplog, mike, 1..2); }
+
+            let mut checkpoint = saved.as_ref().map(|data| TrackerCheckpoint::decode(data).unwrap());
+            a.merge_with_checkpoint(&oplog, b.local_frontier_ref(), &mut checkpoint);
+            saved = checkpoint.map(|cp| cp.encode());
+            assert_eq!(a.content(), oplog.checkout(a.local_frontier_ref()).content());
+        }
+
+        // Truncated checkpoints are rejected.
+        let data = saved.unwrap();
+        for len in 0..data.len() {
+            assert!(TrackerCheckpoint::decode(&data[..len]).is_err());
+        }
+    }
+
+    fn fuzz_checkpoints(seed: u64) {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let oplog = random_oplog(seed, 100);
+
+        let mut branch = ListBranch::new();
+        let mut checkpoint: Option<TrackerCheckpoint> = None;
+        for _ in 0..10 {
+            // Sometimes start again from an unrelated version, to check unusable checkpoints are
+            // ignored.
+            if rng.gen_bool(0.2) {
+                branch = oplog.checkout(random_version(&oplog, &mut rng).as_ref());
+            }
+
+            // Sometimes save and reload the checkpoint.
+            if rng.gen_bool(0.5) {
+                checkpoint = checkpoint.map(|cp| TrackerCheckpoint::decode(&cp.encode()).unwrap());
+            }
+
+            let merging = random_version(&oplog, &mut rng);
+            let mut expected = branch.clone();
+            expected.merge_with_xf_ops(&oplog, merging.as_ref());
+            branch.merge_with_checkpoint(&oplog, merging.as_ref(), &mut checkpoint);
+
+            assert_eq!(branch.content(), expected.content());
+            assert_eq!(branch.local_frontier_ref(), expected.local_frontier_ref());
+        }
+    }
+
+    #[test]
+    fn fuzz_checkpoints_once() {
+        for seed in 0..30 {
+            fuzz_checkpoints(seed);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn fuzz_checkpoints_forever() {
+        for seed in 0.. {
+            if seed % 100 == 0 { println!("Iteration {}", seed); }
+            fuzz_checkpoints(seed);
+        }
+    }
+
+    fn random_edits(branch: &mut ListBranch, oplog: &mut ListOpLog, agent: AgentId, rng: &mut SmallRng) {
+        for _ in 0..rng.gen_range(1..5) {
+            let len = branch.len();
+            if len > 0 && rng.gen_bool(0.3) {
+                let start = rng.gen_range(0..len);
+                let end = rng.gen_range(start + 1..=len.min(start + 3));
+                branch.delete(oplog, agent, start..end);
+            } else {
+                let content = random_str(rng.gen_range(1..4), rng, false);
+                branch.insert(oplog, agent, rng.gen_range(0..=len), &content);
+            }
+        }
+    }
+
+    fn fuzz_checkpoints_concurrent_branch(seed: u64) {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut oplog = ListOpLog::new();
+        let agents: Vec<AgentId> = ["seph", "mike", "kaarina", "joseph"].iter()
+            .map(|name| oplog.get_or_create_agent_id(name))
+            .collect();
+        let (seph, mike, kaarina, joseph) = (agents[0], agents[1], agents[2], agents[3]);
+
+        let mut trunk = ListBranch::new();
+        random_edits(&mut trunk, &mut oplog, seph, &mut rng);
+        let mut side = trunk.clone();
+        let mut other = trunk.clone();
+        random_edits(&mut trunk, &mut oplog, seph, &mut rng);
+        random_edits(&mut other, &mut oplog, mike, &mut rng);
+
+        // The side branch forks from one head of the checkpoint's base, so its operations are
+        // concurrent with the other head. Sometimes its made from before the fork.
+        if rng.gen_bool(0.5) { side = trunk.clone(); }
+        if rng.gen_bool(0.5) { random_edits(&mut side, &mut oplog, joseph, &mut rng); }
+
+        trunk.merge(&oplog, other.local_frontier_ref());
+        let mut branch = trunk.clone();
+        random_edits(&mut trunk, &mut oplog, seph, &mut rng);
+        random_edits(&mut branch, &mut oplog, kaarina, &mut rng);
+
+        let mut checkpoint: Option<TrackerCheckpoint> = None;
+        branch.merge_with_checkpoint(&oplog, trunk.local_frontier_ref(), &mut checkpoint);
+        assert!(checkpoint.is_some());
+
+        for _ in 0..3 {
+            random_edits(&mut side, &mut oplog, joseph, &mut rng);
+            random_edits(&mut trunk, &mut oplog, seph, &mut rng);
+
+            if rng.gen_bool(0.5) {
+                checkpoint = checkpoint.map(|cp| TrackerCheckpoint::decode(&cp.encode()).unwrap());
+            }
+
+            let merging = if rng.gen_bool(0.5) {
+                side.local_frontier()
+            } else {
+                oplog.cg.graph.version_union(side.local_frontier_ref(), trunk.local_frontier_ref())
+            };
+            let mut expected = branch.clone();
+            expected.merge_with_xf_ops(&oplog, merging.as_ref());
+            branch.merge_with_checkpoint(&oplog, merging.as_ref(), &mut checkpoint);
+
+            assert_eq!(branch.content(), expected.content());
+            assert_eq!(branch.local_frontier_ref(), expected.local_frontier_ref());
+        }
+    }
+
+    #[test]
+    fn fuzz_checkpoints_concurrent_branch_once() {
+        for seed in 0..30 {
+            fuzz_checkpoints_concurrent_branch(seed);
+        }
+    }
+}
@@ -13,6 +13,10 @@ use crate::{CausalGraph, Frontier};
 use crate::rle::{KVPair, RleVec};
 
 pub mod operation;
+pub mod anchor;
+pub mod sync;
+pub mod line_merge;
+pub mod undo;
 mod list;
 mod check;
 pub(crate) mod op_iter;
@@ -32,7 +36,10 @@ mod oplog_merge_fuzzer;
 
 pub(crate) mod buffered_iter;
 mod stochastic_summary;
-mod merge;
+mod prune;
+pub(crate) mod merge;
+pub use merge::InsertConflict;
+pub use crate::listmerge::merge::TrackerCheckpoint;
 
 #[cfg(feature = "gen_test_data")]
 mod gen_random;
@@ -121,6 +128,14 @@ pub struct ListOpLog {
     // TODO: Replace me with a compact form of this data.
     pub(crate) operations: RleVec<KVPair<ListOpMetrics>>,
 
+    /// The version history was pruned at (see [`prune_before`](ListOpLog::prune_before)).
+    /// Operations in the history of this version are not stored. If the oplog has never been
+    /// pruned, this is ROOT.
+    pub(crate) start_version: Frontier,
+
+    /// The document's content at start_version.
+    pub(crate) start_content: jumprope::JumpRope,
+
     // /// This is the LocalVersion for the entire oplog. So, if you merged every change we store into
     // /// a branch, this is the version of that branch.
     // ///
@@ -171,7 +171,7 @@ impl ListOpLog {
     }
 
     pub(crate) fn iter_fast(&self) -> OpMetricsWithContent {
-        OpMetricsWithContent::new(self, (0..self.len()).into())
+        OpMetricsWithContent::new(self, (self.ops_start()..self.len()).into())
     }
 
     pub fn iter(&self) -> impl Iterator<Item=TextOperation> + '_ {
@@ -1,5 +1,6 @@
 use std::ops::Range;
-use rle::{HasLength, SplitableSpan};
+use jumprope::JumpRope;
+use rle::{HasLength, SplitableSpan, SplitableSpanCtx};
 use crate::{AgentId, Frontier, LV};
 use crate::list::{ListBranch, ListOpLog};
 use crate::causalgraph::graph::GraphEntrySimple;
@@ -10,7 +11,7 @@ use crate::dtrange::DTRange;
 use crate::causalgraph::agent_span::*;
 use crate::rev_range::RangeRev;
 use crate::rle::KVPair;
-use crate::unicount::{chars_to_bytes, count_chars};
+use crate::unicount::{chars_to_bytes, consume_chars, count_chars};
 
 impl Default for ListOpLog {
     fn default() -> Self {
@@ -25,10 +26,16 @@ impl ListOpLog {
             cg: Default::default(),
             operation_ctx: ListOperationCtx::new(),
             operations: Default::default(),
+            start_version: Frontier::root(),
+            start_content: JumpRope::new(),
             // inserted_content: "".to_string(),
         }
     }
 
+    /// Check out the document at some version.
+    ///
+    /// If the oplog has been pruned, the version must be ROOT or contain the version history was
+    /// pruned at. This panics otherwise.
     pub fn checkout(&self, local_version: &[LV]) -> ListBranch {
         let mut branch = ListBranch::new();
         branch.merge(self, local_version);
@@ -36,15 +43,20 @@ impl ListOpLog {
     }
 
     pub fn checkout_tip(&self) -> ListBranch {
-        let mut branch = ListBranch::new();
-        branch.merge(self, self.cg.version.as_ref());
-        branch
+        self.checkout(self.cg.version.as_ref())
     }
 
     pub fn get_or_create_agent_id(&mut self, name: &str) -> AgentId {
         self.cg.agent_assignment.get_or_create_agent_id(name)
     }
 
+    /// Create a new agent for a local editing session, with a random name. See
+    /// [`CausalGraph::new_session_agent`](crate::CausalGraph::new_session_agent).
+    #[cfg(feature = "random_agents")]
+    pub fn new_session_agent(&mut self) -> AgentId {
+        self.cg.new_session_agent()
+    }
+
     pub(crate) fn get_agent_id(&self, name: &str) -> Option<AgentId> {
         self.cg.agent_assignment.get_agent_id(name)
     }
@@ -174,6 +186,38 @@ impl ListOpLog {
         }));
     }
 
+    /// Fill in the content of the operations in `range`, if we don't already know it. This is used
+    /// to add content to an oplog which was loaded without it.
+    pub(crate) fn fill_op_content(&mut self, mut range: DTRange, kind: ListOpKind, mut content: &str) {
+        while !range.is_empty() {
+            let idx = self.operations.find_index(range.start).unwrap();
+            let KVPair(start, op) = &self.operations.0[idx];
+            let offset = range.start - *start;
+            let len_here = usize::min(op.len() - offset, range.len());
+            let content_here = consume_chars(&mut content, len_here);
+
+            if op.kind == kind && op.content_pos.is_none() {
+                // Split the entry so only the operations in range get the content.
+                let mut pieces = Vec::with_capacity(3);
+                let mut filled = self.operations.0[idx].clone();
+                if offset > 0 {
+                    let rest = filled.truncate_ctx(offset, &self.operation_ctx);
+                    pieces.push(filled);
+                    filled = rest;
+                }
+                let after = (filled.len() > len_here)
+                    .then(|| filled.truncate_ctx(len_here, &self.operation_ctx));
+
+                filled.1.content_pos = Some(self.operation_ctx.push_str(kind, content_here));
+                pieces.push(filled);
+                pieces.extend(after);
+                self.operations.0.splice(idx..idx + 1, pieces);
+            }
+
+            range.start += len_here;
+        }
+    }
+
     /// Push new operations to the opset. Operation parents specified by parents parameter.
     ///
     /// Returns the single item version after merging. (The resulting LocalVersion after calling
@@ -0,0 +1,315 @@
+//! Pruning removes old operations from an oplog, replacing them with a snapshot of the document.
+//!
+//! After pruning, the oplog still stores the causal graph (which is tiny) so it can keep syncing
+//! with remote peers. But operations from before the prune point are gone, so the oplog can only
+//! check out (and merge) versions which contain the prune point.
+
+use crate::list::op_metrics::ListOperationCtx;
+use crate::list::{ListBranch, ListOpLog};
+use crate::rle::{KVPair, RleVec};
+use crate::{Frontier, LV};
+
+impl ListOpLog {
+    /// Delete operations in the history of `frontier`, replacing them with a snapshot of the
+    /// document. When the oplog is encoded, the snapshot is stored in the file's StartBranch chunk.
+    ///
+    /// The frontier should be a version every peer has acknowledged. Operations are stored in local
+    /// version order, so only a prefix of the oplog can be pruned. If the oplog has operations
+    /// which are concurrent with `frontier` (but have lower local versions than some of the
+    /// operations in it), history is pruned at an earlier version instead, so those operations can
+    /// still be checked out. Returns the version history was actually pruned at.
+    ///
+    /// After pruning:
+    ///
+    /// - The oplog can only be checked out at versions which contain the returned version
+    /// - Remote operations can only be merged if the returned version is in their history. Merging
+    ///   a patch containing an operation which is concurrent with it fails with `DataMissing`.
+    pub fn prune_before(&mut self, frontier: &[LV]) -> Frontier {
+        let end = self.prune_end(frontier);
+        if end <= self.ops_start() { return self.start_version.clone(); }
+        let frontier = self.prefix_version(end);
+        let frontier = frontier.as_ref();
+
+        let start_content = self.checkout(frontier).content.borrow().clone();
+
+        // Copy the remaining operations (and their content) into new storage.
+        let mut operation_ctx = ListOperationCtx::new();
+        let mut operations = RleVec::new();
+        for KVPair(lv, mut op) in self.operations.iter_range_ctx((end..self.len()).into(), &self.operation_ctx) {
+            op.content_pos = op.get_content(&self.operation_ctx)
+                .map(|content| operation_ctx.push_str(op.kind, content));
+            operations.push(KVPair(lv, op));
+        }
+
+        self.operation_ctx = operation_ctx;
+        self.operations = operations;
+        self.start_version = frontier.into();
+        self.start_content = start_content;
+        self.start_version.clone()
+    }
+
+    /// The version containing all operations before `end`.
+    fn prefix_version(&self, end: LV) -> Frontier {
+        let mut version = Frontier::root();
+        version.advance(&self.cg.graph, (0..end).into());
+        version
+    }
+
+    /// Find how many operations can be pruned when pruning the history of `frontier`. Every
+    /// pruned operation must be in the history of `frontier`, and every remaining operation must
+    /// have all the pruned operations in its history.
+    fn prune_end(&self, frontier: &[LV]) -> usize {
+        let mut end = frontier.last().map_or(0, |v| v + 1);
+        let mut target: Frontier = frontier.into();
+
+        loop {
+            let prefix = self.prefix_version(end);
+
+            // Cut before the first operation which isn't in the target's history.
+            let (only_prefix, _) = self.cg.graph.diff(prefix.as_ref(), target.as_ref());
+            if let Some(span) = only_prefix.first() {
+                end = span.start;
+                continue;
+            }
+
+            // If a remaining operation doesn't contain the prefix, cut again inside its history.
+            let concurrent = self.cg.graph.iter_range((end..self.len()).into())
+                .find(|entry| !self.cg.graph.frontier_contains_frontier(entry.parents.as_ref(), prefix.as_ref()));
+            match concurrent {
+                Some(entry) => { target = entry.parents; }
+                None => { return end; }
+            }
+        }
+    }
+
+    /// Returns the version history was pruned at, or ROOT if history has never been pruned.
+    pub fn pruned_version(&self) -> &[LV] {
+        self.start_version.as_ref()
+    }
+
+    /// Make a branch containing the document at the version history was pruned at. Checkouts start
+    /// from here.
+    pub(crate) fn start_branch(&self) -> ListBranch {
+        ListBranch {
+            version: self.start_version.clone(),
+            content: self.start_content.clone().into(),
+        }
+    }
+
+    /// Panics if `version` is from before the version history was pruned at. The operations
+    /// needed to reach it are gone. ROOT is allowed.
+    pub(crate) fn assert_not_before_prune(&self, version: &[LV]) {
+        assert!(self.start_version.is_root() || version.is_empty()
+            || self.cg.graph.frontier_contains_frontier(version, self.start_version.as_ref()),
+            "Version {:?} is from before the oplog was pruned", version);
+    }
+
+    /// The local version of the first operation stored in the oplog. This is 0 unless the oplog
+    /// has been pruned.
+    pub(crate) fn ops_start(&self) -> LV {
+        self.start_version.0.last().map_or(0, |v| v + 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::encoding::{ENCODE_FULL, ENCODE_PATCH};
+    use jumprope::JumpRope;
+    use crate::list::{ListBranch, ListOpLog};
+    use crate::list::anchor::AnchorBias;
+    use crate::list::operation::ListOpKind;
+
+    fn make_oplog() -> ListOpLog {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "hello world");
+        let v = oplog.local_frontier();
+        oplog.add_insert_at(seph, v.as_ref(), 5, " there");
+        oplog.add_delete_at(mike, v.as_ref(), 0..1);
+        oplog.add_insert(mike, 0, "H");
+        oplog
+    }
+
+    #[test]
+    fn prune_and_keep_merging() {
+        let mut oplog = make_oplog();
+        let full = oplog.clone();
+        let prune_version = oplog.local_frontier();
+
+        oplog.prune_before(prune_version.as_ref());
+        assert_eq!(oplog.pruned_version(), prune_version.as_ref());
+        assert_eq!(oplog.checkout_tip().content().to_string(), "Hello there world");
+        assert!(oplog.operation_ctx.ins_content.is_empty());
+
+        // Concurrent changes after the prune point still merge.
+        let mut other = full.clone();
+        for oplog in [&mut oplog, &mut other] {
+            let seph = oplog.get_or_create_agent_id("seph");
+            let mike = oplog.get_or_create_agent_id("mike");
+            oplog.add_insert_at(seph, prune_version.as_ref(), 17, "!");
+            oplog.add_delete_at(mike, prune_version.as_ref(), 1..5);
+        }
+        assert_eq!(oplog.checkout_tip().content(), other.checkout_tip().content());
+        assert_eq!(oplog.checkout_tip().content().to_string(), "H there world!");
+
+        // Pruning again works too.
+        let v = oplog.local_frontier();
+        oplog.prune_before(v.as_ref());
+        assert_eq!(oplog.checkout_tip().content().to_string(), "H there world!");
+        oplog.dbg_check(true);
+    }
+
+    #[test]
+    fn pruned_oplog_round_trips() {
+        let mut oplog = make_oplog();
+        let mut full = oplog.clone();
+        let prune_version = oplog.local_frontier();
+        oplog.prune_before(prune_version.as_ref());
+
+        let bytes = oplog.encode(ENCODE_FULL);
+        assert!(bytes.len() < full.encode(ENCODE_FULL).len());
+
+        // The file contains the snapshot, so it can be loaded into an empty oplog.
+        let mut loaded = ListOpLog::load_from(&bytes).unwrap();
+        assert_eq!(loaded.checkout_tip().content().to_string(), "Hello there world");
+        assert_eq!(loaded, oplog);
+        assert_ne!(loaded, full);
+
+        // And changes made by peers who have the whole history can be merged in.
+        let seph = full.get_or_create_agent_id("seph");
+        full.add_insert(seph, 0, ">> ");
+        let patch = full.encode_from(ENCODE_PATCH, prune_version.as_ref());
+        loaded.decode_and_add(&patch).unwrap();
+        oplog.decode_and_add(&patch).unwrap();
+        assert_eq!(loaded.checkout_tip().content().to_string(), ">> Hello there world");
+        assert_eq!(oplog.checkout_tip().content().to_string(), ">> Hello there world");
+        assert_eq!(loaded, oplog);
+
+        // Re-encoding the loaded oplog keeps the snapshot.
+        let reloaded = ListOpLog::load_from(&loaded.encode(ENCODE_FULL)).unwrap();
+        assert_eq!(reloaded.checkout_tip().content().to_string(), ">> Hello there world");
+    }
+
+    #[test]
+    fn merging_ops_from_before_prune_point_fails() {
+        let mut oplog = make_oplog();
+        let mut full = oplog.clone();
+        let prune_version = oplog.local_frontier();
+        oplog.prune_before(prune_version.as_ref());
+
+        // This operation is concurrent with the prune version.
+        let seph = full.get_or_create_agent_id("seph");
+        full.add_insert_at(seph, &[0], 0, "x");
+        let patch = full.encode_from(ENCODE_PATCH, prune_version.as_ref());
+
+        let len = oplog.len();
+        assert!(oplog.decode_and_add(&patch).is_err());
+        assert_eq!(oplog.len(), len);
+        assert_eq!(oplog.checkout_tip().content().to_string(), "Hello there world");
+    }
+
+    #[test]
+    fn prune_with_concurrent_ops_prunes_less() {
+        let mut oplog = make_oplog();
+        // The delete at 17 is concurrent with 16, so we can only prune up to their common parent.
+        assert_eq!(oplog.prune_before(&[16]).as_ref(), &[10]);
+        assert_eq!(oplog.checkout_tip().content().to_string(), "Hello there world");
+        oplog.dbg_check(true);
+    }
+
+    #[test]
+    fn prune_with_merged_concurrent_ops() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "hello");
+        oplog.add_insert_at(seph, &[4], 5, "x");
+        oplog.add_insert_at(mike, &[4], 0, "y");
+        oplog.add_insert_at(seph, &[5], 6, "z");
+        // Every peer has [7], but mike's insert (6) is concurrent with it.
+        oplog.add_insert(mike, 0, "!");
+        let expected = oplog.checkout_tip().content().to_string();
+
+        assert_eq!(oplog.prune_before(&[7]).as_ref(), &[4]);
+        assert_eq!(oplog.checkout_tip().content().to_string(), expected);
+        assert_eq!(oplog.checkout(&[7]).content().to_string(), "helloxz");
+        oplog.dbg_check(true);
+    }
+
+    #[test]
+    fn branches_and_xf_from_root_after_pruning() {
+        let mut oplog = make_oplog();
+        let prune_version = oplog.local_frontier();
+        oplog.prune_before(prune_version.as_ref());
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, ">> ");
+
+        let mut branch = ListBranch::new();
+        branch.merge(&oplog, oplog.cg.version.as_ref());
+        assert_eq!(branch.content().to_string(), ">> Hello there world");
+
+        let mut content = JumpRope::new();
+        for (_, op) in oplog.iter_xf_operations() {
+            let Some(op) = op else { continue; };
+            match op.kind {
+                ListOpKind::Ins => content.insert(op.start(), op.content_as_str().unwrap()),
+                ListOpKind::Del => content.remove(op.range().into()),
+            }
+        }
+        assert_eq!(content.to_string(), ">> Hello there world");
+
+        // A new branch doesn't know about any characters yet.
+        let anchor = oplog.anchor_at(oplog.cg.version.as_ref(), 5, AnchorBias::Left);
+        assert_eq!(ListBranch::new().resolve_anchor(&oplog, anchor), None);
+        assert_eq!(branch.resolve_anchor(&oplog, anchor), Some(5));
+    }
+
+    fn pruned_hello() -> ListOpLog {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hello");
+        oplog.add_insert(seph, 5, " world");
+        oplog.prune_before(&[10]);
+        oplog
+    }
+
+    #[test]
+    fn checkout_root_after_pruning() {
+        let oplog = pruned_hello();
+        assert_eq!(oplog.checkout(&[]).content().to_string(), "");
+        assert_eq!(oplog.checkout(&[10]).content().to_string(), "hello world");
+    }
+
+    #[test]
+    #[should_panic(expected = "before the oplog was pruned")]
+    fn checkout_before_prune_point_panics() {
+        pruned_hello().checkout(&[3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "before the oplog was pruned")]
+    fn merge_before_prune_point_panics() {
+        ListBranch::new().merge(&pruned_hello(), &[3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "before the oplog was pruned")]
+    fn xf_from_before_prune_point_panics() {
+        let oplog = pruned_hello();
+        let _ = oplog.iter_xf_operations_from(&[3], &[10]).count();
+    }
+
+    #[test]
+    #[should_panic(expected = "before the oplog was pruned")]
+    fn xf_to_before_prune_point_panics() {
+        let oplog = pruned_hello();
+        let _ = oplog.iter_xf_operations_from(&[], &[3]).count();
+    }
+
+    #[test]
+    #[should_panic(expected = "before the oplog was pruned")]
+    fn merge_lines_before_prune_point_panics() {
+        pruned_hello().merge_lines(&[3], &[10]);
+    }
+}
@@ -1,8 +1,6 @@
-// WIP.
-
-use crate::causalgraph::agent_span::AgentVersion;
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersionOwned;
 use crate::list::ListOpLog;
-use crate::LV;
+use crate::{Frontier, LV};
 
 impl ListOpLog {
 
@@ -22,75 +20,126 @@ impl ListOpLog {
     /// ancestor of the other peer's version. (Eg, I'm modifying a document and you're just
     /// observing it.)
     ///
-    /// Ny design here is a hybrid approach. I'm going to construct a fixed-sized chunk of known
+    /// My design here is a hybrid approach. I'm going to construct a fixed-sized chunk of known
     /// versions we can send to our remote peer. (And the remote peer can do the same with us). The
     /// chunk will contain exponentially less information the further back in time we scan; so the
     /// more time which has passed since we have a common ancestor, the more wasted bytes of changes
     /// we'll send to the remote peer. But this approach will always only need 1RTT to sync.
     ///
     /// Its not perfect, but it'll do donkey. It'll do.
-    #[allow(unused)]
-    fn get_stochastic_version(&self, target_count: usize) -> Vec<AgentVersion> {
-        // TODO: WIP.
-        let target_count = target_count.max(self.cg.version.len());
-        let mut result = Vec::with_capacity(target_count + 10);
-
+    ///
+    /// Use [`ListOpLog::intersect_with_stochastic_version`] on the remote peer to find the common
+    /// version from which changes should be sent.
+    ///
+    /// If the oplog is empty, this returns an empty list. Descending from ROOT is implied anyway.
+    pub fn stochastic_version(&self, target_count: usize) -> Vec<RemoteVersionOwned> {
         let time_len = self.len();
+        if time_len == 0 { return vec![]; }
 
-        // If we have no changes, just return the empty set. Descending from ROOT is implied anyway.
-        if time_len == 0 { return result; }
-
-        let mut push_time = |t: LV| {
-            result.push(self.cg.agent_assignment.local_to_agent_version(t));
-        };
-
-        // No matter what, we'll send the current frontier:
-        for t in self.cg.version.iter() {
-            push_time(*t);
-        }
+        // No matter what, we'll send the current frontier.
+        let mut versions: Vec<LV> = self.cg.version.as_ref().to_vec();
 
         // So we want about target_count items. I'm assuming there's an exponentially decaying
         // probability of syncing as we go further back in time. This is a big assumption - and
         // probably not true in practice. But it'll do. (TODO: Quadratic might be better?)
         //
-        // Given factor, the approx number of operations we'll return is log_f(|ops|).
+        // Given factor, the approx number of versions we'll return is log_f(|ops|).
         // Solving for f gives f = |ops|^(1/target).
-        if target_count > self.cg.version.len() {
-            // Note I'm using n_ops here rather than time, since this easily scales time by the
-            // approximate size of the transmitted operations. TODO: This might be a faulty
-            // assumption given we're probably sending inserted content? Hm!
-            let remaining_count = target_count - self.cg.version.len();
-            let n_ops = self.operations.0.len();
-            let mut factor = f32::powf(n_ops as f32, 1f32 / (remaining_count) as f32);
-            factor = factor.max(1.1);
-
-            let mut t_inv = 1f32;
-            while t_inv < time_len as f32 {
-                dbg!(t_inv);
-                push_time(time_len - (t_inv as usize));
+        let remaining_count = target_count.saturating_sub(versions.len());
+        if remaining_count > 0 {
+            let factor = f32::powf(time_len as f32, 1f32 / remaining_count as f32).max(1.1);
+
+            let mut t_inv = factor;
+            while (t_inv as usize) < time_len {
+                versions.push(time_len - (t_inv as usize));
                 t_inv *= factor;
             }
+
+            // The very first operation is a useful fallback for peers which have diverged a lot.
+            versions.push(0);
         }
 
-        result
+        versions.sort_unstable();
+        versions.dedup();
+        versions.into_iter()
+            .map(|v| self.cg.agent_assignment.local_to_remote_version(v).to_owned())
+            .collect()
+    }
+
+    /// Find the common version between this oplog and a remote peer, given a list of versions
+    /// from [`ListOpLog::stochastic_version`] on the remote peer.
+    ///
+    /// The returned frontier is the greatest version we know the remote peer has. Sending the
+    /// peer `self.encode_from(opts, &common)` will give them everything they're missing, though
+    /// the patch might also contain some operations they already know about.
+    pub fn intersect_with_stochastic_version(&self, versions: &[RemoteVersionOwned]) -> Frontier {
+        // Versions we don't know about are ignored.
+        let mut known: Vec<LV> = versions.iter()
+            .filter_map(|rv| self.cg.agent_assignment.try_remote_to_local_version(rv.into()).ok())
+            .collect();
+        known.sort_unstable();
+        known.dedup();
+
+        self.cg.graph.find_dominators(&known)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::list::encoding::ENCODE_PATCH;
     use crate::list::ListOpLog;
 
     #[test]
-    fn test_versions_since() {
-        let mut oplog = ListOpLog::new();
-        // Should be an empty set
-        assert_eq!(oplog.get_stochastic_version(10), &[]);
+    fn empty_stochastic_version() {
+        let oplog = ListOpLog::new();
+        assert!(oplog.stochastic_version(10).is_empty());
+        assert!(oplog.intersect_with_stochastic_version(&[]).is_root());
+    }
 
+    #[test]
+    fn stochastic_version_is_bounded() {
+        let mut oplog = ListOpLog::new();
         oplog.get_or_create_agent_id("seph");
-        oplog.add_insert(0, 0, "a");
-        oplog.add_insert(0, 0, "a");
-        oplog.add_insert(0, 0, "a");
-        oplog.add_insert(0, 0, "a");
-        dbg!(oplog.get_stochastic_version(10));
+        for _ in 0..1000 {
+            oplog.add_insert(0, 0, "a");
+        }
+
+        let versions = oplog.stochastic_version(10);
+        assert!(versions.len() <= 12);
+        // The current version is always included.
+        assert_eq!(versions.last().unwrap().1, 999);
+        assert_eq!(versions[0].1, 0);
+
+        // A peer with the same history finds the whole thing in common.
+        assert_eq!(oplog.intersect_with_stochastic_version(&versions), oplog.cg.version);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sync_with_stochastic_version() {
+        let mut a = ListOpLog::new();
+        a.get_or_create_agent_id("seph");
+        for i in 0..100 {
+            a.add_insert(0, i, "a");
+        }
+
+        // b forks off a's history, then both peers make changes.
+        let mut b = ListOpLog::load_from(&a.encode(Default::default())).unwrap();
+        for i in 0..20 {
+            a.add_insert(0, 100 + i, "b");
+        }
+        b.get_or_create_agent_id("mike");
+        b.add_insert(1, 0, "xyz");
+
+        // a sends its sample to b, and b replies with the changes a is missing (and its own sample).
+        let a_sample = a.stochastic_version(8);
+        let common = b.intersect_with_stochastic_version(&a_sample);
+        assert!(b.cg.graph.frontier_contains_frontier(&[99], common.as_ref()));
+        a.decode_and_add(&b.encode_from(ENCODE_PATCH, common.as_ref())).unwrap();
+
+        let b_sample = b.stochastic_version(8);
+        let common = a.intersect_with_stochastic_version(&b_sample);
+        b.decode_and_add(&a.encode_from(ENCODE_PATCH, common.as_ref())).unwrap();
+
+        assert_eq!(a, b);
+    }
+}
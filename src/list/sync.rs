@@ -0,0 +1,293 @@
+//! A transport-agnostic protocol for syncing two peers' oplogs.
+//!
+//! Each side of a connection owns a [`SyncSession`]. The peers first swap [version
+//! summaries](VersionSummary) naming every operation they know about. From the remote peer's
+//! summary we can figure out which of our operations they're missing, and send those operations in
+//! a patch. Each patch is acknowledged once it has been merged. Once both patches have been merged,
+//! the peers have converged.
+//!
+//! The session doesn't care how messages get to the remote peer. Messages may be lost or arrive out
+//! of order. When a patch can't be merged because an earlier patch went missing, the receiver
+//! sends its summary again and the sender resends everything which hasn't been acknowledged.
+
+use crate::causalgraph::agent_assignment::remote_ids::RemoteFrontierOwned;
+use crate::causalgraph::summary::VersionSummary;
+use crate::encoding::parseerror::ParseError;
+use crate::list::encoding::ENCODE_PATCH;
+use crate::list::ListOpLog;
+use crate::Frontier;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// A message sent between two peers' [`SyncSession`]s.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SyncMessage {
+    /// A summary of every operation the sender knows about.
+    Summary(VersionSummary),
+    /// A set of operations, encoded as a binary patch.
+    Patch(Vec<u8>),
+    /// Sent in reply to a patch once its been merged. This names the version of the merged patch.
+    Ack(RemoteFrontierOwned),
+}
+
+/// The local state of a sync session with one remote peer.
+#[derive(Debug, Clone, Default)]
+pub struct SyncSession {
+    /// The most recent summary the remote peer has sent us, if any.
+    remote_summary: Option<VersionSummary>,
+
+    /// (Local) versions we know the remote peer has. This is built from the remote peer's summary,
+    /// the patches they've acknowledged and the operations they've sent us.
+    remote_version: Frontier,
+
+    /// The versions we've sent the remote peer. This includes patches which may not have arrived
+    /// yet, so new patches don't repeat them. Its reset to remote_version when the remote peer
+    /// tells us its missing something.
+    sent_version: Frontier,
+
+    /// Have we sent the remote peer our summary?
+    sent_summary: bool,
+}
+
+impl SyncSession {
+    pub fn new() -> Self { Self::default() }
+
+    /// Start syncing. The returned message should be sent to the remote peer.
+    ///
+    /// Its fine for both peers to call this - but at least one peer must. This can also be called
+    /// again at any time (eg after reconnecting) to make the remote peer resend anything we're
+    /// missing.
+    pub fn start(&mut self, oplog: &ListOpLog) -> SyncMessage {
+        self.sent_summary = true;
+        SyncMessage::Summary(oplog.cg.agent_assignment.summarize_versions())
+    }
+
+    /// Make a patch containing every operation we haven't already sent the remote peer, if there
+    /// are any.
+    fn make_patch(&mut self, oplog: &ListOpLog) -> Option<SyncMessage> {
+        if oplog.cg.graph.frontier_contains_frontier(self.sent_version.as_ref(), oplog.local_frontier_ref()) {
+            return None;
+        }
+
+        let patch = oplog.encode_from(ENCODE_PATCH, self.sent_version.as_ref());
+        self.sent_version = oplog.local_frontier();
+        Some(SyncMessage::Patch(patch))
+    }
+
+    /// Forget about anything we've sent the remote peer which they haven't confirmed. The next
+    /// patch will contain everything they haven't confirmed.
+    fn reset_sent_version(&mut self) {
+        self.sent_version = self.remote_version.clone();
+    }
+
+    /// Process a message from the remote peer, merging any operations it contains into the oplog.
+    ///
+    /// Returns the messages which should be sent to the remote peer in reply (if any).
+    pub fn receive(&mut self, oplog: &mut ListOpLog, msg: SyncMessage) -> Result<Vec<SyncMessage>, ParseError> {
+        let mut reply = vec![];
+
+        match msg {
+            SyncMessage::Summary(summary) => {
+                if !self.sent_summary {
+                    reply.push(self.start(oplog));
+                }
+
+                // Everything in common is stuff the remote peer already has. Anything else in the
+                // summary is something we're missing, and which they'll send us. Any patches we've
+                // sent which aren't in the summary might have been lost, so they're sent again.
+                let (common, _) = oplog.cg.intersect_with_summary(&summary, &[]);
+                self.remote_version.merge_union(common.as_ref(), &oplog.cg.graph);
+                self.remote_summary = Some(summary);
+                self.reset_sent_version();
+
+                reply.extend(self.make_patch(oplog));
+            }
+            SyncMessage::Patch(patch) => {
+                match oplog.decode_and_add(&patch) {
+                    Ok(version) => {
+                        self.remote_version.merge_union(version.as_ref(), &oplog.cg.graph);
+                        self.sent_version.merge_union(version.as_ref(), &oplog.cg.graph);
+                        reply.push(SyncMessage::Ack(oplog.cg.agent_assignment.local_to_remote_frontier_owned(version.as_ref())));
+                    }
+                    Err(ParseError::DataMissing | ParseError::BaseVersionUnknown) => {
+                        // The patch is based on operations we don't have. This happens when an
+                        // earlier patch was lost or hasn't arrived yet. Go back to what the remote
+                        // peer has confirmed, and resend our summary so they can send us a patch
+                        // from the right place.
+                        self.remote_version = match &self.remote_summary {
+                            Some(summary) => oplog.cg.intersect_with_summary(summary, &[]).0,
+                            None => Frontier::root(),
+                        };
+                        self.reset_sent_version();
+                        reply.push(self.start(oplog));
+                    }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            SyncMessage::Ack(version) => {
+                // If the remote peer names versions we don't know about, the message is bogus.
+                let version = oplog.cg.agent_assignment.try_remote_to_local_frontier(version.iter())
+                    .map_err(|_| ParseError::BaseVersionUnknown)?;
+                self.remote_version.merge_union(version.as_ref(), &oplog.cg.graph);
+            }
+        }
+
+        Ok(reply)
+    }
+
+    /// Call this when there are new local changes in the oplog. Returns a patch containing any
+    /// changes the remote peer doesn't know about yet.
+    ///
+    /// This does nothing until the remote peer's summary has arrived.
+    pub fn local_changes(&mut self, oplog: &ListOpLog) -> Option<SyncMessage> {
+        self.remote_summary.as_ref()?;
+        self.make_patch(oplog)
+    }
+
+    /// Returns true once both peers have every operation the other peer knows about. That is, the
+    /// remote peer has confirmed it has everything we know about, and we've merged everything in
+    /// their summary.
+    pub fn is_converged(&self, oplog: &ListOpLog) -> bool {
+        let Some(summary) = &self.remote_summary else { return false; };
+
+        oplog.cg.intersect_with_summary(summary, &[]).1.is_none()
+            && oplog.cg.graph.frontier_contains_frontier(self.remote_version.as_ref(), oplog.local_frontier_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use crate::list::ListOpLog;
+    use crate::list::sync::{SyncMessage, SyncSession};
+
+    /// Deliver messages between two peers until there's nothing left to send.
+    fn pump(a: (&mut SyncSession, &mut ListOpLog), b: (&mut SyncSession, &mut ListOpLog), first: Vec<SyncMessage>) {
+        let (sa, oa) = a;
+        let (sb, ob) = b;
+
+        // Each message is tagged with whether its headed to b.
+        let mut queue: VecDeque<(bool, SyncMessage)> = first.into_iter().map(|m| (true, m)).collect();
+        while let Some((to_b, msg)) = queue.pop_front() {
+            let reply = if to_b { sb.receive(ob, msg) } else { sa.receive(oa, msg) }.unwrap();
+            queue.extend(reply.into_iter().map(|m| (!to_b, m)));
+        }
+    }
+
+    #[test]
+    fn sync_partial_overlap() {
+        let mut a = ListOpLog::new();
+        a.get_or_create_agent_id("seph");
+        a.add_insert(0, 0, "hi there");
+
+        // b has some of a's changes, and some changes of its own.
+        let mut b = ListOpLog::load_from(&a.encode(Default::default())).unwrap();
+        a.add_insert(0, 8, " everyone");
+        b.get_or_create_agent_id("mike");
+        b.add_delete_without_content(1, 0..2);
+
+        let mut sa = SyncSession::new();
+        let mut sb = SyncSession::new();
+        assert!(!sa.is_converged(&a));
+
+        let start = vec![sa.start(&a)];
+        pump((&mut sa, &mut a), (&mut sb, &mut b), start);
+
+        assert!(sa.is_converged(&a));
+        assert!(sb.is_converged(&b));
+        assert_eq!(a, b);
+        assert_eq!(a.checkout_tip().content().to_string(), " there everyone");
+
+        // New local changes are sent as patches.
+        b.add_insert(1, 0, "yo");
+        assert!(!sb.is_converged(&b));
+        let patch = sb.local_changes(&b).unwrap();
+        assert!(matches!(patch, SyncMessage::Patch(_)));
+        assert!(sb.local_changes(&b).is_none());
+        let ack = sa.receive(&mut a, patch).unwrap();
+        assert!(matches!(ack[..], [SyncMessage::Ack(_)]));
+        assert!(sa.is_converged(&a));
+
+        // b doesn't know a has the changes until they're acknowledged.
+        assert!(!sb.is_converged(&b));
+        for msg in ack { assert!(sb.receive(&mut b, msg).unwrap().is_empty()); }
+        assert!(sb.is_converged(&b));
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn sync_both_start() {
+        let mut a = ListOpLog::new();
+        a.get_or_create_agent_id("seph");
+        a.add_insert(0, 0, "aaa");
+        let mut b = ListOpLog::new();
+        b.get_or_create_agent_id("mike");
+        b.add_insert(0, 0, "bbb");
+
+        let mut sa = SyncSession::new();
+        let mut sb = SyncSession::new();
+        let msg_a = sa.start(&a);
+        let msg_b = sb.start(&b);
+
+        let reply_b = sb.receive(&mut b, msg_a).unwrap();
+        let reply_a = sa.receive(&mut a, msg_b).unwrap();
+        // We've already sent our summaries, so only the patches come back.
+        assert_eq!(reply_a.len(), 1);
+        assert_eq!(reply_b.len(), 1);
+
+        let ack_b: Vec<_> = reply_a.into_iter().flat_map(|msg| sb.receive(&mut b, msg).unwrap()).collect();
+        let ack_a: Vec<_> = reply_b.into_iter().flat_map(|msg| sa.receive(&mut a, msg).unwrap()).collect();
+        for msg in ack_b { assert!(sa.receive(&mut a, msg).unwrap().is_empty()); }
+        for msg in ack_a { assert!(sb.receive(&mut b, msg).unwrap().is_empty()); }
+
+        assert!(sa.is_converged(&a));
+        assert!(sb.is_converged(&b));
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn sync_recovers_from_lost_and_reordered_patches() {
+        let mut a = ListOpLog::new();
+        a.get_or_create_agent_id("seph");
+        a.add_insert(0, 0, "hi");
+        let mut b = ListOpLog::new();
+
+        let mut sa = SyncSession::new();
+        let mut sb = SyncSession::new();
+        let start = vec![sa.start(&a)];
+        pump((&mut sa, &mut a), (&mut sb, &mut b), start);
+        assert_eq!(a, b);
+
+        // A patch from a goes missing.
+        a.add_insert(0, 2, " there");
+        let _lost = sa.local_changes(&a).unwrap();
+        a.add_insert(0, 0, ">> ");
+        let next = sa.local_changes(&a).unwrap();
+        assert!(!sa.is_converged(&a));
+
+        // b can't merge the next patch, so it asks for everything it's missing.
+        let reply = sb.receive(&mut b, next).unwrap();
+        assert!(matches!(reply[..], [SyncMessage::Summary(_)]));
+        pump((&mut sb, &mut b), (&mut sa, &mut a), reply);
+        assert!(sa.is_converged(&a));
+        assert!(sb.is_converged(&b));
+        assert_eq!(a, b);
+        assert_eq!(b.checkout_tip().content().to_string(), ">> hi there");
+
+        // Two patches arrive in the wrong order.
+        a.add_insert(0, 0, "1");
+        let first = sa.local_changes(&a).unwrap();
+        a.add_insert(0, 0, "2");
+        let second = sa.local_changes(&a).unwrap();
+
+        let mut reply = sb.receive(&mut b, second).unwrap();
+        reply.extend(sb.receive(&mut b, first).unwrap());
+        pump((&mut sb, &mut b), (&mut sa, &mut a), reply);
+        assert!(sa.is_converged(&a));
+        assert!(sb.is_converged(&b));
+        assert_eq!(a, b);
+        assert_eq!(b.checkout_tip().content().to_string(), "21>> hi there");
+    }
+}
@@ -0,0 +1,687 @@
+//! Undo / redo support for local edits.
+//!
+//! In a collaborative editor, undo should only undo the user's *own* changes. If Alice types a
+//! sentence and Bob concurrently fixes a typo elsewhere in the document, Alice pressing ctrl+z
+//! should remove her sentence and leave Bob's fix alone.
+//!
+//! The [`UndoManager`] does this by remembering the operations (by local version) made by one
+//! agent. Undoing a group of operations generates new operations which invert them, based on the
+//! current state of the document:
+//!
+//! - Undoing an insert deletes any of the inserted characters which are still in the document.
+//! - Undoing a delete inserts the deleted content again, at the place the deleted characters were
+//!   in the document. Characters which another agent has also deleted stay deleted.
+//!
+//! Because the inverse operations are computed against the branch's current version, this works
+//! correctly even when concurrent remote edits have been merged in since the original change.
+//! Undo and redo are themselves normal edits, so they sync to other peers like any other change.
+
+use std::ops::Range;
+use rle::{HasLength, MergableSpan};
+use smartstring::alias::String as SmartString;
+use crate::dtrange::{is_underwater, UNDERWATER_START};
+use crate::list::{ListBranch, ListOpLog};
+use crate::list::merge::UNKNOWN_CHAR;
+use crate::list::operation::{ListOpKind, TextOperation};
+use crate::listmerge::merge::ItemTracker;
+use crate::{AgentId, DTRange, LV};
+
+/// A group of operations which are undone and redone together.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+struct UndoGroup {
+    /// The local versions of the operations in this group, in ascending order.
+    spans: Vec<DTRange>,
+    /// The time of the most recent edit in the group.
+    last_time: u64,
+}
+
+impl UndoGroup {
+    fn push(&mut self, span: DTRange) {
+        if let Some(last) = self.spans.last_mut() {
+            if last.can_append(&span) {
+                last.append(span);
+                return;
+            }
+        }
+        self.spans.push(span);
+    }
+}
+
+/// Tracks local edits made by one agent on a [`ListBranch`], and allows those edits to be undone
+/// and redone.
+///
+/// Edits are grouped together into undo steps in two ways:
+///
+/// - Explicitly, by calling [`end_group`](UndoManager::end_group) at the end of each transaction.
+/// - By time. If a group timeout is set, edits made within the timeout of the previous edit are
+///   merged into the same group.
+///
+/// Times are passed in by the caller (in milliseconds, from any monotonic clock). Diamond types
+/// doesn't read the system clock itself, since it isn't available everywhere.
+///
+/// Remote changes should be merged into the branch the normal way. They are never undone.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UndoManager {
+    agent: AgentId,
+    undo_stack: Vec<UndoGroup>,
+    redo_stack: Vec<UndoGroup>,
+
+    /// Edits within this many milliseconds of the previous edit are merged into the same undo
+    /// group. If None, groups are only split by calls to end_group.
+    group_timeout: Option<u64>,
+
+    /// Set when the group at the top of the undo stack has been closed, and the next edit should
+    /// start a new group.
+    group_closed: bool,
+
+    /// When undo or redo inserts deleted content again, the new characters stand in for the
+    /// deleted ones. This maps each range of original characters to the first of its stand ins.
+    /// Entries are removed once nothing in the undo or redo stacks refers to them.
+    restored: Vec<(DTRange, LV)>,
+}
+
+impl UndoManager {
+    /// Create a new undo manager tracking edits by the named agent. Edits are only grouped by
+    /// explicit calls to [`end_group`](UndoManager::end_group).
+    pub fn new(agent: AgentId) -> Self {
+        Self::new_with_timeout(agent, None)
+    }
+
+    /// Create a new undo manager which also groups together edits made within `group_timeout`
+    /// milliseconds of each other.
+    pub fn new_with_timeout(agent: AgentId, group_timeout: Option<u64>) -> Self {
+        Self {
+            agent,
+            undo_stack: vec![],
+            redo_stack: vec![],
+            group_timeout,
+            group_closed: true,
+            restored: vec![],
+        }
+    }
+
+    /// The agent whose edits are being tracked.
+    pub fn agent(&self) -> AgentId { self.agent }
+
+    pub fn can_undo(&self) -> bool { !self.undo_stack.is_empty() }
+
+    pub fn can_redo(&self) -> bool { !self.redo_stack.is_empty() }
+
+    /// Mark the end of a transaction. The next edit will start a new undo group.
+    pub fn end_group(&mut self) {
+        self.group_closed = true;
+    }
+
+    /// Forget all undo and redo history.
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.restored.clear();
+        self.group_closed = true;
+    }
+
+    /// Record an edit which has been made by this manager's agent. This is only needed for edits
+    /// which weren't made via [`insert`](UndoManager::insert) or
+    /// [`delete`](UndoManager::delete) (eg, edits made with
+    /// [`ListBranch::apply_local_operations`]).
+    ///
+    /// Recording a new edit clears the redo stack.
+    pub fn record(&mut self, span: DTRange, time: u64) {
+        if span.is_empty() { return; }
+        if !self.redo_stack.is_empty() {
+            self.redo_stack.clear();
+            self.compact_restored();
+        }
+
+        let merge = !self.group_closed && match self.group_timeout {
+            None => true,
+            Some(timeout) => self.undo_stack.last()
+                .is_some_and(|g| time <= g.last_time.saturating_add(timeout)),
+        };
+
+        if !merge || self.undo_stack.is_empty() {
+            self.undo_stack.push(UndoGroup::default());
+        }
+        let group = self.undo_stack.last_mut().unwrap();
+        group.push(span);
+        group.last_time = time;
+        self.group_closed = false;
+    }
+
+    /// Insert content into the branch, recording the change for undo.
+    pub fn insert(&mut self, oplog: &mut ListOpLog, branch: &mut ListBranch, pos: usize, ins_content: &str, time: u64) -> LV {
+        let start = oplog.len();
+        let lv = branch.insert(oplog, self.agent, pos, ins_content);
+        self.record((start..oplog.len()).into(), time);
+        lv
+    }
+
+    /// Delete content from the branch, recording the change for undo.
+    pub fn delete(&mut self, oplog: &mut ListOpLog, branch: &mut ListBranch, del_span: Range<usize>, time: u64) -> LV {
+        let start = oplog.len();
+        let lv = branch.delete(oplog, self.agent, del_span);
+        self.record((start..oplog.len()).into(), time);
+        lv
+    }
+
+    /// Undo the most recent group of edits. Returns false if there was nothing to undo.
+    ///
+    /// The branch must contain all of the edits being undone.
+    ///
+    /// Each call replays the branch's whole history to list the document's characters (including
+    /// deleted characters). Then each undone operation scans that list. So undo takes time
+    /// proportional to the size of the history, plus (operations undone * edited regions of the
+    /// document). Thats fine in response to a keypress, but it'll be slow in a loop over a long
+    /// undo stack.
+    pub fn undo(&mut self, oplog: &mut ListOpLog, branch: &mut ListBranch) -> bool {
+        let Some(group) = self.undo_stack.pop() else { return false; };
+        let inverse = self.apply_inverse(oplog, branch, &group);
+        self.redo_stack.push(inverse);
+        self.compact_restored();
+        self.group_closed = true;
+        true
+    }
+
+    /// Redo the most recently undone group of edits. Returns false if there was nothing to redo.
+    ///
+    /// This costs the same as [`undo`](UndoManager::undo).
+    pub fn redo(&mut self, oplog: &mut ListOpLog, branch: &mut ListBranch) -> bool {
+        let Some(group) = self.redo_stack.pop() else { return false; };
+        let inverse = self.apply_inverse(oplog, branch, &group);
+        self.undo_stack.push(inverse);
+        self.compact_restored();
+        self.group_closed = true;
+        true
+    }
+
+    /// Apply operations to the branch which invert the operations in the group, and return the
+    /// group of operations which were applied.
+    fn apply_inverse(&mut self, oplog: &mut ListOpLog, branch: &mut ListBranch, group: &UndoGroup) -> UndoGroup {
+        let mut result = UndoGroup { spans: vec![], last_time: group.last_time };
+
+        // Operations are inverted in the reverse order they happened.
+        let mut ops = vec![];
+        for &span in group.spans.iter().rev() {
+            assert!(span.start >= oplog.ops_start(), "Cannot undo operations which have been pruned");
+            assert!(oplog.cg.graph.frontier_contains_version(branch.local_frontier_ref(), span.last()),
+                "Branch does not contain the operations being undone");
+
+            let entries: Vec<_> = oplog.iter_history_range(span).collect();
+            for entry in entries.into_iter().rev() {
+                let start = ops.len();
+                for (op, _) in oplog.iter_range_simple(entry.span) {
+                    // Within a graph entry, each operation's parent is the one before it.
+                    let parents = if op.0 == entry.span.start { entry.parents.clone() }
+                    else { (op.0 - 1).into() };
+                    ops.push((op, parents));
+                }
+                ops[start..].reverse();
+            }
+        }
+
+        // The document's items are only listed once. The list is kept up to date as each inverse
+        // is applied, so the inverse of each operation is calculated based on the branch's version
+        // after the previous inverse has been applied. Deleted content is put back in relative to
+        // the document when it was deleted, so the tracker is moved back to each delete's version.
+        let mut tracker = ItemTracker::new(oplog, branch.local_frontier_ref());
+        let mut items = ItemStates(tracker.items_with_deletes());
+
+        for (op, parents) in ops {
+            let lv = op.0;
+            let start = oplog.len();
+            match op.1.kind {
+                ListOpKind::Ins => {
+                    let expanded = self.expand_restored((lv..lv + op.len()).into());
+                    let inverse = items.delete_visible(branch, &expanded);
+                    if inverse.is_empty() { continue; }
+                    branch.apply_local_operations(oplog, self.agent, &inverse);
+                }
+                ListOpKind::Del => {
+                    let restored = items.restorable(&tracker.delete_targets((lv..lv + op.len()).into()));
+                    if restored.is_empty() { continue; }
+
+                    // The content goes back in after the character which was before it when it
+                    // was deleted. Anchoring to the left means the content goes back in the same
+                    // place if the characters are restored in reverse order.
+                    tracker.move_to(oplog, parents.as_ref());
+                    let del_start = op.1.start();
+                    let before = (del_start > 0).then(|| {
+                        // If the character has since been deleted and restored, use its stand in.
+                        let item = tracker.item_at(del_start - 1);
+                        self.expand_restored((item..item + 1).into()).iter().rev()
+                            .map(|r| r.start)
+                            .find(|&i| items.is_visible(i))
+                            .unwrap_or(item)
+                    });
+                    let pos = items.pos_after(before);
+
+                    let content = item_content(oplog, &restored);
+                    branch.apply_local_operations(oplog, self.agent, &[TextOperation::new_insert(pos, &content)]);
+                    items.insert(pos, (start..oplog.len()).into());
+
+                    // The inserted characters stand in for the deleted characters if the
+                    // delete's insert is undone later.
+                    let mut next = start;
+                    for range in restored {
+                        self.push_restored(range, next);
+                        next += range.len();
+                    }
+                }
+            }
+            result.push((start..oplog.len()).into());
+        }
+
+        result
+    }
+
+    fn push_restored(&mut self, range: DTRange, new_start: LV) {
+        if let Some((last_range, last_start)) = self.restored.last_mut() {
+            if last_range.end == range.start && *last_start + last_range.len() == new_start {
+                last_range.end = range.end;
+                return;
+            }
+        }
+        self.restored.push((range, new_start));
+    }
+
+    /// Remove the entries in `restored` which aren't connected to any operation in the undo or
+    /// redo stacks. Without this, the list would grow forever.
+    ///
+    /// Entries are kept if they're linked (in either direction) to a kept entry, since undoing a
+    /// delete also uses the stand ins of the characters around the deleted content.
+    fn compact_restored(&mut self) {
+        if self.restored.is_empty() { return; }
+
+        let mut used = vec![false; self.restored.len()];
+        let mut queue: Vec<DTRange> = self.undo_stack.iter().chain(self.redo_stack.iter())
+            .flat_map(|group| group.spans.iter().copied())
+            .collect();
+        while let Some(r) = queue.pop() {
+            for (i, &(orig, new_start)) in self.restored.iter().enumerate() {
+                if used[i] { continue; }
+                let new: DTRange = (new_start..new_start + orig.len()).into();
+                if r.intersect(&orig).is_some() || r.intersect(&new).is_some() {
+                    used[i] = true;
+                    queue.push(orig);
+                    queue.push(new);
+                }
+            }
+        }
+
+        let mut used = used.into_iter();
+        self.restored.retain(|_| used.next().unwrap());
+    }
+
+    /// Find all the characters standing in for the characters in `items`, including the items
+    /// themselves.
+    fn expand_restored(&self, items: DTRange) -> Vec<DTRange> {
+        let mut result = vec![items];
+        let mut i = 0;
+        while i < result.len() {
+            let r = result[i];
+            for &(orig, new_start) in &self.restored {
+                let start = orig.start.max(r.start);
+                let end = orig.end.min(r.end);
+                if start < end {
+                    let offset = start - orig.start;
+                    result.push((new_start + offset..new_start + offset + end - start).into());
+                }
+            }
+            i += 1;
+        }
+        result.sort_unstable_by_key(|r| r.start);
+        result
+    }
+}
+
+/// Every item in the branch in document order, with the number of times each item has been
+/// deleted (0 for visible items). This is built once for each undo, and kept up to date as the
+/// inverse operations are applied.
+struct ItemStates(Vec<(DTRange, usize)>);
+
+impl ItemStates {
+    /// Make operations deleting the characters in `items` which are still visible, and mark them
+    /// as deleted.
+    fn delete_visible(&mut self, branch: &ListBranch, items: &[DTRange]) -> Vec<TextOperation> {
+        let mut ops = vec![];
+        let mut result = Vec::with_capacity(self.0.len());
+        let mut pos = 0;
+        for &(range, deletes) in &self.0 {
+            if deletes > 0 {
+                result.push((range, deletes));
+                continue;
+            }
+
+            let mut cuts: Vec<DTRange> = items.iter().filter_map(|item| {
+                let start = range.start.max(item.start);
+                let end = range.end.min(item.end);
+                (start < end).then(|| (start..end).into())
+            }).collect();
+            cuts.sort_unstable_by_key(|r| r.start);
+
+            let mut next = range.start;
+            for cut in cuts {
+                if next < cut.start { result.push(((next..cut.start).into(), 0)); }
+                let del_pos = pos + cut.start - range.start;
+                ops.push(branch.make_delete_op(del_pos..del_pos + cut.len()));
+                result.push((cut, 1));
+                next = cut.end;
+            }
+            if next < range.end { result.push(((next..range.end).into(), 0)); }
+            pos += range.len();
+        }
+        self.0 = result;
+
+        // The deletes are applied in reverse order so the positions of the remaining deletes are
+        // unaffected.
+        ops.reverse();
+        ops
+    }
+
+    /// Find the items in `targets` which should be inserted again to undo a delete, in document
+    /// order. Items which have also been deleted by another operation stay deleted.
+    fn restorable(&self, targets: &[DTRange]) -> Vec<DTRange> {
+        let mut result: Vec<DTRange> = vec![];
+        for &(range, deletes) in &self.0 {
+            if deletes != 1 { continue; }
+            let run_start = result.len();
+            for target in targets {
+                let start = range.start.max(target.start);
+                let end = range.end.min(target.end);
+                if start < end { result.push((start..end).into()); }
+            }
+            result[run_start..].sort_unstable_by_key(|r| r.start);
+        }
+        result
+    }
+
+    fn is_visible(&self, item: LV) -> bool {
+        self.0.iter().any(|&(range, deletes)| deletes == 0 && range.contains(item))
+    }
+
+    /// The position directly after the named item, or the start of the document if its None. If
+    /// the item isn't visible, this is the position the item would be at.
+    fn pos_after(&self, item: Option<LV>) -> usize {
+        let Some(item) = item else { return 0; };
+        let mut pos = 0;
+        for &(range, deletes) in &self.0 {
+            if range.contains(item) {
+                return if deletes == 0 { pos + item - range.start + 1 } else { pos };
+            }
+            if deletes == 0 { pos += range.len(); }
+        }
+        panic!("Item missing from document");
+    }
+
+    /// Add newly inserted items at `pos`. Like other inserts, they go directly after the item
+    /// before them.
+    fn insert(&mut self, pos: usize, new_items: DTRange) {
+        let mut idx = 0;
+        let mut p = 0;
+        while p < pos {
+            let (range, deletes) = self.0[idx];
+            idx += 1;
+            if deletes > 0 { continue; }
+            if p + range.len() > pos {
+                // Split the run.
+                let split = range.start + pos - p;
+                self.0[idx - 1].0.end = split;
+                self.0.insert(idx, ((split..range.end).into(), 0));
+                break;
+            }
+            p += range.len();
+        }
+        self.0.insert(idx, (new_items, 0));
+    }
+}
+
+/// Get the content of the named items (in document order).
+fn item_content(oplog: &ListOpLog, items: &[DTRange]) -> SmartString {
+    let mut content = SmartString::new();
+    for &range in items {
+        if is_underwater(range.start) {
+            // Characters from a pruned oplog's start content.
+            let start = range.start - UNDERWATER_START;
+            content.extend(oplog.start_content.slice_chars(start..start + range.len()));
+            continue;
+        }
+
+        // The content of an insert is stored in the order the characters were inserted, which is
+        // the same order as their versions.
+        for (op, op_content) in oplog.iter_range_simple(range) {
+            match op_content {
+                Some(c) => content.push_str(c),
+                None => content.extend(std::iter::repeat_n(UNKNOWN_CHAR, op.len())),
+            }
+        }
+    }
+    content
+}
+
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use crate::list::ListOpLog;
+    use crate::list::undo::UndoManager;
+    use crate::list_fuzzer_tools::{random_str, random_version};
+
+    #[test]
+    fn undo_redo_local_edits() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mut branch = oplog.checkout_tip();
+        let mut undo = UndoManager::new(seph);
+
+        undo.insert(&mut oplog, &mut branch, 0, "hello", 0);
+        undo.end_group();
+        undo.insert(&mut oplog, &mut branch, 5, " world", 0);
+        undo.delete(&mut oplog, &mut branch, 0..1, 0);
+        undo.insert(&mut oplog, &mut branch, 0, "H", 0);
+        undo.end_group();
+        assert_eq!(branch.content().to_string(), "Hello world");
+
+        assert!(undo.undo(&mut oplog, &mut branch));
+        assert_eq!(branch.content().to_string(), "hello");
+        assert!(undo.undo(&mut oplog, &mut branch));
+        assert_eq!(branch.content().to_string(), "");
+        assert!(!undo.undo(&mut oplog, &mut branch));
+
+        assert!(undo.redo(&mut oplog, &mut branch));
+        assert_eq!(branch.content().to_string(), "hello");
+        assert!(undo.redo(&mut oplog, &mut branch));
+        assert_eq!(branch.content().to_string(), "Hello world");
+        assert!(!undo.can_redo());
+
+        // New edits clear the redo stack.
+        undo.undo(&mut oplog, &mut branch);
+        undo.insert(&mut oplog, &mut branch, 5, "!", 0);
+        assert!(!undo.can_redo());
+        assert_eq!(branch.content().to_string(), "hello!");
+
+        // Undo and redo are normal edits.
+        assert_eq!(oplog.checkout_tip().content().to_string(), "hello!");
+    }
+
+    #[test]
+    fn groups_by_time() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mut branch = oplog.checkout_tip();
+        let mut undo = UndoManager::new_with_timeout(seph, Some(500));
+
+        undo.insert(&mut oplog, &mut branch, 0, "a", 1000);
+        undo.insert(&mut oplog, &mut branch, 1, "b", 1200);
+        undo.insert(&mut oplog, &mut branch, 2, "c", 1600);
+        undo.insert(&mut oplog, &mut branch, 3, "d", 3000);
+        undo.end_group();
+        undo.insert(&mut oplog, &mut branch, 4, "e", 3100);
+
+        undo.undo(&mut oplog, &mut branch);
+        assert_eq!(branch.content().to_string(), "abcd");
+        undo.undo(&mut oplog, &mut branch);
+        assert_eq!(branch.content().to_string(), "abc");
+        undo.undo(&mut oplog, &mut branch);
+        assert_eq!(branch.content().to_string(), "");
+    }
+
+    #[test]
+    fn undo_only_undoes_own_edits() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(mike, 0, "abc xyz");
+        let base = oplog.local_frontier();
+
+        let mut branch = oplog.checkout(base.as_ref());
+        let mut undo = UndoManager::new(seph);
+        undo.insert(&mut oplog, &mut branch, 3, " hi", 0);
+        undo.end_group();
+        undo.delete(&mut oplog, &mut branch, 7..10, 0);
+        undo.end_group();
+        assert_eq!(branch.content().to_string(), "abc hi ");
+
+        // Mike concurrently edits around the same text.
+        oplog.add_insert_at(mike, base.as_ref(), 0, ">> ");
+        oplog.add_delete_at(mike, base.as_ref(), 5..6);
+        oplog.add_insert_at(mike, base.as_ref(), 7, "!");
+        branch.merge(&oplog, oplog.local_frontier_ref());
+        assert_eq!(branch.content().to_string(), ">> abc hi !");
+
+        // Undoing the delete puts the deleted text back in the right place. Mike's delete of 'y'
+        // isn't undone.
+        undo.undo(&mut oplog, &mut branch);
+        assert_eq!(branch.content().to_string(), ">> abc hi xz!");
+        undo.undo(&mut oplog, &mut branch);
+        assert_eq!(branch.content().to_string(), ">> abc xz!");
+
+        undo.redo(&mut oplog, &mut branch);
+        assert_eq!(branch.content().to_string(), ">> abc hi xz!");
+
+        // Other peers see the same result.
+        assert_eq!(oplog.checkout_tip().content().to_string(), branch.content().to_string());
+    }
+
+    #[test]
+    fn tmp_reversed() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mut branch = oplog.checkout_tip();
+        let mut undo = UndoManager::new(seph);
+        undo.insert(&mut oplog, &mut branch, 0, "xy", 0);
+        undo.end_group();
+        for c in ["c", "b", "a"] { branch.insert(&mut oplog, seph, 1, c); }
+        undo.end_group();
+        undo.delete(&mut oplog, &mut branch, 0..5, 0);
+        undo.end_group();
+        dbg!(&oplog.operations);
+        undo.undo(&mut oplog, &mut branch);
+        assert_eq!(branch.content().to_string(), "xabcy");
+        undo.delete(&mut oplog, &mut branch, 1..3, 0);
+        undo.undo(&mut oplog, &mut branch);
+        assert_eq!(branch.content().to_string(), "xabcy");
+    }
+
+    #[test]
+    fn restored_is_compacted() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mut branch = oplog.checkout_tip();
+        let mut undo = UndoManager::new(seph);
+
+        undo.insert(&mut oplog, &mut branch, 0, "abc", 0);
+        undo.end_group();
+        undo.delete(&mut oplog, &mut branch, 1..2, 0);
+        undo.end_group();
+
+        undo.undo(&mut oplog, &mut branch);
+        undo.undo(&mut oplog, &mut branch);
+        assert_eq!(undo.restored.len(), 1);
+
+        // Nothing can refer to the restored characters once the redo stack is cleared.
+        undo.insert(&mut oplog, &mut branch, 0, "x", 0);
+        assert!(undo.restored.is_empty());
+        assert_eq!(branch.content().to_string(), "x");
+    }
+
+    fn fuzz_undo_redo(seed: u64) {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let mut branch = oplog.checkout_tip();
+        let mut undo = UndoManager::new(seph);
+
+        // With concurrent edits, the content after undo isn't as simple to predict. We just check
+        // that undo works and all peers agree.
+        let concurrent = rng.gen_bool(0.5);
+        let mut history = vec![String::new()];
+
+        for _ in 0..10 {
+            for _ in 0..rng.gen_range(1..4) {
+                let len = branch.len();
+                if len > 0 && rng.gen_bool(0.4) {
+                    let start = rng.gen_range(0..len);
+                    let end = rng.gen_range(start + 1..=len.min(start + 4));
+                    undo.delete(&mut oplog, &mut branch, start..end, 0);
+                } else {
+                    let content = random_str(rng.gen_range(1..4), &mut rng, false);
+                    undo.insert(&mut oplog, &mut branch, rng.gen_range(0..=len), &content, 0);
+                }
+            }
+            undo.end_group();
+
+            if concurrent && rng.gen_bool(0.4) {
+                let mut other = oplog.checkout(random_version(&oplog, &mut rng).as_ref());
+                let len = other.len();
+                if len > 0 && rng.gen_bool(0.5) {
+                    let start = rng.gen_range(0..len);
+                    other.delete(&mut oplog, mike, start..len.min(start + 3));
+                } else {
+                    other.insert(&mut oplog, mike, rng.gen_range(0..=len), "mm");
+                }
+                branch.merge(&oplog, oplog.local_frontier_ref());
+            }
+            history.push(branch.content().to_string());
+
+            if history.len() > 2 && rng.gen_bool(0.2) {
+                // Undo, and drop the redo stack with the next edit.
+                undo.undo(&mut oplog, &mut branch);
+                history.pop();
+            } else if rng.gen_bool(0.3) {
+                undo.undo(&mut oplog, &mut branch);
+                if !concurrent { assert_eq!(branch.content().to_string(), history[history.len() - 2]); }
+                undo.redo(&mut oplog, &mut branch);
+            }
+            if !concurrent { assert_eq!(&branch.content().to_string(), history.last().unwrap()); }
+        }
+
+        // Undo everything, then redo everything.
+        for expected in history.iter().rev().skip(1) {
+            assert!(undo.undo(&mut oplog, &mut branch));
+            if !concurrent { assert_eq!(&branch.content().to_string(), expected); }
+        }
+        assert!(!undo.can_undo());
+        for expected in history.iter().skip(1) {
+            assert!(undo.redo(&mut oplog, &mut branch));
+            if !concurrent { assert_eq!(&branch.content().to_string(), expected); }
+        }
+
+        assert_eq!(oplog.checkout_tip().content().to_string(), branch.content().to_string());
+    }
+
+    #[test]
+    fn fuzz_undo_redo_once() {
+        for seed in 0..100 {
+            fuzz_undo_redo(seed);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn fuzz_undo_redo_forever() {
+        for seed in 0.. {
+            if seed % 100 == 0 { println!("Iteration {}", seed); }
+            fuzz_undo_redo(seed);
+        }
+    }
+}
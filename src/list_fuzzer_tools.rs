@@ -4,12 +4,14 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::sleep;
 use std::time::Duration;
 use rand::prelude::SmallRng;
+use rand::SeedableRng;
 use jumprope::JumpRope;
 use rand::Rng;
 use smallvec::smallvec;
 use rle::MergeableIterator;
 use rle::zip::{rle_zip, rle_zip3};
-use crate::{AgentId, LV};
+use crate::{AgentId, Frontier, LV};
+use crate::list::{ListBranch, ListOpLog};
 use crate::listmerge::simple_oplog::*;
 
 const USE_UNICODE: bool = true;
@@ -110,6 +112,48 @@ pub(crate) fn make_random_change(oplog: &mut SimpleOpLog, branch: &SimpleBranch,
 //     // doc.check(false);
 // }
 
+/// Make an oplog with random concurrent edits from 3 agents, which merge each other's changes from
+/// time to time.
+pub(crate) fn random_oplog(seed: u64, steps: usize) -> ListOpLog {
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut oplog = ListOpLog::new();
+    let agents = ["a", "b", "c"].map(|name| oplog.get_or_create_agent_id(name));
+    let mut branches = [ListBranch::new(), ListBranch::new(), ListBranch::new()];
+
+    for _ in 0..steps {
+        let idx = rng.gen_range(0..branches.len());
+        let branch = &mut branches[idx];
+        let agent = agents[idx];
+
+        let len = branch.len();
+        if len == 0 || rng.gen_bool(0.6) {
+            let pos = rng.gen_range(0..=len);
+            let content = random_str(rng.gen_range(1..4), &mut rng, USE_UNICODE);
+            branch.insert(&mut oplog, agent, pos, &content);
+        } else {
+            let pos = rng.gen_range(0..len);
+            let del_len = rng.gen_range(1..=(len - pos).min(3));
+            branch.delete(&mut oplog, agent, pos..pos + del_len);
+        }
+
+        if rng.gen_bool(0.2) {
+            let other = rng.gen_range(0..branches.len());
+            let version = branches[other].local_frontier();
+            branches[idx].merge(&oplog, version.as_ref());
+        }
+    }
+
+    oplog
+}
+
+/// Pick a random version in the oplog. This is the dominators of 0-2 random operations.
+pub(crate) fn random_version(oplog: &ListOpLog, rng: &mut SmallRng) -> Frontier {
+    let mut v: Vec<LV> = (0..rng.gen_range(0..3)).map(|_| rng.gen_range(0..oplog.len())).collect();
+    v.sort_unstable();
+    v.dedup();
+    oplog.cg.graph.find_dominators(&v)
+}
+
 pub(crate) fn choose_2<'a, T>(arr: &'a mut [T], rng: &mut SmallRng) -> (usize, &'a mut T, usize, &'a mut T) {
     loop {
         // Then merge 2 branches at random
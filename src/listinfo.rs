@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use rle::HasLength;
+use crate::{CausalGraph, CreateValue, DTRange, LV};
+use crate::list::operation::{ListOpKind, TextOperation};
+use crate::listmerge::merge::TransformedResult::{BaseMoved, DeleteAlreadyHappened};
+use crate::textinfo::TextInfo;
+
+/// The oplog data for a list CRDT.
+///
+/// Lists are ordered sequences of values, and they're merged using the same algorithm as text
+/// documents. The positional operations are stored in a [`TextInfo`] (without any content), and
+/// the value of each item is stored separately, keyed by the version of the operation which
+/// inserted it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ListInfo {
+    pub(crate) ops: TextInfo,
+
+    /// The value of every item ever inserted into the list.
+    pub(crate) values: BTreeMap<LV, CreateValue>,
+
+    /// The items in the list at the current version (in order), named by the version of their
+    /// insert.
+    pub(crate) items: Vec<LV>,
+}
+
+impl ListInfo {
+    /// Iterate over the items in the list at the current version.
+    pub(crate) fn live_items(&self) -> impl Iterator<Item = (LV, &CreateValue)> + '_ {
+        self.items.iter().map(|item| (*item, &self.values[item]))
+    }
+
+    /// Apply the changes between two versions to a list of items. This is the list equivalent of
+    /// [`TextInfo::merge_into`].
+    ///
+    /// `new_item` is called to create each inserted item, and `removed` is called with each item
+    /// deleted by the merged operations.
+    pub(crate) fn merge_into<T, N, R>(&self, items: &mut Vec<T>, cg: &CausalGraph, from: &[LV], merge_frontier: &[LV], mut new_item: N, mut removed: R)
+        where N: FnMut(LV) -> T, R: FnMut(T)
+    {
+        self.ops.with_xf_iter(cg, from, merge_frontier, |iter, _| {
+            for (lv, origin_op, xf) in iter {
+                let len = origin_op.len();
+                match (origin_op.kind, xf) {
+                    (ListOpKind::Ins, BaseMoved(pos)) => {
+                        assert!(pos <= items.len());
+                        let ids = lv..lv + len;
+                        if origin_op.loc.fwd {
+                            items.splice(pos..pos, ids.map(&mut new_item));
+                        } else {
+                            items.splice(pos..pos, ids.rev().map(&mut new_item));
+                        }
+                    }
+
+                    (_, DeleteAlreadyHappened) => {}, // Discard.
+
+                    (ListOpKind::Del, BaseMoved(pos)) => {
+                        assert!(pos + len <= items.len());
+                        items.drain(pos..pos + len).for_each(&mut removed);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Get the items in the list at some (possibly historical) version.
+    pub(crate) fn items_at(&self, cg: &CausalGraph, frontier: &[LV]) -> Vec<LV> {
+        let mut result = vec![];
+        self.merge_into(&mut result, cg, &[], frontier, |lv| lv, |_| {});
+        result
+    }
+
+    /// Insert a new item at the current version.
+    pub(crate) fn local_insert(&mut self, v: LV, pos: usize, value: CreateValue) {
+        assert!(pos <= self.items.len());
+        self.ops.local_push_op(list_insert_op(pos, 1), (v..v + 1).into());
+        self.values.insert(v, value);
+        self.items.insert(pos, v);
+    }
+
+    /// Delete the items in the named range at the current version. Returns the deleted items.
+    pub(crate) fn local_delete(&mut self, v_range: DTRange, del_range: DTRange) -> Vec<LV> {
+        assert!(del_range.end <= self.items.len());
+        self.ops.local_push_op(TextOperation::new_delete(del_range.into()), v_range);
+        self.items.drain(del_range.start..del_range.end).collect()
+    }
+
+    /// Add an operation from a remote peer. `values` names the value of each inserted item (and
+    /// must be empty for deletes). Returns the items which were deleted in the current version.
+    pub(crate) fn remote_push_op(&mut self, cg: &CausalGraph, v_range: DTRange, op: TextOperation, values: Vec<CreateValue>) -> Vec<LV> {
+        debug_assert!(op.content.is_none());
+        debug_assert_eq!(v_range.len(), op.len());
+        if op.kind == ListOpKind::Ins {
+            assert_eq!(values.len(), op.len());
+            self.values.extend(v_range.iter().zip(values));
+        } else {
+            assert!(values.is_empty());
+        }
+
+        let old_frontier = self.ops.frontier.clone();
+        self.ops.remote_push_op_unknown_parents(op, v_range, &cg.graph);
+
+        // The new operation might be concurrent with other operations in the list, so merge it
+        // into the current items.
+        let mut removed = vec![];
+        let mut items = std::mem::take(&mut self.items);
+        self.merge_into(&mut items, cg, old_frontier.as_ref(), self.ops.frontier.as_ref(), |lv| lv, |lv| removed.push(lv));
+        self.items = items;
+        removed
+    }
+}
+
+/// An insert of `len` items at `pos`. List operations never store content.
+pub(crate) fn list_insert_op(pos: usize, len: usize) -> TextOperation {
+    TextOperation {
+        loc: (pos..pos + len).into(),
+        kind: ListOpKind::Ins,
+        content: None,
+    }
+}
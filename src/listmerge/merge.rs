@@ -11,7 +11,7 @@ use content_tree::*;
 use rle::{AppendRle, HasLength, MergeableIterator, Searchable, Trim, TrimCtx};
 use rle::intersect::rle_intersect_rev;
 use crate::listmerge::{DocRangeIndex, M2Tracker, SpaceIndex};
-use crate::listmerge::yjsspan::{INSERTED, NOT_INSERTED_YET, FugueSpan};
+use crate::listmerge::yjsspan::{deleted_n_state, INSERTED, NOT_INSERTED_YET, FugueSpan};
 use crate::list::operation::{ListOpKind, TextOperation};
 use crate::dtrange::{DTRange, UNDERWATER_START};
 use crate::rle::{KVPair, RleSpanHelpers, RleVec};
@@ -40,6 +40,10 @@ use crate::list::ListOpLog;
 #[cfg(feature = "ops_to_old")]
 use crate::listmerge::to_old::OldCRDTOpInternal;
 use crate::unicount::consume_chars;
+use crate::encoding::bufparser::BufParser;
+use crate::encoding::parseerror::ParseError;
+use crate::encoding::varint::{mix_bit_usize, push_usize, strip_bit_usize};
+use crate::frontier::is_sorted_slice;
 
 const ALLOW_FF: bool = true;
 
@@ -97,8 +101,8 @@ impl M2Tracker {
         Self {
             range_tree,
             index,
-            #[cfg(feature = "merge_conflict_checks")]
-            concurrent_inserts_collide: false,
+            insert_collisions: vec![],
+            report_collisions: false,
             #[cfg(feature = "ops_to_old")]
             dbg_ops: vec![]
         }
@@ -176,10 +180,9 @@ impl M2Tracker {
 
             // When preparing example data, its important that the data can merge the same
             // regardless of editing trace (so the output isn't dependent on the algorithm used to
-            // merge).
-            #[cfg(feature = "merge_conflict_checks")] {
-                //println!("Concurrent changes {:?} vs {:?}", item.id, other_entry.id);
-                self.concurrent_inserts_collide = true;
+            // merge). Collisions are also reported to applications.
+            if self.report_collisions {
+                self.insert_collisions.push((item.id, other_entry.id));
             }
 
             // This code could be better optimized, but its already O(n * log n), and its extremely
@@ -623,6 +626,218 @@ pub(crate) struct TransformedOpsIter<'a> {
 
     // TODO: This tracker allocates - which we don't need to do if we're FF-ing.
     phase2: Option<(M2Tracker, SpanningTreeWalker<'a>)>,
+
+    /// A checkpoint to resume from instead of building a new tracker, if its usable.
+    checkpoint: Option<TrackerCheckpoint>,
+    /// The version of the tracker's underwater content.
+    tracker_base: Frontier,
+    /// Should the tracker record where merged inserts collide with concurrent inserts?
+    report_collisions: bool,
+}
+
+/// Saved merge state, which lets a later merge resume from where an earlier merge finished instead
+/// of rebuilding the tracker from scratch. Merging usually needs to replay all the operations since
+/// the common ancestor of the merged versions, which is slow when concurrent branches are long
+/// lived. See [`ListBranch::merge_with_checkpoint`](crate::list::ListBranch::merge_with_checkpoint).
+///
+/// Checkpoints are only valid for the oplog they were made with. They're used when the next merge
+/// is from a version which contains everything in the checkpoint, and which doesn't have
+/// operations concurrent with the checkpoint's base. Otherwise they're ignored.
+///
+/// A checkpoint holds the whole merge tracker, which grows with every operation merged through
+/// it. So keeping a checkpoint in memory uses *more* memory than merging without one. Use
+/// [`encode`](TrackerCheckpoint::encode) and [`decode`](TrackerCheckpoint::decode) to persist
+/// checkpoints at chosen versions instead of holding them.
+#[derive(Debug)]
+pub struct TrackerCheckpoint {
+    tracker: M2Tracker,
+    /// The tracker's underwater content is the document at this version.
+    base: Frontier,
+    /// The tracker contains every operation in this version (after base).
+    applied: Frontier,
+    /// The version the tracker's state is currently at.
+    version: Frontier,
+}
+
+/// Bumped whenever the encoding of checkpoints changes.
+const CHECKPOINT_FORMAT_VERSION: usize = 0;
+
+fn write_frontier(into: &mut Vec<u8>, frontier: &Frontier) {
+    push_usize(into, frontier.len());
+    for v in frontier.iter() {
+        push_usize(into, *v);
+    }
+}
+
+fn read_frontier(buf: &mut BufParser) -> Result<Frontier, ParseError> {
+    let len = buf.next_usize()?;
+    let versions = (0..len)
+        .map(|_| buf.next_usize())
+        .collect::<Result<SmallVec<[LV; 2]>, _>>()?;
+    if !is_sorted_slice::<true, _>(&versions) { return Err(ParseError::GenericInvalidData); }
+    Ok(Frontier::from_sorted(&versions))
+}
+
+/// Returns true if every version in span is in one of the (sorted, non-overlapping) ids.
+fn ids_cover(ids: &[DTRange], span: DTRange) -> bool {
+    let mut pos = span.start;
+    let mut i = ids.partition_point(|r| r.end <= pos);
+    while pos < span.end {
+        match ids.get(i) {
+            Some(r) if r.start <= pos => {
+                pos = r.end;
+                i += 1;
+            }
+            _ => { return false; }
+        }
+    }
+    true
+}
+
+impl TrackerCheckpoint {
+    /// The number of entries in the saved tracker. This is a rough measure of its memory usage.
+    pub fn num_entries(&self) -> usize {
+        self.tracker.range_tree.count_entries()
+    }
+
+    /// Encode the checkpoint, so it can be saved (eg in the storage engine) and dropped from
+    /// memory. Use [`decode`](TrackerCheckpoint::decode) to load it again.
+    ///
+    /// Checkpoints name operations by their local versions, so the encoded checkpoint can only be
+    /// used with the oplog it was made with (or a copy with the same local versions).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = vec![];
+        push_usize(&mut result, CHECKPOINT_FORMAT_VERSION);
+        write_frontier(&mut result, &self.base);
+        write_frontier(&mut result, &self.applied);
+        write_frontier(&mut result, &self.version);
+
+        // The items in document order.
+        push_usize(&mut result, self.tracker.range_tree.count_entries());
+        for item in self.tracker.range_tree.raw_iter() {
+            push_usize(&mut result, item.id.len());
+            push_usize(&mut result, item.id.start);
+            push_usize(&mut result, item.origin_left);
+            push_usize(&mut result, item.right_parent);
+            let state = if item.state == NOT_INSERTED_YET { 0 } else { 1 + item.state.num_deletes() };
+            push_usize(&mut result, mix_bit_usize(state, item.ever_deleted));
+        }
+
+        // And the target of each delete. Inserts in the index are rebuilt from the items.
+        let mut deletes: Vec<(LV, RangeRev)> = vec![];
+        let mut pos = 0;
+        for entry in self.tracker.index.raw_iter() {
+            if let DelTarget(target) = entry.inner {
+                deletes.push((pos, target));
+            }
+            pos += entry.len;
+        }
+        push_usize(&mut result, deletes.len());
+        for (lv, target) in deletes {
+            push_usize(&mut result, lv);
+            push_usize(&mut result, mix_bit_usize(target.span.len(), target.fwd));
+            push_usize(&mut result, target.span.start);
+        }
+
+        result
+    }
+
+    /// Load a checkpoint saved with [`encode`](TrackerCheckpoint::encode). Like any other
+    /// checkpoint, its ignored if it can't be used for a merge.
+    pub fn decode(data: &[u8]) -> Result<Self, ParseError> {
+        let mut buf = BufParser(data);
+        if buf.next_usize()? != CHECKPOINT_FORMAT_VERSION {
+            return Err(ParseError::UnsupportedProtocolVersion);
+        }
+        let base = read_frontier(&mut buf)?;
+        let applied = read_frontier(&mut buf)?;
+        let version = read_frontier(&mut buf)?;
+
+        let read_span = |buf: &mut BufParser, len: usize| -> Result<DTRange, ParseError> {
+            let start = buf.next_usize()?;
+            let end = start.checked_add(len)
+                .filter(|_| len > 0)
+                .ok_or(ParseError::GenericInvalidData)?;
+            Ok((start..end).into())
+        };
+
+        let num_items = buf.next_usize()?;
+        let mut items: Vec<FugueSpan> = vec![];
+        for _ in 0..num_items {
+            let len = buf.next_usize()?;
+            let id = read_span(&mut buf, len)?;
+            let origin_left = buf.next_usize()?;
+            let right_parent = buf.next_usize()?;
+            let (state, ever_deleted) = strip_bit_usize(buf.next_usize()?);
+            let state = match state {
+                0 => NOT_INSERTED_YET,
+                1 => INSERTED,
+                n => deleted_n_state(u32::try_from(n - 1).map_err(|_| ParseError::GenericInvalidData)?),
+            };
+            items.push(FugueSpan {
+                id,
+                origin_left,
+                right_parent,
+                state,
+                ever_deleted,
+            });
+        }
+
+        let num_deletes = buf.next_usize()?;
+        let mut deletes: Vec<(DTRange, RangeRev)> = vec![];
+        for _ in 0..num_deletes {
+            let lv = buf.next_usize()?;
+            let (len, fwd) = strip_bit_usize(buf.next_usize()?);
+            let span = read_span(&mut buf, len)?;
+            let lv_end = lv.checked_add(len).ok_or(ParseError::GenericInvalidData)?;
+            deletes.push(((lv..lv_end).into(), RangeRev { span, fwd }));
+        }
+        buf.expect_empty()?;
+
+        // Items and deletes can't overlap. Item parents and delete targets must be items.
+        let mut ids: Vec<DTRange> = items.iter().map(|item| item.id).collect();
+        ids.sort_unstable_by_key(|r| r.start);
+        let mut all_ids: Vec<DTRange> = ids.iter().copied()
+            .chain(deletes.iter().map(|(lvs, _)| *lvs))
+            .collect();
+        all_ids.sort_unstable_by_key(|r| r.start);
+        if all_ids.windows(2).any(|w| w[0].end > w[1].start) {
+            return Err(ParseError::GenericInvalidData);
+        }
+
+        let is_item = |lv: LV| lv == usize::MAX || ids_cover(&ids, (lv..lv + 1).into());
+        if !items.iter().all(|item| is_item(item.origin_left) && is_item(item.right_parent))
+            || !deletes.iter().all(|(_, target)| ids_cover(&ids, target.span)) {
+            return Err(ParseError::GenericInvalidData);
+        }
+
+        let mut range_tree = ContentTreeRaw::new();
+        let mut index = ContentTreeRaw::new();
+        pad_index_to(&mut index, all_ids.iter().map(|r| r.end).max().unwrap_or(0));
+        for item in items {
+            range_tree.push_notify(item, notify_for(&mut index));
+        }
+        for (lvs, target) in deletes {
+            index.replace_range_at_offset(lvs.start, MarkerEntry {
+                len: lvs.len(),
+                inner: DelTarget(target),
+            });
+        }
+
+        Ok(TrackerCheckpoint {
+            tracker: M2Tracker {
+                range_tree,
+                index,
+                insert_collisions: vec![],
+                report_collisions: false,
+                #[cfg(feature = "ops_to_old")]
+                dbg_ops: vec![]
+            },
+            base,
+            applied,
+            version,
+        })
+    }
 }
 
 impl<'a> TransformedOpsIter<'a> {
@@ -682,18 +897,89 @@ impl<'a> TransformedOpsIter<'a> {
             new_ops,
             next_frontier: Frontier::from(from_frontier),
             phase2: None,
+            checkpoint: None,
+            tracker_base: Frontier::root(),
+            report_collisions: false,
         }
     }
 
+    pub(crate) fn new_with_checkpoint(subgraph: &'a Graph, aa: &'a AgentAssignment, op_ctx: &'a ListOperationCtx, ops: &'a RleVec<KVPair<ListOpMetrics>>, from_frontier: &[LV], merge_frontier: &[LV], checkpoint: Option<TrackerCheckpoint>) -> Self {
+        let mut iter = Self::new(subgraph, aa, op_ctx, ops, from_frontier, merge_frontier);
+        iter.checkpoint = checkpoint;
+        iter
+    }
+
     pub(crate) fn into_frontier(self) -> Frontier {
         self.next_frontier
     }
 
+    /// Consume the iterator, returning the merged frontier and a checkpoint for resuming later
+    /// merges. The iterator should be exhausted first.
+    pub(crate) fn into_frontier_and_checkpoint(self) -> (Frontier, Option<TrackerCheckpoint>) {
+        let checkpoint = match self.phase2 {
+            Some((mut tracker, walker)) => {
+                tracker.insert_collisions.clear();
+                tracker.report_collisions = false;
+                Some(TrackerCheckpoint {
+                    tracker,
+                    base: self.tracker_base,
+                    applied: self.next_frontier.clone(),
+                    version: walker.into_frontier(),
+                })
+            }
+            // We didn't need a tracker. The checkpoint we were given (if any) is still valid.
+            None => self.checkpoint,
+        };
+        (self.next_frontier, checkpoint)
+    }
+
+    /// Check if the checkpoint can be used to merge from our current frontier.
+    fn checkpoint_usable(&self, cp: &TrackerCheckpoint) -> bool {
+        // Decoded checkpoints could name versions from some other oplog.
+        let len = self.subgraph.len();
+        if [&cp.base, &cp.applied, &cp.version].iter().any(|f| f.iter().any(|v| *v >= len)) {
+            return false;
+        }
+
+        // The checkpoint can't contain anything we don't have.
+        if !self.subgraph.frontier_contains_frontier(self.next_frontier.as_ref(), cp.applied.as_ref()) {
+            return false;
+        }
+
+        // The merged version must contain the checkpoint's base, and every operation the tracker
+        // still needs to add must come after it. The tracker can't retreat anything underwater, so
+        // operations concurrent with the base can't be merged.
+        let merged = self.subgraph.find_dominators_2(self.next_frontier.as_ref(), self.merge_frontier.as_ref());
+        if !self.subgraph.frontier_contains_frontier(merged.as_ref(), cp.base.as_ref()) {
+            return false;
+        }
+
+        let (_, merge_spans) = self.subgraph.diff_rev(cp.applied.as_ref(), merged.as_ref());
+        merge_spans.iter().all(|span| {
+            self.subgraph.iter_range(*span).all(|entry| {
+                self.subgraph.frontier_contains_frontier(entry.parents.as_ref(), cp.base.as_ref())
+            })
+        })
+    }
+
+    /// Record where merged inserts collide with concurrent inserts, so they can be read with
+    /// [`take_insert_collisions`](Self::take_insert_collisions). This is off by default, since
+    /// most merges don't need it.
+    pub(crate) fn report_insert_collisions(&mut self) {
+        self.report_collisions = true;
+    }
+
     /// Returns if concurrent inserts ever collided at the same location while traversing.
     #[cfg(feature = "merge_conflict_checks")]
     pub(crate) fn concurrent_inserts_collided(&self) -> bool {
-        self.phase2.as_ref().map_or(false, |(tracker, _)| {
-            tracker.concurrent_inserts_collide
+        self.phase2.as_ref().is_some_and(|(tracker, _)| !tracker.insert_collisions.is_empty())
+    }
+
+    /// Take the list of (inserted span, concurrent span) pairs which collided since this was last
+    /// called.
+    pub(crate) fn take_insert_collisions(&mut self) -> Vec<(DTRange, DTRange)> {
+        self.phase2.as_mut().map_or_else(Vec::new, |(tracker, _)| {
+            std::mem::take(&mut tracker.insert_collisions)
         })
     }
 }
@@ -888,17 +1174,41 @@ impl<'a> Iterator for TransformedOpsIter<'a> {
             None => {
                 // First time through this code we'll end up here. Walk the conflicting
                 // operations to populate the tracker and walker structures.
-                let mut tracker = M2Tracker::new();
-                // dbg!(&self.conflict_ops);
-                let frontier = tracker.walk(
-                    self.subgraph, self.aa,
-                    self.op_ctx,
-                    self.ops,
-                    std::mem::take(&mut self.common_ancestor),
-                    &self.conflict_ops,
-                    None);
+                let checkpoint = self.checkpoint.take()
+                    .filter(|cp| self.checkpoint_usable(cp));
+
+                let (mut tracker, frontier) = if let Some(cp) = checkpoint {
+                    // Resume from the checkpoint. We only need to add the operations we have which
+                    // the checkpoint doesn't.
+                    let (spans, _) = self.subgraph.diff_rev(self.next_frontier.as_ref(), cp.applied.as_ref());
+                    let mut tracker = cp.tracker;
+                    let frontier = tracker.walk(
+                        self.subgraph, self.aa,
+                        self.op_ctx,
+                        self.ops,
+                        cp.version,
+                        &spans,
+                        None);
+                    self.tracker_base = cp.base;
+                    (tracker, frontier)
+                } else {
+                    let mut tracker = M2Tracker::new();
+                    self.tracker_base = self.common_ancestor.clone();
+                    // dbg!(&self.conflict_ops);
+                    let frontier = tracker.walk(
+                        self.subgraph, self.aa,
+                        self.op_ctx,
+                        self.ops,
+                        std::mem::take(&mut self.common_ancestor),
+                        &self.conflict_ops,
+                        None);
+                    (tracker, frontier)
+                };
                 // dbg!(&tracker);
 
+                // Collisions are only reported for the operations being merged.
+                tracker.report_collisions = self.report_collisions;
+
                 let walker = SpanningTreeWalker::new(self.subgraph, &self.new_ops, frontier);
                 self.phase2 = Some((tracker, walker));
                 // This is a kinda gross way to do this. TODO: Rewrite without .unwrap() somehow?
@@ -966,6 +1276,127 @@ pub fn reverse_str(s: &str) -> SmartString {
     result
 }
 
+/// Make a tracker containing every operation up to frontier, with the item states at frontier.
+fn tracker_at(cg: &CausalGraph, ctx: &ListOperationCtx, ops: &RleVec<KVPair<ListOpMetrics>>, frontier: &[LV]) -> M2Tracker {
+    // This uses the same subgraph trick as TextInfo::with_xf_iter.
+    let conflict = cg.graph.find_conflicting_simple(&[], frontier);
+    let op_spans = ops.iter().map(|e| e.span())
+        .rev()
+        .merge_spans_rev();
+    let iter = rle_intersect_rev(op_spans, conflict.rev_spans.iter().copied())
+        .map(|pair| pair.0);
+
+    let (subgraph, _ff) = cg.graph.subgraph_raw(iter.clone(), frontier);
+    let frontier = cg.graph.project_onto_subgraph_raw(iter.clone(), frontier);
+    let rev_spans: Vec<DTRange> = iter.collect();
+
+    let mut tracker = M2Tracker::new();
+    let walk_end = tracker.walk(&subgraph, &cg.agent_assignment, ctx, ops, Frontier::root(), &rev_spans, None);
+
+    // The walk finishes at some arbitrary version. Move the tracker to the requested version so
+    // the item states are correct.
+    let (retreat_rev, advance_rev) = subgraph.diff_rev(walk_end.as_ref(), frontier.as_ref());
+    for range in retreat_rev {
+        tracker.retreat_by_range(range);
+    }
+    for range in advance_rev.into_iter().rev() {
+        tracker.advance_by_range(range);
+    }
+    tracker
+}
+
+impl M2Tracker {
+    /// List the items in the tracker in document order. Underwater items past `start_len` are
+    /// skipped.
+    fn items_in_order<T, F: Fn(&FugueSpan) -> T>(&self, start_len: usize, f: F) -> Vec<(DTRange, T)> {
+        let start_end = UNDERWATER_START + start_len;
+        self.range_tree.iter()
+            .filter(|e| e.id.start < start_end || !e.is_underwater())
+            .map(|e| {
+                let id = if e.is_underwater() { DTRange::new(e.id.start, e.id.end.min(start_end)) } else { e.id };
+                (id, f(&e))
+            })
+            .collect()
+    }
+
+    /// The items deleted by the delete operations in `range`.
+    fn delete_targets(&self, mut range: DTRange) -> Vec<DTRange> {
+        let mut result = vec![];
+        while !range.is_empty() {
+            let cursor = self.index.cursor_at_offset_pos(range.start, false);
+            let entry = cursor.get_raw_entry();
+            let len = usize::min(entry.len - cursor.offset, range.len());
+            let DelTarget(target) = entry.inner else {
+                panic!("Operation is not a delete");
+            };
+            result.push(target.range(cursor.offset, cursor.offset + len));
+            range.start += len;
+        }
+        result
+    }
+}
+
+/// List every item inserted into a document at some version in document order, along with whether
+/// each item is visible at that version. Unlike merge_into, deleted items are included - which is
+/// what lets data anchored to an item keep its place once the item has been deleted.
+///
+/// `start_len` is the number of items in the document before the first operation in `ops` (ie, the
+/// length of a pruned oplog's start content). These items are named by underwater versions.
+pub(crate) fn items_in_order(cg: &CausalGraph, ctx: &ListOperationCtx, ops: &RleVec<KVPair<ListOpMetrics>>, frontier: &[LV], start_len: usize) -> Vec<(DTRange, bool)> {
+    tracker_at(cg, ctx, ops, frontier)
+        .items_in_order(start_len, |e| e.state == INSERTED)
+}
+
+/// A tracker containing every item in a document, which can be moved between versions to look up
+/// the document's items at each version.
+pub(crate) struct ItemTracker {
+    tracker: M2Tracker,
+    version: Frontier,
+    start_len: usize,
+}
+
+impl ItemTracker {
+    /// Make a tracker with every operation in the oplog up to `version`.
+    pub(crate) fn new(oplog: &ListOpLog, version: &[LV]) -> Self {
+        Self {
+            tracker: tracker_at(&oplog.cg, &oplog.operation_ctx, &oplog.operations, version),
+            version: version.into(),
+            start_len: oplog.start_content.len_chars(),
+        }
+    }
+
+    /// List every item in document order, with the number of times each item has been deleted at
+    /// the tracker's version (which is 0 for visible items).
+    pub(crate) fn items_with_deletes(&self) -> Vec<(DTRange, usize)> {
+        self.tracker.items_in_order(self.start_len, |e| e.state.num_deletes())
+    }
+
+    /// The items deleted by the delete operations in `range`. The operations must be in the
+    /// history of the tracker's version.
+    pub(crate) fn delete_targets(&self, range: DTRange) -> Vec<DTRange> {
+        self.tracker.delete_targets(range)
+    }
+
+    /// Move the tracker to another version. This only needs to replay the operations which differ
+    /// between the versions.
+    pub(crate) fn move_to(&mut self, oplog: &ListOpLog, version: &[LV]) {
+        let (retreat_rev, advance_rev) = oplog.cg.graph.diff_rev(self.version.as_ref(), version);
+        for range in retreat_rev {
+            self.tracker.retreat_by_range(range);
+        }
+        for range in advance_rev.into_iter().rev() {
+            self.tracker.advance_by_range(range);
+        }
+        self.version = version.into();
+    }
+
+    /// The visible item at `pos` in the document, at the tracker's version.
+    pub(crate) fn item_at(&self, pos: usize) -> LV {
+        let cursor = self.tracker.range_tree.cursor_at_content_pos(pos, false);
+        cursor.get_item().unwrap()
+    }
+}
+
 impl TextInfo {
     pub(crate) fn get_xf_operations_full<'a>(&'a self, subgraph: &'a Graph, aa: &'a AgentAssignment, from: &[LV], merging: &[LV]) -> TransformedOpsIter<'a> {
         TransformedOpsIter::new(subgraph, aa, &self.ctx, &self.ops, from, merging)
@@ -1006,6 +1437,12 @@ impl TextInfo {
         f(iter, final_frontier)
     }
 
+    /// List every item inserted into the document at some version in document order, along with
+    /// whether each item is visible at that version. See [`items_in_order`].
+    pub(crate) fn items_in_order(&self, cg: &CausalGraph, frontier: &[LV]) -> Vec<(DTRange, bool)> {
+        items_in_order(cg, &self.ctx, &self.ops, frontier, 0)
+    }
+
     /// Iterate through all the *transformed* operations from some point in time. Internally, the
     /// OpLog stores all changes as they were when they were created. This makes a lot of sense from
     /// CRDT academic point of view (and makes signatures and all that easy). But its is rarely
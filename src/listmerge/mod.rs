@@ -15,6 +15,7 @@ use content_tree::{ContentTreeRaw, RawPositionMetricsUsize};
 use crate::listmerge::markers::MarkerEntry;
 use crate::listmerge::metrics::MarkerMetrics;
 use crate::listmerge::yjsspan::FugueSpan;
+use crate::DTRange;
 
 mod yjsspan;
 pub(crate) mod merge;
@@ -47,8 +48,11 @@ struct M2Tracker {
     /// - For deletes, this names the time at which the delete happened.
     index: SpaceIndex,
 
-    #[cfg(feature = "merge_conflict_checks")]
-    concurrent_inserts_collide: bool,
+    /// Every time an inserted item is integrated next to concurrent inserts, we record the
+    /// (inserted span, concurrent span) pair here when report_collisions is set. This is used to
+    /// report conflicts.
+    insert_collisions: Vec<(DTRange, DTRange)>,
+    report_collisions: bool,
 
     #[cfg(feature = "ops_to_old")]
     dbg_ops: Vec<to_old::OldCRDTOpInternal>,
@@ -54,6 +54,12 @@ pub struct FugueSpan {
 }
 
 impl FugueSpanState {
+    /// The number of times the item has been deleted. This is 0 for items which are visible (or
+    /// not inserted yet).
+    pub(crate) fn num_deletes(self) -> usize {
+        self.0.saturating_sub(1) as usize
+    }
+
     /// Note this doesn't (can't) set the ever_deleted flag. Use yjsspan.delete() instead.
     fn delete(&mut self) {
         if self.0 == NOT_INSERTED_YET.0 {
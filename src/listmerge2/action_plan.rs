@@ -725,6 +725,7 @@ mod test {
     #[test]
     fn test_trivial_graphs() {
         let mut g = ConflictSubgraph {
+            base: Frontier::root(),
             ops: vec![],
             // last: usize::MAX,
         };
@@ -738,6 +739,7 @@ mod test {
         ]);
 
         let mut g = ConflictSubgraph {
+            base: Frontier::root(),
             ops: vec![
                 ConflictGraphEntry {
                     parents: smallvec![],
@@ -762,6 +764,7 @@ mod test {
         ]);
 
         let mut g = ConflictSubgraph {
+            base: Frontier::root(),
             ops: vec![
                 ConflictGraphEntry {
                     parents: smallvec![1, 2],
@@ -798,6 +801,7 @@ mod test {
     #[test]
     fn diamonds() {
         let mut g: ConflictSubgraph<EntryState> = ConflictSubgraph {
+            base: Frontier::root(),
             ops: vec![
                 ConflictGraphEntry { // 0 Y
                     parents: smallvec![1, 2],
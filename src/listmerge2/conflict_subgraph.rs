@@ -5,7 +5,7 @@ use std::fmt::Debug;
 use crate::causalgraph::graph::Graph;
 use crate::causalgraph::graph::tools::DiffFlag;
 use crate::listmerge2::{ConflictGraphEntry, ConflictSubgraph};
-use crate::{CausalGraph, LV};
+use crate::{CausalGraph, Frontier, LV};
 
 
 // Sorted highest to lowest (so we compare the highest first).
@@ -41,10 +41,12 @@ struct QueueEntry {
     version: RevSortFrontier,
     flag: DiffFlag,
     // These are indexes into the output for child items that need their parents updated when
-    // they get inserted.
+    // they get inserted. NO_CHILD for markers, which only track which versions are shared.
     child_index: usize,
 }
 
+const NO_CHILD: usize = usize::MAX;
+
 impl PartialEq<Self> for QueueEntry {
     fn eq(&self, other: &Self) -> bool {
         // self.frontier == other.frontier
@@ -80,13 +82,14 @@ impl Graph {
     /// This method also contains the complexity of:
     ///
     /// - diff / find_conflicting. The resulting conflict subgraph only contains items which
-    ///   are in the difference between parameter frontiers `a` and `b`.
+    ///   are in the difference between parameter frontiers `a` and `b`, and the shared items
+    ///   needed to connect them. The walk stops at the first version which is in the history of
+    ///   both, and that version is stored as the subgraph's `base`.
     /// - (soon) subgraph.
     pub(crate) fn make_conflict_graph_between<S: Default>(&self, a: &[LV], b: &[LV]) -> ConflictSubgraph<S> {
-        // TODO: Short circuits.
         if a == b {
             // Nothing to do here.
-            return ConflictSubgraph { ops: vec![] };
+            return ConflictSubgraph { ops: vec![], base: a.into() };
         }
 
         // let mut result: Vec<ActionGraphEntry> = vec![];
@@ -109,15 +112,22 @@ impl Graph {
         });
 
         let mut root_children: SmallVec<[usize; 2]> = smallvec![];
+        let mut base = Frontier::root();
 
         // The heap is sorted such that we pull the highest items first.
         let mut queue: BinaryHeap<QueueEntry> = BinaryHeap::new();
 
-        if !self.frontier_contains_frontier(b, a) {
-            queue.push(QueueEntry { version: a.into(), flag: DiffFlag::OnlyA, child_index: 0 });
-        }
-        if !self.frontier_contains_frontier(a, b) {
-            queue.push(QueueEntry { version: b.into(), flag: DiffFlag::OnlyB, child_index: 0 });
+        // If one version contains the other, the contained version is still added as a set of
+        // markers. They don't appear in the output, but they let us notice when the walk reaches
+        // shared history.
+        for (v, other, flag) in [(a, b, DiffFlag::OnlyA), (b, a, DiffFlag::OnlyB)] {
+            if !self.frontier_contains_frontier(other, v) {
+                queue.push(QueueEntry { version: v.into(), flag, child_index: 0 });
+            } else {
+                for &v in v {
+                    queue.push(QueueEntry { version: v.into(), flag, child_index: NO_CHILD });
+                }
+            }
         }
 
         // Loop until we've collapsed the graph down to a single element.
@@ -125,25 +135,42 @@ impl Graph {
             // println!("pop {:?}", &entry);
             let mut flag = entry.flag;
             let Some((&v, merged_with)) = entry.version.0.split_last() else {
-                root_children.push(entry.child_index);
+                if entry.child_index != NO_CHILD { root_children.push(entry.child_index); }
                 continue;
             };
 
-            let mut num_children = 1;
+            let mut num_children = 0;
 
             // Regardless of if its a merge or not, we'll mark the entry as pointing here.
             let mut new_index = result.len();
-            result[entry.child_index].parents.push(new_index);
+            if entry.child_index != NO_CHILD {
+                num_children += 1;
+                result[entry.child_index].parents.push(new_index);
+            }
 
             while let Some(peek_entry) = queue.peek() {
                 if peek_entry.version == entry.version {
                     if peek_entry.flag != flag { flag = DiffFlag::Shared; }
-                    num_children += 1;
-                    result[peek_entry.child_index].parents.push(new_index);
+                    if peek_entry.child_index != NO_CHILD {
+                        num_children += 1;
+                        result[peek_entry.child_index].parents.push(new_index);
+                    }
                     queue.pop();
                 } else { break; }
             }
 
+            // Markers are always merged into the walk from the other version. This is just
+            // defensive.
+            if num_children == 0 { continue; }
+
+            if flag == DiffFlag::Shared && queue.is_empty() {
+                // Everything from here back is in the history of both versions. Stop here, and
+                // use this version as the root.
+                base = Frontier::from_sorted(&entry.version.0);
+                result.push(Self::root_entry(num_children));
+                break;
+            }
+
             if !merged_with.is_empty() {
                 // Merge. We'll make an entry just for this merge because it keeps the logic here
                 // more simple.
@@ -216,8 +243,10 @@ impl Graph {
 
                 if peek_v == last {
                     // Just add to new_item.
-                    num_children += 1;
-                    result[peek_entry.child_index].parents.push(new_index);
+                    if peek_entry.child_index != NO_CHILD {
+                        num_children += 1;
+                        result[peek_entry.child_index].parents.push(new_index);
+                    }
                 } else {
                     debug_assert!(peek_v < last);
                     // Push the range from peek_entry.v to v.
@@ -228,17 +257,28 @@ impl Graph {
                         state: Default::default(),
                     });
 
+                    // Markers still split the txn here, in case this is where the walk stops.
                     new_index += 1;
-                    num_children = 2;
-                    result[peek_entry.child_index].parents.push(new_index);
+                    num_children = 1;
+                    if peek_entry.child_index != NO_CHILD {
+                        num_children += 1;
+                        result[peek_entry.child_index].parents.push(new_index);
+                    }
                     last = peek_v;
                 }
 
                 if peek_entry.flag != flag { flag = DiffFlag::Shared; }
             }
 
-            // Emit the remainder of this txn.
             debug_assert_eq!(result.len(), new_index);
+            if flag == DiffFlag::Shared && queue.is_empty() {
+                // The rest of this txn (and everything before it) is shared.
+                base = Frontier::new_1(last);
+                result.push(Self::root_entry(num_children));
+                break;
+            }
+
+            // Emit the remainder of this txn.
             result.push(ConflictGraphEntry {
                 parents: smallvec![],
                 span: (containing_txn.span.start..last+1).into(),
@@ -253,15 +293,10 @@ impl Graph {
             });
         };
 
-        assert!(!root_children.is_empty());
-        if root_children.as_ref() != &[result.len() - 1] {
+        // If we stopped at a shared version, the last entry is already the root.
+        if !root_children.is_empty() && root_children.as_ref() != &[result.len() - 1] {
             let root_index = result.len();
-            result.push(ConflictGraphEntry {
-                parents: smallvec![],
-                span: Default::default(),
-                num_children: root_children.len(),
-                state: Default::default(),
-            });
+            result.push(Self::root_entry(root_children.len()));
             for r in root_children {
                 result[r].parents.push(root_index);
             }
@@ -269,6 +304,16 @@ impl Graph {
 
         ConflictSubgraph {
             ops: result,
+            base,
+        }
+    }
+
+    fn root_entry<S: Default>(num_children: usize) -> ConflictGraphEntry<S> {
+        ConflictGraphEntry {
+            parents: smallvec![],
+            span: Default::default(),
+            num_children,
+            state: Default::default(),
         }
     }
 }
@@ -349,7 +394,33 @@ mod test {
         result.dbg_check();
 
         let plan = result.make_plan();
-        plan.simulate_plan(&graph, &[]);
+        plan.simulate_plan(&graph, result.base.as_ref());
+    }
+
+    #[test]
+    fn stops_at_shared_history() {
+        let graph = Graph::from_simple_items(&[
+            GraphEntrySimple { span: (0..10).into(), parents: Frontier::root() },
+            GraphEntrySimple { span: (10..15).into(), parents: Frontier::new_1(5) },
+        ]);
+
+        // b is a descendant of a. Only the operations after a are in the subgraph.
+        let result = graph.make_conflict_graph_between::<()>(&[5], &[8]);
+        result.dbg_check();
+        assert_eq!(result.base.as_ref(), &[5]);
+        let ops: usize = result.ops.iter().map(|e| e.span.len()).sum();
+        assert_eq!(ops, 3);
+
+        // Concurrent changes. The subgraph starts where they branched.
+        let result = graph.make_conflict_graph_between::<()>(&[9], &[14]);
+        result.dbg_check();
+        assert_eq!(result.base.as_ref(), &[5]);
+        let ops: usize = result.ops.iter().map(|e| e.span.len()).sum();
+        assert_eq!(ops, 9);
+
+        check(&graph, &[5], &[8]);
+        check(&graph, &[8], &[5]);
+        check(&graph, &[9], &[14]);
     }
 
     #[test]
@@ -397,7 +468,7 @@ mod test {
         let plan = result.make_plan();
         plan.dbg_check(true);
         plan.dbg_print();
-        plan.simulate_plan(&graph, &[]);
+        plan.simulate_plan(&graph, result.base.as_ref());
     }
 
     #[test]
@@ -441,21 +512,23 @@ mod test {
 
     #[test]
     fn fuzz_action_plans() {
-        with_random_cgs(123, (1, 100), |_i, cg, _frontiers| {
+        with_random_cgs(123, (1, 100), |_i, cg, frontiers| {
             let mut subgraph = cg.graph.make_conflict_graph_between(&[], cg.version.as_ref());
             let plan = subgraph.make_plan();
             plan.simulate_plan(&cg.graph, &[]);
 
-            // TODO:
-            // for fs in frontiers.windows(2) {
-            //     let start = fs[0].as_ref();
-            //     let merge_in = fs[1].as_ref();
-            //     let mut subgraph = cg.graph.make_conflict_graph_between(start, merge_in);
-            //     let plan = subgraph.make_plan();
-            //
-            //     // let base = cg.graph.
-            //     plan.simulate_plan(&cg.graph, start);
-            // }
+            for fs in frontiers.windows(2) {
+                let start = fs[0].as_ref();
+                let merge_in = fs[1].as_ref();
+                let mut subgraph = cg.graph.make_conflict_graph_between(start, merge_in);
+                subgraph.dbg_check();
+                let plan = subgraph.make_plan();
+
+                // The plan starts at the subgraph's base, which is in the history of both versions.
+                assert!(cg.graph.frontier_contains_frontier(start, subgraph.base.as_ref()));
+                assert!(cg.graph.frontier_contains_frontier(merge_in, subgraph.base.as_ref()));
+                plan.simulate_plan(&cg.graph, subgraph.base.as_ref());
+            }
         });
     }
 }
@@ -2,13 +2,10 @@ use std::cmp::Ordering;
 use std::ops::Range;
 use rle::{HasLength, MergableSpan, merge_items, MergeIter, SplitableSpan};
 use crate::frontier::{debug_assert_sorted, is_sorted_slice};
-use crate::list::op_iter::OpMetricsIter;
-use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
-use crate::list::operation::ListOpKind;
-use crate::listmerge2::action_plan::{MergePlan, MergePlanAction};
+use crate::listmerge2::action_plan::{ApplyAction, MergePlan, MergePlanAction};
 use crate::listmerge2::Index;
 use crate::listmerge2::yjsspan::{YjsSpan, SpanState, YjsSpanWithState};
-use crate::rle::{KVPair, RleVec};
+use crate::{DTRange, LV};
 
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -18,7 +15,7 @@ struct IndexInfo {
 }
 
 #[derive(Debug, Clone)]
-struct IndexGapBuffer {
+pub(super) struct IndexGapBuffer {
     // Gap buffer size = items.len().
     // == indexes[xx].len() at all times.
     items: Vec<YjsSpan>,
@@ -30,14 +27,13 @@ struct IndexGapBuffer {
     index_info: Vec<IndexInfo>, // Index -> length of gap_start for this index.
 }
 
-fn next_contains<I: Eq + Copy>(i: &mut usize, needle: I, haystack: &[I]) -> bool {
-    // TODO: I think this loop could correctly be an if() statement in cases where we check every
-    // item - and I think that is guaranteed in all cases in this code.
-    while *i < haystack.len() {
-        if haystack[*i] == needle { return true; }
+/// Check if the sorted haystack contains needle. This is called with increasing needles, so i keeps
+/// track of where we got up to.
+fn next_contains<I: Ord + Copy>(i: &mut usize, needle: I, haystack: &[I]) -> bool {
+    while *i < haystack.len() && haystack[*i] < needle {
         *i += 1;
     }
-    false
+    *i < haystack.len() && haystack[*i] == needle
 }
 
 impl IndexGapBuffer {
@@ -76,7 +72,7 @@ impl IndexGapBuffer {
         result
     }
 
-    fn new_with_num_indexes(num_indexes: usize) -> Self {
+    pub(super) fn new_with_num_indexes(num_indexes: usize) -> Self {
         Self::new_internal(num_indexes, 16)
         // Self::new_internal(num_indexes, 256)
     }
@@ -90,12 +86,13 @@ impl IndexGapBuffer {
     //     self.items.len()
     // }
 
-    fn dbg_check(&self) {
+    pub(super) fn dbg_check(&self) {
         let buffer_size = self.items.len();
         let num_indexes = self.index_info.len();
 
         assert_eq!(self.states.len(), buffer_size * num_indexes);
         for (i, states) in self.states.chunks_exact(buffer_size).enumerate() {
+            if !self.index_info[i].active { continue; }
 
             let start_actual_len: usize = self.items[..self.gap_start_idx].iter()
                 .zip(states[..self.gap_start_idx].iter().copied())
@@ -201,7 +198,9 @@ impl IndexGapBuffer {
         self.move_gap(split_i + 1);
 
         // Will this little short circuit actually happen much in practice?
-        if self.gap_end_idx < self.items.len() && remainder.can_append(&self.items[self.gap_end_idx]) {
+        if self.gap_end_idx < self.items.len() && remainder.can_append(&self.items[self.gap_end_idx])
+            && self.states_match(self.gap_start_idx - 1, self.gap_end_idx)
+        {
             self.items[self.gap_end_idx].prepend(remainder);
         } else {
             // We need to reinsert the remainder regardless. For that we need room:
@@ -340,16 +339,15 @@ impl IndexGapBuffer {
                 let new_gap_end = self.gap_end_idx - moved_range.len();
 
                 // The annoying part: Updating all the indexes and states.
-                let mut base = 0;
                 let items_len = self.items.len();
-                for index_info in self.index_info.iter_mut() {
+                for (index, index_info) in self.index_info.iter_mut().enumerate() {
                     if !index_info.active { continue; }
+                    let base = index * items_len;
                     let (moved_size, r2) = count_moved_size(&self.items, &self.states, moved_range.clone(), base);
                     // dbg!(moved_size);
 
                     self.states.copy_within(r2, new_gap_end+base);
                     index_info.before_gap_len -= moved_size;
-                    base += items_len;
                 }
 
                 // The easy part - move the actual items.
@@ -365,14 +363,13 @@ impl IndexGapBuffer {
                 if moved_range.is_empty() { return; } // Nothing to do!
 
                 // Update the indexes and states. Code adapted from above.
-                let mut base = 0;
                 let items_len = self.items.len();
-                for index_info in self.index_info.iter_mut() {
+                for (index, index_info) in self.index_info.iter_mut().enumerate() {
                     if !index_info.active { continue; }
+                    let base = index * items_len;
                     let (moved_size, r2) = count_moved_size(&self.items, &self.states, moved_range.clone(), base);
                     self.states.copy_within(r2, self.gap_start_idx+base);
                     index_info.before_gap_len += moved_size;
-                    base += items_len;
                 }
 
                 // Move the items themselves.
@@ -551,176 +548,166 @@ impl IndexGapBuffer {
         }
     }
 
-    fn mark_deleted_front(&mut self, i: usize, offset: usize, del_len: usize, index: Index, other_indexes: &[Index]) {
-        debug_assert!(i < self.gap_start_idx);
-        debug_assert_sorted(other_indexes);
-
-        debug_assert!(offset < self.items[i].len());
-        // if offset == self.items[i].len() {
-        //     offset = 0;
-        //     i += 1;
-        // }
-
-        let items_len = self.items.len();
-        let mut base = i;
-        let n = self.gap_start_idx - i;
-        let mut other_index_i = 0;
-
-        if offset > 0 {
-            // We need to split this item - since the second half will be marked deleted and the
-            // first half will stay with its current state.
-            if self.gap_size() == 0 { self.grow(); }
-
-            let remainder = self.items[i].truncate(offset);
-            self.items.copy_within(i+1..self.gap_start_idx, i + 2);
-            self.items[i + 1] = remainder;
+    /// The slot after i, skipping the gap.
+    fn next_slot(&self, i: usize) -> usize {
+        if i + 1 == self.gap_start_idx { self.gap_end_idx } else { i + 1 }
+    }
 
-            // And duplicate the state.
-            for (idx, info) in self.index_info.iter_mut().enumerate().rev() {
-                if info.active {
-                    if idx == index || next_contains(&mut other_index_i, idx, other_indexes) {
-                        // We can't mark things as deleted if they're in the NotInsertedYet state.
-                        // Everything in the range should already be deleted (so no change) or inserted.
-                        debug_assert!(self.states[base..base + n]
-                            .iter().copied()
-                            .all(|s| s != SpanState::NotInsertedYet));
-
-                        self.states[base + 1..base + n + 1].fill(SpanState::Deleted);
-                        info.before_gap_len -= del_len;
-                    } else {
-                        self.states.copy_within(base..base + n, base + 1);
-                    }
-                }
-                base += items_len;
+    /// Find the slot containing the named item, and the offset of the item within it.
+    fn find_lv(&self, lv: LV) -> (usize, usize) {
+        // The items we're looking for are usually near the gap, so search outwards from there.
+        let mut before = self.gap_start_idx;
+        let mut after = self.gap_end_idx;
+        loop {
+            if after < self.items.len() {
+                if self.items[after].id.contains(lv) { return (after, lv - self.items[after].id.start); }
+                after += 1;
             }
-
-            self.gap_start_idx += 1;
-        } else {
-            // Since offset is 0, we just mark the items as deleted.
-            for (idx, info) in self.index_info.iter_mut().enumerate().rev() {
-                if info.active {
-                    if idx == index || next_contains(&mut other_index_i, idx, other_indexes) {
-                        debug_assert!(self.states[base..base + n]
-                            .iter().copied()
-                            .all(|s| s != SpanState::NotInsertedYet));
-
-                        self.states[base..base + n].fill(SpanState::Deleted);
-                        info.before_gap_len -= del_len;
-                    }
-                }
-                base += items_len;
+            if before > 0 {
+                before -= 1;
+                if self.items[before].id.contains(lv) { return (before, lv - self.items[before].id.start); }
+            } else if after >= self.items.len() {
+                panic!("Item missing from list");
             }
         }
     }
 
-    fn mark_deleted_tail(&mut self, i: usize, offset: usize, index: Index, other_indexes: &[Index]) {
-        // Crimes! This is a modified copy of mark_deleted_front.
-        debug_assert!(i >= self.gap_end_idx);
-        debug_assert_sorted(other_indexes);
-        debug_assert!(offset < self.items[i].len());
+    /// The position directly after the named item, as (slot, offset). usize::MAX is the start of
+    /// the list. Positions before the gap always compare less than positions after the gap.
+    fn pos_after(&self, lv: LV) -> (usize, usize) {
+        if lv == usize::MAX {
+            return (if self.gap_start_idx == 0 { self.gap_end_idx } else { 0 }, 0);
+        }
 
-        let items_len = self.items.len();
-        let mut base = self.gap_end_idx;
-        let mut other_index_i = 0;
+        let (i, offset) = self.find_lv(lv);
+        if offset + 1 < self.items[i].len() { (i, offset + 1) } else { (self.next_slot(i), 0) }
+    }
 
-        if offset > 0 {
-            // We need to split this item - since the *first* half will be marked deleted and the
-            // second half will stay with its current state.
-            if self.gap_size() == 0 { self.grow(); }
+    /// The position of the named item. usize::MAX is the end of the list.
+    fn pos_before(&self, lv: LV) -> (usize, usize) {
+        if lv == usize::MAX { (self.items.len(), 0) } else { self.find_lv(lv) }
+    }
 
-            let remainder = self.items[i].truncate_keeping_right(offset);
-            self.items.copy_within(self.gap_end_idx..i, self.gap_end_idx - 1);
-            self.items[i - 1] = remainder;
+    /// Insert the new item at pos in the index, and mark it as inserted in other_indexes too.
+    ///
+    /// If there are concurrent items at the same location (items which aren't in the index yet),
+    /// this uses the same YjsMod / Fugue logic as listmerge's M2Tracker to decide where the item
+    /// goes. `ins_before(other)` is called to order the item relative to a concurrent sibling
+    /// (with the same parents) starting at `other`. It should return true if the new item should
+    /// be placed first.
+    pub(super) fn integrate_insert<F>(&mut self, id: DTRange, pos: usize, index: Index, other_indexes: &[Index], mut ins_before: F)
+        where F: FnMut(LV) -> bool
+    {
+        debug_assert!(is_sorted_slice::<true, _>(other_indexes));
 
-            // So item i stays as-is (though its truncated), and item i-1 is the last item which
-            // is marked as deleted.
+        // Move the gap to directly after the left origin. Then the items we scan are at the start
+        // of the tail.
+        let origin_left = if pos == 0 {
+            self.move_gap(0);
+            usize::MAX
+        } else {
+            let (i, offset) = self.find(index, pos - 1, false);
+            let origin_left = self.items[i].id.start + offset;
+            self.move_gap_and_split(i, offset + 1);
+            origin_left
+        };
 
-            // And duplicate the state.
-            let n = i - self.gap_end_idx;
-            for (idx, info) in self.index_info.iter_mut().enumerate().rev() {
-                if info.active {
-                    if idx == index || next_contains(&mut other_index_i, idx, other_indexes) {
-                        // If we're marking things as deleted, they have to first be in the inserted
-                        // state!
-                        debug_assert!(self.states[base..base + n]
-                            .iter().copied()
-                            .all(|s| s != SpanState::NotInsertedYet));
-
-                        self.states[base - 1..base + n].fill(SpanState::Deleted);
+        // The right parent is the next item which has been inserted in this index, if its a sibling
+        // of our item.
+        let right_parent = (self.gap_end_idx..self.items.len())
+            .find(|&i| self.states[self.state_idx_at(index, i)] != SpanState::NotInsertedYet)
+            .map_or(usize::MAX, |i| {
+                let item = &self.items[i];
+                if item.origin_left == origin_left { item.id.start } else { usize::MAX }
+            });
+
+        let left_pos = (self.gap_end_idx, 0);
+        let mut i = self.gap_end_idx;
+        let mut scanning = false;
+        let mut scan_start = i;
+
+        while i < self.items.len() {
+            if self.states[self.state_idx_at(index, i)] != SpanState::NotInsertedYet { break; }
+            let other = self.items[i];
+
+            match self.pos_after(other.origin_left).cmp(&left_pos) {
+                Ordering::Less => { break; }
+                Ordering::Greater => {}
+                Ordering::Equal => {
+                    if right_parent == other.origin_right {
+                        // Concurrent siblings.
+                        if ins_before(other.id.start) { break; } else { scanning = false; }
                     } else {
-                        self.states.copy_within(base..base + n + 1, base - 1);
+                        let my_right_pos = self.pos_before(right_parent);
+                        let other_right_pos = self.pos_before(other.origin_right);
+
+                        if other_right_pos < my_right_pos {
+                            if !scanning {
+                                scanning = true;
+                                scan_start = i;
+                            }
+                        } else {
+                            scanning = false;
+                        }
                     }
                 }
-                base += items_len;
             }
 
-            self.gap_end_idx -= 1;
-        } else {
-            // Since offset is 0, we just mark the items as deleted.
-            let n = i - self.gap_end_idx + 1;
-            for (idx, info) in self.index_info.iter_mut().enumerate().rev() {
-                if info.active {
-                    if idx == index || next_contains(&mut other_index_i, idx, other_indexes) {
-                        // If we're marking things as deleted, they have to first be in the inserted
-                        // state!
-                        debug_assert!(self.states[base..base + n]
-                            .iter().copied()
-                            .all(|s| s != SpanState::NotInsertedYet));
-
-                        self.states[base..base + n].fill(SpanState::Deleted);
-                    }
-                }
-                base += items_len;
-            }
+            i += 1;
         }
-    }
+        if scanning { i = scan_start; }
 
-    fn mark_deleted(&mut self, pos: usize, del_len: usize, index: Index, other_indexes: &[Index]) {
-        assert!(self.index_info[index].active);
-
-        // First find the deleted item and figure out the range of items which will be deleted.
+        // Put the new item at the end of the gap, before slot i.
+        if self.gap_size() == 0 { i += self.grow(); }
+        self.move_gap(i);
 
-        let (start_i, start_offset) = self.find(index, pos, false);
-        let len_remaining = self.items[start_i].len() - start_offset;
-        // if len_remaining >= del_count {
-        //     // Only this item is modified. Special case when there's no concurrency.
-        //     todo!()
-        // }
+        let new_i = self.gap_start_idx;
+        self.items[new_i] = YjsSpan { id, origin_left, origin_right: right_parent };
+        self.set_item_state_inserted(new_i, index, other_indexes);
+        self.gap_start_idx += 1;
+        self.add_to_gap_len(new_i, index, other_indexes, id.len());
+    }
 
-        // Now find the end. To avoid rescanning too much, I'll use the currently found item as an
-        // anchor if we can. (And because deletes are often short).
-        let end = pos + del_len;
-        let gap_pos = self.index_info[index].before_gap_len;
+    /// Mark del_len characters starting at pos in the index as deleted. The items are also marked
+    /// as deleted in other_indexes.
+    pub(super) fn mark_deleted(&mut self, pos: usize, mut del_len: usize, index: Index, other_indexes: &[Index]) {
+        assert!(self.index_info[index].active);
+        debug_assert_sorted(other_indexes);
 
-        if end <= gap_pos {
-            // Scan from i.
-            let (end_i, end_offset) = if len_remaining >= del_len {
-                (start_i, start_offset + del_len)
-            } else {
-                self.find_front(index, del_len + start_offset, true, start_i)
-            };
+        while del_len > 0 {
+            // Move the gap to just before the next item to delete. Deleted items aren't visible in
+            // the index, so the next item is always at pos.
+            let (i, offset) = self.find(index, pos, false);
+            self.move_gap_and_split(i, offset);
+
+            let mut i = self.gap_end_idx;
+            debug_assert_eq!(self.states[self.state_idx_at(index, i)], SpanState::Inserted);
+
+            let len = self.items[i].len();
+            if len > del_len {
+                // Split the item where it is, so only its start is deleted.
+                if self.gap_size() == 0 {
+                    self.grow();
+                    i = self.gap_end_idx;
+                }
 
-            // The whole edit is in the front.
-            self.move_gap_and_split(end_i, end_offset);
-            self.mark_deleted_front(start_i, start_offset, del_len, index, other_indexes);
-        } else if start_i >= self.gap_end_idx {
-            let (end_i, end_offset) = if len_remaining >= del_len {
-                (start_i, start_offset + del_len)
-            } else {
-                self.find_tail(index, del_len + start_offset, true, start_i)
-            };
+                let remainder = self.items[i].truncate(del_len);
+                self.items[i - 1] = self.items[i];
+                self.items[i] = remainder;
+                self.copy_item_state(i, i - 1);
+                self.gap_end_idx -= 1;
+                i -= 1;
+            }
 
-            // The whole edit is in the tail.
-            self.move_gap_and_split(start_i, start_offset);
-            self.mark_deleted_tail(end_i, end_offset, index, other_indexes);
-        } else {
-            // The deleted range is partially in the front and partially in the tail.
-            let (end_i, end_offset) = self.find_tail(index, end - gap_pos, true, self.gap_start_idx);
+            // The item is after the gap, so the lengths before the gap don't change.
+            let state_idx = self.state_idx_at(index, i);
+            self.states[state_idx] = SpanState::Deleted;
+            for &other in other_indexes {
+                let state_idx = self.state_idx_at(other, i);
+                self.states[state_idx] = SpanState::Deleted;
+            }
 
-            // I could do the start and end in either order here; but doing the end first means
-            // if the list grows I don't need need to adjust any indexes.
-            self.mark_deleted_tail(end_i, end_offset, index, other_indexes);
-            self.mark_deleted_front(start_i, start_offset, gap_pos - pos, index, other_indexes);
+            del_len -= len.min(del_len);
         }
     }
 
@@ -814,6 +801,23 @@ impl IndexGapBuffer {
         panic!("Content position overflowed gap_pos")
     }
 
+    /// Find the item containing the character `count` characters before the gap. This is the same
+    /// as find_front with prefer_first = false, but it scans backwards from the gap.
+    fn find_front_rev(&self, index: Index, mut count: usize) -> (usize, usize) {
+        debug_assert!(count > 0);
+        let index_start = self.start_state_idx(index);
+
+        for i in (0..self.gap_start_idx).rev() {
+            if self.states[index_start + i] != SpanState::Inserted { continue; }
+
+            let len = self.items[i].len();
+            if count <= len { return (i, len - count); }
+            count -= len;
+        }
+
+        panic!("Content position overflowed gap_pos")
+    }
+
     /// returns (index, offset).
     fn find_tail(&self, index: Index, mut count: usize, prefer_first: bool, from_i: usize) -> (usize, usize) {
         let index_start = self.start_state_idx(index);
@@ -850,8 +854,12 @@ impl IndexGapBuffer {
 
         return if content_pos < gap_pos || (prefer_first && content_pos == gap_pos) {
             // We're looking for the item in the first half of the gap buffer.
-            // TODO: Consider scanning backwards when we can.
-            self.find_front(index, content_pos, prefer_first, 0)
+            if !prefer_first && content_pos > gap_pos / 2 {
+                // Edits are usually near the gap, so scan backwards from there.
+                self.find_front_rev(index, gap_pos - content_pos)
+            } else {
+                self.find_front(index, content_pos, prefer_first, 0)
+            }
         } else {
             self.find_tail(index, content_pos - gap_pos, prefer_first, self.gap_end_idx)
         }
@@ -874,8 +882,105 @@ impl IndexGapBuffer {
     }
 }
 
+impl IndexGapBuffer {
+    /// Run the actions in a merge plan. Integrating operations needs the oplog, so apply actions
+    /// are passed to `apply`.
+    pub(super) fn run_plan<F>(&mut self, plan: &MergePlan, mut apply: F)
+        where F: FnMut(&mut Self, &ApplyAction)
+    {
+        for action in plan.actions.iter() {
+            match action {
+                MergePlanAction::Apply(apply_action) => apply(self, apply_action),
+                MergePlanAction::ClearInsertedItems => {
+                    // Clearing the list here would keep it small. But then we'd lose the states of
+                    // the items in any other indexes the caller is tracking, so the items are kept.
+                }
+                MergePlanAction::ForkIndex { src, dest } => self.fork_index(*src, *dest),
+                MergePlanAction::DropIndex(index) => {
+                    self.index_info[*index].active = false;
+                    // We don't need to clear it or anything. Just leave whatever junk was in there
+                    // before.
+                }
+                MergePlanAction::MaxIndex(index, from) => self.max_index(*index, from),
+            }
+        }
+    }
+
+    /// Copy the states of the items in src into dest, and make dest active.
+    pub(super) fn fork_index(&mut self, src: Index, dest: Index) {
+        assert!(self.index_info[src].active);
+        self.index_info[dest] = self.index_info[src];
+
+        // TODO: Benchmark this with std::ptr::copy_nonoverlapping.
+        let src_start = self.start_state_idx(src);
+        let dest_start = self.start_state_idx(dest);
+        self.states.copy_within(
+            src_start..src_start+self.gap_start_idx,
+            dest_start
+        );
+        self.states.copy_within(
+            src_start+self.gap_end_idx..src_start+self.items.len(),
+            dest_start+self.gap_end_idx
+        );
+    }
+
+    /// Set each item's state in index to the max of its state in index and the from indexes.
+    fn max_index(&mut self, index: Index, from: &[Index]) {
+        assert!(self.index_info[index].active);
+        let mut before_gap_len = self.index_info[index].before_gap_len;
+
+        // TODO: We can reverse the loop order. Might be worth benchmarking or spending some
+        // time with godbolt to figure out which is faster.
+        let start_idx = self.start_state_idx(index);
+
+        // First do the start (the bit before the gap):
+        for i in 0..self.gap_start_idx {
+            let ii = i + start_idx;
+            for &src_idx in from {
+                let from_idx = self.state_idx_at(src_idx, i);
+                let old_state = self.states[ii];
+                let merge_state = self.states[from_idx];
+                match (old_state, merge_state) {
+                    (a, b) if a == b => { continue; },
+                    (SpanState::NotInsertedYet, SpanState::Inserted) => {
+                        before_gap_len += self.items[i].len();
+                    },
+                    (SpanState::NotInsertedYet, SpanState::Deleted) => {},
+                    (SpanState::Inserted, SpanState::Deleted) => {
+                        before_gap_len -= self.items[i].len();
+                    },
+                    _ => { continue; },
+                }
+                debug_assert!(merge_state > old_state);
+                self.states[ii] = merge_state;
+            }
+        }
+
+        self.index_info[index].before_gap_len = before_gap_len;
+
+        // Then do the bit after the gap. There's probably a way to merge these loops
+        // together rather than copy+pasting+changing. But there's 2 reasons I'm not doing
+        // that:
+        // 1. They're a bit different because we don't need to update the index size in each
+        //    case.
+        // 2. This is hot code. We want the compiler to unroll these loops as much as it can
+        //    anyway, so reusing code here is a non-goal.
+        for i in self.gap_end_idx..self.items.len() {
+            let ii = i + start_idx;
+            for &src_idx in from {
+                let from_idx = self.state_idx_at(src_idx, i);
+                let old_state = self.states[ii];
+                let merge_state = self.states[from_idx];
+                if merge_state > old_state {
+                    self.states[ii] = merge_state;
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
-struct GapBufferReader<'a> {
+pub(super) struct GapBufferReader<'a> {
     buffer: &'a IndexGapBuffer,
     index: Index,
     i: usize,
@@ -921,119 +1026,6 @@ impl IndexGapBuffer {
     }
 }
 
-fn run_plan(plan: &MergePlan, op_metrics: &RleVec<KVPair<ListOpMetrics>>, ctx: &ListOperationCtx) {
-    if plan.actions.is_empty() { return; } // Nothing to do anyway.
-    if plan.indexes_used == 1 {
-        // In this case, we don't actually need to set up the gap buffer. We can just copy input ->
-        // output.
-        eprintln!("TODO: Update me to do less work!");
-    }
-
-    let mut buffer = IndexGapBuffer::new_with_num_indexes(plan.indexes_used);
-    for action in plan.actions.iter() {
-        match action {
-            MergePlanAction::Apply(apply_action) => {
-                for metrics in OpMetricsIter::new(op_metrics, ctx, apply_action.span) {
-                    match metrics.1.kind {
-                        ListOpKind::Ins => {
-
-                        }
-                        ListOpKind::Del => {
-                            // For delete operations, we're marking the state certain number of ops as
-                            // deleted
-                        }
-                    }
-                    todo!()
-                }
-            }
-            MergePlanAction::ClearInsertedItems => {
-                for (i, s) in buffer.index_info.iter_mut().enumerate() {
-                    // Not sure if this check is needed. The only active index should be index 0.
-                    assert_eq!(s.active, i == 0);
-                }
-
-                buffer.items[0] = YjsSpan::new_undiff_max();
-                buffer.states[0] = SpanState::Inserted; // [0] is the start of index 0.
-                buffer.gap_start_idx = 1;
-                buffer.gap_end_idx = buffer.items.len();
-            }
-            MergePlanAction::ForkIndex { src, dest } => {
-                assert!(buffer.index_info[*src].active);
-                buffer.index_info[*dest] = buffer.index_info[*src];
-
-                // TODO: Benchmark this with std::ptr::copy_nonoverlapping.
-                let src_start = buffer.start_state_idx(*src);
-                let dest_start = buffer.start_state_idx(*dest);
-                buffer.states.copy_within(
-                    src_start..src_start+buffer.gap_start_idx,
-                    dest_start
-                );
-                buffer.states.copy_within(
-                    src_start+buffer.gap_end_idx..src_start+buffer.items.len(),
-                    dest_start+buffer.gap_end_idx
-                );
-            }
-            MergePlanAction::DropIndex(index) => {
-                buffer.index_info[*index].active = false;
-                // We don't need to clear it or anything. Just leave whatever junk was in there
-                // before.
-            }
-            MergePlanAction::MaxIndex(index, from) => {
-                assert!(buffer.index_info[*index].active);
-                let mut before_gap_len = buffer.index_info[*index].before_gap_len;
-
-                // TODO: We can reverse the loop order. Might be worth benchmarking or spending some
-                // time with godbolt to figure out which is faster.
-                let start_idx = buffer.start_state_idx(*index);
-
-                // First do the start (the bit before the gap):
-                for i in 0..buffer.gap_start_idx {
-                    let ii = i + start_idx;
-                    for &src_idx in from {
-                        let from_idx = buffer.state_idx_at(src_idx, i);
-                        let old_state = buffer.states[ii];
-                        let merge_state = buffer.states[from_idx];
-                        match (old_state, merge_state) {
-                            (a, b) if a == b => { continue; },
-                            (SpanState::NotInsertedYet, SpanState::Inserted) => {
-                                before_gap_len += buffer.items[i].len();
-                            },
-                            (SpanState::NotInsertedYet, SpanState::Deleted) => {},
-                            (SpanState::Inserted, SpanState::Deleted) => {
-                                before_gap_len -= buffer.items[i].len();
-                            },
-                            _ => { continue; },
-                        }
-                        debug_assert!(merge_state > old_state);
-                        buffer.states[ii] = merge_state;
-                    }
-                }
-
-                buffer.index_info[*index].before_gap_len = before_gap_len;
-
-                // Then do the bit after the gap. There's probably a way to merge these loops
-                // together rather than copy+pasting+changing. But there's 2 reasons I'm not doing
-                // that:
-                // 1. They're a bit different because we don't need to update the index size in each
-                //    case.
-                // 2. This is hot code. We want the compiler to unroll these loops as much as it can
-                //    anyway, so reusing code here is a non-goal.
-                for i in buffer.gap_end_idx..buffer.items.len() {
-                    let ii = i + start_idx;
-                    for &src_idx in from {
-                        let from_idx = buffer.state_idx_at(src_idx, i);
-                        let old_state = buffer.states[ii];
-                        let merge_state = buffer.states[from_idx];
-                        if merge_state > old_state {
-                            buffer.states[ii] = merge_state;
-                        }
-                    }
-                }
-            }
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use rand::prelude::SmallRng;
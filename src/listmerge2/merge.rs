@@ -0,0 +1,299 @@
+//! This file contains the code to actually run a [`MergePlan`] on an [`IndexGapBuffer`], and apply
+//! the result to a document.
+//!
+//! To merge b into a document at version a, we run the plan for the conflict subgraph between a and
+//! b. The buffer starts with a single (huge) undifferentiated item, which stands in for the
+//! document at the subgraph's base version. Like the underwater item in listmerge, the end of this
+//! item is never visible.
+//!
+//! The plan uses its own indexes, and we add one extra index which tracks version a. It starts out
+//! the same as index 0, and every operation in the history of a is applied to it too. When the plan
+//! finishes, index 0 contains the merged version. Comparing it with the extra index tells us what
+//! to insert into and remove from the document.
+
+use std::cmp::Ordering;
+use jumprope::JumpRopeBuf;
+use smallvec::SmallVec;
+use rle::{HasLength, TrimCtx};
+use crate::causalgraph::agent_assignment::AgentAssignment;
+use crate::list::ListOpLog;
+use crate::list::merge::UNKNOWN_CHAR;
+use crate::list::op_iter::OpMetricsIter;
+use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
+use crate::list::operation::ListOpKind;
+use crate::listmerge2::action_plan::ApplyAction;
+use crate::listmerge2::index_gap_buffer::IndexGapBuffer;
+use crate::listmerge2::Index;
+use crate::listmerge2::yjsspan::SpanState::Inserted;
+use crate::rle::{KVPair, RleSpanHelpers, RleVec};
+use crate::{AgentId, DTRange, LV};
+
+#[derive(Debug)]
+struct PlanRunner<'a> {
+    aa: &'a AgentAssignment,
+    ctx: &'a ListOperationCtx,
+    ops: &'a RleVec<KVPair<ListOpMetrics>>,
+
+    /// The index tracking the version we're merging into.
+    from_index: Index,
+    /// The operations which aren't in the history of the version we're merging into. Sorted.
+    new_ops: SmallVec<[DTRange; 4]>,
+}
+
+impl<'a> PlanRunner<'a> {
+    fn apply(&self, buffer: &mut IndexGapBuffer, action: &ApplyAction) {
+        // Operations already in the version we're merging into are applied to from_index too.
+        let mut span = action.span;
+        while !span.is_empty() {
+            let next = self.new_ops.partition_point(|r| r.end <= span.start);
+            let (end, is_new) = match self.new_ops.get(next) {
+                Some(r) if r.start <= span.start => (r.end.min(span.end), true),
+                Some(r) => (r.start.min(span.end), false),
+                None => (span.end, false),
+            };
+
+            let mut other_indexes = action.update_other_indexes.clone();
+            // from_index is the highest index, so the list stays sorted.
+            if !is_new { other_indexes.push(self.from_index); }
+
+            self.apply_range(buffer, (span.start..end).into(), action.index, &other_indexes);
+            span.start = end;
+        }
+    }
+
+    fn apply_range(&self, buffer: &mut IndexGapBuffer, range: DTRange, index: Index, other_indexes: &[Index]) {
+        let mut iter = OpMetricsIter::new(self.ops, self.ctx, range);
+        while let Some(mut pair) = iter.next() {
+            loop {
+                let span = self.aa.local_span_to_agent_span(pair.span());
+                let remainder = pair.trim_ctx(span.len(), iter.ctx);
+
+                match pair.1.kind {
+                    ListOpKind::Ins => self.insert(buffer, span.agent, &pair, index, other_indexes),
+                    // Reversed deletes remove the same set of items, so we can ignore the direction
+                    // here.
+                    ListOpKind::Del => buffer.mark_deleted(pair.1.start(), pair.len(), index, other_indexes),
+                }
+
+                if let Some(r) = remainder {
+                    pair = r;
+                } else { break; }
+            }
+        }
+    }
+
+    fn insert(&self, buffer: &mut IndexGapBuffer, agent: AgentId, pair: &KVPair<ListOpMetrics>, index: Index, other_indexes: &[Index]) {
+        let op = &pair.1;
+        if !op.loc.fwd && pair.len() > 1 {
+            // Each item in a reversed insert goes in at the same position, in front of the item
+            // before it. Integrating the items one at a time gives each one the right origin.
+            let mut pair = pair.clone();
+            while let Some(rest) = pair.trim_ctx(1, self.ctx) {
+                self.insert(buffer, agent, &pair, index, other_indexes);
+                pair = rest;
+            }
+            self.insert(buffer, agent, &pair, index, other_indexes);
+            return;
+        }
+
+        let lv_span = pair.span();
+        let aa = self.aa;
+        buffer.integrate_insert(lv_span, op.start(), index, other_indexes, |other| {
+            // Concurrent items. Order by agent names, then sequence numbers.
+            let my_name = aa.get_agent_name(agent);
+            let (other_agent, other_seq) = aa.local_to_agent_version(other);
+            let other_name = aa.get_agent_name(other_agent);
+
+            match my_name.cmp(other_name) {
+                Ordering::Less => true,
+                Ordering::Equal => aa.local_to_agent_version(lv_span.start).1 < other_seq,
+                Ordering::Greater => false,
+            }
+        });
+    }
+
+    /// Update the content (at the version tracked by from_index) to the merged version in index 0.
+    fn update_content(&self, buffer: &IndexGapBuffer, content: &mut JumpRopeBuf) {
+        let mut pos = 0;
+
+        for ((item, from_state), (_, state)) in buffer.iter(self.from_index).zip(buffer.iter(0)) {
+            let len = item.len();
+            match (from_state == Inserted, state == Inserted) {
+                (true, true) => { pos += len; }
+                (true, false) => { content.remove(pos..pos + len); }
+                (false, true) => {
+                    // Undifferentiated items are always in the document we started with, so this
+                    // is a newly merged insert.
+                    let mut new_content = String::new();
+                    let mut iter = OpMetricsIter::new(self.ops, self.ctx, item.id);
+                    while let Some(pair) = iter.next() {
+                        if let Some(c) = iter.get_content(&pair) {
+                            new_content.push_str(c);
+                        } else {
+                            new_content.extend(std::iter::repeat_n(UNKNOWN_CHAR, pair.len()));
+                        }
+                    }
+                    content.insert(pos, &new_content);
+                    pos += len;
+                }
+                (false, false) => {}
+            }
+        }
+    }
+}
+
+impl ListOpLog {
+    /// Merge the changes in `merging` into the content of a document at version `from`, using the
+    /// listmerge2 engine.
+    ///
+    /// The merge plan only covers the operations since the last version shared by `from` and
+    /// `merging`, so that version (and everything it depends on) must be in the oplog.
+    pub(crate) fn merge_content_with_plan(&self, content: &mut JumpRopeBuf, from: &[LV], merging: &[LV]) {
+        let mut subgraph = self.cg.graph.make_conflict_graph_between(from, merging);
+        let plan = subgraph.make_plan();
+        if plan.actions.is_empty() { return; }
+
+        let from_index = plan.indexes_used;
+        let mut buffer = IndexGapBuffer::new_with_num_indexes(plan.indexes_used + 1);
+        buffer.fork_index(0, from_index);
+
+        let runner = PlanRunner {
+            aa: &self.cg.agent_assignment,
+            ctx: &self.operation_ctx,
+            ops: &self.operations,
+            from_index,
+            new_ops: self.cg.graph.diff(from, merging).1,
+        };
+        buffer.run_plan(&plan, |buffer, action| runner.apply(buffer, action));
+        if cfg!(debug_assertions) { buffer.dbg_check(); }
+        runner.update_content(&buffer, content);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use crate::list::{ListBranch, ListOpLog};
+    use crate::list::operation::{ListOpKind, TextOperation};
+    use crate::rev_range::RangeRev;
+    use crate::list_fuzzer_tools::{random_oplog, random_version};
+    use crate::LV;
+
+    /// Check listmerge2 produces the same result as listmerge when merging `merging` into a branch
+    /// at `from`.
+    fn check_merge(oplog: &ListOpLog, from: &[LV], merging: &[LV]) {
+        let mut expected = ListBranch::new_at_local_version(oplog, from);
+        let mut actual = expected.clone();
+        expected.merge_with_xf_ops(oplog, merging);
+        actual.merge_with_plan(oplog, merging);
+
+        assert_eq!(expected.content(), actual.content());
+        assert_eq!(expected.local_frontier_ref(), actual.local_frontier_ref());
+    }
+
+    #[test]
+    fn concurrent_inserts() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let a = oplog.add_insert_at(seph, &[], 0, "aaa");
+        let b = oplog.add_insert_at(mike, &[], 0, "bbb");
+        let c = oplog.add_insert_at(seph, &[a, b], 3, "ccc");
+        oplog.add_delete_at(mike, &[b], 1..2);
+
+        let mut branch = ListBranch::new();
+        branch.merge_with_plan(&oplog, &[c]);
+        assert_eq!(branch.content().to_string(), "bbbcccaaa");
+        branch.merge_with_plan(&oplog, oplog.local_frontier_ref());
+        assert_eq!(branch.content(), oplog.checkout_tip().content());
+
+        check_merge(&oplog, &[], oplog.local_frontier_ref());
+        check_merge(&oplog, &[a], &[b]);
+        check_merge(&oplog, &[b], &[a]);
+        check_merge(&oplog, &[a], &[c]);
+        check_merge(&oplog, &[c], oplog.local_frontier_ref());
+    }
+
+    #[test]
+    fn reversed_inserts() {
+        // Local edits never produce reversed inserts, but they can still show up in an oplog.
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let a = oplog.add_insert_at(seph, &[], 0, "xy");
+        let b = oplog.add_operations_at(mike, &[a], &[TextOperation {
+            loc: RangeRev { span: (1..4).into(), fwd: false },
+            kind: ListOpKind::Ins,
+            content: Some("abc".into()),
+        }]);
+        let c = oplog.add_insert_at(seph, &[a], 1, "zz");
+
+        // The same edits, typed one character at a time.
+        let mut expected_oplog = ListOpLog::new();
+        let seph = expected_oplog.get_or_create_agent_id("seph");
+        let mike = expected_oplog.get_or_create_agent_id("mike");
+        let mut v = expected_oplog.add_insert_at(seph, &[], 0, "xy");
+        for ch in ["a", "b", "c"] {
+            v = expected_oplog.add_insert_at(mike, &[v], 1, ch);
+        }
+        expected_oplog.add_insert_at(seph, &[a], 1, "zz");
+
+        let mut branch = ListBranch::new();
+        branch.merge_with_plan(&oplog, &[b]);
+        assert_eq!(branch.content().to_string(), "xcbay");
+        branch.merge_with_plan(&oplog, &[c]);
+        assert_eq!(branch.content(), expected_oplog.checkout_tip().content());
+
+        let mut branch = ListBranch::new_at_local_version(&oplog, &[c]);
+        branch.merge_with_plan(&oplog, &[b]);
+        assert_eq!(branch.content(), expected_oplog.checkout_tip().content());
+    }
+
+    #[test]
+    fn merges_into_pruned_oplogs() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "hi there");
+        let a = oplog.add_insert_at(seph, &[base], 0, "AA");
+        let b = oplog.add_delete_at(mike, &[base], 2..3);
+        let pruned = oplog.prune_before(&[base]);
+        assert_eq!(pruned.as_ref(), &[base]);
+
+        check_merge(&oplog, &[a], &[b]);
+        check_merge(&oplog, &[b], oplog.local_frontier_ref());
+
+        // Branches from before the prune point start from the snapshot.
+        let mut branch = ListBranch::new();
+        branch.merge_with_plan(&oplog, oplog.local_frontier_ref());
+        assert_eq!(branch.content(), oplog.checkout_tip().content());
+    }
+
+    fn fuzz_equivalence(seed: u64) {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let oplog = random_oplog(seed, 100);
+
+        check_merge(&oplog, &[], oplog.local_frontier_ref());
+        for _ in 0..10 {
+            let from = random_version(&oplog, &mut rng);
+            let merging = random_version(&oplog, &mut rng);
+            check_merge(&oplog, from.as_ref(), merging.as_ref());
+        }
+    }
+
+    #[test]
+    fn fuzz_equivalence_once() {
+        for seed in 0..20 {
+            fuzz_equivalence(seed);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn fuzz_equivalence_forever() {
+        for seed in 0.. {
+            if seed % 100 == 0 { println!("Iteration {}", seed); }
+            fuzz_equivalence(seed);
+        }
+    }
+}
@@ -6,12 +6,13 @@ mod dot;
 mod index_gap_buffer;
 mod yjsspan;
 mod conflict_subgraph;
+mod merge;
 
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use smallvec::{SmallVec, smallvec};
 use rle::SplitableSpan;
-use crate::{DTRange, LV};
+use crate::{DTRange, Frontier, LV};
 
 type Index = usize;
 
@@ -28,6 +29,8 @@ struct ConflictGraphEntry<S: Default = ()> {
 #[derive(Debug, Clone)]
 pub(super) struct ConflictSubgraph<S: Default = ()> {
     ops: Vec<ConflictGraphEntry<S>>,
+    /// The version at the root of the subgraph. Everything before this is shared history.
+    base: Frontier,
     // last: usize,
 }
 
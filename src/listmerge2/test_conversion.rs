@@ -274,6 +274,7 @@ impl Graph {
             }
         };
         ConflictSubgraph {
+            base: Frontier::root(),
             ops: result,
             // last,
         }
@@ -125,7 +125,9 @@ impl MergableSpan for YjsSpan {
 
     fn can_append(&self, other: &Self) -> bool {
         match (self.is_undiff(), other.is_undiff()) {
-            (true, true) => true,
+            // Undifferentiated items are only merged back together with their neighbours. The
+            // merge plan runner uses their IDs as origins, so they need to stay unique.
+            (true, true) => self.id.end == other.id.start,
             (false, false) => {
                 self.id.can_append(&other.id)
                     && other.origin_left == other.id.start - 1
@@ -147,7 +149,8 @@ impl MergableSpan for YjsSpan {
     fn prepend(&mut self, other: Self) {
         debug_assert!(other.can_append(self));
         if self.is_undiff() {
-            self.id.end += other.len();
+            self.id.start -= other.len();
+            self.origin_left = other.origin_left;
         } else {
             self.id.prepend(other.id);
             self.origin_left = other.origin_left;
@@ -1,5 +1,5 @@
 use std::collections::{BTreeMap, BTreeSet};
-use smallvec::smallvec;
+use smallvec::{smallvec, SmallVec};
 use std::cmp::Ordering;
 use jumprope::JumpRopeBuf;
 use smartstring::alias::String as SmartString;
@@ -9,16 +9,20 @@ use serde::{Serialize, Serializer};
 
 use rle::{HasLength, SplitableSpanCtx};
 use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
-use crate::{AgentId, CRDTKind, CreateValue, DTRange, DTValue, OpLog, LV, LVKey, RegisterInfo, RegisterValue, ROOT_CRDT_ID, SerializedOps, ValPair};
+use crate::{AgentId, CollectionOp, CRDTKind, CreateValue, RemoteCollectionOp, DTRange, DTValue, OpLog, LV, LVKey, RegisterInfo, RegisterValue, ROOT_CRDT_ID, SerializedOps, ValPair, FormattedSpan, MarkAnchor, MarkExpand, MarkOp, Primitive, RemoteMarkAnchor, RemoteMarkOp};
 use crate::encoding::bufparser::BufParser;
 use crate::encoding::cg_entry::{read_cg_entry_into_cg, write_cg_entry_iter};
 use crate::encoding::map::{ReadMap, WriteMap};
 use crate::encoding::parseerror::ParseError;
 use crate::branch::btree_range_for_crdt;
+use crate::causalgraph::graph::Graph;
 use crate::frontier::{is_sorted_iter_uniq, is_sorted_slice};
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
-use crate::list::operation::TextOperation;
+use crate::list::operation::{ListOpKind, TextOperation};
+use crate::listinfo::list_insert_op;
+use crate::rev_range::RangeRev;
 use crate::rle::{KVPair, RleSpanHelpers};
+use crate::textmarks::{anchors_for_range, formatted_spans, visible_items};
 
 #[cfg(feature = "serde")]
 impl Serialize for OpLog {
@@ -40,6 +44,114 @@ impl From<&ValPair> for RegisterValue {
     }
 }
 
+impl RegisterInfo {
+    /// Iterate over the register's current (possibly conflicting) values.
+    pub(crate) fn current_values(&self) -> impl Iterator<Item = (LV, &CreateValue)> + '_ {
+        self.supremum.iter().map(|s| {
+            let (lv, value) = &self.ops[*s];
+            (*lv, value)
+        })
+    }
+
+    /// Set the register to a new value, which supercedes all of the register's current values.
+    /// Returns the indexes of the superceded values.
+    fn local_set(&mut self, v: LV, value: CreateValue) -> SmallVec<[usize; 2]> {
+        let new_idx = self.ops.len();
+        self.ops.push((v, value));
+        std::mem::replace(&mut self.supremum, smallvec![new_idx])
+    }
+
+    /// Set the register to a value from a remote peer. The new value might be concurrent with the
+    /// register's current values. Returns the indexes of the superceded values, or None if the
+    /// operation is already known.
+    fn remote_set(&mut self, graph: &Graph, v: LV, value: CreateValue) -> Option<SmallVec<[usize; 2]>> {
+        // If the entry already contains the new op, ignore it.
+        if self.ops.binary_search_by_key(&v, |e| e.0).is_ok() {
+            return None;
+        }
+
+        if let Some(last_op) = self.ops.last() {
+            // The added operation must have a higher local version than the last version.
+            assert!(last_op.0 < v);
+        }
+
+        let new_idx = self.ops.len();
+        self.ops.push((v, value));
+
+        // The normal case is that the new operation replaces the old value. A faster implementation
+        // would special case that and fall back to the more complex version if need be.
+        let mut new_sup = smallvec![];
+        let mut superceded = smallvec![];
+
+        for s_idx in &self.supremum {
+            match graph.version_cmp(self.ops[*s_idx].0, v) {
+                None => {
+                    // Versions are concurrent. Leave the old entry in the supremum.
+                    new_sup.push(*s_idx);
+                }
+                Some(Ordering::Less) => {
+                    // The most common case. The new version dominates the old version.
+                    superceded.push(*s_idx);
+                }
+                Some(_) => {
+                    // Either the versions are equal, or the newly inserted version is earlier than
+                    // the existing version. Either way, this is an invalid operation.
+                    panic!("Invalid state");
+                }
+            }
+        }
+        // The new index is the highest, so pushing it last keeps the supremum sorted.
+        new_sup.push(new_idx);
+        self.supremum = new_sup;
+        Some(superceded)
+    }
+
+    fn dbg_check(&self, graph: &Graph, cg_len: usize, deep: bool, item_type: &mut BTreeMap<LV, CRDTKind>) {
+        // Check the supremum is sorted
+        assert!(is_sorted_slice::<true, _>(&self.supremum));
+
+        // Record the type of all the items
+        for op in &self.ops {
+            match op.1 {
+                CreateValue::Primitive(_) => {}
+                CreateValue::NewCRDT(crdt_type) => {
+                    item_type.insert(op.0, crdt_type);
+                }
+            }
+
+            assert!(op.0 < cg_len);
+        }
+
+        // Check the operations are sorted
+        assert!(is_sorted_iter_uniq(self.ops.iter().map(|(v, _)| *v)));
+
+        if deep && !self.ops.is_empty() {
+            // Check the supremum is correct.
+            let all_versions = self.ops.iter().map(|(v, _)| *v).collect::<Vec<_>>();
+            let dominators = graph.find_dominators(&all_versions);
+
+            let sup_versions = self.supremum.iter().map(|idx| self.ops[*idx].0).collect::<Vec<_>>();
+            assert_eq!(dominators.as_ref(), &sup_versions);
+        }
+    }
+}
+
+/// Mark the CRDTs stored in superceded register values as deleted. Returns the deleted containers,
+/// whose children need to be deleted too.
+fn mark_superceded(deleted_crdts: &mut BTreeSet<LVKey>, ops: &[ValPair], superceded: &[usize]) -> Vec<LV> {
+    let mut to_delete = vec![];
+    for idx in superceded {
+        let (lv, val) = &ops[*idx];
+        if let CreateValue::NewCRDT(kind) = val {
+            assert!(deleted_crdts.insert(*lv));
+            if kind.is_container() {
+                to_delete.push(*lv);
+            }
+        }
+    }
+    to_delete
+}
+
 impl OpLog {
     pub(crate) fn dbg_check(&self, deep: bool) {
         self.cg.dbg_check(deep);
@@ -51,43 +163,108 @@ impl OpLog {
         // Map operations
         let mut expected_idx_count = 0;
         for ((crdt, key), info) in self.map_keys.iter() {
-            // Check the supremum is sorted
-            assert!(is_sorted_slice::<true, _>(&info.supremum));
-
-            // Record the type of all the items
-            for op in &info.ops {
-                match op.1 {
-                    CreateValue::Primitive(_) => {}
-                    CreateValue::NewCRDT(crdt_type) => {
-                        item_type.insert(op.0, crdt_type);
-                    }
-                }
-
-                assert!(op.0 < cg_len);
-            }
-
-            // Check the operations are sorted
-            assert!(is_sorted_iter_uniq(info.ops.iter().map(|(v, _)| *v)));
+            // Map keys are only created when they're set.
+            assert!(!info.ops.is_empty());
+            info.dbg_check(&self.cg.graph, cg_len, deep, &mut item_type);
 
             // Check the index contains the correct items
-            for idx in info.supremum.iter() {
-                let v = info.ops[*idx].0;
+            for (v, _) in info.current_values() {
                 let (idx_crdt, idx_key) = self.map_index.get(&v).unwrap();
                 assert_eq!(idx_crdt, crdt);
                 assert_eq!(idx_key, key);
                 expected_idx_count += 1;
+            }
+        }
+        assert_eq!(self.map_index.len(), expected_idx_count);
 
-                if deep {
-                    // Check the supremum is correct.
-                    let all_versions = info.ops.iter().map(|(v, _)| *v).collect::<Vec<_>>();
-                    let dominators = self.cg.graph.find_dominators(&all_versions);
+        // Register operations
+        let mut expected_idx_count = 0;
+        for (crdt, info) in self.registers.iter() {
+            info.dbg_check(&self.cg.graph, cg_len, deep, &mut item_type);
 
-                    let sup_versions = info.supremum.iter().map(|idx| info.ops[*idx].0).collect::<Vec<_>>();
-                    assert_eq!(dominators.as_ref(), &sup_versions);
+            for (v, _) in info.current_values() {
+                assert_eq!(self.register_index.get(&v), Some(crdt));
+                expected_idx_count += 1;
+            }
+        }
+        assert_eq!(self.register_index.len(), expected_idx_count);
+
+        // Collection operations
+        let mut expected_idx_count = 0;
+        for (crdt, info) in self.collections.iter() {
+            // Note collections can be created by other collections, which might appear later in
+            // this loop. So the type of the collection itself is checked below.
+            assert!(is_sorted_iter_uniq(info.ops.iter().map(|(v, _)| *v)));
+
+            let mut expected_live = BTreeSet::new();
+            for (v, op) in info.ops.iter() {
+                assert!(*v < cg_len);
+                assert_eq!(self.collection_index.get(v), Some(crdt));
+                expected_idx_count += 1;
+
+                match op {
+                    CollectionOp::Insert(value) => {
+                        if let CreateValue::NewCRDT(kind) = value {
+                            item_type.insert(*v, *kind);
+                        }
+                        expected_live.insert(*v);
+                    }
+                    CollectionOp::Remove(target) => {
+                        assert!(info.item_value(*target).is_some());
+                        if deep {
+                            assert_eq!(self.cg.graph.version_cmp(*target, *v), Some(Ordering::Less));
+                        }
+                        expected_live.remove(target);
+                    }
                 }
             }
+            assert_eq!(expected_live, info.live);
         }
-        assert_eq!(self.map_index.len(), expected_idx_count);
+        assert_eq!(self.collection_index.len(), expected_idx_count);
+        for crdt in self.collections.keys() {
+            assert_eq!(*item_type.get(crdt).unwrap(), CRDTKind::Collection);
+        }
+        for crdt in self.registers.keys() {
+            assert_eq!(*item_type.get(crdt).unwrap(), CRDTKind::Register);
+        }
+
+        // List operations
+        let mut expected_idx_count = 0;
+        for (crdt, info) in self.lists.iter() {
+            assert_eq!(*item_type.get(crdt).unwrap(), CRDTKind::List);
+            assert!(is_sorted_iter_uniq(info.ops.ops.iter().map(|KVPair(v, _)| *v)));
+
+            // Every inserted item has a value.
+            let mut inserted = BTreeSet::new();
+            for KVPair(v, op) in info.ops.ops.iter() {
+                assert!(op.content_pos.is_none());
+                if op.kind == ListOpKind::Ins {
+                    inserted.extend(*v..*v + op.len());
+                }
+            }
+            assert!(inserted.iter().copied().eq(info.values.keys().copied()));
+            for (v, value) in info.values.iter() {
+                if let CreateValue::NewCRDT(kind) = value {
+                    item_type.insert(*v, *kind);
+                }
+            }
+
+            for v in info.ops.frontier.as_ref() {
+                assert!(*v < cg_len);
+                assert_eq!(self.list_index.get(v), Some(crdt));
+                expected_idx_count += 1;
+            }
+
+            if deep {
+                let all_versions = info.ops.ops.iter().map(|op| op.last()).collect::<Vec<_>>();
+                let dominators = self.cg.graph.find_dominators(&all_versions);
+                assert_eq!(dominators, info.ops.frontier);
+
+                // Check the cached items are correct.
+                assert_eq!(info.items, info.items_at(&self.cg, info.ops.frontier.as_ref()));
+            }
+        }
+        assert_eq!(self.list_index.len(), expected_idx_count);
 
         // And now text operations
         let mut expected_idx_count = 0;
@@ -115,37 +292,83 @@ impl OpLog {
         }
         assert_eq!(self.text_index.len(), expected_idx_count);
 
+        // Text formatting marks
+        let mut expected_idx_count = 0;
+        for (crdt, marks) in self.text_marks.iter() {
+            assert!(!marks.is_empty());
+            assert!(is_sorted_iter_uniq(marks.iter().map(|(v, _)| *v)));
+            let info = &self.texts[crdt];
+
+            for (v, op) in marks.iter() {
+                assert!(*v < cg_len);
+                assert_eq!(self.mark_index.get(v), Some(crdt));
+                expected_idx_count += 1;
+
+                for item in [op.start.item(), op.end.item()].into_iter().flatten() {
+                    assert!(info.is_insert(item));
+                    if deep {
+                        assert_eq!(self.cg.graph.version_cmp(item, *v), Some(Ordering::Less));
+                    }
+                }
+            }
+        }
+        assert_eq!(self.mark_index.len(), expected_idx_count);
+
         if deep {
             // Find all the CRDTs which have been created then later overwritten or deleted.
             let mut deleted_crdts = BTreeSet::new();
             let mut directly_overwritten_maps = vec![];
-            for reg_info in self.map_keys.values() {
+            for reg_info in self.map_keys.values().chain(self.registers.values()) {
                 for (idx, (lv, val)) in reg_info.ops.iter().enumerate() {
                     if !reg_info.supremum.contains(&idx) {
                         if let CreateValue::NewCRDT(kind) = val {
                             deleted_crdts.insert(*lv);
 
-                            if *kind == CRDTKind::Map {
+                            if kind.is_container() {
                                 directly_overwritten_maps.push(*lv);
                             }
                         }
                     }
                 }
             }
+            for info in self.lists.values() {
+                let live: BTreeSet<LV> = info.items.iter().copied().collect();
+                for (v, value) in info.values.iter() {
+                    if let CreateValue::NewCRDT(kind) = value {
+                        if !live.contains(v) {
+                            deleted_crdts.insert(*v);
+
+                            if kind.is_container() {
+                                directly_overwritten_maps.push(*v);
+                            }
+                        }
+                    }
+                }
+            }
+            for info in self.collections.values() {
+                for (v, op) in info.ops.iter() {
+                    if let CollectionOp::Insert(CreateValue::NewCRDT(kind)) = op {
+                        if !info.live.contains(v) {
+                            deleted_crdts.insert(*v);
+
+                            if kind.is_container() {
+                                directly_overwritten_maps.push(*v);
+                            }
+                        }
+                    }
+                }
+            }
 
             // Now find everything that has been removed indirectly
             let mut queue = directly_overwritten_maps;
             while let Some(crdt_id) = queue.pop() {
-                for (_, info) in btree_range_for_crdt(&self.map_keys, crdt_id) {
-                    for s in info.supremum.iter() {
-                        let (lv, create_val) = &info.ops[*s];
-                        if let CreateValue::NewCRDT(kind) = create_val {
-                            assert!(deleted_crdts.insert(*lv));
-
-                            if *kind == CRDTKind::Map {
-                                // Go through this CRDT's children.
-                                queue.push(*lv);
-                            }
+                for (lv, create_val) in self.children_of(crdt_id) {
+                    if let CreateValue::NewCRDT(kind) = create_val {
+                        assert!(deleted_crdts.insert(lv));
+
+                        if kind.is_container() {
+                            // Go through this CRDT's children.
+                            queue.push(lv);
                         }
                     }
                 }
@@ -195,31 +418,74 @@ impl OpLog {
         Default::default()
     }
 
+    /// Create a new agent for a local editing session, with a random name. See
+    /// [`CausalGraph::new_session_agent`].
+    #[cfg(feature = "random_agents")]
+    pub fn new_session_agent(&mut self) -> AgentId {
+        self.cg.new_session_agent()
+    }
+
+    /// Returns true if the CRDT is a map. (Including the root map.)
+    pub(crate) fn is_map(&self, crdt: LVKey) -> bool {
+        crdt == ROOT_CRDT_ID || self.maps.contains(&crdt)
+    }
+
     // The way I'm using this below, it should be idempotent.
     fn create_child_crdt(&mut self, v: LV, kind: CRDTKind) {
         match kind {
-            CRDTKind::Map => {}
-            CRDTKind::Register => {}
-            CRDTKind::Collection => {}
+            CRDTKind::Map => {
+                self.maps.insert(v);
+            }
+            CRDTKind::Register => {
+                self.registers.entry(v).or_default();
+            }
+            CRDTKind::Collection => {
+                self.collections.entry(v).or_default();
+            }
             CRDTKind::Text => {
                 self.texts.entry(v).or_default();
             }
+            CRDTKind::List => {
+                self.lists.entry(v).or_default();
+            }
         }
     }
 
+    /// Iterate over the current values directly inside a container CRDT.
+    fn children_of(&self, crdt: LVKey) -> impl Iterator<Item = (LV, &CreateValue)> + '_ {
+        let map_children = btree_range_for_crdt(&self.map_keys, crdt)
+            .flat_map(|(_, info)| info.current_values());
+
+        let register_children = self.registers.get(&crdt)
+            .into_iter()
+            .flat_map(|info| info.current_values());
+
+        let collection_children = self.collections.get(&crdt)
+            .into_iter()
+            .flat_map(|info| info.live_items());
+
+        let list_children = self.lists.get(&crdt)
+            .into_iter()
+            .flat_map(|info| info.live_items());
+
+        map_children.chain(register_children).chain(collection_children).chain(list_children)
+    }
+
     fn recursive_mark_deleted_inner(&mut self, mut to_delete: Vec<LV>) {
         while let Some(crdt) = to_delete.pop() {
-            for (_, info) in btree_range_for_crdt(&self.map_keys, crdt) {
-                for s in info.supremum.iter() {
-                    let (lv, create_val) = &info.ops[*s];
-                    if let CreateValue::NewCRDT(kind) = create_val {
-                        assert!(self.deleted_crdts.insert(*lv));
-
-                        if *kind == CRDTKind::Map {
-                            // Go through this CRDT's children.
-                            to_delete.push(*lv);
-                        }
-                    }
+            let child_crdts: Vec<(LV, CRDTKind)> = self.children_of(crdt)
+                .filter_map(|(lv, create_val)| match create_val {
+                    CreateValue::NewCRDT(kind) => Some((lv, *kind)),
+                    CreateValue::Primitive(_) => None,
+                })
+                .collect();
+
+            for (lv, kind) in child_crdts {
+                assert!(self.deleted_crdts.insert(lv));
+
+                if kind.is_container() {
+                    // Go through this CRDT's children.
+                    to_delete.push(lv);
                 }
             }
         }
@@ -234,28 +500,15 @@ impl OpLog {
         let entry = self.map_keys.entry((crdt, key.into()))
             .or_default();
 
-        let new_idx = entry.ops.len();
+        let superceded = entry.local_set(v, value);
 
-        let mut to_delete = vec![];
         // Remove the old supremum from the index
-        for idx in &entry.supremum {
-            let (lv, val) = &entry.ops[*idx];
-            if let CreateValue::NewCRDT(kind) = val {
-                assert!(self.deleted_crdts.insert(*lv));
-                if *kind == CRDTKind::Map {
-                    to_delete.push(*lv);
-                }
-            }
-
-            self.map_index.remove(lv);
+        for idx in &superceded {
+            self.map_index.remove(&entry.ops[*idx].0);
         }
-
-        entry.supremum = smallvec![new_idx];
-        entry.ops.push((v, value));
-
         self.map_index.insert(v, (crdt, key.into()));
 
-        // dbg!((crdt, key, &to_delete));
+        let to_delete = mark_superceded(&mut self.deleted_crdts, &entry.ops, &superceded);
         self.recursive_mark_deleted_inner(to_delete);
         v
     }
@@ -269,51 +522,56 @@ impl OpLog {
         let entry = self.map_keys.entry((crdt, key.into()))
             .or_default();
 
-        // If the entry already contains the new op, ignore it.
-        if entry.ops.binary_search_by_key(&v, |e| e.0).is_ok() {
-            return;
+        let Some(superceded) = entry.remote_set(&self.cg.graph, v, value) else { return; };
+
+        // Concurrent values are left in the index.
+        for idx in &superceded {
+            self.map_index.remove(&entry.ops[*idx].0);
         }
+        self.map_index.insert(v, (crdt, key.into()));
 
-        if let Some(last_op) = entry.ops.last() {
-            // The added operation must have a higher local version than the last version.
-            assert!(last_op.0 < v);
+        let to_delete = mark_superceded(&mut self.deleted_crdts, &entry.ops, &superceded);
+        self.recursive_mark_deleted_inner(to_delete);
+    }
+
+    /// Set the value of a standalone register CRDT.
+    pub fn local_register_set(&mut self, agent: AgentId, crdt: LVKey, value: CreateValue) -> LV {
+        let v = self.cg.assign_local_op(agent, 1).start;
+        if let CreateValue::NewCRDT(kind) = value {
+            self.create_child_crdt(v, kind);
         }
 
-        let new_idx = entry.ops.len();
-        entry.ops.push((v, value));
+        let entry = self.registers.get_mut(&crdt).unwrap();
+        let superceded = entry.local_set(v, value);
 
-        // The normal case is that the new operation replaces the old value. A faster implementation
-        // would special case that and fall back to the more complex version if need be.
-        let mut new_sup = smallvec![new_idx];
-        self.map_index.insert(v, (crdt, key.into()));
-        let mut to_delete = vec![];
+        for idx in &superceded {
+            self.register_index.remove(&entry.ops[*idx].0);
+        }
+        self.register_index.insert(v, crdt);
 
-        for s_idx in &entry.supremum {
-            let (old_lv, old_val) = &entry.ops[*s_idx];
-            match self.cg.graph.version_cmp(*old_lv, v) {
-                None => {
-                    // Versions are concurrent. Leave the old entry in index.
-                    new_sup.push(*s_idx);
-                }
-                Some(Ordering::Less) => {
-                    // The most common case. The new version dominates the old version. Remove the
-                    // old (version, value) pair.
-                    if let CreateValue::NewCRDT(kind) = old_val {
-                        assert!(self.deleted_crdts.insert(*old_lv));
-                        if *kind == CRDTKind::Map {
-                            to_delete.push(*old_lv);
-                        }
-                    }
-                    self.map_index.remove(old_lv);
-                }
-                Some(_) => {
-                    // Either the versions are equal, or the newly inserted version is earlier than
-                    // the existing version. Either way, this is an invalid operation.
-                    panic!("Invalid state");
-                }
-            }
+        let to_delete = mark_superceded(&mut self.deleted_crdts, &entry.ops, &superceded);
+        self.recursive_mark_deleted_inner(to_delete);
+        v
+    }
+
+    // Like remote_map_set, this requires that the lv has already been added to the causal graph.
+    pub fn remote_register_set(&mut self, crdt: LVKey, v: LV, value: CreateValue) {
+        if let CreateValue::NewCRDT(kind) = value {
+            self.create_child_crdt(v, kind);
         }
-        entry.supremum = new_sup;
+
+        // Like map keys, the register is created if its missing. This lets register operations be
+        // merged before the operation which created the register.
+        let entry = self.registers.entry(crdt).or_default();
+
+        let Some(superceded) = entry.remote_set(&self.cg.graph, v, value) else { return; };
+
+        for idx in &superceded {
+            self.register_index.remove(&entry.ops[*idx].0);
+        }
+        self.register_index.insert(v, crdt);
+
+        let to_delete = mark_superceded(&mut self.deleted_crdts, &entry.ops, &superceded);
         self.recursive_mark_deleted_inner(to_delete);
     }
 
@@ -357,15 +615,191 @@ impl OpLog {
         }
     }
 
+    /// Add formatting to the named range of characters in a text CRDT. `expand` controls whether
+    /// text inserted at the edges of the range later will have the formatting too.
+    ///
+    /// Returns the version of the mark operation.
+    pub fn local_mark(&mut self, agent: AgentId, crdt: LVKey, range: std::ops::Range<usize>, expand: MarkExpand, key: &str, value: Primitive) -> LV {
+        let info = &self.texts[&crdt];
+        let items = visible_items(info, &self.cg, info.frontier.as_ref());
+        let (start, end) = anchors_for_range(&items, range, expand);
+
+        let v = self.cg.assign_local_op(agent, 1).start;
+        self.push_mark_op(crdt, v, MarkOp { start, end, key: key.into(), value });
+        v
+    }
+
+    /// Add a formatting mark from a remote peer. The version must already be in the causal graph,
+    /// and the anchors must name characters inserted into the text. Marks we already have are
+    /// ignored.
+    pub fn remote_mark(&mut self, crdt: LVKey, v: LV, op: MarkOp) {
+        if self.text_marks.get(&crdt).is_some_and(|marks| marks.binary_search_by_key(&v, |e| e.0).is_ok()) {
+            return;
+        }
+        self.push_mark_op(crdt, v, op);
+    }
+
+    /// Returns true if the mark's anchors name characters in the text. Remote marks need to be
+    /// checked with this before they're added.
+    pub(crate) fn mark_anchors_valid(&self, crdt: LVKey, op: &MarkOp) -> bool {
+        let Some(info) = self.texts.get(&crdt) else { return false; };
+        [op.start.item(), op.end.item()].into_iter()
+            .flatten()
+            .all(|item| info.is_insert(item))
+    }
+
+    fn push_mark_op(&mut self, crdt: LVKey, v: LV, op: MarkOp) {
+        assert!(self.mark_anchors_valid(crdt, &op), "Mark anchors must name characters in the text");
+        let marks = self.text_marks.entry(crdt).or_default();
+        if let Some((last_v, _)) = marks.last() {
+            assert!(*last_v < v);
+        }
+        marks.push((v, op));
+        self.mark_index.insert(v, crdt);
+    }
+
+    fn push_collection_op(&mut self, crdt: LVKey, v: LV, op: CollectionOp) {
+        if let CollectionOp::Insert(CreateValue::NewCRDT(kind)) = &op {
+            self.create_child_crdt(v, *kind);
+        }
+
+        let info = self.collections.get_mut(&crdt).unwrap();
+        if let Some(removed) = info.push_op(v, op) {
+            if let Some(CreateValue::NewCRDT(kind)) = info.item_value(removed) {
+                let kind = *kind;
+                // The item might already be deleted if the collection itself has been deleted.
+                if self.deleted_crdts.insert(removed) && kind.is_container() {
+                    self.recursive_mark_deleted_inner(vec![removed]);
+                }
+            }
+        }
+
+        self.collection_index.insert(v, crdt);
+    }
+
+    /// Insert a new item into a collection. The returned version is the ID of the new item.
+    pub fn local_collection_insert(&mut self, agent: AgentId, crdt: LVKey, value: CreateValue) -> LV {
+        let v = self.cg.assign_local_op(agent, 1).start;
+        self.push_collection_op(crdt, v, CollectionOp::Insert(value));
+        v
+    }
+
+    /// Remove the named item from a collection.
+    pub fn local_collection_remove(&mut self, agent: AgentId, crdt: LVKey, item: LV) -> LV {
+        let v = self.cg.assign_local_op(agent, 1).start;
+        self.push_collection_op(crdt, v, CollectionOp::Remove(item));
+        v
+    }
+
+    // Like remote_map_set, this requires that the lv has already been added to the causal graph.
+    pub fn remote_collection_op(&mut self, crdt: LVKey, v: LV, op: CollectionOp) {
+        let info = self.collections.get(&crdt).unwrap();
+        // If the collection already contains the new op, ignore it.
+        if info.ops.binary_search_by_key(&v, |e| e.0).is_ok() {
+            return;
+        }
+
+        self.push_collection_op(crdt, v, op);
+    }
+
+    /// Mark any CRDTs in items deleted from a list as deleted.
+    fn mark_list_items_deleted(&mut self, crdt: LVKey, removed: Vec<LV>) {
+        let info = &self.lists[&crdt];
+        let removed_crdts: Vec<(LV, CRDTKind)> = removed.into_iter()
+            .filter_map(|item| match info.values[&item] {
+                CreateValue::NewCRDT(kind) => Some((item, kind)),
+                CreateValue::Primitive(_) => None,
+            })
+            .collect();
+
+        for (item, kind) in removed_crdts {
+            // The item might already be deleted if the list itself has been deleted.
+            if self.deleted_crdts.insert(item) && kind.is_container() {
+                self.recursive_mark_deleted_inner(vec![item]);
+            }
+        }
+    }
+
+    fn update_list_index(&mut self, crdt: LVKey, old_frontier: &[LV]) {
+        for v in old_frontier {
+            let old_index_item = self.list_index.remove(v);
+            assert!(old_index_item.is_some());
+        }
+        for v in self.lists[&crdt].ops.frontier.as_ref() {
+            self.list_index.insert(*v, crdt);
+        }
+    }
+
+    /// Insert a new item into a list. The returned version is the ID of the new item.
+    pub fn local_list_insert(&mut self, agent: AgentId, crdt: LVKey, pos: usize, value: CreateValue) -> LV {
+        let v = self.cg.assign_local_op(agent, 1).start;
+        if let CreateValue::NewCRDT(kind) = value {
+            self.create_child_crdt(v, kind);
+        }
+
+        let entry = self.lists.get_mut(&crdt).unwrap();
+        let old_frontier = entry.ops.frontier.clone();
+        entry.local_insert(v, pos, value);
+        self.update_list_index(crdt, old_frontier.as_ref());
+        v
+    }
+
+    /// Delete the items in the named range from a list.
+    pub fn local_list_delete(&mut self, agent: AgentId, crdt: LVKey, del_range: std::ops::Range<usize>) -> DTRange {
+        let v_range = self.cg.assign_local_op(agent, del_range.len());
+
+        let entry = self.lists.get_mut(&crdt).unwrap();
+        let old_frontier = entry.ops.frontier.clone();
+        let removed = entry.local_delete(v_range, del_range.into());
+        self.update_list_index(crdt, old_frontier.as_ref());
+        self.mark_list_items_deleted(crdt, removed);
+        v_range
+    }
+
+    /// Add a list operation from a remote peer. The operation must not contain content. Instead,
+    /// inserts name the value of each inserted item in `values`. Like remote_text_op, the
+    /// operation's versions must already be in the causal graph.
+    pub(crate) fn remote_list_op(&mut self, crdt: LVKey, v_range: DTRange, op: TextOperation, values: Vec<CreateValue>) {
+        for (v, value) in v_range.iter().zip(values.iter()) {
+            if let CreateValue::NewCRDT(kind) = value {
+                self.create_child_crdt(v, *kind);
+            }
+        }
+
+        let entry = self.lists.get_mut(&crdt).unwrap();
+        let old_frontier = entry.ops.frontier.clone();
+        let removed = entry.remote_push_op(&self.cg, v_range, op, values);
+        self.update_list_index(crdt, old_frontier.as_ref());
+        self.mark_list_items_deleted(crdt, removed);
+    }
+
+    /// Insert an item into a list from a remote peer.
+    pub fn remote_list_insert(&mut self, crdt: LVKey, v: LV, pos: usize, value: CreateValue) {
+        self.remote_list_op(crdt, (v..v + 1).into(), list_insert_op(pos, 1), vec![value]);
+    }
+
+    /// Delete items in a list from a remote peer. `loc` names the deleted items, which are deleted
+    /// in reverse order if `loc` is reversed.
+    pub fn remote_list_delete(&mut self, crdt: LVKey, v_range: DTRange, loc: RangeRev) {
+        let op = TextOperation { loc, kind: ListOpKind::Del, content: None };
+        self.remote_list_op(crdt, v_range, op, vec![]);
+    }
+
     // Its quite annoying, but RegisterInfo objects store the supremum as an array of indexes. This
     // returns the active index and (if necessary) the set of indexes of conflicting values.
     pub(crate) fn tie_break_mv<'a>(&self, reg: &'a RegisterInfo) -> (usize, Option<impl Iterator<Item = usize> + 'a>) {
-        match reg.supremum.len() {
+        self.tie_break_idxes(&reg.ops, &reg.supremum)
+    }
+
+    /// Same as tie_break_mv above, but for an arbitrary set of concurrent indexes into a register's
+    /// ops list. This is used when checking out registers at historical versions.
+    pub(crate) fn tie_break_idxes<'a>(&self, ops: &[ValPair], idxes: &'a [usize]) -> (usize, Option<impl Iterator<Item = usize> + 'a>) {
+        match idxes.len() {
             0 => panic!("Internal consistency violation"),
-            1 => (reg.supremum[0], None),
+            1 => (idxes[0], None),
             _ => {
-                let active_idx = reg.supremum.iter()
-                    .map(|s| (*s, self.cg.agent_assignment.local_to_agent_version(reg.ops[*s].0)))
+                let active_idx = idxes.iter()
+                    .map(|s| (*s, self.cg.agent_assignment.local_to_agent_version(ops[*s].0)))
                     .max_by(|(_, a), (_, b)| {
                         self.cg.agent_assignment.tie_break_agent_versions(*a, *b)
                     })
@@ -373,7 +807,7 @@ impl OpLog {
 
                 (
                     active_idx,
-                    Some(reg.supremum.iter().copied().filter(move |i| *i != active_idx))
+                    Some(idxes.iter().copied().filter(move |i| *i != active_idx))
                 )
             }
         }
@@ -387,13 +821,30 @@ impl OpLog {
     }
 
     pub fn checkout_text(&self, crdt: LVKey) -> JumpRopeBuf {
+        self.checkout_text_at(crdt, self.cg.version.as_ref())
+    }
+
+    /// Check out the content of a text CRDT at some (possibly historical) version.
+    pub fn checkout_text_at(&self, crdt: LVKey, frontier: &[LV]) -> JumpRopeBuf {
         let info = self.texts.get(&crdt).unwrap();
 
         let mut result = JumpRopeBuf::new();
-        info.merge_into(&mut result, &self.cg, &[], self.cg.version.as_ref());
+        info.merge_into(&mut result, &self.cg, &[], frontier);
         result
     }
 
+    /// Check out the content of a text CRDT along with its formatting.
+    pub fn checkout_formatted_text(&self, crdt: LVKey) -> Vec<FormattedSpan> {
+        self.checkout_formatted_text_at(crdt, self.cg.version.as_ref())
+    }
+
+    /// Check out the content and formatting of a text CRDT at some (possibly historical) version.
+    pub fn checkout_formatted_text_at(&self, crdt: LVKey, frontier: &[LV]) -> Vec<FormattedSpan> {
+        let info = self.texts.get(&crdt).unwrap();
+        let marks = self.text_marks.get(&crdt).map_or(&[][..], |marks| marks.as_slice());
+        formatted_spans(info, marks, &self.cg, frontier)
+    }
+
     pub fn checkout_map(&self, crdt: LVKey) -> BTreeMap<SmartString, Box<DTValue>> {
         let empty_str: SmartString = "".into();
         // dbg!((crdt, empty_str.clone())..(crdt, empty_str));
@@ -405,23 +856,37 @@ impl OpLog {
         };
 
         iter.map(|((_, key), info)| {
-            let inner = match self.resolve_mv(info) {
-                RegisterValue::Primitive(p) => DTValue::Primitive(p),
-                RegisterValue::OwnedCRDT(kind, child_crdt) => {
-                    match kind {
-                        CRDTKind::Map => DTValue::Map(self.checkout_map(child_crdt)),
-                        CRDTKind::Text => DTValue::Text(self.checkout_text(child_crdt).to_string()),
-                        _ => unimplemented!(),
-                        // CRDTKind::Register => {}
-                        // CRDTKind::Collection => {}
-                        // CRDTKind::Text => {}
-                    }
-                }
-            };
-            (key.clone(), Box::new(inner))
+            (key.clone(), Box::new(self.checkout_value(self.resolve_mv(info))))
         }).collect()
     }
 
+    fn checkout_value(&self, value: RegisterValue) -> DTValue {
+        match value {
+            RegisterValue::Primitive(p) => DTValue::Primitive(p),
+            RegisterValue::OwnedCRDT(CRDTKind::Map, crdt) => DTValue::Map(self.checkout_map(crdt)),
+            RegisterValue::OwnedCRDT(CRDTKind::Text, crdt) => DTValue::Text(self.checkout_text(crdt).to_string()),
+            RegisterValue::OwnedCRDT(CRDTKind::Collection, crdt) => DTValue::Collection(self.checkout_collection(crdt)),
+            RegisterValue::OwnedCRDT(CRDTKind::Register, crdt) => DTValue::Register(Box::new(self.checkout_value(self.checkout_register(crdt).value))),
+            RegisterValue::OwnedCRDT(CRDTKind::List, crdt) => DTValue::List(self.checkout_list(crdt)),
+        }
+    }
+
+    /// Check out the current items in a collection, keyed by the version of each item's insert.
+    pub fn checkout_collection(&self, crdt: LVKey) -> BTreeMap<LV, Box<DTValue>> {
+        let info = self.collections.get(&crdt).unwrap();
+        info.live_items()
+            .map(|(item, value)| (item, Box::new(self.checkout_value(create_to_snapshot(item, value)))))
+            .collect()
+    }
+
+    /// Check out the current items in a list.
+    pub fn checkout_list(&self, crdt: LVKey) -> Vec<DTValue> {
+        let info = self.lists.get(&crdt).unwrap();
+        info.live_items()
+            .map(|(item, value)| self.checkout_value(create_to_snapshot(item, value)))
+            .collect()
+    }
+
     pub fn checkout(&self) -> BTreeMap<SmartString, Box<DTValue>> {
         self.checkout_map(ROOT_CRDT_ID)
     }
@@ -461,6 +926,27 @@ impl OpLog {
         } else { key }
     }
 
+    pub fn collection_at_path(&self, path: &[&str]) -> LVKey {
+        let (kind, key) = self.crdt_at_path(path);
+        if kind != CRDTKind::Collection {
+            panic!("Unexpected CRDT kind {:?}", kind);
+        } else { key }
+    }
+
+    pub fn register_at_path(&self, path: &[&str]) -> LVKey {
+        let (kind, key) = self.crdt_at_path(path);
+        if kind != CRDTKind::Register {
+            panic!("Unexpected CRDT kind {:?}", kind);
+        } else { key }
+    }
+
+    pub fn list_at_path(&self, path: &[&str]) -> LVKey {
+        let (kind, key) = self.crdt_at_path(path);
+        if kind != CRDTKind::List {
+            panic!("Unexpected CRDT kind {:?}", kind);
+        } else { key }
+    }
+
     pub fn text_changes_since(&self, text: LVKey, since_frontier: &[LV]) -> Vec<(DTRange, Option<TextOperation>)> {
         let info = self.texts.get(&text).unwrap();
         info.xf_operations_from(&self.cg, since_frontier, self.cg.version.as_ref())
@@ -496,6 +982,10 @@ impl OpLog {
         let mut cg_changes = Vec::new();
         let mut text_crdts_to_send = BTreeSet::new();
         let mut map_crdts_to_send = BTreeSet::new();
+        let mut collection_crdts_to_send = BTreeSet::new();
+        let mut register_crdts_to_send = BTreeSet::new();
+        let mut list_crdts_to_send = BTreeSet::new();
+        let mut mark_crdts_to_send = BTreeSet::new();
         for range_rev in diff_rev.iter() {
             let iter = self.cg.iter_range(*range_rev);
             write_cg_entry_iter(&mut cg_changes, iter, &mut write_map, &self.cg);
@@ -508,6 +998,22 @@ impl OpLog {
                 // dbg!(map_crdt, key);
                 map_crdts_to_send.insert((*map_crdt, key));
             }
+
+            for (_, collection_crdt) in self.collection_index.range(*range_rev) {
+                collection_crdts_to_send.insert(*collection_crdt);
+            }
+
+            for (_, register_crdt) in self.register_index.range(*range_rev) {
+                register_crdts_to_send.insert(*register_crdt);
+            }
+
+            for (_, list_crdt) in self.list_index.range(*range_rev) {
+                list_crdts_to_send.insert(*list_crdt);
+            }
+
+            for (_, text_crdt) in self.mark_index.range(*range_rev) {
+                mark_crdts_to_send.insert(*text_crdt);
+            }
         }
 
         // Serialize map operations
@@ -533,6 +1039,25 @@ impl OpLog {
             }
         }
 
+        // Serialize register operations
+        let mut register_ops = Vec::new();
+        for crdt in register_crdts_to_send {
+            let crdt_name = self.crdt_name_to_remote(crdt);
+            let entry = &self.registers[&crdt];
+            for r in diff_rev.iter() {
+                let start_idx = entry.ops
+                    .binary_search_by_key(&r.start, |e| e.0)
+                    .unwrap_or_else(|idx| idx);
+
+                for pair in &entry.ops[start_idx..] {
+                    if pair.0 >= r.end { break; }
+
+                    let rv = self.cg.agent_assignment.local_to_remote_version(pair.0);
+                    register_ops.push((crdt_name, rv, pair.1.clone()));
+                }
+            }
+        }
+
         // Serialize text operations
         let mut text_context = ListOperationCtx::new();
         let mut text_ops = Vec::new();
@@ -557,23 +1082,109 @@ impl OpLog {
             }
         }
 
+        // Serialize collection operations
+        let mut collection_ops = Vec::new();
+        for crdt in collection_crdts_to_send {
+            let crdt_name = self.crdt_name_to_remote(crdt);
+            let info = &self.collections[&crdt];
+            for r in diff_rev.iter() {
+                let start_idx = info.ops
+                    .binary_search_by_key(&r.start, |e| e.0)
+                    .unwrap_or_else(|idx| idx);
+
+                for (lv, op) in &info.ops[start_idx..] {
+                    if *lv >= r.end { break; }
+
+                    let rv = self.cg.agent_assignment.local_to_remote_version(*lv);
+                    let op_out = match op {
+                        CollectionOp::Insert(value) => RemoteCollectionOp::Insert(value.clone()),
+                        CollectionOp::Remove(target) => RemoteCollectionOp::Remove(
+                            self.cg.agent_assignment.local_to_remote_version(*target)
+                        ),
+                    };
+                    collection_ops.push((crdt_name, rv, op_out));
+                }
+            }
+        }
+
+        // Serialize list operations
+        let mut list_ops = Vec::new();
+        for crdt in list_crdts_to_send {
+            let crdt_name = self.crdt_name_to_remote(crdt);
+            let info = &self.lists[&crdt];
+            for r in diff_rev.iter() {
+                for KVPair(lv, op) in info.ops.ops.iter_range_ctx(*r, &info.ops.ctx) {
+                    let values = if op.kind == ListOpKind::Ins {
+                        (lv..lv + op.len()).map(|v| info.values[&v].clone()).collect()
+                    } else { vec![] };
+
+                    let rv = self.cg.agent_assignment.local_to_remote_version(lv);
+                    list_ops.push((crdt_name, rv, op, values));
+                }
+            }
+        }
+
+        // Serialize formatting marks
+        let mut mark_ops = Vec::new();
+        let remote_anchor = |anchor: MarkAnchor| match anchor {
+            MarkAnchor::Start => RemoteMarkAnchor::Start,
+            MarkAnchor::Before(item) => RemoteMarkAnchor::Before(self.cg.agent_assignment.local_to_remote_version(item)),
+            MarkAnchor::After(item) => RemoteMarkAnchor::After(self.cg.agent_assignment.local_to_remote_version(item)),
+            MarkAnchor::End => RemoteMarkAnchor::End,
+        };
+        for crdt in mark_crdts_to_send {
+            let crdt_name = self.crdt_name_to_remote(crdt);
+            let marks = &self.text_marks[&crdt];
+            for r in diff_rev.iter() {
+                let start_idx = marks
+                    .binary_search_by_key(&r.start, |e| e.0)
+                    .unwrap_or_else(|idx| idx);
+
+                for (lv, op) in &marks[start_idx..] {
+                    if *lv >= r.end { break; }
+
+                    let rv = self.cg.agent_assignment.local_to_remote_version(*lv);
+                    mark_ops.push((crdt_name, rv, RemoteMarkOp {
+                        start: remote_anchor(op.start),
+                        end: remote_anchor(op.end),
+                        key: op.key.clone(),
+                        value: op.value.clone(),
+                    }));
+                }
+            }
+        }
+
         SerializedOps {
             cg_changes,
             map_ops,
             text_ops,
             text_context,
+            collection_ops,
+            register_ops,
+            list_ops,
+            mark_ops,
         }
     }
 
 
-    pub fn merge_ops(&mut self, changes: SerializedOps) -> Result<DTRange, ParseError> {
+    /// Merge operations from [`ops_since`](OpLog::ops_since) into this oplog. Any operations we
+    /// already know about are ignored. If the changes are invalid, an error is returned and the
+    /// oplog is left unchanged.
+    pub fn merge_ops(&mut self, mut changes: SerializedOps) -> Result<DTRange, ParseError> {
         let mut read_map = ReadMap::new();
 
+        // If the changes are invalid, the causal graph entries we've merged are unwound before
+        // returning, so the oplog is left unchanged.
         let old_end = self.cg.len();
+        let num_known_agents = self.cg.agent_assignment.client_data.len();
+        let old_frontier = self.cg.version.clone();
 
         let mut buf = BufParser(&changes.cg_changes);
         while !buf.is_empty() {
-            read_cg_entry_into_cg(&mut buf, true, &mut self.cg, &mut read_map)?;
+            if let Err(err) = read_cg_entry_into_cg(&mut buf, true, &mut self.cg, &mut read_map) {
+                self.cg.truncate_to(old_end, num_known_agents, old_frontier);
+                return Err(err);
+            }
         }
 
         let new_end = self.cg.len();
@@ -584,6 +1195,14 @@ impl OpLog {
         // and only append new changes.
         if new_range.is_empty() { return Ok(new_range); }
 
+        // Marks are the only operations which can fail to merge. Check them all before anything
+        // is applied.
+        let Some(mark_ops) = self.remote_marks_to_local(&changes, new_range) else {
+            self.cg.truncate_to(old_end, num_known_agents, old_frontier);
+            return Err(ParseError::GenericInvalidData);
+        };
+        changes.mark_ops.clear();
+
         for (crdt_r_name, rv, key, val) in changes.map_ops {
             let lv = self.cg.agent_assignment.remote_to_local_version(rv);
             if new_range.contains(lv) {
@@ -593,6 +1212,76 @@ impl OpLog {
             }
         }
 
+        // Registers can hold CRDTs too, so (like collections) their operations are applied in order.
+        let mut register_ops = changes.register_ops.into_iter()
+            .map(|(crdt_r_name, rv, val)| {
+                let lv = self.cg.agent_assignment.remote_to_local_version(rv);
+                (lv, crdt_r_name, val)
+            })
+            .filter(|(lv, _, _)| new_range.contains(*lv))
+            .collect::<Vec<_>>();
+        register_ops.sort_unstable_by_key(|(lv, _, _)| *lv);
+
+        for (lv, crdt_r_name, val) in register_ops {
+            let crdt_id = self.remote_to_crdt_name(crdt_r_name);
+            self.remote_register_set(crdt_id, lv, val);
+        }
+
+        // Collection and list operations are merged before text operations, since they can create
+        // new text CRDTs.
+        enum ContainerOp<'a> {
+            Collection(RemoteCollectionOp<'a>),
+            List(ListOpMetrics, Vec<CreateValue>),
+        }
+
+        let mut container_ops = changes.collection_ops.into_iter()
+            .map(|(crdt_r_name, rv, op)| {
+                let lv = self.cg.agent_assignment.remote_to_local_version(rv);
+                (lv, crdt_r_name, ContainerOp::Collection(op))
+            })
+            .filter(|(lv, _, _)| new_range.contains(*lv))
+            .collect::<Vec<_>>();
+
+        let empty_ctx = ListOperationCtx::new();
+        for (crdt_r_name, rv, mut op_metrics, mut values) in changes.list_ops {
+            let lv = self.cg.agent_assignment.remote_to_local_version(rv);
+            let end = lv + op_metrics.len();
+
+            if end <= new_range.start { continue; }
+            let lv = if lv < new_range.start {
+                // Trim the new operation.
+                let trim = new_range.start - lv;
+                op_metrics.truncate_keeping_right_ctx(trim, &empty_ctx);
+                if op_metrics.kind == ListOpKind::Ins { values.drain(..trim); }
+                new_range.start
+            } else { lv };
+
+            container_ops.push((lv, crdt_r_name, ContainerOp::List(op_metrics, values)));
+        }
+
+        // Collections and lists can contain each other, so the operations need to be applied in
+        // order.
+        container_ops.sort_unstable_by_key(|(lv, _, _)| *lv);
+
+        for (lv, crdt_r_name, op) in container_ops {
+            let crdt_id = self.remote_to_crdt_name(crdt_r_name);
+            match op {
+                ContainerOp::Collection(op) => {
+                    let op = match op {
+                        RemoteCollectionOp::Insert(value) => CollectionOp::Insert(value),
+                        RemoteCollectionOp::Remove(target) => CollectionOp::Remove(
+                            self.cg.agent_assignment.remote_to_local_version(target)
+                        ),
+                    };
+                    self.remote_collection_op(crdt_id, lv, op);
+                }
+                ContainerOp::List(op_metrics, values) => {
+                    let v_range: DTRange = (lv..lv + op_metrics.len()).into();
+                    self.remote_list_op(crdt_id, v_range, op_metrics.to_operation(&empty_ctx), values);
+                }
+            }
+        }
+
         for (crdt_r_name, rv, mut op_metrics) in changes.text_ops {
             let lv = self.cg.agent_assignment.remote_to_local_version(rv);
             let mut v_range: DTRange = (lv..lv + op_metrics.len()).into();
@@ -610,9 +1299,84 @@ impl OpLog {
             self.remote_text_op(crdt_id, v_range, op);
         }
 
+        // Marks are anchored to characters in the text, so they're merged last.
+        for (crdt_id, lv, op) in mark_ops {
+            self.remote_mark(crdt_id, lv, op);
+        }
+
         Ok(new_range)
     }
 
+    /// Convert the new mark operations in `changes` to local versions. Returns None if a mark
+    /// doesn't name a text CRDT, or its anchors don't name characters in the text. The text and
+    /// its characters can be created by other operations in `changes`, which haven't been applied
+    /// yet.
+    fn remote_marks_to_local(&self, changes: &SerializedOps, new_range: DTRange) -> Option<Vec<(LVKey, LV, MarkOp)>> {
+        let local_version = |rv: RemoteVersion| self.cg.agent_assignment.remote_to_local_version(rv);
+
+        // Text CRDTs created by the new operations.
+        let is_new_text = |value: &CreateValue| matches!(value, CreateValue::NewCRDT(CRDTKind::Text));
+        let mut new_texts: BTreeSet<LV> = BTreeSet::new();
+        new_texts.extend(changes.map_ops.iter()
+            .filter(|(_, _, _, value)| is_new_text(value))
+            .map(|(_, rv, _, _)| local_version(*rv)));
+        new_texts.extend(changes.register_ops.iter()
+            .filter(|(_, _, value)| is_new_text(value))
+            .map(|(_, rv, _)| local_version(*rv)));
+        new_texts.extend(changes.collection_ops.iter()
+            .filter(|(_, _, op)| matches!(op, RemoteCollectionOp::Insert(value) if is_new_text(value)))
+            .map(|(_, rv, _)| local_version(*rv)));
+        for (_, rv, _, values) in changes.list_ops.iter() {
+            let lv = local_version(*rv);
+            new_texts.extend(values.iter().enumerate()
+                .filter(|(_, value)| is_new_text(value))
+                .map(|(i, _)| lv + i));
+        }
+
+        // Characters inserted by the new operations.
+        let new_inserts: Vec<(LVKey, DTRange)> = changes.text_ops.iter()
+            .filter(|(_, _, op)| op.kind == ListOpKind::Ins)
+            .map(|(crdt_r_name, rv, op)| {
+                let lv = local_version(*rv);
+                (self.remote_to_crdt_name(*crdt_r_name), (lv..lv + op.len()).into())
+            })
+            .collect();
+
+        let mut result = vec![];
+        for (crdt_r_name, rv, op) in changes.mark_ops.iter() {
+            let lv = local_version(*rv);
+            if !new_range.contains(lv) { continue; }
+
+            let crdt_id = self.remote_to_crdt_name(*crdt_r_name);
+            let text = self.texts.get(&crdt_id);
+            if text.is_none() && !new_texts.contains(&crdt_id) { return None; }
+
+            let local_anchor = |anchor: RemoteMarkAnchor| match anchor {
+                RemoteMarkAnchor::Start => MarkAnchor::Start,
+                RemoteMarkAnchor::Before(item) => MarkAnchor::Before(local_version(item)),
+                RemoteMarkAnchor::After(item) => MarkAnchor::After(local_version(item)),
+                RemoteMarkAnchor::End => MarkAnchor::End,
+            };
+            let op = MarkOp {
+                start: local_anchor(op.start),
+                end: local_anchor(op.end),
+                key: op.key.clone(),
+                value: op.value.clone(),
+            };
+
+            let anchors_valid = [op.start.item(), op.end.item()].into_iter()
+                .flatten()
+                .all(|item| {
+                    text.is_some_and(|info| info.is_insert(item))
+                        || new_inserts.iter().any(|(c, range)| *c == crdt_id && range.contains(item))
+                });
+            if !anchors_valid { return None; }
+
+            result.push((crdt_id, lv, op));
+        }
+        Some(result)
+    }
+
     pub fn xf_text_changes_since(&self, text_crdt: LVKey, since: &[LV]) -> Vec<(DTRange, Option<TextOperation>)> {
         let textinfo = self.texts.get(&text_crdt).unwrap();
         textinfo.xf_operations_from(&self.cg, since, textinfo.frontier.as_ref())
@@ -624,7 +1388,9 @@ impl OpLog {
 mod tests {
     #[cfg(feature = "serde")]
     use serde::{Deserialize, Serialize};
-    use crate::{CRDTKind, CreateValue, OpLog, Primitive, ROOT_CRDT_ID, SerializedOps};
+    use std::collections::BTreeMap;
+    use smartstring::alias::String as SmartString;
+    use crate::{CRDTKind, CreateValue, DTValue, FormattedSpan, MarkExpand, OpLog, Primitive, RegisterValue, RemoteMarkAnchor, ROOT_CRDT_ID, SerializedOps};
     use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
     use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
     use crate::list::operation::TextOperation;
@@ -775,8 +1541,292 @@ mod tests {
         oplog2.merge_ops(full_update).unwrap();
     }
 
+    #[test]
+    fn collections() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+
+        let set = oplog.local_map_set(seph, ROOT_CRDT_ID, "set", CreateValue::NewCRDT(CRDTKind::Collection));
+        let a = oplog.local_collection_insert(seph, set, CreateValue::Primitive(Primitive::I64(1)));
+        let b = oplog.local_collection_insert(seph, set, CreateValue::Primitive(Primitive::I64(1)));
+        let text = oplog.local_collection_insert(seph, set, CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_text_op(seph, text, TextOperation::new_insert(0, "hi"));
+        oplog.local_collection_remove(seph, set, a);
+        oplog.dbg_check(true);
+
+        assert_eq!(oplog.collection_at_path(&["set"]), set);
+        assert_eq!(oplog.checkout_collection(set), BTreeMap::from([
+            (b, Box::new(DTValue::Primitive(Primitive::I64(1)))),
+            (text, Box::new(DTValue::Text("hi".into()))),
+        ]));
+
+        // Removing an item holding a CRDT deletes the CRDT.
+        oplog.local_collection_remove(seph, set, text);
+        oplog.dbg_check(true);
+        assert!(oplog.deleted_crdts.contains(&text));
+
+        let mut oplog2 = OpLog::new();
+        oplog2.merge_ops(oplog.ops_since(&[])).unwrap();
+        oplog2.dbg_check(true);
+        assert_eq!(oplog.checkout(), oplog2.checkout());
+    }
+
+    #[test]
+    fn collection_add_wins() {
+        let mut oplog1 = OpLog::new();
+        let seph = oplog1.cg.get_or_create_agent_id("seph");
+        let set = oplog1.local_map_set(seph, ROOT_CRDT_ID, "set", CreateValue::NewCRDT(CRDTKind::Collection));
+        let item = oplog1.local_collection_insert(seph, set, CreateValue::Primitive(Primitive::Str("x".into())));
+        let inner = oplog1.local_collection_insert(seph, set, CreateValue::NewCRDT(CRDTKind::Collection));
+
+        let mut oplog2 = OpLog::new();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        let kaarina = oplog2.cg.get_or_create_agent_id("kaarina");
+
+        // Concurrently, seph removes the item and kaarina adds an equal item.
+        oplog1.local_collection_remove(seph, set, item);
+        oplog1.local_collection_remove(seph, set, inner);
+        let new_item = oplog2.local_collection_insert(kaarina, set, CreateValue::Primitive(Primitive::Str("x".into())));
+        oplog2.local_collection_insert(kaarina, inner, CreateValue::Primitive(Primitive::Nil));
+        oplog2.local_collection_remove(kaarina, set, item);
+
+        oplog1.merge_ops(oplog2.ops_since(&[])).unwrap();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog1.dbg_check(true);
+        oplog2.dbg_check(true);
+
+        // Item IDs are local versions, so they differ between peers.
+        let set1 = oplog1.collection_at_path(&["set"]);
+        assert_eq!(oplog1.checkout_collection(set1).into_values().collect::<Vec<_>>(), vec![
+            Box::new(DTValue::Primitive(Primitive::Str("x".into())))
+        ]);
+        assert_eq!(oplog2.checkout_collection(set), BTreeMap::from([
+            (new_item, Box::new(DTValue::Primitive(Primitive::Str("x".into())))),
+        ]));
+    }
+
+    #[test]
+    fn register_conflicts() {
+        let mut oplog1 = OpLog::new();
+        let seph = oplog1.cg.get_or_create_agent_id("seph");
+        let reg = oplog1.local_map_set(seph, ROOT_CRDT_ID, "reg", CreateValue::NewCRDT(CRDTKind::Register));
+        assert_eq!(oplog1.checkout_register(reg).value, RegisterValue::Primitive(Primitive::Nil));
+        let inner = oplog1.local_register_set(seph, reg, CreateValue::NewCRDT(CRDTKind::Map));
+        oplog1.local_map_set(seph, inner, "x", CreateValue::Primitive(Primitive::I64(1)));
+        oplog1.dbg_check(true);
+
+        let mut oplog2 = OpLog::new();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        let kaarina = oplog2.cg.get_or_create_agent_id("kaarina");
+
+        // Seph and kaarina both set the register concurrently.
+        oplog1.local_register_set(seph, reg, CreateValue::Primitive(Primitive::I64(10)));
+        oplog2.local_register_set(kaarina, reg, CreateValue::Primitive(Primitive::I64(20)));
+
+        oplog1.merge_ops(oplog2.ops_since(&[])).unwrap();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog1.dbg_check(true);
+        oplog2.dbg_check(true);
+        assert!(oplog1.deleted_crdts.contains(&inner));
+
+        // Both peers pick the same winner, and report the other value as a conflict.
+        // (The versions of each value are local versions, so they differ between peers.)
+        let state = oplog1.checkout_register(reg);
+        let state2 = oplog2.checkout_register(reg);
+        assert_eq!(state.value, state2.value);
+        assert_eq!(state.conflicts_with[0].1, state2.conflicts_with[0].1);
+        assert_eq!(state.conflicts_with.len(), 1);
+        let values = [&state.value, &state.conflicts_with[0].1];
+        assert!(values.contains(&&RegisterValue::Primitive(Primitive::I64(10))));
+        assert!(values.contains(&&RegisterValue::Primitive(Primitive::I64(20))));
+        assert_eq!(oplog1.checkout(), oplog2.checkout());
+
+        // Setting the register again resolves the conflict.
+        oplog1.local_register_set(seph, oplog1.register_at_path(&["reg"]), CreateValue::Primitive(Primitive::Bool(true)));
+        oplog1.dbg_check(true);
+        assert!(oplog1.checkout_register(reg).conflicts_with.is_empty());
+        assert_eq!(*oplog1.checkout()["reg"], DTValue::Register(Box::new(DTValue::Primitive(Primitive::Bool(true)))));
+    }
+
+    #[test]
+    fn lists() {
+        let mut oplog1 = OpLog::new();
+        let seph = oplog1.cg.get_or_create_agent_id("seph");
+        let list = oplog1.local_map_set(seph, ROOT_CRDT_ID, "list", CreateValue::NewCRDT(CRDTKind::List));
+        oplog1.local_list_insert(seph, list, 0, CreateValue::Primitive(Primitive::I64(1)));
+        oplog1.local_list_insert(seph, list, 1, CreateValue::Primitive(Primitive::I64(3)));
+        let inner = oplog1.local_list_insert(seph, list, 1, CreateValue::NewCRDT(CRDTKind::Map));
+        oplog1.local_map_set(seph, inner, "x", CreateValue::Primitive(Primitive::I64(2)));
+        oplog1.dbg_check(true);
+        assert_eq!(oplog1.list_at_path(&["list"]), list);
+        assert_eq!(oplog1.checkout_list(list).len(), 3);
+
+        let mut oplog2 = OpLog::new();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog2.dbg_check(true);
+        assert_eq!(oplog1.checkout(), oplog2.checkout());
+        let kaarina = oplog2.cg.get_or_create_agent_id("kaarina");
+
+        // Seph deletes the map while kaarina concurrently inserts at the start and the end.
+        oplog1.local_list_delete(seph, list, 1..2);
+        assert!(oplog1.deleted_crdts.contains(&inner));
+        oplog2.local_list_insert(kaarina, list, 0, CreateValue::Primitive(Primitive::I64(0)));
+        oplog2.local_list_insert(kaarina, list, 4, CreateValue::Primitive(Primitive::I64(4)));
+
+        oplog1.merge_ops(oplog2.ops_since(&[])).unwrap();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog1.dbg_check(true);
+        oplog2.dbg_check(true);
+        assert!(oplog2.deleted_crdts.contains(&inner));
+        assert_eq!(oplog1.checkout(), oplog2.checkout());
+
+        let expected: Vec<DTValue> = [0, 1, 3, 4].into_iter()
+            .map(|n| DTValue::Primitive(Primitive::I64(n)))
+            .collect();
+        assert_eq!(oplog1.checkout_list(list), expected);
+        assert_eq!(*oplog1.checkout()["list"], DTValue::List(expected));
+    }
+
+    fn spans(oplog: &OpLog, crdt: usize) -> Vec<(String, Vec<(SmartString, Primitive)>)> {
+        oplog.checkout_formatted_text(crdt).into_iter()
+            .map(|FormattedSpan { text, marks }| (text, marks.into_iter().collect()))
+            .collect()
+    }
+
+    #[test]
+    fn invalid_mark_leaves_oplog_unchanged() {
+        let mut oplog1 = OpLog::new();
+        let seph = oplog1.cg.get_or_create_agent_id("seph");
+        let text = oplog1.local_map_set(seph, ROOT_CRDT_ID, "text", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog1.local_text_op(seph, text, TextOperation::new_insert(0, "hello"));
+        oplog1.local_mark(seph, text, 0..5, MarkExpand::None, "bold", Primitive::Bool(true));
+
+        // Anchor the mark to the map operation, which isn't a character in the text.
+        let mut changes = oplog1.ops_since(&[]);
+        changes.mark_ops[0].2.start = RemoteMarkAnchor::Before(changes.map_ops[0].1);
 
+        let mut oplog2 = OpLog::new();
+        assert!(oplog2.merge_ops(changes).is_err());
+        assert_eq!(oplog2.cg.len(), 0);
+        assert!(oplog2.texts.is_empty());
+        assert!(oplog2.checkout().is_empty());
 
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog2.dbg_check(true);
+        assert_eq!(spans(&oplog1, text), spans(&oplog2, text));
+    }
+
+    #[test]
+    fn text_marks() {
+        let mut oplog1 = OpLog::new();
+        let seph = oplog1.cg.get_or_create_agent_id("seph");
+        let text = oplog1.local_map_set(seph, ROOT_CRDT_ID, "text", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog1.local_text_op(seph, text, TextOperation::new_insert(0, "hello world"));
+        let unformatted = oplog1.cg.version.clone();
+
+        let bold = || ("bold".into(), Primitive::Bool(true));
+        let link = || ("link".into(), Primitive::Str("x".into()));
+        oplog1.local_mark(seph, text, 0..5, MarkExpand::After, "bold", Primitive::Bool(true));
+        oplog1.local_mark(seph, text, 6..11, MarkExpand::None, "link", Primitive::Str("x".into()));
+        oplog1.dbg_check(true);
+        assert_eq!(spans(&oplog1, text), vec![
+            ("hello".into(), vec![bold()]),
+            (" ".into(), vec![]),
+            ("world".into(), vec![link()]),
+        ]);
+
+        // Bold expands to text typed at the end. Links don't.
+        oplog1.local_text_op(seph, text, TextOperation::new_insert(11, "?"));
+        oplog1.local_text_op(seph, text, TextOperation::new_insert(5, "!"));
+        assert_eq!(spans(&oplog1, text), vec![
+            ("hello!".into(), vec![bold()]),
+            (" ".into(), vec![]),
+            ("world".into(), vec![link()]),
+            ("?".into(), vec![]),
+        ]);
+        assert_eq!(oplog1.checkout_formatted_text_at(text, unformatted.as_ref()), vec![
+            FormattedSpan { text: "hello world".into(), marks: BTreeMap::new() },
+        ]);
+
+        let mut oplog2 = OpLog::new();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog2.dbg_check(true);
+        assert_eq!(spans(&oplog1, text), spans(&oplog2, text));
+        let kaarina = oplog2.cg.get_or_create_agent_id("kaarina");
+
+        // Seph colours and unbolds the start while kaarina concurrently types in the middle and
+        // colours the same text.
+        oplog1.local_mark(seph, text, 0..3, MarkExpand::None, "color", Primitive::Str("red".into()));
+        oplog1.local_mark(seph, text, 0..2, MarkExpand::None, "bold", Primitive::Nil);
+        oplog2.local_text_op(kaarina, text, TextOperation::new_insert(1, "ee"));
+        oplog2.local_mark(kaarina, text, 0..5, MarkExpand::None, "color", Primitive::Str("blue".into()));
+
+        oplog1.merge_ops(oplog2.ops_since(&[])).unwrap();
+        oplog2.merge_ops(oplog1.ops_since(&[])).unwrap();
+        oplog1.dbg_check(true);
+        oplog2.dbg_check(true);
+        let result = spans(&oplog1, text);
+        assert_eq!(result, spans(&oplog2, text));
+        assert_eq!(oplog1.checkout_text(text).to_string(), "heeello! world?");
+
+        // The concurrently inserted text ended up inside seph's marks, so the whole range is
+        // coloured with the winning colour. Only "heee" was unbolded.
+        let winner = result[0].1.iter().find(|(k, _)| k == "color").unwrap().1.clone();
+        assert_eq!(result[0], ("heee".into(), vec![("color".into(), winner.clone())]));
+        assert_eq!(result[1], ("l".into(), vec![bold(), ("color".into(), winner)]));
+
+        // Marks survive their anchors being deleted.
+        oplog1.local_text_op(seph, text, TextOperation::new_delete(0..6));
+        oplog1.local_text_op(seph, text, TextOperation::new_insert(0, "yo"));
+        oplog1.dbg_check(true);
+        assert_eq!(spans(&oplog1, text)[..2], [
+            ("yo".into(), vec![]),
+            ("o!".into(), vec![bold()]),
+        ]);
+    }
+
+    #[test]
+    fn float_and_bytes_values() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "x", CreateValue::Primitive(Primitive::F64(1.5)));
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "hash", CreateValue::Primitive(Primitive::Bytes(vec![0xde, 0xad])));
+
+        let mut oplog2 = OpLog::new();
+        oplog2.merge_ops(oplog.ops_since(&[])).unwrap();
+        oplog2.dbg_check(true);
+        assert_eq!(oplog.checkout(), oplog2.checkout());
+        assert_eq!(*oplog2.checkout()["x"], DTValue::Primitive(Primitive::F64(1.5)));
+        assert_eq!(*oplog2.checkout()["hash"], DTValue::Primitive(Primitive::Bytes(vec![0xde, 0xad])));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_primitives() {
+        let values = [
+            Primitive::Nil,
+            Primitive::Bool(true),
+            Primitive::I64(-10),
+            Primitive::F64(0.25),
+            Primitive::Str("hi".into()),
+            Primitive::Bytes(vec![1, 2, 3]),
+        ];
+        for v in values {
+            let json = serde_json::to_string(&v).unwrap();
+            let result: Primitive = serde_json::from_str(&json).unwrap();
+            assert_eq!(result, v);
+        }
+
+        // JSON has no way to represent NaN or infinity, so they're written out using their bits.
+        for v in [f64::NAN, -f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let json = serde_json::to_string(&Primitive::F64(v)).unwrap();
+            assert!(json.starts_with(r#"{"F64Bits":"#));
+            assert_eq!(serde_json::from_str::<Primitive>(&json).unwrap(), Primitive::F64(v));
+        }
+        assert_eq!(serde_json::from_str::<Primitive>("null").unwrap(), Primitive::Nil);
+        assert_eq!(serde_json::from_str::<Primitive>(r#""NaN""#).unwrap(), Primitive::Str("NaN".into()));
+        assert!(serde_json::from_str::<Primitive>(r#"{"x":1}"#).is_err());
+    }
 
     #[cfg(feature = "serde")]
     #[test]
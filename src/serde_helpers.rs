@@ -133,6 +133,60 @@ impl From<DTRange> for DTRangeTuple {
 }
 
 
+/// Serialize the float in a `Primitive::F64`. JSON has no way to write NaN or infinity (serde_json
+/// writes them as null, which would read back as Nil). So non-finite floats are written as
+/// `{"F64Bits": <the float's bits>}` instead.
+pub(crate) mod primitive_f64 {
+    use std::fmt;
+    use serde::{de, Deserializer, Serializer};
+    use serde::de::{MapAccess, Visitor};
+    use serde::ser::SerializeMap;
+
+    const BITS_KEY: &str = "F64Bits";
+
+    pub(crate) fn serialize<S>(val: &f64, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        if val.is_finite() {
+            serializer.serialize_f64(*val)
+        } else {
+            let mut map = serializer.serialize_map(Some(1))?;
+            map.serialize_entry(BITS_KEY, &val.to_bits())?;
+            map.end()
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<f64, D::Error> where D: Deserializer<'de> {
+        struct F64Visitor;
+
+        impl<'de> Visitor<'de> for F64Visitor {
+            type Value = f64;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a number or {\"F64Bits\": bits}")
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<f64, E> { Ok(v) }
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<f64, E> { Ok(v as f64) }
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<f64, E> { Ok(v as f64) }
+
+            fn visit_map<V>(self, mut map: V) -> Result<f64, V::Error> where V: MapAccess<'de> {
+                let key: String = map.next_key()?
+                    .ok_or_else(|| de::Error::missing_field(BITS_KEY))?;
+                if key != BITS_KEY {
+                    return Err(de::Error::unknown_field(&key, &[BITS_KEY]));
+                }
+                let bits: u64 = map.next_value()?;
+                if map.next_key::<String>()?.is_some() {
+                    return Err(de::Error::invalid_length(2, &self));
+                }
+                Ok(f64::from_bits(bits))
+            }
+        }
+
+        deserializer.deserialize_any(F64Visitor)
+    }
+}
+
+
 
 
 // impl<'de> Deserialize<'de> for TimeSpanRev {
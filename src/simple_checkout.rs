@@ -6,38 +6,50 @@ use smartstring::alias::String as SmartString;
 pub enum SimpleVal {
     Text(String),
     Map(BTreeMap<SmartString, Box<SimpleVal>>),
+    Collection(BTreeMap<LV, Box<SimpleVal>>),
+    Register(Box<SimpleVal>),
+    List(Vec<SimpleVal>),
     Primitive(Primitive),
 }
 
 impl Branch {
+    fn simple_val_of(&self, value: &RegisterValue) -> SimpleVal {
+        match value {
+            RegisterValue::Primitive(primitive) => {
+                SimpleVal::Primitive(primitive.clone())
+            }
+            RegisterValue::OwnedCRDT(inner_kind, inner_key) => {
+                self.simple_val_at(*inner_key, *inner_kind)
+            }
+        }
+    }
+
     fn simple_val_at(&self, key: LV, kind: CRDTKind) -> SimpleVal {
         match kind {
             CRDTKind::Map => {
                 let mut map = BTreeMap::new();
                 for (key, state) in self.maps.get(&key).unwrap() {
                     // TODO: Rewrite this as an iterator map then collect().
-                    map.insert(key.clone(), Box::new(match &state.value {
-                        RegisterValue::Primitive(primitive) => {
-                            SimpleVal::Primitive(primitive.clone())
-                        }
-                        RegisterValue::OwnedCRDT(inner_kind, inner_key) => {
-                            self.simple_val_at(*inner_key, *inner_kind)
-                        }
-                    }));
+                    map.insert(key.clone(), Box::new(self.simple_val_of(&state.value)));
                 }
                 SimpleVal::Map(map)
             }
             CRDTKind::Register => {
-                // TODO
-                SimpleVal::Primitive(Primitive::Nil)
+                SimpleVal::Register(Box::new(self.simple_val_of(&self.registers.get(&key).unwrap().value)))
             }
             CRDTKind::Collection => {
-                // todo!();
-                SimpleVal::Primitive(Primitive::Nil)
+                SimpleVal::Collection(self.collections.get(&key).unwrap().iter()
+                    .map(|(item, value)| (*item, Box::new(self.simple_val_of(value))))
+                    .collect())
             }
             CRDTKind::Text => {
                 SimpleVal::Text(self.texts.get(&key).unwrap().to_string())
             }
+            CRDTKind::List => {
+                SimpleVal::List(self.lists.get(&key).unwrap().iter()
+                    .map(|(_, value)| self.simple_val_of(value))
+                    .collect())
+            }
         }
     }
 
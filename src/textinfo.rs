@@ -4,7 +4,7 @@ use crate::dtrange::DTRange;
 use crate::frontier::Frontier;
 use crate::list::op_iter::{OpMetricsWithContent, OpMetricsIter};
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
-use crate::list::operation::TextOperation;
+use crate::list::operation::{ListOpKind, TextOperation};
 use crate::LV;
 use crate::rle::KVPair;
 use crate::rle::rle_vec::RleVec;
@@ -62,6 +62,11 @@ impl TextInfo {
         self.frontier.advance_sparse(graph, v_range);
     }
 
+    /// Returns true if the named version inserted an item into this document.
+    pub(crate) fn is_insert(&self, v: LV) -> bool {
+        self.ops.find(v).is_some_and(|KVPair(_, op)| op.kind == ListOpKind::Ins)
+    }
+
     pub fn local_push_op(&mut self, op: TextOperation, v_range: DTRange) {
         self.push_op_internal(op, v_range);
         self.frontier.replace_with_1(v_range.last());
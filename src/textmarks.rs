@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use jumprope::JumpRopeBuf;
+use rle::HasLength;
+use smartstring::alias::String as SmartString;
+use crate::{CausalGraph, FormattedSpan, LV, MarkAnchor, MarkExpand, MarkOp, Primitive};
+use crate::textinfo::TextInfo;
+
+// Formatting marks are implemented using the approach from Peritext. Marks are anchored to the gaps
+// before or after specific characters, and to find out which characters a mark covers, we lay out
+// every character ever inserted into the document (including deleted characters) in document order.
+//
+// Each character in that order takes up 3 slots: the gap before it, the character itself and the
+// gap after it. The gap after one character and the gap before the next are different places - if
+// text is inserted between the two characters, it ends up between those gaps.
+
+impl MarkAnchor {
+    /// The character this anchor is attached to, if any.
+    pub(crate) fn item(self) -> Option<LV> {
+        match self {
+            MarkAnchor::Before(item) | MarkAnchor::After(item) => Some(item),
+            MarkAnchor::Start | MarkAnchor::End => None,
+        }
+    }
+}
+
+/// Find the anchors for a mark over the named range of (visible) items.
+pub(crate) fn anchors_for_range(items: &[LV], range: Range<usize>, expand: MarkExpand) -> (MarkAnchor, MarkAnchor) {
+    assert!(range.start < range.end, "Cannot mark an empty range");
+    assert!(range.end <= items.len(), "Mark range is past the end of the document");
+
+    let start = if matches!(expand, MarkExpand::Before | MarkExpand::Both) {
+        if range.start == 0 { MarkAnchor::Start } else { MarkAnchor::After(items[range.start - 1]) }
+    } else {
+        MarkAnchor::Before(items[range.start])
+    };
+
+    let end = if matches!(expand, MarkExpand::After | MarkExpand::Both) {
+        items.get(range.end).map_or(MarkAnchor::End, |item| MarkAnchor::Before(*item))
+    } else {
+        MarkAnchor::After(items[range.end - 1])
+    };
+
+    (start, end)
+}
+
+/// The visible items in a text document at the named version, in document order.
+pub(crate) fn visible_items(info: &TextInfo, cg: &CausalGraph, frontier: &[LV]) -> Vec<LV> {
+    info.items_in_order(cg, frontier).into_iter()
+        .filter(|(_, visible)| *visible)
+        .flat_map(|(range, _)| range.iter())
+        .collect()
+}
+
+/// Check out a text document with formatting at some version. Adjacent characters with the same
+/// formatting are merged into a single span.
+pub(crate) fn formatted_spans(info: &TextInfo, marks: &[(LV, MarkOp)], cg: &CausalGraph, frontier: &[LV]) -> Vec<FormattedSpan> {
+    let mut content = JumpRopeBuf::new();
+    info.merge_into(&mut content, cg, &[], frontier);
+    let content = content.to_string();
+
+    let order = info.items_in_order(cg, frontier);
+
+    // The start of each run in `order`, mapped to the position of that run.
+    let mut run_pos = BTreeMap::new();
+    let mut pos = 0;
+    for (range, _) in order.iter() {
+        run_pos.insert(range.start, pos);
+        pos += range.len();
+    }
+    let order_pos = |item: LV| -> usize {
+        let (start, pos) = run_pos.range(..=item).next_back().unwrap();
+        pos + item - start
+    };
+
+    let slot = |anchor: MarkAnchor| -> usize {
+        match anchor {
+            MarkAnchor::Start => 0,
+            MarkAnchor::Before(item) => order_pos(item) * 3 + 1,
+            MarkAnchor::After(item) => order_pos(item) * 3 + 3,
+            MarkAnchor::End => usize::MAX,
+        }
+    };
+
+    // Marks which aren't in the checked out version are ignored.
+    let marks: Vec<(LV, &MarkOp, Range<usize>)> = marks.iter()
+        .filter(|(v, _)| cg.graph.frontier_contains_version(frontier, *v))
+        .map(|(v, op)| (*v, op, slot(op.start)..slot(op.end)))
+        .collect();
+
+    // The formatting only changes at mark boundaries, so it's only recalculated when we pass one.
+    let mut boundaries: Vec<usize> = marks.iter()
+        .flat_map(|(_, _, range)| [range.start, range.end])
+        .collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+    let mut next_boundary = 0;
+
+    let formatting_at = |char_slot: usize| -> BTreeMap<SmartString, Primitive> {
+        // Find the winning mark for each key.
+        let mut by_key: BTreeMap<&SmartString, Vec<LV>> = BTreeMap::new();
+        for (v, op, range) in marks.iter() {
+            if range.contains(&char_slot) {
+                by_key.entry(&op.key).or_default().push(*v);
+            }
+        }
+
+        by_key.into_iter()
+            .filter_map(|(key, versions)| {
+                // The versions are sorted, since the marks are sorted.
+                let winner = cg.graph.find_dominators(&versions).iter()
+                    .copied()
+                    .max_by(|a, b| cg.agent_assignment.tie_break_versions(*a, *b))
+                    .unwrap();
+                let (_, op, _) = marks.iter().find(|(v, _, _)| *v == winner).unwrap();
+                (op.value != Primitive::Nil).then(|| (key.clone(), op.value.clone()))
+            })
+            .collect()
+    };
+
+    let mut result: Vec<FormattedSpan> = vec![];
+    let mut chars = content.chars();
+    for (range, visible) in order.iter() {
+        if !*visible { continue; }
+
+        for char_slot in (run_pos[&range.start]..run_pos[&range.start] + range.len()).map(|pos| pos * 3 + 2) {
+            let c = chars.next().unwrap();
+
+            let mut changed = result.is_empty();
+            while next_boundary < boundaries.len() && boundaries[next_boundary] < char_slot {
+                next_boundary += 1;
+                changed = true;
+            }
+
+            if !changed {
+                result.last_mut().unwrap().text.push(c);
+                continue;
+            }
+
+            let formatting = formatting_at(char_slot);
+            match result.last_mut() {
+                Some(span) if span.marks == formatting => span.text.push(c),
+                _ => result.push(FormattedSpan { text: c.into(), marks: formatting }),
+            }
+        }
+    }
+
+    result
+}